@@ -0,0 +1,55 @@
+#![no_main]
+
+//! Everything a port delivers goes through the same path as the acks during an upload: split
+//! into frames, decoded, and checked for a DFU response.
+
+use std::path::PathBuf;
+
+use libfuzzer_sys::fuzz_target;
+use tudelft_serial_upload::color_eyre::eyre::bail;
+use tudelft_serial_upload::color_eyre::Result;
+use tudelft_serial_upload::{Serial, Transport};
+
+/// Hands out the fuzz input, in pieces of a size picked by the input itself, and fails when it
+/// runs out instead of waiting for the read timeout.
+struct Input {
+    data: Vec<u8>,
+    piece: usize,
+}
+
+impl Transport for Input {
+    fn read_all(&mut self, buf: &mut [u8]) -> Result<()> {
+        if self.data.len() < buf.len() {
+            bail!("end of input");
+        }
+        buf.copy_from_slice(&self.data[..buf.len()]);
+        self.data.drain(..buf.len());
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.data.is_empty() {
+            bail!("end of input");
+        }
+        let n = buf.len().min(self.data.len()).min(self.piece);
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data.drain(..n);
+        Ok(n)
+    }
+
+    fn write_all(&mut self, _buf: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&piece, data)) = data.split_first() else {
+        return;
+    };
+    let input = Input {
+        data: data.to_vec(),
+        piece: piece as usize % 16 + 1,
+    };
+    let mut serial = Serial::from_transport(PathBuf::from("/dev/fuzz"), Box::new(input));
+    while serial.wait_for_ack().is_ok() {}
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tudelft_serial_upload::slip::{decode_frame, encode_frame, Header};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(frame) = decode_frame(data) else {
+        return;
+    };
+
+    // a frame like the ones the host sends comes out the same when it is encoded again
+    let header = frame.header;
+    if header.data_integrity && header == Header::for_packet(header.seq, header.len) {
+        let again = decode_frame(&encode_frame(header.seq, &frame.payload)).unwrap();
+        assert_eq!(again, frame);
+    }
+});
@@ -0,0 +1,275 @@
+//! Uploads for async programs, with the `async` feature. The serial I/O is still blocking, so it
+//! happens on a thread of its own, and the async side only waits for it without blocking the
+//! executor. Nothing here depends on a particular runtime, the events are a `Stream` of
+//! `futures-core`.
+
+use std::collections::VecDeque;
+use std::future::{poll_fn, Future};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use futures_core::Stream;
+
+use crate::config::UploadConfig;
+use crate::progress::{ProgressEvent, ProgressSink};
+use crate::report::UploadReport;
+use crate::upload::upload_file_with_config;
+use crate::PortSelector;
+
+#[derive(Default)]
+struct State {
+    events: VecDeque<ProgressEvent>,
+    result: Option<Result<UploadReport>>,
+    /// Set once the upload thread is done, after its last event.
+    finished: bool,
+    task_waker: Option<Waker>,
+    events_waker: Option<Waker>,
+}
+
+type Shared = Arc<Mutex<State>>;
+
+/// Upload a file to a connected board like [`upload_file_with_config`](crate::upload_file_with_config),
+/// from async code. The upload starts right away, on a thread of its own.
+///
+/// Returns the upload, to await its [`UploadReport`], and the [`ProgressEvents`] it goes through.
+/// Dropping the [`UploadTask`] before it finishes cancels the upload like the
+/// [cancel flag](UploadConfig::cancel_flag) does (which it sets when the config has one): the
+/// bootloader is told to stop before the next data packet, and the port is purged.
+///
+/// ```no_run
+/// # use tudelft_serial_upload::{upload_async, PortSelector, UploadConfig};
+/// # async fn run() -> tudelft_serial_upload::color_eyre::Result<()> {
+/// let config = UploadConfig::default();
+/// let (upload, mut events) = upload_async(PortSelector::AutoManufacturer, "firmware.elf", config);
+/// while let Some(event) = events.next().await {
+///     println!("{event:?}");
+/// }
+/// let report = upload.await?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn upload_async(
+    port: PortSelector,
+    file: impl Into<PathBuf>,
+    config: UploadConfig,
+) -> (UploadTask, ProgressEvents) {
+    let file = file.into();
+    let state = Shared::default();
+    let cancel = config.cancel.clone().unwrap_or_default();
+
+    let previous = config.progress.clone();
+    let events = state.clone();
+    let config = config
+        .cancel_flag(cancel.clone())
+        .on_progress(move |event| {
+            if let Some(ProgressSink(previous)) = &previous {
+                previous(event.clone());
+            }
+            let mut state = events.lock().unwrap();
+            state.events.push_back(event);
+            let waker = state.events_waker.take();
+            drop(state);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        });
+
+    let finish = state.clone();
+    let spawned = thread::Builder::new()
+        .name("upload".to_string())
+        .spawn(move || {
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                upload_file_with_config(port, &file, &config)
+            }))
+            .unwrap_or_else(|_| Err(eyre!("the upload thread panicked")));
+            finish_with(&finish, result);
+        });
+    if let Err(e) = spawned {
+        finish_with(&state, Err(eyre!("failed to start the upload thread: {e}")));
+    }
+
+    (
+        UploadTask {
+            state: state.clone(),
+            cancel,
+        },
+        ProgressEvents { state },
+    )
+}
+
+fn finish_with(state: &Shared, result: Result<UploadReport>) {
+    let mut state = state.lock().unwrap();
+    state.result = Some(result);
+    state.finished = true;
+    let wakers = [state.task_waker.take(), state.events_waker.take()];
+    drop(state);
+    for waker in wakers.into_iter().flatten() {
+        waker.wake();
+    }
+}
+
+/// An upload started with [`upload_async`], which resolves to the [`UploadReport`] when it is done.
+/// Dropping it before then cancels the upload.
+pub struct UploadTask {
+    state: Shared,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Future for UploadTask {
+    type Output = Result<UploadReport>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.task_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for UploadTask {
+    fn drop(&mut self) {
+        if !self.state.lock().unwrap().finished {
+            self.cancel.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The [`ProgressEvent`]s of an upload started with [`upload_async`], in order. Ends after the
+/// [`Finished`](ProgressEvent::Finished) or [`Error`](ProgressEvent::Error) of the last attempt.
+pub struct ProgressEvents {
+    state: Shared,
+}
+
+impl ProgressEvents {
+    /// The next event, or `None` when the upload is done, for when the `Stream` combinators of
+    /// the `futures` crate aren't around.
+    pub async fn next(&mut self) -> Option<ProgressEvent> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for ProgressEvents {
+    type Item = ProgressEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ProgressEvent>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(event) = state.events.pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        if state.finished {
+            return Poll::Ready(None);
+        }
+        state.events_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::path::PathBuf;
+    use std::pin::pin;
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+
+    use super::upload_async;
+    use crate::config::UploadConfig;
+    use crate::elf::{test_application, test_elf};
+    use crate::progress::ProgressEvent;
+    use crate::{PortSelector, SIMULATED_PORT};
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// The smallest executor there is, parking the thread until the future can go on.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    fn elf_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}-{}.elf", std::process::id()));
+        let image = test_application(2000);
+        std::fs::write(&path, test_elf(&[(0x0001_8000, &image)])).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_upload_async() {
+        let file = elf_file("async-upload");
+        let (upload, mut events) =
+            upload_async(PortSelector::Simulated, &file, UploadConfig::default());
+
+        let mut received = Vec::new();
+        while let Some(event) = block_on(events.next()) {
+            received.push(event);
+        }
+        let report = block_on(upload).unwrap();
+        assert_eq!(report.port, PathBuf::from(SIMULATED_PORT));
+        assert_eq!(
+            received[..3],
+            [
+                ProgressEvent::Started,
+                ProgressEvent::StartDfuSent,
+                ProgressEvent::InitSent
+            ]
+        );
+        assert_eq!(received.last(), Some(&ProgressEvent::Finished));
+        let chunks = received
+            .iter()
+            .filter(|e| matches!(e, ProgressEvent::Chunk { .. }))
+            .count();
+        assert_eq!(chunks, report.chunks);
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[test]
+    fn test_dropping_the_upload_cancels_it() {
+        let file = elf_file("async-cancel");
+        // hold the upload after its first data packet, until it was dropped
+        let (dropped, wait) = channel::<()>();
+        let wait = Mutex::new(wait);
+        let config = UploadConfig::default().on_progress(move |event| {
+            if matches!(event, ProgressEvent::Chunk { index: 0, .. }) {
+                let _ = wait.lock().unwrap().recv();
+            }
+        });
+        let (upload, mut events) = upload_async(PortSelector::Simulated, &file, config);
+
+        assert_eq!(block_on(events.next()), Some(ProgressEvent::Started));
+        drop(upload);
+        drop(dropped);
+
+        let last = std::iter::from_fn(|| block_on(events.next())).last();
+        assert_eq!(
+            last,
+            Some(ProgressEvent::Error("the upload was cancelled".to_string()))
+        );
+        let _ = std::fs::remove_file(&file);
+    }
+}
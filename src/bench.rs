@@ -0,0 +1,444 @@
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::{Help, Result};
+
+use crate::config::UploadConfig;
+use crate::report::UploadReport;
+use crate::serial::Serial;
+use crate::transport::ControlLine;
+use crate::upload::select_ports;
+use crate::PortSelector;
+
+/// The parameter ranges swept by [`benchmark`]. Every combination of packet size and window size is tried.
+#[derive(Clone, Debug)]
+pub struct BenchmarkOptions {
+    /// Size in bytes of the generated image that is uploaded.
+    pub image_size: usize,
+    pub packet_sizes: Vec<usize>,
+    pub window_sizes: Vec<usize>,
+    /// How many times every combination is uploaded.
+    pub repetitions: usize,
+    /// Reset the board into the bootloader before every upload, by asserting the line for the
+    /// duration, like [`UploadConfig::reset_before_upload`]. Without it the board has to stay in
+    /// the bootloader by itself between uploads, see [`benchmark`].
+    pub reset: Option<(ControlLine, Duration)>,
+}
+
+impl Default for BenchmarkOptions {
+    fn default() -> Self {
+        Self {
+            image_size: 32 * 1024,
+            packet_sizes: vec![256, 512, 1024],
+            window_sizes: vec![1, 2, 4],
+            repetitions: 1,
+            reset: None,
+        }
+    }
+}
+
+/// The combined result of all repetitions for a single combination of settings.
+#[derive(Clone, Debug)]
+pub struct BenchmarkRun {
+    pub packet_size: usize,
+    pub window_size: usize,
+    pub bytes: usize,
+    pub duration: Duration,
+    pub retries: usize,
+    /// Set when one of the uploads failed. The remaining repetitions are skipped in that case.
+    pub error: Option<String>,
+}
+
+impl BenchmarkRun {
+    /// Effective upload speed in bytes per second.
+    pub fn throughput(&self) -> f64 {
+        if self.duration.is_zero() {
+            return 0.0;
+        }
+        self.bytes as f64 / self.duration.as_secs_f64()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BenchmarkReport {
+    pub runs: Vec<BenchmarkRun>,
+}
+
+impl BenchmarkReport {
+    /// The fastest combination for which every upload succeeded.
+    /// Between equally fast ones the one that needed the fewest retries wins.
+    pub fn recommended(&self) -> Option<&BenchmarkRun> {
+        self.runs
+            .iter()
+            .filter(|r| r.error.is_none())
+            .max_by(|a, b| {
+                a.throughput()
+                    .total_cmp(&b.throughput())
+                    .then(b.retries.cmp(&a.retries))
+            })
+    }
+}
+
+impl Display for BenchmarkReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>7} {:>7} {:>10} {:>13} {:>8}  result",
+            "packet", "window", "time", "throughput", "retries"
+        )?;
+        for run in &self.runs {
+            writeln!(
+                f,
+                "{:>7} {:>7} {:>9.2}s {:>8.1} kB/s {:>8}  {}",
+                run.packet_size,
+                run.window_size,
+                run.duration.as_secs_f64(),
+                run.throughput() / 1024.0,
+                run.retries,
+                run.error.as_deref().unwrap_or("ok")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// How fast the data packets of a [`Serial::benchmark`] were acknowledged.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThroughputStats {
+    pub bytes: usize,
+    pub packets: usize,
+    pub duration: Duration,
+    /// The mean time from writing a packet until it was acknowledged, including the time it took
+    /// to send it again when it had to be.
+    pub mean_ack_latency: Duration,
+    /// 95% of the packets were acknowledged within this time.
+    pub p95_ack_latency: Duration,
+    /// Number of packets that had to be sent again.
+    pub retransmissions: usize,
+}
+
+impl ThroughputStats {
+    pub(crate) fn new(
+        bytes: usize,
+        duration: Duration,
+        mut latencies: Vec<Duration>,
+        retransmissions: usize,
+    ) -> Self {
+        latencies.sort();
+        let packets = latencies.len();
+        let mean_ack_latency = match packets {
+            0 => Duration::ZERO,
+            n => latencies.iter().sum::<Duration>() / n as u32,
+        };
+        // the nearest rank, so the latency that 95% of the packets got at most
+        let p95_ack_latency = match packets {
+            0 => Duration::ZERO,
+            n => latencies[(n * 95).div_ceil(100) - 1],
+        };
+        Self {
+            bytes,
+            packets,
+            duration,
+            mean_ack_latency,
+            p95_ack_latency,
+            retransmissions,
+        }
+    }
+
+    /// Effective speed in bytes per second.
+    pub fn throughput(&self) -> f64 {
+        if self.duration.is_zero() {
+            return 0.0;
+        }
+        self.bytes as f64 / self.duration.as_secs_f64()
+    }
+}
+
+impl Display for ThroughputStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes in {} packets in {:.2}s, {:.1} kB/s, acks after {:.1}ms on average and {:.1}ms for 95% of the packets, {} retransmissions",
+            self.bytes,
+            self.packets,
+            self.duration.as_secs_f64(),
+            self.throughput() / 1024.0,
+            self.mean_ack_latency.as_secs_f64() * 1000.0,
+            self.p95_ack_latency.as_secs_f64() * 1000.0,
+            self.retransmissions
+        )
+    }
+}
+
+/// Send `bytes` of data packets to the first port `port` finds with [`Serial::benchmark`], and
+/// return how fast that went.
+pub fn benchmark_throughput(port: PortSelector, bytes: usize) -> Result<ThroughputStats> {
+    let (paths, _) = select_ports(port, &UploadConfig::default())?;
+    let path = paths.into_iter().next().ok_or_else(|| {
+        eyre!("No serial port to benchmark").suggestion("Make sure the usb is plugged in")
+    })?;
+
+    Serial::open(path)?.benchmark(bytes)
+}
+
+/// Upload a generated test image over and over, sweeping the packet size and window size over the
+/// ranges in the [`BenchmarkOptions`]. The [`BenchmarkReport`] displays as a table of the throughput and
+/// retries of every combination, and [recommends](BenchmarkReport::recommended) the settings that worked best.
+///
+/// Note that this overwrites whatever application is on the board. The test image is never
+/// started, so the board stays in the bootloader from one upload to the next. When an upload
+/// fails halfway the bootloader might not take the next one, so unless the board is reset by
+/// [`BenchmarkOptions::reset`] it has to be put back into the bootloader by hand (and the
+/// benchmark run again) when the runs after a failed one fail too.
+pub fn benchmark(port: PortSelector, options: &BenchmarkOptions) -> Result<BenchmarkReport> {
+    let (paths, _) = select_ports(port, &UploadConfig::default())?;
+    let path = paths.into_iter().next().ok_or_else(|| {
+        eyre!("No serial port to benchmark").suggestion("Make sure the usb is plugged in")
+    })?;
+
+    run_benchmark(options, |image, config| {
+        Serial::open(path.clone())?.try_do_upload(image, config)
+    })
+}
+
+fn run_benchmark(
+    options: &BenchmarkOptions,
+    mut upload: impl FnMut(&[u8], &UploadConfig) -> Result<UploadReport>,
+) -> Result<BenchmarkReport> {
+    if options.packet_sizes.is_empty() || options.window_sizes.is_empty() {
+        bail!("the benchmark needs at least one packet size and one window size to try");
+    }
+
+    let configs: Vec<_> = options
+        .packet_sizes
+        .iter()
+        .flat_map(|&p| {
+            options.window_sizes.iter().map(move |&w| {
+                // starting the test image would take the board out of the bootloader,
+                // and the next run with it
+                let config = UploadConfig::default()
+                    .packet_size(p)
+                    .window_size(w)
+                    .reset_after_upload(false);
+                match options.reset {
+                    Some((line, pulse)) => config.reset_before_upload(line, pulse),
+                    None => config,
+                }
+            })
+        })
+        .collect();
+    // find invalid settings before spending minutes on the valid ones
+    for config in &configs {
+        config.validate()?;
+    }
+
+    let image = test_image(options.image_size);
+    let mut runs = Vec::new();
+    for config in configs {
+        eprintln!(
+            "benchmarking packet size {}, window size {}",
+            config.packet_size, config.window_size
+        );
+
+        let mut run = BenchmarkRun {
+            packet_size: config.packet_size,
+            window_size: config.window_size,
+            bytes: 0,
+            duration: Duration::ZERO,
+            retries: 0,
+            error: None,
+        };
+        for _ in 0..options.repetitions {
+            match upload(&image, &config) {
+                Ok(report) => {
+                    run.bytes += report.bytes;
+                    run.duration += report.duration;
+                    run.retries += report.retries;
+                }
+                Err(e) => {
+                    eprintln!("WARNING: {e}");
+                    run.error = Some(e.to_string());
+                    break;
+                }
+            }
+        }
+        runs.push(run);
+    }
+
+    Ok(BenchmarkReport { runs })
+}
+
+/// Pseudo-random bytes, so the image exercises escaping and isn't trivially compressible.
+pub(crate) fn test_image(size: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1d;
+    (0..size).map(|_| xorshift(&mut state) as u8).collect()
+}
+
+/// xorshift64, random enough for generated data and the tests, and the same every run.
+pub(crate) fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{run_benchmark, test_image, BenchmarkOptions, ThroughputStats};
+    use crate::clock::FakeClock;
+    use crate::emulator::{emulator_serial, Emulator};
+    use crate::transport::ControlLine;
+
+    #[test]
+    fn test_benchmark_against_emulator() {
+        let options = BenchmarkOptions {
+            image_size: 4096,
+            packet_sizes: vec![512, 1024],
+            window_sizes: vec![1, 4],
+            repetitions: 2,
+            ..Default::default()
+        };
+
+        let report = run_benchmark(&options, |image, config| {
+            let emulator = Emulator::new();
+            let mut serial = emulator_serial(&emulator);
+            let report = serial.try_do_upload(image, config)?;
+            assert_eq!(emulator.image_size(), Some(image.len() as u32));
+            assert!(emulator.init_packet().is_some());
+            assert_eq!(emulator.image(), image);
+            assert!(emulator.stopped());
+            Ok(report)
+        })
+        .unwrap();
+
+        assert_eq!(report.runs.len(), 4);
+        assert!(report.runs.iter().all(|r| r.error.is_none()));
+        assert!(report.runs.iter().all(|r| r.bytes == 2 * 4096));
+
+        let best = report.recommended().unwrap();
+        assert_eq!((best.packet_size, best.window_size), (1024, 4));
+    }
+
+    #[test]
+    fn test_benchmark_runs_back_to_back() {
+        let options = BenchmarkOptions {
+            image_size: 2048,
+            packet_sizes: vec![256, 512],
+            window_sizes: vec![1, 2],
+            repetitions: 2,
+            ..Default::default()
+        };
+
+        // one board for every run, which has to still be in the bootloader for the next one
+        let emulator = Emulator::new();
+        let report = run_benchmark(&options, |image, config| {
+            emulator_serial(&emulator).try_do_upload(image, config)
+        })
+        .unwrap();
+        assert_eq!(report.runs.len(), 4);
+        assert!(report.runs.iter().all(|r| r.error.is_none()));
+        assert_eq!(emulator.image(), test_image(2048));
+
+        // a board that runs its application until it is reset into the bootloader again
+        let options = BenchmarkOptions {
+            reset: Some((ControlLine::Dtr, Duration::from_millis(10))),
+            ..options
+        };
+        let emulator = Emulator::new().running_application();
+        let report = run_benchmark(&options, |image, config| {
+            emulator_serial(&emulator).try_do_upload(image, config)
+        })
+        .unwrap();
+        assert!(report.runs.iter().all(|r| r.error.is_none()));
+        let resets = emulator
+            .control_lines()
+            .into_iter()
+            .filter(|&line| line == (ControlLine::Dtr, true))
+            .count();
+        assert_eq!(resets, 4 * 2);
+    }
+
+    #[test]
+    fn test_failed_runs_are_not_recommended() {
+        let options = BenchmarkOptions {
+            image_size: 2048,
+            packet_sizes: vec![512],
+            window_sizes: vec![1, 2],
+            repetitions: 1,
+            ..Default::default()
+        };
+
+        // the second data packet (the 5th frame, after the ping) gets lost, and without a window
+        // also every time it is sent again
+        let report = run_benchmark(&options, |image, config| {
+            let mut emulator = Emulator::new().drop_frame(4);
+            if config.window_size == 1 {
+                emulator = (5..5 + config.max_retries).fold(emulator, Emulator::drop_frame);
+            }
+            emulator_serial(&emulator).try_do_upload(image, config)
+        })
+        .unwrap();
+
+        assert!(report.runs[0].error.is_some());
+        assert!(report.runs[1].error.is_none());
+        assert_eq!(report.runs[1].retries, 2);
+        assert_eq!(report.recommended().unwrap().window_size, 2);
+    }
+
+    #[test]
+    fn test_invalid_settings_fail_early() {
+        let options = BenchmarkOptions {
+            window_sizes: vec![8],
+            ..Default::default()
+        };
+        assert!(run_benchmark(&options, |_, _| unreachable!()).is_err());
+        assert_eq!(test_image(16), test_image(16));
+    }
+
+    #[test]
+    fn test_serial_benchmark() {
+        let clock = Arc::new(FakeClock::new());
+        // the third data packet gets lost once
+        let emulator = Emulator::new()
+            .clock(clock.clone())
+            .response_time(Duration::from_millis(10))
+            .drop_frame(2);
+        let mut serial = emulator_serial(&emulator);
+        serial
+            .set_timeouts(Duration::from_millis(100), Duration::from_millis(100))
+            .unwrap();
+
+        let stats = serial.benchmark(4000).unwrap();
+        assert_eq!(emulator.image(), test_image(4000));
+        assert_eq!(
+            (stats.bytes, stats.packets, stats.retransmissions),
+            (4000, 8, 1)
+        );
+        // the packet that got lost waited for the read timeout
+        assert!(stats.p95_ack_latency > Duration::from_millis(100));
+        assert!(stats.mean_ack_latency > Duration::from_millis(10));
+        assert!(stats.throughput() > 0.0);
+    }
+
+    #[test]
+    fn test_throughput_stats() {
+        let ms = Duration::from_millis;
+        let latencies = (1..=20).rev().map(ms).collect();
+        let stats = ThroughputStats::new(20 * 1024, ms(500), latencies, 2);
+        assert_eq!(stats.packets, 20);
+        assert_eq!(stats.mean_ack_latency, Duration::from_micros(10_500));
+        assert_eq!(stats.p95_ack_latency, ms(19));
+        assert_eq!(
+            stats.to_string(),
+            "20480 bytes in 20 packets in 0.50s, 40.0 kB/s, acks after 10.5ms on average and 19.0ms for 95% of the packets, 2 retransmissions"
+        );
+
+        let nothing = ThroughputStats::new(0, Duration::ZERO, Vec::new(), 0);
+        assert_eq!(nothing.p95_ack_latency, Duration::ZERO);
+        assert_eq!(nothing.throughput(), 0.0);
+    }
+}
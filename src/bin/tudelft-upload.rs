@@ -0,0 +1,271 @@
+use std::env;
+use std::process::exit;
+use std::time::Duration;
+
+use tudelft_serial_upload::color_eyre::eyre::{bail, eyre, WrapErr};
+use tudelft_serial_upload::color_eyre::Result;
+use tudelft_serial_upload::{
+    abort_dfu, benchmark, benchmark_throughput, check_drivers, erase, loopback_test, short_hash,
+    upload_file_with_config, upload_history, BenchmarkOptions, BoardProfile, ControlLine,
+    PortSelector, UploadConfig,
+};
+
+/// How long `--reset` holds the board in reset.
+const RESET_PULSE: Duration = Duration::from_millis(10);
+
+const USAGE: &str = "\
+usage:
+    tudelft-upload upload [--port <port>] [--board <profile.toml>] [--baud <rate>]
+                          [--timeout <seconds>] [--packet-size <bytes>] [--window <n>]
+                          [--reset <dtr|rts>] [--retries <n>] [--attempts <n>]
+                          [--deadline <seconds>] [--packet-delay <ms>] [--init-wait <ms>]
+                          [--no-ping] [--verify] [--adaptive] [--trace] [--record <file>]
+                          [--record-history] [--verbose] [--json] <file.elf>
+    tudelft-upload bench [--port <port>] [--image-size <bytes>] [--packet-sizes <n,n,..>]
+                         [--windows <n,n,..>] [--repetitions <n>] [--reset <dtr|rts>]
+                         [--latency]
+    tudelft-upload abort [--port <port>] [--reset <dtr|rts>] [--timeout <seconds>]
+    tudelft-upload erase [--port <port>] [--board <profile.toml>] [--reset <dtr|rts>]
+                         [--timeout <seconds>]
+    tudelft-upload loopback [--port <port>] [--baud <rate>]
+    tudelft-upload history [--limit <n>]
+    tudelft-upload doctor
+
+<port> is `auto` (the default), `first`, `all`, `interactive`, `interactive:<filter>` to only
+list the ports matching the filter, the path of a serial port, `env:<VAR>` for the port in an
+environment variable, `index:<n>` for the n-th FTDI device the driver lists, `serial:<number>`
+for the FTDI device with that serial number, `description:<text>` for the FTDI device whose
+description is, or else contains, the text, or `simulated` for a simulated board, which
+TUDELFT_UPLOAD_SIMULATE=1 also uses instead of any other port. Separate several with commas to
+try them in order, like `env:DRONE_PORT,auto,interactive`.";
+
+fn main() {
+    let _ = tudelft_serial_upload::color_eyre::install();
+
+    if let Err(e) = run(env::args().skip(1).collect()) {
+        eprintln!("{e:?}");
+        exit(1);
+    }
+}
+
+fn run(args: Vec<String>) -> Result<()> {
+    let Some((command, mut args)) = args.split_first() else {
+        println!("{USAGE}");
+        return Ok(());
+    };
+
+    let mut port = "auto".to_string();
+    let mut positional = Vec::new();
+    let mut options = BenchmarkOptions::default();
+    let mut limit = 20;
+    let mut verbose = false;
+    let mut json = false;
+    let mut latency = false;
+    let mut config = UploadConfig::default();
+    let mut baud_rate = BoardProfile::tudelft_drone().baud_rate;
+
+    while let Some((arg, rest)) = args.split_first() {
+        args = rest;
+        if !arg.starts_with("--") {
+            positional.push(arg.as_str());
+            continue;
+        }
+
+        match arg.as_str() {
+            "--verbose" => {
+                verbose = true;
+                continue;
+            }
+            "--json" => {
+                json = true;
+                continue;
+            }
+            "--latency" => {
+                latency = true;
+                continue;
+            }
+            "--no-ping" => {
+                config = config.ping(false);
+                continue;
+            }
+            "--verify" => {
+                config = config.verify(true);
+                continue;
+            }
+            "--record-history" => {
+                config = config.record_history(true);
+                continue;
+            }
+            "--adaptive" => {
+                config = config.adaptive_packet_size(true);
+                continue;
+            }
+            "--trace" => {
+                config = config.trace(|frame| eprintln!("{frame}"));
+                continue;
+            }
+            _ => {}
+        }
+
+        let Some((value, rest)) = args.split_first() else {
+            bail!("missing value for {arg}\n\n{USAGE}");
+        };
+        args = rest;
+
+        match arg.as_str() {
+            "--port" => port = value.clone(),
+            "--board" => {
+                let board = BoardProfile::from_toml(value)?;
+                baud_rate = board.baud_rate;
+                config = config.board(board);
+            }
+            "--baud" => {
+                baud_rate = parse(arg, value)?.try_into()?;
+                config = config.baud_rate(baud_rate);
+            }
+            "--timeout" => {
+                config = config.serial_timeout(Duration::from_secs(parse(arg, value)? as u64))
+            }
+            "--retries" => config = config.max_retries(parse(arg, value)?),
+            "--attempts" => config = config.max_upload_attempts(parse(arg, value)?),
+            "--deadline" => {
+                config = config.deadline(Duration::from_secs(parse(arg, value)? as u64))
+            }
+            "--window" => config = config.window_size(parse(arg, value)?),
+            "--packet-size" => config = config.packet_size(parse(arg, value)?),
+            "--init-wait" => {
+                config = config.init_wait(Duration::from_millis(parse(arg, value)? as u64))
+            }
+            "--record" => config = config.record_to(value),
+            "--packet-delay" => {
+                config = config.packet_delay(Duration::from_millis(parse(arg, value)? as u64))
+            }
+            "--reset" => {
+                let line = match value.as_str() {
+                    "dtr" => ControlLine::Dtr,
+                    "rts" => ControlLine::Rts,
+                    _ => bail!("invalid value {value:?} for {arg}, expected `dtr` or `rts`"),
+                };
+                config = config.reset_before_upload(line, RESET_PULSE);
+                options.reset = Some((line, RESET_PULSE));
+            }
+            "--image-size" => options.image_size = parse(arg, value)?,
+            "--packet-sizes" => options.packet_sizes = parse_list(arg, value)?,
+            "--windows" => options.window_sizes = parse_list(arg, value)?,
+            "--repetitions" => options.repetitions = parse(arg, value)?,
+            "--limit" => limit = parse(arg, value)?,
+            _ => bail!("unknown option {arg}\n\n{USAGE}"),
+        }
+    }
+
+    let selector = parse_selector(&port);
+
+    match (command.as_str(), positional.as_slice()) {
+        ("upload", [file]) => {
+            let report = upload_file_with_config(selector, file, &config)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                if verbose {
+                    print!("{}", report.phase_table());
+                }
+                println!(
+                    "uploaded firmware {} over {:?} at {:.1}kB/s",
+                    short_hash(&report.sha256),
+                    report.port,
+                    report.throughput() / 1024.0
+                );
+            }
+        }
+        ("bench", []) if latency => {
+            println!("{}", benchmark_throughput(selector, options.image_size)?);
+        }
+        ("bench", []) => {
+            let report = benchmark(selector, &options)?;
+            print!("{report}");
+            match report.recommended() {
+                Some(best) => println!(
+                    "recommended settings: packet size {}, window size {}",
+                    best.packet_size, best.window_size
+                ),
+                None => println!("none of the settings tried resulted in a successful upload"),
+            }
+        }
+        ("abort", []) => {
+            let path = abort_dfu(selector, &config)?;
+            if options.reset.is_some() {
+                println!("the board on {path:?} is back in the bootloader");
+            } else {
+                println!("reset the board on {path:?} to upload again");
+            }
+        }
+        ("erase", []) => {
+            let path = erase(selector, &config)?;
+            println!("the board on {path:?} stays in the bootloader until the next upload");
+        }
+        ("loopback", []) => {
+            loopback_test(selector, baud_rate)?;
+        }
+        ("history", []) => {
+            let entries = upload_history(limit)?;
+            if entries.is_empty() {
+                println!("no uploads recorded (enable this with `upload --record-history`, or UploadConfig::record_history)");
+            }
+            for entry in entries {
+                println!("{entry}");
+            }
+        }
+        ("doctor", []) => {
+            print!("{}", check_drivers());
+        }
+        _ => bail!("{USAGE}"),
+    }
+
+    Ok(())
+}
+
+fn parse_selector(port: &str) -> PortSelector {
+    if port.contains(',') {
+        return PortSelector::Chain(port.split(',').map(parse_selector).collect());
+    }
+
+    match port {
+        "auto" => PortSelector::AutoManufacturer,
+        "first" => PortSelector::SearchFirst,
+        "all" => PortSelector::SearchAll,
+        "interactive" => PortSelector::ChooseInteractive,
+        "simulated" => PortSelector::Simulated,
+        _ => {
+            #[cfg(feature = "ftdi")]
+            if let Some(index) = port.strip_prefix("index:").and_then(|i| i.parse().ok()) {
+                return PortSelector::DeviceIndex(index);
+            }
+            #[cfg(feature = "ftdi")]
+            if let Some(serial) = port.strip_prefix("serial:") {
+                return PortSelector::BySerialNumber(serial.to_string());
+            }
+            #[cfg(feature = "ftdi")]
+            if let Some(description) = port.strip_prefix("description:") {
+                return PortSelector::ByDescription(description.to_string());
+            }
+
+            if let Some(var) = port.strip_prefix("env:") {
+                PortSelector::Env(var.to_string())
+            } else if let Some(filter) = port.strip_prefix("interactive:") {
+                PortSelector::ChooseInteractiveFiltered(filter.to_string())
+            } else {
+                PortSelector::Named(port.to_string())
+            }
+        }
+    }
+}
+
+fn parse(arg: &str, value: &str) -> Result<usize> {
+    value
+        .parse()
+        .wrap_err_with(|| eyre!("invalid value {value:?} for {arg}"))
+}
+
+fn parse_list(arg: &str, value: &str) -> Result<Vec<usize>> {
+    value.split(',').map(|v| parse(arg, v.trim())).collect()
+}
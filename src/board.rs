@@ -0,0 +1,424 @@
+//! Everything that is specific to the hardware the crate uploads to, so it can be reused for
+//! other boards with the same kind of bootloader.
+
+use std::fs::read_to_string;
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::{Report, Result};
+use serde::Deserialize;
+
+use crate::config::{BOOTLOADER_START_ADDRESS, DEFAULT_APP_START_ADDRESS, DEFAULT_PRODUCT_NAMES};
+use crate::image::RAM;
+
+/// The baud rate of the FT231X on the lab boards.
+pub const DEFAULT_BAUD_RATE: u32 = 921_600;
+
+/// The baud rates the FT231X can be set to.
+const SUPPORTED_BAUD_RATES: RangeInclusive<u32> = 300..=3_000_000;
+
+/// FTDI chips make their baud rate by dividing this by a divisor in eighths: exactly 1 or 1.5,
+/// or anything from 2 up.
+#[cfg(feature = "ftdi")]
+const FTDI_BAUD_CLOCK_EIGHTHS: u32 = 8 * 3_000_000;
+/// The largest divisor, in eighths, the FTDI chips support.
+#[cfg(feature = "ftdi")]
+const MAX_FTDI_DIVISOR: u32 = 16_383 * 8 + 7;
+/// How far off the baud rate an FTDI chip makes may be from the one asked for, in parts of 1.
+/// The bootloader has its own rounding error, and together they must stay under about 5%.
+#[cfg(feature = "ftdi")]
+const MAX_BAUD_RATE_ERROR: f64 = 0.02;
+
+/// A USB vendor and product id pair, written as `"0403:6015"` in a board profile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct UsbId {
+    pub vid: u16,
+    pub pid: u16,
+}
+
+/// The dialect of the DFU protocol the bootloader speaks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum Protocol {
+    /// Nordic's serial DFU over HCI frames, as in the nRF51 SDK.
+    #[default]
+    NordicHci,
+}
+
+/// The chip, flash layout, USB ids and protocol of a kind of board.
+///
+/// [`BoardProfile::tudelft_drone`] describes the drone boards of the Embedded Systems Lab, and is
+/// what an [`UploadConfig`](crate::UploadConfig) uses unless it is given another one with
+/// [`UploadConfig::board`](crate::UploadConfig::board).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BoardProfile {
+    pub name: String,
+    pub chip: String,
+    /// All of flash.
+    pub flash: Range<u32>,
+    /// Where the application starts in flash, after anything (like a SoftDevice) before it.
+    pub app_start: u32,
+    /// Where the bootloader starts in flash. The application has to end before this.
+    pub bootloader_start: u32,
+    /// RAM, including the address right past it, where the stack pointer usually starts.
+    pub ram: RangeInclusive<u32>,
+    /// The ids of the USB serial chip on the board, to find it with
+    /// [`PortSelector::AutoManufacturer`](crate::PortSelector::AutoManufacturer).
+    pub usb_ids: Vec<UsbId>,
+    /// Names to find the USB serial chip by when the operating system doesn't report its ids,
+    /// see [`UploadConfig::product_names`](crate::UploadConfig::product_names).
+    pub product_names: Vec<String>,
+    pub baud_rate: u32,
+    pub protocol: Protocol,
+}
+
+impl Default for BoardProfile {
+    fn default() -> Self {
+        Self::tudelft_drone()
+    }
+}
+
+impl BoardProfile {
+    /// The drone boards used in the Embedded Systems Lab: an nRF51822 with the S110 SoftDevice and an FT231X.
+    pub fn tudelft_drone() -> Self {
+        Self {
+            name: "tudelft-drone".to_string(),
+            chip: "nRF51822".to_string(),
+            flash: 0..0x0004_0000,
+            app_start: DEFAULT_APP_START_ADDRESS,
+            bootloader_start: BOOTLOADER_START_ADDRESS,
+            ram: RAM,
+            usb_ids: vec![UsbId {
+                vid: 0x0403,
+                pid: 0x6015,
+            }],
+            product_names: DEFAULT_PRODUCT_NAMES
+                .iter()
+                .map(|n| n.to_string())
+                .collect(),
+            baud_rate: DEFAULT_BAUD_RATE,
+            protocol: Protocol::NordicHci,
+        }
+    }
+
+    /// Load a profile from a TOML file like this one, which describes the lab boards:
+    ///
+    /// ```toml
+    /// name = "tudelft-drone"
+    /// chip = "nRF51822"
+    /// flash_start = 0x0
+    /// flash_size = 0x40000
+    /// app_start = 0x18000
+    /// bootloader_start = 0x3c000
+    /// ram_start = 0x20000000
+    /// ram_size = 0x8000
+    /// usb_ids = ["0403:6015"]
+    /// product_names = ["FT231X USB UART"]
+    /// baud_rate = 921600
+    /// protocol = "nordic-hci"
+    /// ```
+    ///
+    /// The layout keys are required, the others default to those of
+    /// [`tudelft_drone`](Self::tudelft_drone).
+    pub fn from_toml(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = read_to_string(path).wrap_err_with(|| format!("failed to read {path:?}"))?;
+        Self::from_toml_str(&text).wrap_err_with(|| format!("invalid board profile {path:?}"))
+    }
+
+    pub(crate) fn from_toml_str(text: &str) -> Result<Self> {
+        let file: ProfileFile = toml::from_str(text)?;
+        let defaults = Self::tudelft_drone();
+
+        let end = |start: u32, size: u32, what: &str| {
+            start
+                .checked_add(size)
+                .ok_or_else(|| eyre!("the {what} doesn't fit in the 32-bit address space"))
+        };
+        let profile = Self {
+            name: file.name.unwrap_or(defaults.name),
+            chip: file.chip.unwrap_or(defaults.chip),
+            flash: file.flash_start..end(file.flash_start, file.flash_size, "flash")?,
+            app_start: file.app_start,
+            bootloader_start: file.bootloader_start,
+            ram: file.ram_start..=end(file.ram_start, file.ram_size, "RAM")?,
+            usb_ids: file.usb_ids.unwrap_or(defaults.usb_ids),
+            product_names: file.product_names.unwrap_or(defaults.product_names),
+            baud_rate: file.baud_rate.unwrap_or(defaults.baud_rate),
+            protocol: file.protocol.unwrap_or(defaults.protocol),
+        };
+
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    /// Whether one of the [USB ids](Self::usb_ids) is this vendor and product id, as reported in hex by the OS.
+    pub(crate) fn matches_usb_id(&self, vid: &str, pid: &str) -> bool {
+        let (Ok(vid), Ok(pid)) = (u16::from_str_radix(vid, 16), u16::from_str_radix(pid, 16))
+        else {
+            return false;
+        };
+        self.usb_ids.contains(&UsbId { vid, pid })
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.flash.is_empty() {
+            bail!("board {:?} has no flash", self.name);
+        }
+        if self.ram.start() >= self.ram.end() {
+            bail!("board {:?} has no RAM", self.name);
+        }
+        if self.flash.start < *self.ram.end() && *self.ram.start() < self.flash.end {
+            bail!(
+                "the flash (0x{:08x}-0x{:08x}) and RAM (0x{:08x}-0x{:08x}) of board {:?} overlap",
+                self.flash.start,
+                self.flash.end,
+                self.ram.start(),
+                self.ram.end(),
+                self.name
+            );
+        }
+        if !(self.flash.start..=self.flash.end).contains(&self.bootloader_start) {
+            bail!(
+                "the bootloader of board {:?} starts at 0x{:08x}, outside of its flash",
+                self.name,
+                self.bootloader_start
+            );
+        }
+        if !self.flash.contains(&self.app_start) || self.app_start >= self.bootloader_start {
+            bail!(
+                "the application on board {:?} can't start at 0x{:08x}, it has to be in flash before the bootloader at 0x{:08x}",
+                self.name,
+                self.app_start,
+                self.bootloader_start
+            );
+        }
+        check_baud_rate(self.baud_rate)
+    }
+}
+
+/// Fail with an error that names the rate when the serial chip can't do `baud_rate`, instead of
+/// leaving it to the driver, which only reports a status code.
+pub(crate) fn check_baud_rate(baud_rate: u32) -> Result<()> {
+    if !SUPPORTED_BAUD_RATES.contains(&baud_rate) {
+        bail!(
+            "unsupported baud rate {baud_rate}: must be between {} and {}",
+            SUPPORTED_BAUD_RATES.start(),
+            SUPPORTED_BAUD_RATES.end()
+        );
+    }
+    #[cfg(feature = "ftdi")]
+    check_ftdi_baud_rate(baud_rate)?;
+    Ok(())
+}
+
+/// Fail when an FTDI chip can't make a baud rate close enough to `baud_rate`, naming the
+/// nearest rates it can make. D2XX would set the nearest one without saying anything.
+#[cfg(feature = "ftdi")]
+fn check_ftdi_baud_rate(baud_rate: u32) -> Result<()> {
+    let (above, below) = ftdi_baud_rates_around(baud_rate);
+    let error = |rate: f64| (rate - baud_rate as f64).abs() / baud_rate as f64;
+    if [above, below]
+        .into_iter()
+        .flatten()
+        .any(|r| error(r) <= MAX_BAUD_RATE_ERROR)
+    {
+        return Ok(());
+    }
+
+    let nearest: Vec<_> = [below, above]
+        .into_iter()
+        .flatten()
+        .map(|r| format!("{}", r.round()))
+        .collect();
+    Err(eyre!(
+        "the FTDI chip can't make a baud rate close enough to {baud_rate}, the nearest ones it can make are {}",
+        nearest.join(" and ")
+    ))
+}
+
+/// The baud rates an FTDI chip can make that are closest above (or at) and below `baud_rate`.
+#[cfg(feature = "ftdi")]
+fn ftdi_baud_rates_around(baud_rate: u32) -> (Option<f64>, Option<f64>) {
+    let valid = |d: u32| d == 8 || d == 12 || (16..=MAX_FTDI_DIVISOR).contains(&d);
+    let rate = |d: u32| FTDI_BAUD_CLOCK_EIGHTHS as f64 / d as f64;
+
+    // a larger divisor makes a lower rate
+    let exact = FTDI_BAUD_CLOCK_EIGHTHS / baud_rate.max(1);
+    let above = (8..=exact.min(MAX_FTDI_DIVISOR)).rev().find(|&d| valid(d));
+    let below = (exact.max(8)..=MAX_FTDI_DIVISOR).find(|&d| valid(d) && rate(d) < baud_rate as f64);
+    (above.map(rate), below.map(rate))
+}
+
+impl TryFrom<String> for UsbId {
+    type Error = Report;
+
+    fn try_from(id: String) -> Result<Self> {
+        let parse = |s: &str| u16::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+        match id.split_once(':') {
+            Some((vid, pid)) => match (parse(vid), parse(pid)) {
+                (Some(vid), Some(pid)) => Ok(UsbId { vid, pid }),
+                _ => bail!("invalid usb id {id:?}, expected hex ids like \"0403:6015\""),
+            },
+            None => bail!("invalid usb id {id:?}, expected vendor:product like \"0403:6015\""),
+        }
+    }
+}
+
+/// A board profile the way it is written in TOML, see [`BoardProfile::from_toml`].
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    name: Option<String>,
+    chip: Option<String>,
+    flash_start: u32,
+    flash_size: u32,
+    app_start: u32,
+    bootloader_start: u32,
+    ram_start: u32,
+    ram_size: u32,
+    usb_ids: Option<Vec<UsbId>>,
+    product_names: Option<Vec<String>>,
+    baud_rate: Option<u32>,
+    protocol: Option<Protocol>,
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "ftdi")]
+    use super::{check_baud_rate, ftdi_baud_rates_around};
+    use super::{BoardProfile, UsbId};
+    use crate::config::UploadConfig;
+
+    const DRONE_TOML: &str = r#"
+        # the lab boards
+        name = "tudelft-drone"
+        chip = "nRF51822"
+        flash_start = 0x0
+        flash_size = 0x40000
+        app_start = 0x1_8000
+        bootloader_start = 0x3c000
+        ram_start = 0x20000000
+        ram_size = 0x8000 # 32K
+        usb_ids = ["0403:6015"]
+        product_names = ["FT231X USB UART"]
+        baud_rate = 921600
+        protocol = "nordic-hci"
+    "#;
+
+    #[test]
+    fn test_load_profile() {
+        assert_eq!(
+            BoardProfile::from_toml_str(DRONE_TOML).unwrap(),
+            BoardProfile::tudelft_drone()
+        );
+
+        let custom = BoardProfile::from_toml_str(
+            "flash_start = 0\nflash_size = 0x80000\napp_start = 0x20000\nbootloader_start = 0x78000\n\
+             ram_start = 0x20000000\nram_size = 0x10000\nusb_ids = [\"10c4:ea60\", \"1a86:7523\"]\nbaud_rate = 115200",
+        )
+        .unwrap();
+        assert_eq!(custom.flash, 0..0x80000);
+        assert_eq!(
+            custom.usb_ids,
+            [
+                UsbId {
+                    vid: 0x10c4,
+                    pid: 0xea60
+                },
+                UsbId {
+                    vid: 0x1a86,
+                    pid: 0x7523
+                }
+            ]
+        );
+        assert_eq!(custom.baud_rate, 115200);
+        // not set, so the same as the lab boards
+        assert_eq!(custom.product_names, ["FT231X USB UART"]);
+
+        let err = |toml: &str| {
+            BoardProfile::from_toml_str(toml)
+                .map(|_| ())
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(err(&DRONE_TOML.replace("baud_rate", "baud")).contains("baud"));
+        assert!(err(&DRONE_TOML.replace("ram_size = 0x8000", "")).contains("ram_size"));
+        assert!(err(&DRONE_TOML.replace("\"0403:6015\"", "\"0403\"")).contains("usb_ids"));
+        assert!(err(&DRONE_TOML.replace("\"nRF51822\"", "nRF51822")).contains("chip"));
+    }
+
+    #[test]
+    fn test_validate_profile() {
+        let invalid = |change: &dyn Fn(&mut BoardProfile)| {
+            let mut profile = BoardProfile::tudelft_drone();
+            change(&mut profile);
+            profile.validate().is_err()
+        };
+
+        assert!(!invalid(&|_| {}));
+        assert!(invalid(&|p| p.flash = 0..0));
+        assert!(invalid(&|p| p.ram = 0x0003_0000..=0x0005_0000));
+        assert!(invalid(&|p| p.app_start = p.bootloader_start));
+        assert!(invalid(&|p| p.bootloader_start = 0x0010_0000));
+        assert!(invalid(&|p| p.app_start = 0x0010_0000));
+        assert!(invalid(&|p| p.baud_rate = 0));
+        assert!(invalid(&|p| p.baud_rate = 12_000_000));
+
+        let zero_flash = DRONE_TOML.replace("0x40000", "0");
+        assert!(BoardProfile::from_toml_str(&zero_flash).is_err());
+    }
+
+    #[test]
+    fn test_usb_ids() {
+        let drone = BoardProfile::tudelft_drone();
+        assert!(drone.matches_usb_id("403", "6015"));
+        assert!(drone.matches_usb_id("0403", "6015"));
+        assert!(!drone.matches_usb_id("0403", "6001"));
+        assert!(!drone.matches_usb_id("", "6015"));
+
+        // what the config derives from the profile, as it was before there were profiles
+        let config = UploadConfig::default();
+        assert_eq!(config.app_start(), 0x0001_8000);
+        assert_eq!(config.available_flash(), 0x0003_c000 - 0x0001_8000);
+        assert_eq!(config.usb_product_names(), ["FT231X USB UART"]);
+        assert_eq!(config.baud(), 921_600);
+
+        let slow = UploadConfig::default().baud_rate(115_200);
+        assert_eq!(slow.baud(), 115_200);
+        assert!(slow.validate().is_ok());
+        let err = UploadConfig::default().baud_rate(5_000_000).validate();
+        assert!(err.unwrap_err().to_string().contains("5000000"));
+    }
+
+    #[test]
+    #[cfg(feature = "ftdi")]
+    fn test_ftdi_baud_rates() {
+        for baud_rate in [9600, 115_200, 921_600, 1_000_000, 2_000_000, 3_000_000] {
+            check_baud_rate(baud_rate).unwrap();
+        }
+
+        // 3MHz divided by 1 and 1.5, and nothing between those
+        assert_eq!(
+            ftdi_baud_rates_around(2_500_000),
+            (Some(3_000_000.0), Some(2_000_000.0))
+        );
+        assert_eq!(
+            check_baud_rate(2_500_000).unwrap_err().to_string(),
+            "the FTDI chip can't make a baud rate close enough to 2500000, the nearest ones it can make are 2000000 and 3000000"
+        );
+        assert_eq!(
+            ftdi_baud_rates_around(1_700_000),
+            (Some(2_000_000.0), Some(1_500_000.0))
+        );
+        assert!(check_baud_rate(1_700_000).is_err());
+        // 3MHz / 2.125
+        assert_eq!(
+            ftdi_baud_rates_around(1_411_765).1.unwrap().round(),
+            1_411_765.0
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Source of time for everything in the upload path that waits or measures.
+///
+/// The protocol is full of fixed waits, so tests swap in a fake clock
+/// to run a complete upload without actually sleeping for seconds. Clocks are shared with the
+/// threads an upload starts, so they have to be `Send` and `Sync`.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+#[derive(Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        sleep(duration)
+    }
+}
+
+/// A clock that only advances when something sleeps on it, for tests and for replaying a
+/// [recording](crate::UploadConfig::record_to) without waiting.
+pub struct FakeClock {
+    start: Instant,
+    elapsed: std::sync::Mutex<Duration>,
+}
+
+impl FakeClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Default::default(),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
@@ -0,0 +1,754 @@
+use std::fmt::{self, Debug, Formatter};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+
+use crate::board::{check_baud_rate, BoardProfile};
+use crate::dfu::{ImageType, InitPacket, IntegrityCheck};
+use crate::elf::ConversionOptions;
+use crate::progress::{ProgressEvent, ProgressSink};
+use crate::selector::MultipleMatches;
+use crate::trace::{TraceSink, TracedFrame};
+use crate::transport::{ControlLine, LineSettings, Transport};
+use crate::SERIAL_TIMEOUT;
+
+/// Size of the data chunks the image is split into when no other size is configured.
+pub const DEFAULT_PACKET_SIZE: usize = 512;
+
+/// The largest number of data packets that can be unacknowledged at once. Sequence numbers
+/// are only 3 bits wide, so with 8 or more in flight acks would become ambiguous.
+pub const MAX_WINDOW_SIZE: usize = 7;
+
+/// Where the application starts in flash on the lab boards, right after the SoftDevice.
+pub const DEFAULT_APP_START_ADDRESS: u32 = 0x0001_8000;
+
+/// Where the bootloader starts in flash on the lab boards. The application has to end before this.
+pub const BOOTLOADER_START_ADDRESS: u32 = 0x0003_c000;
+
+/// How long the bootloader may take to erase the flash by default, see [`UploadConfig::erase_timeout`].
+pub const DEFAULT_ERASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait after the init packet by default, see [`UploadConfig::init_wait`].
+pub const DEFAULT_INIT_WAIT: Duration = Duration::from_secs(1);
+
+/// The latency timer of the USB serial adapter by default, see [`UploadConfig::latency_timer`].
+pub const DEFAULT_LATENCY_TIMER: Duration = Duration::from_millis(2);
+
+/// The longest latency timer the FTDI chips support.
+const MAX_LATENCY_TIMER: Duration = Duration::from_millis(255);
+
+/// How long to wait after writing every packet by default, see [`UploadConfig::packet_delay`].
+pub const DEFAULT_PACKET_DELAY: Duration = Duration::from_millis(40);
+
+/// How often a packet is sent again by default, see [`UploadConfig::max_retries`].
+pub const DEFAULT_MAX_RETRIES: usize = 3;
+
+/// How long to wait before sending a packet again the first time by default, see
+/// [`UploadConfig::retry_backoff`].
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(20);
+
+/// How much longer every further wait before sending a packet again is by default, see
+/// [`UploadConfig::retry_backoff`].
+pub const DEFAULT_RETRY_BACKOFF_FACTOR: u32 = 4;
+
+/// How long to wait before the next attempt at an upload by default, see [`UploadConfig::upload_retry_delay`].
+pub const DEFAULT_UPLOAD_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long opening a port may take by default, see [`UploadConfig::open_timeout`].
+pub const DEFAULT_OPEN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often opening a busy port is tried by default, see [`UploadConfig::open_attempts`].
+pub const DEFAULT_OPEN_ATTEMPTS: usize = 5;
+
+/// How long the hook set with [`UploadConfig::before_reset`] gets by default.
+pub const DEFAULT_BEFORE_RESET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the FT231X on the drone boards calls itself, see [`UploadConfig::product_names`].
+pub const DEFAULT_PRODUCT_NAMES: &[&str] = &["FT231X USB UART"];
+
+/// The SLIP header has a 12-bit length field, which has to fit the 4-byte opcode *and* the chunk.
+const MAX_SLIP_PAYLOAD: usize = 0x1000 - 1;
+
+/// Knobs that change how an upload is performed. The defaults match what the
+/// bootloaders on the lab boards are known to accept.
+///
+/// ```
+/// # use tudelft_serial_upload::UploadConfig;
+/// let config = UploadConfig::default().packet_size(1024).window_size(4);
+/// ```
+#[derive(Clone, Debug)]
+pub struct UploadConfig {
+    pub(crate) packet_size: usize,
+    pub(crate) window_size: usize,
+    pub(crate) encode_ahead: bool,
+    pub(crate) adaptive_packet_size: bool,
+    pub(crate) banner: Option<(Vec<u8>, Duration)>,
+    pub(crate) app_start_address: Option<u32>,
+    pub(crate) force: bool,
+    pub(crate) max_image_size: Option<usize>,
+    pub(crate) pad_to: usize,
+    pub(crate) cancel: Option<Arc<AtomicBool>>,
+    pub(crate) deadline: Option<Duration>,
+    pub(crate) verify: bool,
+    pub(crate) reset_after_upload: bool,
+    pub(crate) open_timeout: Duration,
+    pub(crate) open_attempts: usize,
+    pub(crate) line_settings: LineSettings,
+    pub(crate) trace: Option<TraceSink>,
+    pub(crate) progress: Option<ProgressSink>,
+    pub(crate) record: Option<PathBuf>,
+    pub(crate) before_reset: Option<Hook>,
+    pub(crate) before_reset_timeout: Duration,
+    pub(crate) ignore_before_reset_errors: bool,
+    pub(crate) reset: Option<(ControlLine, Duration)>,
+    pub(crate) record_history: bool,
+    pub(crate) history_path: Option<PathBuf>,
+    pub(crate) exclude_ports: Vec<String>,
+    pub(crate) search_timeout: Option<Duration>,
+    pub(crate) max_ports: Option<usize>,
+    pub(crate) product_names: Option<Vec<String>>,
+    pub(crate) conversion: ConversionOptions,
+    pub(crate) board: BoardProfile,
+    pub(crate) erase_timeout: Duration,
+    pub(crate) init_wait: Duration,
+    pub(crate) strict_search_first: bool,
+    pub(crate) multiple_matches: MultipleMatches,
+    pub(crate) baud_rate: Option<u32>,
+    pub(crate) serial_timeout: Duration,
+    pub(crate) latency_timer: Duration,
+    pub(crate) packet_delay: Duration,
+    pub(crate) retry_backoff: (Duration, u32),
+    pub(crate) max_retries: usize,
+    pub(crate) max_upload_attempts: usize,
+    pub(crate) ping: bool,
+    pub(crate) image_type: ImageType,
+    pub(crate) init_packet: InitPacket,
+    pub(crate) integrity_check: IntegrityCheck,
+    pub(crate) upload_retry_delay: Duration,
+}
+
+type HookFn = dyn FnMut(&mut dyn Transport) -> Result<()> + Send;
+
+/// A callback that gets raw access to the port.
+#[derive(Clone)]
+pub(crate) struct Hook(pub(crate) Arc<Mutex<HookFn>>);
+
+impl Debug for Hook {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            packet_size: DEFAULT_PACKET_SIZE,
+            window_size: 1,
+            encode_ahead: false,
+            adaptive_packet_size: false,
+            banner: None,
+            app_start_address: None,
+            force: false,
+            max_image_size: None,
+            pad_to: 4,
+            cancel: None,
+            deadline: None,
+            verify: false,
+            reset_after_upload: true,
+            open_timeout: DEFAULT_OPEN_TIMEOUT,
+            open_attempts: DEFAULT_OPEN_ATTEMPTS,
+            line_settings: LineSettings::default(),
+            trace: None,
+            progress: None,
+            record: None,
+            before_reset: None,
+            before_reset_timeout: DEFAULT_BEFORE_RESET_TIMEOUT,
+            ignore_before_reset_errors: false,
+            reset: None,
+            record_history: false,
+            history_path: None,
+            exclude_ports: Vec::new(),
+            search_timeout: None,
+            max_ports: None,
+            product_names: None,
+            conversion: ConversionOptions::default(),
+            board: BoardProfile::tudelft_drone(),
+            erase_timeout: DEFAULT_ERASE_TIMEOUT,
+            init_wait: DEFAULT_INIT_WAIT,
+            strict_search_first: false,
+            multiple_matches: MultipleMatches::default(),
+            baud_rate: None,
+            serial_timeout: SERIAL_TIMEOUT,
+            latency_timer: DEFAULT_LATENCY_TIMER,
+            packet_delay: DEFAULT_PACKET_DELAY,
+            retry_backoff: (DEFAULT_RETRY_BACKOFF, DEFAULT_RETRY_BACKOFF_FACTOR),
+            max_retries: DEFAULT_MAX_RETRIES,
+            max_upload_attempts: 1,
+            ping: true,
+            image_type: ImageType::Application,
+            init_packet: InitPacket::default(),
+            integrity_check: IntegrityCheck::Crc16,
+            upload_retry_delay: DEFAULT_UPLOAD_RETRY_DELAY,
+        }
+    }
+}
+
+impl UploadConfig {
+    /// The number of bytes of the image sent in every data packet.
+    pub fn packet_size(mut self, packet_size: usize) -> Self {
+        self.packet_size = packet_size;
+        self
+    }
+
+    /// The number of data packets that may be sent before waiting for an acknowledgement.
+    /// A window of 1 (the default) is plain stop-and-wait. Not every bootloader build
+    /// tolerates larger windows, so this is opt-in.
+    pub fn window_size(mut self, window_size: usize) -> Self {
+        self.window_size = window_size;
+        self
+    }
+
+    /// Make the data packets smaller while they keep having to be sent again, and bigger again
+    /// (up to the [packet size](Self::packet_size)) once they get through, for cables that only
+    /// work with small packets. The sizes that were used end up in
+    /// [`UploadReport::packet_sizes`](crate::UploadReport::packet_sizes). Needs a window size of 1.
+    pub fn adaptive_packet_size(mut self, adaptive: bool) -> Self {
+        self.adaptive_packet_size = adaptive;
+        self
+    }
+
+    /// Encode the next data packet on a separate thread while the current one is being sent,
+    /// so the CRC and escaping work overlaps with the (blocking) write to the port.
+    /// What goes over the wire is exactly the same either way.
+    pub fn encode_ahead(mut self, encode_ahead: bool) -> Self {
+        self.encode_ahead = encode_ahead;
+        self
+    }
+
+    /// After the upload, keep listening on the port until the new application prints `banner`
+    /// (for example the version line it prints at boot), for at most `timeout`.
+    /// Whether it was seen ends up in [`UploadReport::banner_seen`](crate::UploadReport::banner_seen),
+    /// and a warning is printed when it wasn't.
+    pub fn expect_banner(mut self, banner: Vec<u8>, timeout: Duration) -> Self {
+        self.banner = Some((banner, timeout));
+        self
+    }
+
+    /// After the stop packet, wait for the bootloader to report the CRC of the image it
+    /// received, and fail the upload when it isn't the CRC of the image that was sent. Not every
+    /// bootloader reports it, which only shows as [`UploadReport::verified`](crate::UploadReport::verified)
+    /// being `Some(false)` after a short wait.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// After the stop packet, tell the bootloader to start the new application, which is on by
+    /// default. Some bootloaders do that by themselves, others otherwise wait for the reset
+    /// button to be pressed.
+    pub fn reset_after_upload(mut self, reset: bool) -> Self {
+        self.reset_after_upload = reset;
+        self
+    }
+
+    /// Give up on a port when opening it takes longer than this, 2 seconds by default. The
+    /// driver can hang while opening a device that is in a bad state, which would otherwise
+    /// keep the other ports from being tried.
+    pub fn open_timeout(mut self, timeout: Duration) -> Self {
+        self.open_timeout = timeout;
+        self
+    }
+
+    /// How often opening a port is tried while it is busy, 5 times by default. Right after
+    /// another program closed the device, the driver can still hold on to it for a moment. The
+    /// wait before the next attempt starts at 100ms and doubles, so the default gives up after
+    /// 1.5 seconds of waiting.
+    pub fn open_attempts(mut self, attempts: usize) -> Self {
+        self.open_attempts = attempts;
+        self
+    }
+
+    /// The data bits, parity and stop bits of the serial line, for bootloaders that were built
+    /// with other ones than the 8N1 of the lab boards. Also used when a port is opened again for
+    /// the next attempt at an upload.
+    pub fn line_settings(mut self, settings: LineSettings) -> Self {
+        self.line_settings = settings;
+        self
+    }
+
+    /// Hand every frame that is sent or received during the upload to `sink`, which can print
+    /// it as hex with its [`Display`](std::fmt::Display) implementation:
+    ///
+    /// ```
+    /// # use tudelft_serial_upload::UploadConfig;
+    /// let config = UploadConfig::default().trace(|frame| eprintln!("{frame}"));
+    /// ```
+    ///
+    /// Without a sink, the last few frames are still added to the error of a failed upload.
+    pub fn trace(mut self, sink: impl Fn(&TracedFrame) + Send + Sync + 'static) -> Self {
+        self.trace = Some(TraceSink(Arc::new(sink)));
+        self
+    }
+
+    /// Call `callback` at every step of the upload, from the thread that uploads. Unlike the
+    /// progress that is printed, this goes from the start packet to every acknowledged data
+    /// packet to the end, so a caller can show it in its own way or send it to another thread
+    /// or task over a channel:
+    ///
+    /// ```
+    /// # use std::sync::mpsc::channel;
+    /// # use tudelft_serial_upload::UploadConfig;
+    /// let (tx, rx) = channel();
+    /// let config = UploadConfig::default().on_progress(move |event| {
+    ///     let _ = tx.send(event);
+    /// });
+    /// ```
+    pub fn on_progress(mut self, callback: impl Fn(ProgressEvent) + Send + Sync + 'static) -> Self {
+        self.progress = Some(ProgressSink(Arc::new(callback)));
+        self
+    }
+
+    /// Write every byte that is sent and received during an upload to the file at `path`, as
+    /// JSON lines with the time since the line before. The file is written anew for every upload
+    /// attempt, so it has the last one. Send it along with the image when an upload fails, it
+    /// can be played back with [`replay`](crate::replay).
+    pub fn record_to(mut self, path: impl Into<PathBuf>) -> Self {
+        self.record = Some(path.into());
+        self
+    }
+
+    /// The flash address the application is linked to start at, and where the bootloader
+    /// will write the first byte of the image. Defaults to the start of the application in the
+    /// [board profile](Self::board).
+    pub fn app_start_address(mut self, address: u32) -> Self {
+        self.app_start_address = Some(address);
+        self
+    }
+
+    /// Skip the sanity checks on the image, like checking that it starts with a valid vector table
+    /// and that it isn't larger than the [maximum image size](Self::max_image_size).
+    /// Only useful when you're flashing something that isn't a normal application.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Refuse to upload images larger than this. Defaults to the flash between the start of
+    /// the application and the bootloader, 144kB on the lab boards, so an image that can't fit
+    /// fails before the upload starts instead of at the end of it. [`force`](Self::force) skips
+    /// the check, for other flash layouts.
+    pub fn max_image_size(mut self, bytes: usize) -> Self {
+        self.max_image_size = Some(bytes);
+        self
+    }
+
+    /// Pad the image with 0xff to a multiple of this many bytes, 4 by default. The flash is
+    /// written a word at a time, and what the bootloader does with a last word that isn't
+    /// complete depends on how it was built. Must be a multiple of 4.
+    pub fn pad_to(mut self, alignment: usize) -> Self {
+        self.pad_to = alignment;
+        self
+    }
+
+    /// Stop the upload when `cancel` is set, for example from the thread of a user interface.
+    /// It is checked before every data packet, after which the bootloader is told to stop
+    /// and the upload fails with [`Cancelled`](crate::Cancelled). Uploads that were cancelled
+    /// are not attempted again.
+    pub fn cancel_flag(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Give up on the upload when it takes longer than `deadline` altogether, counted from when
+    /// the first port is tried, over all ports and [attempts](Self::max_upload_attempts). It is
+    /// checked before every port and attempt, and before every data packet, and waits for an
+    /// ack end at the deadline too. The upload then fails with
+    /// [`DeadlineExceeded`](crate::DeadlineExceeded), which says how many data packets got
+    /// through, after the bootloader is told to stop like for a [cancelled](Self::cancel_flag)
+    /// upload and the port is purged.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Run `hook` right after the port is opened, before anything else is sent to the board.
+    /// This is the place to tell a still running application to stop safely (for example to
+    /// disarm the motors) and wait for it to confirm, before it's reset into the bootloader.
+    ///
+    /// The hook gets raw access to the port, without any of the framing of the DFU protocol.
+    /// Reads and writes start failing once the [`before_reset_timeout`](Self::before_reset_timeout)
+    /// expires. When the hook fails, the upload is aborted, unless
+    /// [`ignore_before_reset_errors`](Self::ignore_before_reset_errors) is set.
+    ///
+    /// ```
+    /// # use tudelft_serial_upload::UploadConfig;
+    /// let config = UploadConfig::default().before_reset(|port| {
+    ///     port.write_all(b"disarm\n")?;
+    ///     let mut reply = [0; 3];
+    ///     port.read_all(&mut reply)
+    /// });
+    /// ```
+    pub fn before_reset(
+        mut self,
+        hook: impl FnMut(&mut dyn Transport) -> Result<()> + Send + 'static,
+    ) -> Self {
+        self.before_reset = Some(Hook(Arc::new(Mutex::new(hook))));
+        self
+    }
+
+    /// How long the [`before_reset`](Self::before_reset) hook may take.
+    pub fn before_reset_timeout(mut self, timeout: Duration) -> Self {
+        self.before_reset_timeout = timeout;
+        self
+    }
+
+    /// Continue with the upload (printing a warning) when the [`before_reset`](Self::before_reset) hook fails.
+    pub fn ignore_before_reset_errors(mut self, ignore: bool) -> Self {
+        self.ignore_before_reset_errors = ignore;
+        self
+    }
+
+    /// Reset the board into the bootloader before the upload, by asserting `line` for `pulse`,
+    /// so it doesn't have to be reset by hand. Only for boards that have the line wired to the
+    /// reset pin of the chip, which not every revision of the drone board does. Happens after
+    /// the [`before_reset`](Self::before_reset) hook. A pulse of 10ms is plenty for the nRF51.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use tudelft_serial_upload::{ControlLine, UploadConfig};
+    /// let config = UploadConfig::default().reset_before_upload(ControlLine::Dtr, Duration::from_millis(10));
+    /// ```
+    pub fn reset_before_upload(mut self, line: ControlLine, pulse: Duration) -> Self {
+        self.reset = Some((line, pulse));
+        self
+    }
+
+    /// Append every upload attempt (with the SHA-256 of the image and the outcome) to the
+    /// history file in the user data directory, to be read back with [`upload_history`](crate::upload_history).
+    /// Problems writing the history are only warned about, they never fail the upload.
+    pub fn record_history(mut self, record: bool) -> Self {
+        self.record_history = record;
+        self
+    }
+
+    /// [`record_history`](Self::record_history) in the file at `path` instead of the one in the
+    /// user data directory, which [`upload_history`](crate::upload_history) doesn't read.
+    pub fn record_history_to(mut self, path: impl Into<PathBuf>) -> Self {
+        self.record_history = true;
+        self.history_path = Some(path.into());
+        self
+    }
+
+    /// Never consider these serial ports when looking for the board, like a built-in debug UART
+    /// that keeps getting picked. Each entry is either the exact name of a port or a glob pattern
+    /// like `/dev/cu.debug-*`. Ports named explicitly with [`PortSelector::Named`](crate::PortSelector::Named)
+    /// are still used.
+    pub fn exclude_ports(mut self, ports: Vec<String>) -> Self {
+        self.exclude_ports = ports;
+        self
+    }
+
+    /// When several ports are tried (like with [`PortSelector::SearchAll`](crate::PortSelector::SearchAll)),
+    /// give up on a port when it doesn't acknowledge the start packet within this time, instead of
+    /// waiting for the normal serial timeout. Once a port answered, the normal timeouts apply again.
+    pub fn search_timeout(mut self, timeout: Duration) -> Self {
+        self.search_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail when [`PortSelector::SearchFirst`](crate::PortSelector::SearchFirst) finds more than
+    /// one usb serial port, instead of warning about it and using the first. With a hub full of
+    /// devices, the first one is easily not the board.
+    pub fn strict_search_first(mut self, strict: bool) -> Self {
+        self.strict_search_first = strict;
+        self
+    }
+
+    /// What to do when a glob pattern in [`PortSelector::Named`](crate::PortSelector::Named), or
+    /// [`PortSelector::AutoManufacturer`](crate::PortSelector::AutoManufacturer), finds several
+    /// ports. By default the user chooses one, or when nobody can (because stdin is not a
+    /// terminal, like in CI) the upload fails with the ports that were found.
+    pub fn multiple_matches(mut self, policy: MultipleMatches) -> Self {
+        self.multiple_matches = policy;
+        self
+    }
+
+    /// Try at most this many ports, skipping the rest.
+    pub fn max_ports(mut self, max_ports: usize) -> Self {
+        self.max_ports = Some(max_ports);
+        self
+    }
+
+    /// Names that identify the serial chip of the drone board for
+    /// [`PortSelector::AutoManufacturer`](crate::PortSelector::AutoManufacturer), for when the
+    /// operating system doesn't report its USB vendor and product id. A port matches when its product or
+    /// manufacturer string contains one of these, ignoring case. Defaults to the names in the
+    /// [board profile](Self::board), which for the lab boards is the name of the FT231X.
+    pub fn product_names(mut self, names: Vec<String>) -> Self {
+        self.product_names = Some(names);
+        self
+    }
+
+    /// How the ELF file is turned into the binary that is uploaded, by `rust-objcopy` or without it.
+    pub fn conversion(mut self, options: ConversionOptions) -> Self {
+        self.conversion = options;
+        self
+    }
+
+    /// The board that is uploaded to, which determines the flash layout, how its serial port is
+    /// found and the baud rate. Defaults to [`BoardProfile::tudelft_drone`].
+    pub fn board(mut self, board: BoardProfile) -> Self {
+        self.board = board;
+        self
+    }
+
+    /// How long the bootloader may take to erase the flash after the start packet. Instead of
+    /// always waiting as long as the slowest board could need, the init packet is sent again
+    /// until the bootloader answers it, so the upload continues as soon as the board is ready.
+    pub fn erase_timeout(mut self, timeout: Duration) -> Self {
+        self.erase_timeout = timeout;
+        self
+    }
+
+    /// How long to wait at most after the init packet was acknowledged, before the first data
+    /// packet, 1 second by default. The flash is erased by then, see
+    /// [`erase_timeout`](Self::erase_timeout), so this only covers the bootloader getting ready
+    /// to write. Bootloaders that send a response to the init packet when they are ready are
+    /// only waited for until it arrives. A board that asks for the first data packets again,
+    /// or doesn't acknowledge them, may need longer. The lab boards are fine with a lot less.
+    pub fn init_wait(mut self, wait: Duration) -> Self {
+        self.init_wait = wait;
+        self
+    }
+
+    /// The baud rate to talk to the bootloader at. Defaults to the baud rate in the
+    /// [board profile](Self::board), which for the lab boards is 921600. Some adapter cables and
+    /// serial ports passed through to a VM only work reliably at a lower rate, like 115200.
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = Some(baud_rate);
+        self
+    }
+
+    /// How long reads and writes wait for the board before the upload fails, and after how long
+    /// without an acknowledgement a warning is printed. Defaults to 5 seconds. A loaded machine
+    /// may need longer, while scripts may rather fail fast when no board is connected.
+    pub fn serial_timeout(mut self, timeout: Duration) -> Self {
+        self.serial_timeout = timeout;
+        self
+    }
+
+    /// How long the FTDI chip waits for more bytes before it passes on what it received. Every
+    /// ack is only a few bytes, so it always waits this long, which the D2XX driver sets to
+    /// 16ms. Defaults to 2ms. Only the FTDI backend has a latency timer, the serial port of the
+    /// operating system ignores this.
+    pub fn latency_timer(mut self, timer: Duration) -> Self {
+        self.latency_timer = timer;
+        self
+    }
+
+    /// Wait this long after writing every packet, before waiting for it to be acknowledged.
+    /// Defaults to 40ms, which is safe for boards that lose packets sent in quick succession.
+    /// The course boards are fine with 5ms, which makes an upload about twice as fast, and 0
+    /// doesn't wait at all. Only applies without pipelining, see [`window_size`](Self::window_size).
+    pub fn packet_delay(mut self, delay: Duration) -> Self {
+        self.packet_delay = delay;
+        self
+    }
+
+    /// Wait `base` before sending a packet again, and `factor` times as long as the wait before
+    /// that for every further time, so a board that is still busy (like with writing the flash)
+    /// gets the time to finish. Defaults to 20ms with a factor of 4, so 20ms, 80ms and 320ms.
+    /// A `base` of 0 sends packets again right away.
+    pub fn retry_backoff(mut self, base: Duration, factor: u32) -> Self {
+        self.retry_backoff = (base, factor);
+        self
+    }
+
+    /// How often a packet is sent again when the board doesn't acknowledge it, or acknowledges
+    /// another packet, before the upload fails. Defaults to 3. The start packet is never sent
+    /// again: when it isn't acknowledged, the board isn't in the bootloader. How many packets
+    /// were sent again ends up in [`UploadReport::retries`](crate::UploadReport::retries).
+    pub fn max_retries(mut self, retries: usize) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// How often the whole upload is tried when it fails halfway, opening the port again and
+    /// starting over with the start packet. Defaults to 1, trying only once. Not used while
+    /// searching for the board on several ports, where a failure most likely means that the
+    /// board isn't on that port.
+    pub fn max_upload_attempts(mut self, attempts: usize) -> Self {
+        self.max_upload_attempts = attempts;
+        self
+    }
+
+    /// How long to wait before the next attempt at the upload, see
+    /// [`max_upload_attempts`](Self::max_upload_attempts). Defaults to 1 second.
+    pub fn upload_retry_delay(mut self, delay: Duration) -> Self {
+        self.upload_retry_delay = delay;
+        self
+    }
+
+    /// Before the start packet, check that the bootloader is listening with a
+    /// [ping](crate::Serial::ping), so a board that wasn't reset fails within half a second
+    /// instead of after the full serial timeout. On by default. Turn it off for bootloaders that
+    /// don't acknowledge a frame without a DFU packet in it.
+    ///
+    /// The ping is a frame of its own, with the first sequence number, so the frames of the
+    /// upload itself have the sequence numbers after it. Without the ping, an upload sends
+    /// exactly what pc-nrfutil sends.
+    pub fn ping(mut self, ping: bool) -> Self {
+        self.ping = ping;
+        self
+    }
+
+    /// What is being uploaded. Defaults to an application. Other images don't start with the
+    /// vector table of an application, so they skip the check for one (see [`force`](Self::force)).
+    pub fn image_type(mut self, image_type: ImageType) -> Self {
+        self.image_type = image_type;
+        self
+    }
+
+    /// What the init packet says about the device and the image, for bootloaders that check it.
+    /// Defaults to wildcards that every bootloader accepts.
+    pub fn init_packet(mut self, packet: InitPacket) -> Self {
+        self.init_packet = packet;
+        self
+    }
+
+    /// How the init packet lets the bootloader check the image. Defaults to the CRC-16 the
+    /// bootloaders on the lab boards expect. With the wrong one, the bootloader rejects the
+    /// image at the end of the upload, or doesn't start it.
+    pub fn integrity_check(mut self, check: IntegrityCheck) -> Self {
+        self.integrity_check = check;
+        self
+    }
+
+    /// Where the application starts in flash.
+    pub(crate) fn app_start(&self) -> u32 {
+        self.app_start_address.unwrap_or(self.board.app_start)
+    }
+
+    /// The names to find the serial chip of the board by.
+    pub(crate) fn usb_product_names(&self) -> &[String] {
+        self.product_names
+            .as_deref()
+            .unwrap_or(&self.board.product_names)
+    }
+
+    /// The baud rate the port is opened at.
+    pub(crate) fn baud(&self) -> u32 {
+        self.baud_rate.unwrap_or(self.board.baud_rate)
+    }
+
+    /// Hand `event` to the [progress callback](Self::on_progress), if there is one.
+    pub(crate) fn report_progress(&self, event: ProgressEvent) {
+        if let Some(ProgressSink(callback)) = &self.progress {
+            callback(event);
+        }
+    }
+
+    /// Whether the [cancel flag](Self::cancel_flag) was set.
+    pub(crate) fn cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    /// The largest image that is uploaded.
+    pub(crate) fn image_size_limit(&self) -> usize {
+        self.max_image_size
+            .unwrap_or_else(|| self.available_flash())
+    }
+
+    /// How many bytes of flash there are for the application.
+    pub(crate) fn available_flash(&self) -> usize {
+        self.board.bootloader_start.saturating_sub(self.app_start()) as usize
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.packet_size == 0 || self.packet_size + 4 > MAX_SLIP_PAYLOAD {
+            bail!(
+                "invalid packet size {}: must be between 1 and {} bytes",
+                self.packet_size,
+                MAX_SLIP_PAYLOAD - 4
+            );
+        }
+
+        if self.window_size == 0 || self.window_size > MAX_WINDOW_SIZE {
+            bail!(
+                "invalid window size {}: must be between 1 and {MAX_WINDOW_SIZE}",
+                self.window_size
+            );
+        }
+
+        if self.retry_backoff.1 == 0 {
+            bail!("the factor of the retry backoff has to be at least 1");
+        }
+
+        if self.adaptive_packet_size && self.window_size > 1 {
+            bail!(
+                "adaptive packet sizes need a window size of 1, not {}",
+                self.window_size
+            );
+        }
+
+        self.board.validate()?;
+        check_baud_rate(self.baud())?;
+        if self.app_start() >= self.board.bootloader_start {
+            bail!(
+                "the application can't start at 0x{:08x}, the bootloader starts at 0x{:08x}",
+                self.app_start(),
+                self.board.bootloader_start
+            );
+        }
+
+        if self.pad_to == 0 || !self.pad_to.is_multiple_of(4) {
+            bail!(
+                "can't pad the image to a multiple of {} bytes, must be a multiple of 4",
+                self.pad_to
+            );
+        }
+
+        if matches!(self.reset, Some((_, pulse)) if pulse.is_zero()) {
+            bail!("the reset pulse can't be 0");
+        }
+        if matches!(&self.banner, Some((banner, _)) if banner.is_empty()) {
+            bail!("the banner to wait for after uploading can't be empty");
+        }
+
+        if self.max_upload_attempts == 0 {
+            bail!("the upload has to be attempted at least once");
+        }
+
+        if self.open_timeout.is_zero() {
+            bail!("the timeout for opening a port must be longer than 0");
+        }
+
+        if self.open_attempts == 0 {
+            bail!("opening a port has to be attempted at least once");
+        }
+
+        if self.serial_timeout.is_zero() {
+            bail!("the serial timeout must be longer than 0");
+        }
+
+        if self.latency_timer > MAX_LATENCY_TIMER {
+            bail!(
+                "invalid latency timer of {}ms: must be at most {}ms",
+                self.latency_timer.as_millis(),
+                MAX_LATENCY_TIMER.as_millis()
+            );
+        }
+
+        if self.max_ports == Some(0) {
+            bail!("the maximum number of ports to try must be at least 1");
+        }
+
+        Ok(())
+    }
+}
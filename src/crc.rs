@@ -1,19 +1,285 @@
-/// This implements the CRC like the original python implementation.
-/// It's hard to say which specific CRC it is, otherwise I'd have used a library.
-/// ChatGPT says it's CCITT, but there are two variants and none look like this one.
-pub fn calc_crc16(data: &[u8], start: Option<u16>) -> u16 {
-    let mut crc = start.unwrap_or(0xffff);
-    for &b in data {
-        crc = (crc >> 8 & 0x00FF) | (crc << 8 & 0xFF00);
-        crc ^= b as u16;
-        crc ^= (crc & 0x00FF) >> 4;
-        crc ^= (crc << 8) << 4;
-        crc ^= ((crc & 0x00FF) << 4) << 1;
-    }
+//! The checksums of the bootloader protocol, the CRC-16 of the frames and images and the CRC-32
+//! of the extended init packet, exported so the firmware on the other end can check
+//! its image with the exact same implementation.
+//!
+//! Nothing here allocates or uses anything outside of `core`.
 
+/// The CRC after the bytes `crc` was for, followed by `b`, the way the original python
+/// implementation computes it.
+const fn bitwise_step(mut crc: u16, b: u8) -> u16 {
+    crc = (crc >> 8 & 0x00FF) | (crc << 8 & 0xFF00);
+    crc ^= b as u16;
+    crc ^= (crc & 0x00FF) >> 4;
+    crc ^= (crc << 8) << 4;
+    crc ^= ((crc & 0x00FF) << 4) << 1;
     crc
 }
 
+/// What every value of the byte that falls out of the top adds to the CRC. The steps above are
+/// all xors and shifts, and the low byte of the CRC only ends up in the high byte, so a byte
+/// comes down to `crc = crc << 8 ^ TABLE[(crc >> 8 ^ b) as usize]`.
+const TABLE: [u16; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = bitwise_step(0, i as u8);
+        i += 1;
+    }
+    table
+};
+
+/// The CRC-16 of [`calc_crc16`], over data that comes in pieces, like the chunks of an image
+/// as they are sent. Feeding it the pieces one after the other gives the CRC of all of them:
+///
+/// ```
+/// # use tudelft_serial_upload::{calc_crc16_default, Crc16};
+/// let crc = Crc16::new().update(b"1234").update(b"56789").finalize();
+/// assert_eq!(crc, 0x29b1);
+/// assert_eq!(crc, calc_crc16_default(b"123456789"));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Crc16 {
+    crc: u16,
+}
+
+impl Default for Crc16 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc16 {
+    pub fn new() -> Self {
+        Self::with_start(0xffff)
+    }
+
+    /// Continue from the CRC of the data before, instead of starting over.
+    pub fn with_start(start: u16) -> Self {
+        Self { crc: start }
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> &mut Self {
+        self.crc = data.iter().fold(self.crc, |crc, &b| {
+            crc << 8 ^ TABLE[(crc >> 8) as u8 as usize ^ b as usize]
+        });
+        self
+    }
+
+    /// The CRC of everything so far. More data can still be added after this.
+    pub fn finalize(&self) -> u16 {
+        self.crc
+    }
+}
+
+/// The CRC-16 the bootloader checks the image and every frame with, continuing from `start`
+/// when it is given. It computes the CRC like the original python implementation, which turns
+/// out to be CRC-16/CCITT-FALSE: polynomial 0x1021, starting at 0xffff, not reflected and not
+/// inverted at the end.
+///
+/// ```
+/// # use tudelft_serial_upload::calc_crc16;
+/// assert_eq!(calc_crc16(b"123456789", None), 0x29b1);
+/// assert_eq!(calc_crc16(b"", None), 0xffff);
+/// assert_eq!(calc_crc16(b"56789", Some(calc_crc16(b"1234", None))), 0x29b1);
+/// ```
+pub fn calc_crc16(data: &[u8], start: Option<u16>) -> u16 {
+    start
+        .map_or_else(Crc16::new, Crc16::with_start)
+        .update(data)
+        .finalize()
+}
+
+/// The CRC-16 of [`calc_crc16`], from the start value the bootloader uses.
+///
+/// ```
+/// # use tudelft_serial_upload::calc_crc16_default;
+/// assert_eq!(calc_crc16_default(b"123456789"), 0x29b1);
+/// assert_eq!(calc_crc16_default(&[0x00]), 0xe1f0);
+/// ```
 pub fn calc_crc16_default(data: &[u8]) -> u16 {
     calc_crc16(data, None)
 }
+
+/// The CRC of [`calc_crc16`] one bit operation at a time, to check the table against.
+#[cfg(test)]
+fn calc_crc16_bitwise(data: &[u8], start: Option<u16>) -> u16 {
+    data.iter()
+        .fold(start.unwrap_or(0xffff), |crc, &b| bitwise_step(crc, b))
+}
+
+/// One byte of the CRC-32 register a bit at a time, with the polynomial reflected.
+const fn crc32_bitwise_step(mut crc: u32, b: u8) -> u32 {
+    crc ^= b as u32;
+    let mut bit = 0;
+    while bit < 8 {
+        crc = if crc & 1 != 0 {
+            crc >> 1 ^ 0xedb8_8320
+        } else {
+            crc >> 1
+        };
+        bit += 1;
+    }
+    crc
+}
+
+/// Like [`TABLE`], for the CRC-32: `crc = crc >> 8 ^ CRC32_TABLE[(crc ^ b) as u8 as usize]`.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = crc32_bitwise_step(0, i as u8);
+        i += 1;
+    }
+    table
+};
+
+/// The CRC-32 of [`calc_crc32`], over data that comes in pieces, like [`Crc16`]:
+///
+/// ```
+/// # use tudelft_serial_upload::{calc_crc32, Crc32};
+/// let crc = Crc32::new().update(b"1234").update(b"56789").finalize();
+/// assert_eq!(crc, 0xcbf4_3926);
+/// assert_eq!(crc, calc_crc32(b"123456789"));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Crc32 {
+    /// The register, which is inverted at the start and again at the end.
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { crc: 0xffff_ffff }
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> &mut Self {
+        self.crc = data.iter().fold(self.crc, |crc, &b| {
+            crc >> 8 ^ CRC32_TABLE[(crc ^ b as u32) as u8 as usize]
+        });
+        self
+    }
+
+    /// The CRC of everything so far. More data can still be added after this.
+    pub fn finalize(&self) -> u32 {
+        !self.crc
+    }
+}
+
+/// The CRC-32 of zip and ethernet (CRC-32/ISO-HDLC: polynomial 0x04c11db7 reflected, starting
+/// at and inverted at the end with 0xffffffff), which the extended init packet has instead of
+/// the CRC-16, see [`IntegrityCheck::Crc32`](crate::IntegrityCheck::Crc32).
+///
+/// ```
+/// # use tudelft_serial_upload::calc_crc32;
+/// assert_eq!(calc_crc32(b"123456789"), 0xcbf4_3926);
+/// assert_eq!(calc_crc32(b""), 0);
+/// ```
+pub fn calc_crc32(data: &[u8]) -> u32 {
+    Crc32::new().update(data).finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        calc_crc16, calc_crc16_bitwise, calc_crc16_default, calc_crc32, crc32_bitwise_step, Crc16,
+        Crc32,
+    };
+    use crate::bench::xorshift;
+
+    #[test]
+    fn test_check_values() {
+        assert_eq!(calc_crc16_default(b"123456789"), 0x29b1);
+        assert_eq!(Crc16::new().update(b"123456789").finalize(), 0x29b1);
+        assert_eq!(Crc16::new().finalize(), calc_crc16_default(&[]));
+        assert_eq!(calc_crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(calc_crc32(&[]), 0);
+        assert_eq!(
+            calc_crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+        assert_eq!(calc_crc32(&[0; 32]), 0x190a_55ad);
+    }
+
+    #[test]
+    fn test_crc32() {
+        let mut state = 0x853c_49e6_748f_ea9b;
+        for _ in 0..100 {
+            let len = xorshift(&mut state) as usize % 5000;
+            let data: Vec<u8> = (0..len).map(|_| xorshift(&mut state) as u8).collect();
+            let bitwise = !data
+                .iter()
+                .fold(0xffff_ffff, |crc, &b| crc32_bitwise_step(crc, b));
+            assert_eq!(calc_crc32(&data), bitwise);
+
+            let split = xorshift(&mut state) as usize % (len + 1);
+            let crc = Crc32::new()
+                .update(&data[..split])
+                .update(&data[split..])
+                .finalize();
+            assert_eq!(crc, bitwise);
+        }
+    }
+
+    #[test]
+    fn test_table_matches_bitwise() {
+        // every start value with every byte: the whole state space of one step
+        for start in 0..=u16::MAX {
+            for b in 0..=u8::MAX {
+                assert_eq!(
+                    calc_crc16(&[b], Some(start)),
+                    calc_crc16_bitwise(&[b], Some(start))
+                );
+            }
+        }
+
+        let mut state = 0x9e37_79b9_7f4a_7c15;
+        for _ in 0..100 {
+            let len = xorshift(&mut state) as usize % 5000;
+            let data: Vec<u8> = (0..len).map(|_| xorshift(&mut state) as u8).collect();
+            assert_eq!(calc_crc16_default(&data), calc_crc16_bitwise(&data, None));
+        }
+    }
+
+    #[test]
+    fn test_crc16_in_pieces() {
+        let (a, b) = (b"1234".as_slice(), b"56789".as_slice());
+        assert_eq!(
+            Crc16::new().update(a).update(b).finalize(),
+            calc_crc16(&[a, b].concat(), None)
+        );
+        assert_eq!(
+            Crc16::with_start(calc_crc16_default(a))
+                .update(b)
+                .finalize(),
+            calc_crc16(b, Some(calc_crc16_default(a)))
+        );
+
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..200 {
+            let len = xorshift(&mut state) as usize % 2000;
+            let data: Vec<u8> = (0..len).map(|_| xorshift(&mut state) as u8).collect();
+            let start =
+                [None, Some(xorshift(&mut state) as u16)][xorshift(&mut state) as usize % 2];
+
+            // split at random places, including empty pieces
+            let mut splits: Vec<usize> = (0..xorshift(&mut state) % 8)
+                .map(|_| xorshift(&mut state) as usize % (len + 1))
+                .collect();
+            splits.sort();
+            let mut crc = start.map_or_else(Crc16::new, Crc16::with_start);
+            let mut last = 0;
+            for split in splits.into_iter().chain([len]) {
+                crc.update(&data[last..split]);
+                last = split;
+            }
+
+            assert_eq!(crc.finalize(), calc_crc16(&data, start), "{len} bytes");
+        }
+    }
+}
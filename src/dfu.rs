@@ -0,0 +1,508 @@
+//! The DFU protocol the bootloader on the drone boards speaks, for tools that want to drive an
+//! upload themselves instead of using [`upload`](crate::upload).
+//!
+//! An upload is a start packet with the size of the image, an init packet with its CRC, the
+//! image itself in data packets, and finally a stop packet. Every packet is wrapped in an HCI
+//! frame with a sequence number, which the board acknowledges. [`DfuSession`] takes care of the
+//! framing and the acknowledgements, the functions in this module build the payloads.
+//!
+//! This module is only available with the `protocol` feature.
+//!
+//! # Stability
+//!
+//! The opcodes and payload layouts are those of the bootloader, and only change when it does,
+//! which would be a breaking release of this crate. [`DfuSession`] may gain methods in minor
+//! releases. Timing is not part of the API: how long is waited after a packet, and how often a
+//! packet is sent again while the bootloader is busy, may change in any release, because it
+//! depends on what the bootloader turns out to need.
+
+// only with the feature, because the example can't reach this module otherwise
+#![cfg_attr(
+    feature = "protocol",
+    doc = r#"
+# Example
+
+```no_run
+use std::path::PathBuf;
+use tudelft_serial_upload::dfu::{DfuSession, Serial};
+
+# fn main() -> tudelft_serial_upload::color_eyre::Result<()> {
+let image = std::fs::read("firmware.bin")?;
+let mut serial = Serial::open(PathBuf::from("/dev/ttyUSB0"))?;
+
+let mut session = DfuSession::new(&mut serial);
+session.send_start(image.len() as u32)?;
+session.send_init(&image)?;
+for chunk in image.chunks(512) {
+    session.send_data(chunk)?;
+}
+session.send_stop()?;
+# Ok(())
+# }
+```
+"#
+)]
+
+use std::time::Duration;
+
+use color_eyre::Result;
+
+use crate::config::{DEFAULT_ERASE_TIMEOUT, DEFAULT_INIT_WAIT};
+use crate::crc::{calc_crc16_default, calc_crc32};
+#[cfg(feature = "protocol")]
+pub use crate::hci::AckFrame;
+pub use crate::serial::Serial;
+
+/// Opcode of the packet with the CRC of the image.
+pub const DFU_INIT_PACKET: u32 = 1;
+/// Opcode of the packet that starts an upload.
+pub const DFU_START_PACKET: u32 = 3;
+/// Opcode of a packet with a part of the image.
+pub const DFU_DATA_PACKET: u32 = 4;
+/// Opcode of the packet that ends an upload.
+pub const DFU_STOP_DATA_PACKET: u32 = 5;
+/// Opcode of the packet that starts the uploaded application, for bootloaders that don't
+/// do that by themselves after the stop packet.
+pub const DFU_ACTIVATE_AND_RESET: u32 = 6;
+
+/// What is in an image, which the start packet tells the bootloader.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ImageType {
+    /// A program, which is what is uploaded almost always.
+    #[default]
+    Application,
+    /// A new bootloader, which replaces the one doing the upload.
+    Bootloader,
+    /// A new SoftDevice, the Bluetooth stack of Nordic.
+    SoftDevice,
+    /// A SoftDevice followed by a bootloader, in one image. The SoftDevice is the first
+    /// `softdevice_size` bytes of it.
+    SoftDeviceAndBootloader { softdevice_size: u32 },
+}
+
+impl ImageType {
+    /// The mode word of the start packet.
+    fn mode(self) -> u32 {
+        match self {
+            Self::SoftDevice => 1,
+            Self::Bootloader => 2,
+            Self::SoftDeviceAndBootloader { .. } => 3,
+            Self::Application => 4,
+        }
+    }
+
+    /// How an image of `image_size` bytes of this type is split up.
+    pub fn sizes(self, image_size: u32) -> ImageSizes {
+        let mut sizes = ImageSizes::default();
+        match self {
+            Self::Application => sizes.application = image_size,
+            Self::Bootloader => sizes.bootloader = image_size,
+            Self::SoftDevice => sizes.softdevice = image_size,
+            Self::SoftDeviceAndBootloader { softdevice_size } => {
+                sizes.softdevice = softdevice_size;
+                sizes.bootloader = image_size.saturating_sub(softdevice_size);
+            }
+        }
+        sizes
+    }
+}
+
+/// The sizes of the parts of an image, as the start packet has them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImageSizes {
+    pub softdevice: u32,
+    pub bootloader: u32,
+    pub application: u32,
+}
+
+/// The payload of the start packet, for an application of `image_size` bytes.
+pub fn start_payload(image_size: u32) -> Vec<u8> {
+    start_payload_typed(
+        ImageType::Application,
+        ImageType::Application.sizes(image_size),
+    )
+}
+
+/// The payload of the start packet, for any type of image.
+pub fn start_payload_typed(image_type: ImageType, sizes: ImageSizes) -> Vec<u8> {
+    let mut res = Vec::new();
+
+    res.extend_from_slice(&DFU_START_PACKET.to_le_bytes());
+    res.extend_from_slice(&image_type.mode().to_le_bytes());
+    res.extend_from_slice(&sizes.softdevice.to_le_bytes());
+    res.extend_from_slice(&sizes.bootloader.to_le_bytes());
+    res.extend_from_slice(&sizes.application.to_le_bytes());
+
+    res
+}
+
+/// The SoftDevice requirement that accepts any SoftDevice.
+pub const ANY_SOFTDEVICE: u16 = 0xfffe;
+
+/// What the init packet says about the device and the image, which the bootloader may check
+/// before it accepts the image.
+///
+/// The defaults are the wildcards that every bootloader accepts. Bootloaders that check these
+/// reject images for other devices, or with an older version than the application on it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InitPacket {
+    pub device_type: u16,
+    pub device_revision: u16,
+    pub app_version: u32,
+    /// The firmware ids of the SoftDevices the image works with.
+    pub softdevice_reqs: Vec<u16>,
+}
+
+impl Default for InitPacket {
+    fn default() -> Self {
+        Self {
+            device_type: 0xffff,
+            device_revision: 0xffff,
+            app_version: 0xffff_ffff,
+            softdevice_reqs: vec![ANY_SOFTDEVICE],
+        }
+    }
+}
+
+impl InitPacket {
+    /// The fields as they are sent, before the CRC of the image.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = vec![];
+
+        res.extend_from_slice(&self.device_type.to_le_bytes());
+        res.extend_from_slice(&self.device_revision.to_le_bytes());
+        res.extend_from_slice(&self.app_version.to_le_bytes());
+        res.extend_from_slice(&(self.softdevice_reqs.len() as u16).to_le_bytes());
+        for req in &self.softdevice_reqs {
+            res.extend_from_slice(&req.to_le_bytes());
+        }
+
+        res
+    }
+
+    /// Read the fields back from the start of `bytes`. Returns them with the rest of `bytes`.
+    pub fn parse(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let u16_at = |i: usize| Some(u16::from_le_bytes(bytes.get(i..i + 2)?.try_into().unwrap()));
+        let count = u16_at(8)? as usize;
+        let softdevice_reqs = (0..count)
+            .map(|i| u16_at(10 + i * 2))
+            .collect::<Option<_>>()?;
+
+        let packet = Self {
+            device_type: u16_at(0)?,
+            device_revision: u16_at(2)?,
+            app_version: u32::from_le_bytes(bytes.get(4..8)?.try_into().unwrap()),
+            softdevice_reqs,
+        };
+        Some((packet, &bytes[10 + count * 2..]))
+    }
+}
+
+/// How the init packet lets the bootloader check the image.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntegrityCheck {
+    /// A CRC-16, which the bootloaders on the lab boards expect.
+    #[default]
+    Crc16,
+    /// A CRC-32, in the extended init packet of newer builds of the serial bootloader.
+    Crc32,
+}
+
+/// The payload of the init packet, with the CRC of the whole `image`.
+#[cfg(any(feature = "protocol", test))]
+pub fn init_payload(image: &[u8]) -> Vec<u8> {
+    init_payload_for(&InitPacket::default(), IntegrityCheck::Crc16, image)
+}
+
+/// The payload of the init packet with these fields, and the CRC of the whole `image`.
+pub fn init_payload_for(packet: &InitPacket, check: IntegrityCheck, image: &[u8]) -> Vec<u8> {
+    let mut res = vec![];
+
+    res.extend_from_slice(&DFU_INIT_PACKET.to_le_bytes());
+    res.extend_from_slice(&packet.to_bytes());
+    match check {
+        IntegrityCheck::Crc16 => {
+            res.extend_from_slice(&calc_crc16_default(image).to_le_bytes());
+            // padding required as per the python reference implementation. No further docs found on this
+            res.extend_from_slice(&[0, 0]);
+        }
+        IntegrityCheck::Crc32 => res.extend_from_slice(&calc_crc32(image).to_le_bytes()),
+    }
+
+    res
+}
+
+/// The payload of a data packet carrying `chunk`.
+pub fn data_payload(chunk: &[u8]) -> Vec<u8> {
+    let mut res = vec![];
+
+    res.extend_from_slice(&DFU_DATA_PACKET.to_le_bytes());
+    res.extend_from_slice(chunk);
+
+    res
+}
+
+/// The payload of the stop packet.
+pub fn stop_payload() -> Vec<u8> {
+    DFU_STOP_DATA_PACKET.to_le_bytes().to_vec()
+}
+
+/// The payload of the activate packet.
+pub fn activate_payload() -> Vec<u8> {
+    DFU_ACTIVATE_AND_RESET.to_le_bytes().to_vec()
+}
+
+/// Sends DFU packets one at a time over a [`Serial`], waiting for every one to be acknowledged.
+///
+/// The packets are not checked to come in a sensible order, that is up to the caller.
+pub struct DfuSession<'a> {
+    serial: &'a mut Serial,
+    erase_timeout: Duration,
+    init_wait: Duration,
+    init_packet: InitPacket,
+    integrity_check: IntegrityCheck,
+}
+
+impl<'a> DfuSession<'a> {
+    pub fn new(serial: &'a mut Serial) -> Self {
+        Self {
+            serial,
+            erase_timeout: DEFAULT_ERASE_TIMEOUT,
+            init_wait: DEFAULT_INIT_WAIT,
+            init_packet: InitPacket::default(),
+            integrity_check: IntegrityCheck::Crc16,
+        }
+    }
+
+    /// How long the bootloader may take to erase the flash after the start packet, see
+    /// [`send_init`](Self::send_init).
+    pub fn erase_timeout(mut self, timeout: Duration) -> Self {
+        self.erase_timeout = timeout;
+        self
+    }
+
+    /// How long [`send_init`](Self::send_init) waits at most for the bootloader to be ready for
+    /// the data packets, 1 second by default.
+    pub fn init_wait(mut self, wait: Duration) -> Self {
+        self.init_wait = wait;
+        self
+    }
+
+    /// What [`send_init`](Self::send_init) says about the device and the image.
+    pub fn init_packet(mut self, packet: InitPacket) -> Self {
+        self.init_packet = packet;
+        self
+    }
+
+    /// How [`send_init`](Self::send_init) lets the bootloader check the image.
+    pub fn integrity_check(mut self, check: IntegrityCheck) -> Self {
+        self.integrity_check = check;
+        self
+    }
+
+    /// Send any payload in the next frame, and wait for the board to acknowledge it.
+    pub fn send(&mut self, payload: &[u8]) -> Result<()> {
+        self.serial.send_data(payload)
+    }
+
+    /// Start an upload of an application of `image_size` bytes, after which the bootloader
+    /// erases the flash.
+    pub fn send_start(&mut self, image_size: u32) -> Result<()> {
+        self.send(&start_payload(image_size))
+    }
+
+    /// Start an upload of any type of image, like [`send_start`](Self::send_start).
+    pub fn send_start_typed(&mut self, image_type: ImageType, sizes: ImageSizes) -> Result<()> {
+        self.send(&start_payload_typed(image_type, sizes))
+    }
+
+    /// Send the CRC of the image. The bootloader doesn't answer while it is still erasing the
+    /// flash after the start packet, so the packet is sent again until it does, for at most the
+    /// [erase timeout](Self::erase_timeout).
+    ///
+    /// Waits for the bootloader to be ready for the data packets afterwards. Bootloaders that
+    /// announce that with a response to the init packet are waited for only until it arrives,
+    /// for the others this always takes the [init wait](Self::init_wait).
+    pub fn send_init(&mut self, image: &[u8]) -> Result<()> {
+        let resent = self.serial.send_data_when_ready(
+            &init_payload_for(&self.init_packet, self.integrity_check, image),
+            self.erase_timeout,
+        )?;
+        self.serial
+            .wait_for_response(DFU_INIT_PACKET, self.init_wait)?;
+        if resent {
+            // the packets that got through late are acknowledged too
+            self.serial.clear_input()?;
+        }
+        Ok(())
+    }
+
+    /// Send the next part of the image.
+    #[cfg(any(feature = "protocol", test))]
+    pub fn send_data(&mut self, chunk: &[u8]) -> Result<()> {
+        self.send(&data_payload(chunk))
+    }
+
+    /// End the upload, after which the bootloader checks the CRC and starts the application.
+    #[cfg(any(feature = "protocol", test))]
+    pub fn send_stop(&mut self) -> Result<()> {
+        self.send(&stop_payload())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        init_payload, init_payload_for, start_payload, start_payload_typed, stop_payload,
+        DfuSession, ImageType, InitPacket, IntegrityCheck,
+    };
+    use expect_test::expect;
+
+    use crate::config::UploadConfig;
+    use crate::emulator::{emulator_serial, Emulator};
+
+    #[test]
+    fn test_custom_upload_sequence() {
+        let image: Vec<u8> = (0..1000u32).map(|i| (i % 253) as u8).collect();
+        let emulator = Emulator::new();
+        let mut serial = emulator_serial(&emulator);
+
+        let mut session = DfuSession::new(&mut serial);
+        session.send_start(image.len() as u32).unwrap();
+        session.send_init(&image).unwrap();
+        // chunks of varying size, which the regular upload never sends
+        for chunk in image.chunks(300).flat_map(|c| c.chunks(97)) {
+            session.send_data(chunk).unwrap();
+        }
+        session.send_stop().unwrap();
+
+        assert_eq!(emulator.image_size(), Some(1000));
+        assert_eq!(emulator.image(), image);
+        assert!(emulator.stopped());
+    }
+
+    /// The frames pc-nrfutil's legacy serial DFU sends for the start, the init and the first 16
+    /// bytes of this image: its first frame has sequence number 1 too, and the ack field is always
+    /// the sequence number after it.
+    fn python_reference() -> (Vec<u8>, [&'static [u8]; 3]) {
+        let golden: [&[u8]; 3] = [
+            &[
+                0xc0, 0xd1, 0x4e, 0x01, 0xe0, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x3d, 0x7e, 0xc0,
+            ],
+            &[
+                0xc0, 0xda, 0x4e, 0x01, 0xd7, 0x01, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff,
+                0xff, 0xff, 0xff, 0x01, 0x00, 0xfe, 0xff, 0x95, 0xfb, 0x00, 0x00, 0xaf, 0xfc, 0xc0,
+            ],
+            &[
+                0xc0, 0xe3, 0x4e, 0x01, 0xce, 0x04, 0x00, 0x00, 0x00, 0xdb, 0xdc, 0xdb, 0xdd, 0x01,
+                0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0xd2,
+                0xfc, 0xc0,
+            ],
+        ];
+        let image = [0xc0, 0xdb].into_iter().chain(1..19).collect();
+        (image, golden)
+    }
+
+    #[test]
+    fn test_frames_match_python_reference() {
+        let (image, golden) = python_reference();
+        let emulator = Emulator::new();
+        let mut serial = emulator_serial(&emulator);
+
+        let mut session = DfuSession::new(&mut serial);
+        session.send_start(image.len() as u32).unwrap();
+        session.send_init(&image).unwrap();
+        session.send_data(&image[..16]).unwrap();
+        assert_eq!(emulator.written(), golden.concat());
+    }
+
+    #[test]
+    fn test_upload_frames() {
+        let (image, golden) = python_reference();
+        let upload = |config: &UploadConfig| {
+            let emulator = Emulator::new();
+            emulator_serial(&emulator)
+                .try_do_upload(&image, config)
+                .unwrap();
+            emulator.written()
+        };
+
+        // without the ping, an upload starts with exactly the frames of pc-nrfutil
+        let config = UploadConfig::default()
+            .packet_size(16)
+            .reset_after_upload(false);
+        assert!(upload(&config.clone().ping(false)).starts_with(&golden.concat()));
+
+        // with it, the ping gets sequence number 1, and the frames of the upload the ones after
+        let frames: String = upload(&config)
+            .split(|&b| b == 0xc0)
+            .filter(|frame| !frame.is_empty())
+            .map(|frame| frame.iter().map(|b| format!("{b:02x}")).collect::<String>() + "\n")
+            .collect();
+        expect![[r#"
+            d10e00213575
+            da4e01d70300000004000000000000000000000014000000f705
+            e34e01ce01000000ffffffffffffffff0100feff95fb00002d37
+            ec4e01c504000000dbdcdbdd0102030405060708090a0b0c0d0eaf70
+            f58e007d040000000f101112a011
+            fe4e00b405000000bb16
+        "#]]
+        .assert_eq(&frames);
+    }
+
+    #[test]
+    fn test_init_packet() {
+        let packet = InitPacket {
+            device_type: 0x0052,
+            device_revision: 0x0003,
+            app_version: 0x0102_0304,
+            softdevice_reqs: vec![0x0064, 0x0080],
+        };
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes, [0x52, 0, 3, 0, 4, 3, 2, 1, 2, 0, 0x64, 0, 0x80, 0]);
+        assert_eq!(InitPacket::parse(&bytes), Some((packet.clone(), &[][..])));
+
+        let payload = init_payload_for(&packet, IntegrityCheck::Crc16, &[0; 4]);
+        assert_eq!(
+            InitPacket::parse(&payload[4..]).unwrap().1,
+            [0xc0, 0x84, 0, 0]
+        );
+        let payload = init_payload_for(&packet, IntegrityCheck::Crc32, &[0; 4]);
+        assert_eq!(
+            InitPacket::parse(&payload[4..]).unwrap().1,
+            0x2144_df1cu32.to_le_bytes()
+        );
+        assert_eq!(InitPacket::parse(&bytes[..12]), None);
+    }
+
+    #[test]
+    fn test_payloads() {
+        assert_eq!(
+            start_payload(0x0102),
+            [3, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 0, 0]
+        );
+        assert_eq!(stop_payload(), [5, 0, 0, 0]);
+
+        // what the python reference sends for an image of only zeroes
+        assert_eq!(
+            init_payload(&[0; 4]),
+            [
+                1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 0x00, 0xfe, 0xff,
+                0xc0, 0x84, 0, 0
+            ]
+        );
+
+        let image_type = ImageType::SoftDeviceAndBootloader {
+            softdevice_size: 0x0100,
+        };
+        assert_eq!(
+            start_payload_typed(image_type, image_type.sizes(0x0300)),
+            [3, 0, 0, 0, 3, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            start_payload_typed(ImageType::Bootloader, ImageType::Bootloader.sizes(0x0102)),
+            [3, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 2, 1, 0, 0, 0, 0, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Checking the drivers an upload needs, without a board attached.
+
+use std::fmt::{self, Display, Formatter};
+
+use serial_enumerator::get_serial_list;
+
+/// How to get the D2XX driver working on this operating system.
+#[cfg(target_os = "macos")]
+pub(crate) const DRIVER_INSTALL: &str = "Install the D2XX driver from <https://ftdichip.com/drivers/d2xx-drivers/>, following the README that comes with it";
+#[cfg(target_os = "linux")]
+pub(crate) const DRIVER_INSTALL: &str = "Install libftd2xx from <https://ftdichip.com/drivers/d2xx-drivers/> and unload the ftdi_sio kernel driver with `sudo rmmod ftdi_sio`, or build without the `ftdi` feature to use the serial port of the kernel";
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+pub(crate) const DRIVER_INSTALL: &str =
+    "Install the D2XX driver from <https://ftdichip.com/drivers/d2xx-drivers/>";
+
+/// What the D2XX driver said, see [`DriverReport::d2xx`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct D2xxReport {
+    /// The version of the library, or why it didn't respond.
+    pub version: Result<String, String>,
+    /// The serial numbers and descriptions of the FTDI devices it lists, or why it couldn't.
+    pub devices: Result<Vec<String>, String>,
+}
+
+/// Which drivers work, found by [`check_drivers`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DriverReport {
+    /// `None` when the crate was built without the `ftdi` feature, and doesn't use D2XX.
+    pub d2xx: Option<D2xxReport>,
+    /// The serial ports of the operating system.
+    pub serial_ports: Vec<String>,
+}
+
+impl DriverReport {
+    /// What keeps uploads from working well, with what to do about it. Empty when nothing does.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Some(d2xx) = &self.d2xx {
+            match (&d2xx.version, &d2xx.devices) {
+                (Err(e), _) | (Ok(_), Err(e)) => problems.push(format!("{e}: {DRIVER_INSTALL}")),
+                (Ok(_), Ok(devices)) if devices.is_empty() && !self.serial_ports.is_empty() => {
+                    problems.push(format!(
+                        "D2XX lists no FTDI devices, so uploads go over the serial port of the operating system, which may not do hardware flow control: {DRIVER_INSTALL}"
+                    ))
+                }
+                _ => {}
+            }
+        }
+        if self.serial_ports.is_empty() {
+            problems
+                .push("no serial ports were found, plug in the board to check that it is".into());
+        }
+        problems
+    }
+}
+
+impl Display for DriverReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.d2xx {
+            None => writeln!(
+                f,
+                "D2XX driver:  not used, built without the `ftdi` feature"
+            )?,
+            Some(d2xx) => {
+                match &d2xx.version {
+                    Ok(version) => writeln!(f, "D2XX driver:  version {version}")?,
+                    Err(e) => writeln!(f, "D2XX driver:  {e}")?,
+                }
+                match &d2xx.devices {
+                    Ok(devices) => writeln!(f, "FTDI devices: {}", list(devices))?,
+                    Err(e) => writeln!(f, "FTDI devices: {e}")?,
+                }
+            }
+        }
+        writeln!(f, "serial ports: {}", list(&self.serial_ports))?;
+
+        let problems = self.problems();
+        if problems.is_empty() {
+            return writeln!(f, "no problems found");
+        }
+        for problem in problems {
+            writeln!(f, "- {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+fn list(items: &[String]) -> String {
+    if items.is_empty() {
+        "none".to_string()
+    } else {
+        items.join(", ")
+    }
+}
+
+/// Check whether the D2XX driver (with the `ftdi` feature) responds and which devices it and
+/// the operating system see, to find out why uploads don't work before a board is at hand.
+///
+/// A D2XX library that is missing altogether already keeps a program built with the `ftdi`
+/// feature from starting, so that can't be reported here.
+pub fn check_drivers() -> DriverReport {
+    #[cfg(feature = "ftdi")]
+    let d2xx = Some(D2xxReport {
+        version: crate::ftdi::library_version().map_err(|e| e.to_string()),
+        devices: crate::ftdi::device_names().map_err(|e| e.to_string()),
+    });
+    #[cfg(not(feature = "ftdi"))]
+    let d2xx = None;
+
+    DriverReport {
+        d2xx,
+        serial_ports: get_serial_list().into_iter().map(|p| p.name).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{D2xxReport, DriverReport, DRIVER_INSTALL};
+
+    #[test]
+    fn test_problems() {
+        let working = D2xxReport {
+            version: Ok("1.4.27".into()),
+            devices: Ok(vec!["DK0F3GQL (ES-Drone)".into()]),
+        };
+        let mut report = DriverReport {
+            d2xx: Some(working.clone()),
+            serial_ports: vec!["/dev/ttyUSB0".into()],
+        };
+        assert_eq!(
+            report.to_string(),
+            "D2XX driver:  version 1.4.27\nFTDI devices: DK0F3GQL (ES-Drone)\nserial ports: /dev/ttyUSB0\nno problems found\n"
+        );
+
+        // the kernel driver has the board
+        report.d2xx = Some(D2xxReport {
+            devices: Ok(vec![]),
+            ..working.clone()
+        });
+        let problems = report.problems();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("D2XX lists no FTDI devices"));
+
+        report.d2xx = Some(D2xxReport {
+            version: Err("the D2XX library doesn't respond: IO_ERROR".into()),
+            ..working
+        });
+        assert_eq!(
+            report.problems(),
+            [format!(
+                "the D2XX library doesn't respond: IO_ERROR: {DRIVER_INSTALL}"
+            )]
+        );
+
+        let report = DriverReport {
+            d2xx: None,
+            serial_ports: vec![],
+        };
+        assert_eq!(
+            report.to_string(),
+            "D2XX driver:  not used, built without the `ftdi` feature\nserial ports: none\n- no serial ports were found, plug in the board to check that it is\n"
+        );
+    }
+}
@@ -0,0 +1,551 @@
+//! Converting ELF files to the flat binary the bootloader expects, without needing `rust-objcopy`.
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+
+const PT_LOAD: u32 = 1;
+const SHT_NOBITS: u32 = 8;
+const SHF_ALLOC: u32 = 2;
+
+/// How an ELF file is turned into a flat binary, both by `rust-objcopy` and without it.
+///
+/// ```
+/// # use tudelft_serial_upload::{ConversionOptions, UploadConfig};
+/// let options = ConversionOptions::default()
+///     .gap_fill(0xff)
+///     .remove_sections(vec![".noinit".to_string()]);
+/// let config = UploadConfig::default().conversion(options);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConversionOptions {
+    pub(crate) gap_fill: u8,
+    pub(crate) only_sections: Vec<String>,
+    pub(crate) remove_sections: Vec<String>,
+    pub(crate) remove_data: bool,
+}
+
+impl ConversionOptions {
+    /// The value of the bytes between sections, 0 by default. Erased flash reads as 0xff.
+    pub fn gap_fill(mut self, gap_fill: u8) -> Self {
+        self.gap_fill = gap_fill;
+        self
+    }
+
+    /// Only put these sections in the binary (`--only-section`).
+    pub fn only_sections(mut self, sections: Vec<String>) -> Self {
+        self.only_sections = sections;
+        self
+    }
+
+    /// Leave these sections out of the binary (`--remove-section`).
+    pub fn remove_sections(mut self, sections: Vec<String>) -> Self {
+        self.remove_sections = sections;
+        self
+    }
+
+    /// Whether to keep the initial values of `.data`, which are copied to RAM at startup.
+    /// Only leave them out when the program doesn't need them.
+    pub fn keep_data(mut self, keep: bool) -> Self {
+        self.remove_data = !keep;
+        self
+    }
+
+    /// Whether sections are picked, so the binary has to be made from the sections instead of
+    /// the LOAD segments.
+    fn selects_sections(&self) -> bool {
+        !self.only_sections.is_empty() || !self.remove_sections.is_empty() || self.remove_data
+    }
+
+    fn keeps(&self, section: &str) -> bool {
+        (self.only_sections.is_empty() || self.only_sections.iter().any(|s| s == section))
+            && !self.remove_sections.iter().any(|s| s == section)
+            && !(self.remove_data && section == ".data")
+    }
+
+    /// The same options as arguments for objcopy.
+    pub(crate) fn objcopy_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.gap_fill != 0 {
+            args.push(format!("--gap-fill=0x{:02x}", self.gap_fill));
+        }
+        for section in &self.only_sections {
+            args.push(format!("--only-section={section}"));
+        }
+        for section in &self.remove_sections {
+            args.push(format!("--remove-section={section}"));
+        }
+        if self.remove_data {
+            args.push("--remove-section=.data".to_string());
+        }
+        args
+    }
+}
+
+/// A part of the ELF file that ends up in flash.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LoadSegment<'a> {
+    /// The physical (load) address, which is where the data is stored in flash.
+    pub address: u32,
+    pub data: &'a [u8],
+}
+
+fn u16_at(elf: &[u8], offset: usize) -> Result<u16> {
+    elf.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| eyre!("elf file is truncated"))
+}
+
+fn u32_at(elf: &[u8], offset: usize) -> Result<u32> {
+    elf.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| eyre!("elf file is truncated"))
+}
+
+/// All LOAD segments of a 32-bit little endian ELF file that contain data, in the order they appear.
+pub fn load_segments(elf: &[u8]) -> Result<Vec<LoadSegment<'_>>> {
+    if elf.get(..4) != Some(b"\x7fELF") {
+        bail!("not an elf file");
+    }
+    if elf.get(4..6) != Some(&[1, 1]) {
+        bail!("only 32-bit little endian elf files are supported, is this compiled for the drone?");
+    }
+
+    let phoff = u32_at(elf, 0x1c)? as usize;
+    let phentsize = u16_at(elf, 0x2a)? as usize;
+    let phnum = u16_at(elf, 0x2c)? as usize;
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let header = phoff + i * phentsize;
+        if u32_at(elf, header)? != PT_LOAD {
+            continue;
+        }
+
+        let offset = u32_at(elf, header + 4)? as usize;
+        let address = u32_at(elf, header + 12)?;
+        let size = u32_at(elf, header + 16)? as usize;
+        if size == 0 {
+            // like .bss, only takes up space in RAM
+            continue;
+        }
+
+        let data = elf
+            .get(offset..offset + size)
+            .ok_or_else(|| eyre!("elf file is truncated"))?;
+        segments.push(LoadSegment { address, data });
+    }
+
+    Ok(segments)
+}
+
+/// The sections of the ELF file that are stored in flash, with their names. Their address
+/// is the load address, found through the LOAD segment they are in.
+fn flash_sections(elf: &[u8]) -> Result<Vec<(String, LoadSegment<'_>)>> {
+    let segments = load_segments(elf)?;
+    let shoff = u32_at(elf, 0x20)? as usize;
+    let shentsize = u16_at(elf, 0x2e)? as usize;
+    let shnum = u16_at(elf, 0x30)? as usize;
+    let shstrndx = u16_at(elf, 0x32)? as usize;
+    if shnum == 0 {
+        bail!("the elf file has no section headers, so sections can't be picked");
+    }
+
+    let strtab = shoff + shstrndx * shentsize;
+    let names_offset = u32_at(elf, strtab + 16)? as usize;
+
+    let mut sections = Vec::new();
+    for i in 0..shnum {
+        let header = shoff + i * shentsize;
+        let kind = u32_at(elf, header + 4)?;
+        let flags = u32_at(elf, header + 8)?;
+        let offset = u32_at(elf, header + 16)? as usize;
+        let size = u32_at(elf, header + 20)? as usize;
+        if flags & SHF_ALLOC == 0 || kind == SHT_NOBITS || size == 0 {
+            continue;
+        }
+
+        let name_start = names_offset + u32_at(elf, header)? as usize;
+        let name = elf
+            .get(name_start..)
+            .and_then(|n| n.split(|&b| b == 0).next())
+            .ok_or_else(|| eyre!("elf file is truncated"))?;
+        let data = elf
+            .get(offset..offset + size)
+            .ok_or_else(|| eyre!("elf file is truncated"))?;
+
+        let file_start = |s: &LoadSegment| s.data.as_ptr() as usize - elf.as_ptr() as usize;
+        let Some(segment) = segments
+            .iter()
+            .find(|s| (file_start(s)..file_start(s) + s.data.len()).contains(&offset))
+        else {
+            // not loaded at all
+            continue;
+        };
+
+        sections.push((
+            String::from_utf8_lossy(name).into_owned(),
+            LoadSegment {
+                address: segment.address + (offset - file_start(segment)) as u32,
+                data,
+            },
+        ));
+    }
+
+    Ok(sections)
+}
+
+/// Everything that ends up in the binary with these options.
+fn flash_contents<'a>(elf: &'a [u8], options: &ConversionOptions) -> Result<Vec<LoadSegment<'a>>> {
+    if !options.selects_sections() {
+        return load_segments(elf);
+    }
+
+    Ok(flash_sections(elf)?
+        .into_iter()
+        .filter(|(name, _)| options.keeps(name))
+        .map(|(_, section)| section)
+        .collect())
+}
+
+/// Lay out the LOAD segments of an ELF file as a flat binary starting at `origin`,
+/// the address in flash where the bootloader puts the first byte of the image.
+/// Gaps between segments are filled like `objcopy -O binary` does, with the gap fill of the options.
+/// When the options pick sections, the binary is made from those sections instead of the LOAD segments.
+pub fn elf_to_bin(elf: &[u8], origin: u32, options: &ConversionOptions) -> Result<Vec<u8>> {
+    let segments = flash_contents(elf, options)?;
+    if segments.is_empty() {
+        bail!("the elf file doesn't contain anything to put in flash");
+    }
+
+    let mut bin = Vec::new();
+    for segment in segments {
+        if segment.address < origin {
+            bail!(
+                "the elf file has a segment at 0x{:08x}, below the start of the application at 0x{origin:08x}. \
+                 Is the program linked for the right memory layout?",
+                segment.address,
+            );
+        }
+
+        let start = (segment.address - origin) as usize;
+        let end = start + segment.data.len();
+        if bin.len() < end {
+            bin.resize(end, options.gap_fill);
+        }
+        bin[start..end].copy_from_slice(segment.data);
+    }
+
+    debug_assert!(
+        verify_bin(elf, &bin, origin, options).is_ok(),
+        "converted binary doesn't match the elf file"
+    );
+    Ok(bin)
+}
+
+/// The address objcopy starts the binary at: the lowest one that has something in it.
+pub(crate) fn objcopy_base(elf: &[u8], options: &ConversionOptions) -> Result<Option<u32>> {
+    Ok(flash_contents(elf, options)?
+        .iter()
+        .map(|s| s.address)
+        .min())
+}
+
+/// How many bytes of every segment [`verify_bin`] compares.
+const SAMPLES_PER_SEGMENT: usize = 64;
+
+/// Check that a flat binary starting at address `base` really is what the LOAD segments of the
+/// ELF file (or the sections picked by the options) say should be in flash: it must be exactly
+/// as long, and a sample of the bytes of every segment (including the first and last one) must match.
+pub fn verify_bin(elf: &[u8], bin: &[u8], base: u32, options: &ConversionOptions) -> Result<()> {
+    let segments = flash_contents(elf, options)?;
+    let end = segments
+        .iter()
+        .map(|s| s.address as usize + s.data.len())
+        .max()
+        .unwrap_or(base as usize);
+    let expected_len = end.saturating_sub(base as usize);
+
+    if bin.is_empty() {
+        bail!("the binary is empty");
+    }
+    if bin.len() != expected_len {
+        bail!(
+            "the binary is {} bytes, but the elf file says it should be {expected_len} bytes",
+            bin.len()
+        );
+    }
+
+    for segment in &segments {
+        let Some(start) = (segment.address as usize).checked_sub(base as usize) else {
+            bail!(
+                "the elf file has a segment at 0x{:08x}, before the start of the binary at 0x{base:08x}",
+                segment.address
+            );
+        };
+
+        let len = segment.data.len();
+        let step = (len / SAMPLES_PER_SEGMENT).max(1);
+        for i in (0..len).step_by(step).chain([len - 1]) {
+            if bin[start + i] != segment.data[i] {
+                bail!(
+                    "the binary doesn't match the elf file at 0x{:08x}",
+                    segment.address as usize + i
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// An application binary for tests: a vector table with `stack_pointer` and `reset_vector`,
+/// followed by `len` more bytes.
+#[cfg(test)]
+pub fn test_binary(stack_pointer: u32, reset_vector: u32, len: usize) -> Vec<u8> {
+    let mut image = stack_pointer.to_le_bytes().to_vec();
+    image.extend_from_slice(&reset_vector.to_le_bytes());
+    image.extend((0..len).map(|i| i as u8));
+    image
+}
+
+/// A [`test_binary`] with a vector table that passes
+/// [`check_vector_table`](crate::image::check_vector_table) for the default board.
+#[cfg(test)]
+pub fn test_application(len: usize) -> Vec<u8> {
+    test_binary(0x2000_4000, 0x0001_80c1, len)
+}
+
+/// Builds minimal ELF files for tests, with one LOAD segment per `(address, data)` pair.
+#[cfg(test)]
+pub fn test_elf(segments: &[(u32, &[u8])]) -> Vec<u8> {
+    let names: Vec<_> = (0..segments.len()).map(|i| format!(".s{i}")).collect();
+    let sections: Vec<_> = segments
+        .iter()
+        .zip(&names)
+        .map(|(&(address, data), name)| (name.as_str(), address, data))
+        .collect();
+    test_elf_with_sections(&sections)
+}
+
+/// Builds minimal ELF files for tests, with a section and a LOAD segment for every
+/// `(name, address, data)`.
+#[cfg(test)]
+pub fn test_elf_with_sections(sections: &[(&str, u32, &[u8])]) -> Vec<u8> {
+    let phoff = 52;
+    let data_start = phoff + 32 * sections.len();
+
+    let mut names = vec![0];
+    let mut name_offsets = Vec::new();
+    for name in [".shstrtab"]
+        .into_iter()
+        .chain(sections.iter().map(|s| s.0))
+    {
+        name_offsets.push(names.len() as u32);
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+    let names_start = data_start + sections.iter().map(|s| s.2.len()).sum::<usize>();
+    let shoff = (names_start + names.len()).next_multiple_of(4);
+    let shnum = sections.len() + 2;
+
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1];
+    elf.resize(16, 0);
+    elf.extend_from_slice(&2u16.to_le_bytes()); // executable
+    elf.extend_from_slice(&40u16.to_le_bytes()); // arm
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&sections[0].1.to_le_bytes()); // entry
+    elf.extend_from_slice(&(phoff as u32).to_le_bytes());
+    elf.extend_from_slice(&(shoff as u32).to_le_bytes());
+    elf.extend_from_slice(&0x0500_0200u32.to_le_bytes()); // flags: EABI 5, hard float
+    elf.extend_from_slice(&52u16.to_le_bytes());
+    elf.extend_from_slice(&32u16.to_le_bytes());
+    elf.extend_from_slice(&(sections.len() as u16).to_le_bytes());
+    elf.extend_from_slice(&40u16.to_le_bytes());
+    elf.extend_from_slice(&(shnum as u16).to_le_bytes());
+    elf.extend_from_slice(&1u16.to_le_bytes()); // shstrtab
+
+    let mut offset = data_start;
+    for (_, address, data) in sections {
+        for field in [PT_LOAD, offset as u32, *address, *address] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [data.len() as u32, data.len() as u32, 5, 4] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+        offset += data.len();
+    }
+    for (_, _, data) in sections {
+        elf.extend_from_slice(data);
+    }
+    elf.extend_from_slice(&names);
+    elf.resize(shoff, 0);
+
+    // the null section, then the names, then one for every segment
+    elf.extend_from_slice(&[0; 40]);
+    for field in [
+        name_offsets[0],
+        3,
+        0,
+        0,
+        names_start as u32,
+        names.len() as u32,
+        0,
+        0,
+        1,
+        0,
+    ] {
+        elf.extend_from_slice(&field.to_le_bytes());
+    }
+    let mut offset = data_start;
+    for (i, (_, address, data)) in sections.iter().enumerate() {
+        let size = data.len() as u32;
+        for field in [
+            name_offsets[i + 1],
+            1,
+            SHF_ALLOC,
+            *address,
+            offset as u32,
+            size,
+            0,
+            0,
+            4,
+            0,
+        ] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+        offset += data.len();
+    }
+
+    elf
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        elf_to_bin, load_segments, test_elf, test_elf_with_sections, verify_bin, ConversionOptions,
+    };
+    use crate::config::UploadConfig;
+    use crate::image::{sha256_hex, PreparedImage};
+
+    #[test]
+    fn test_convert_at_nonzero_origin() {
+        let elf = test_elf(&[(0x0001_8000, &[1, 2, 3, 4]), (0x0001_8008, &[5, 6])]);
+
+        assert_eq!(load_segments(&elf).unwrap().len(), 2);
+        assert_eq!(
+            elf_to_bin(&elf, 0x0001_8000, &ConversionOptions::default()).unwrap(),
+            [1, 2, 3, 4, 0, 0, 0, 0, 5, 6]
+        );
+
+        // linked after the start of the application area, so the image starts with a gap
+        let elf = test_elf(&[(0x0001_8004, &[1, 2])]);
+        assert_eq!(
+            elf_to_bin(&elf, 0x0001_8000, &ConversionOptions::default()).unwrap(),
+            [0, 0, 0, 0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_segment_below_origin() {
+        let elf = test_elf(&[(0x0000_0000, &[1, 2, 3, 4])]);
+        assert!(elf_to_bin(&elf, 0x0001_8000, &ConversionOptions::default()).is_err());
+        assert!(elf_to_bin(b"\x7fELF", 0, &ConversionOptions::default()).is_err());
+        assert!(elf_to_bin(b"not an elf file", 0, &ConversionOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_verify_bin() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let elf = test_elf(&[(0x0001_8000, &data), (0x0001_8400, &[1, 2, 3])]);
+        let bin = elf_to_bin(&elf, 0x0001_8000, &ConversionOptions::default()).unwrap();
+        let options = ConversionOptions::default();
+        verify_bin(&elf, &bin, 0x0001_8000, &options).unwrap();
+
+        // what objcopy leaves behind when it got cut off
+        let truncated = &bin[..bin.len() - 2];
+        assert!(verify_bin(&elf, truncated, 0x0001_8000, &options).is_err());
+        assert!(verify_bin(&elf, &[], 0x0001_8000, &options).is_err());
+
+        let mut stale = bin.clone();
+        stale[bin.len() - 1] ^= 0xff;
+        assert!(verify_bin(&elf, &stale, 0x0001_8000, &options).is_err());
+        stale = bin.clone();
+        stale[0] ^= 0xff;
+        assert!(verify_bin(&elf, &stale, 0x0001_8000, &options).is_err());
+
+        // the wrong start address makes everything shift
+        assert!(verify_bin(&elf, &bin[4..], 0x0001_8004, &options).is_err());
+    }
+
+    /// A program with a gap before `.rodata`, initial values for `.data`, and a `.noinit`
+    /// section that shouldn't be in flash.
+    pub fn sections_fixture() -> Vec<u8> {
+        test_elf_with_sections(&[
+            (".text", 0x0001_8000, &[1, 2, 3, 4, 5, 6]),
+            (".rodata", 0x0001_8008, &[7, 8]),
+            (".data", 0x0001_800a, &[9, 9]),
+            (".noinit", 0x0001_8010, &[0xee; 4]),
+        ])
+    }
+
+    #[test]
+    fn test_conversion_options() {
+        let elf = sections_fixture();
+        let convert = |options: ConversionOptions| elf_to_bin(&elf, 0x0001_8000, &options);
+
+        let all = [
+            1, 2, 3, 4, 5, 6, 0, 0, 7, 8, 9, 9, 0, 0, 0, 0, 0xee, 0xee, 0xee, 0xee,
+        ];
+        assert_eq!(convert(ConversionOptions::default()).unwrap(), all);
+        assert_eq!(
+            convert(ConversionOptions::default().remove_sections(vec![".s9".into()])).unwrap(),
+            all
+        );
+
+        let no_noinit = ConversionOptions::default().remove_sections(vec![".noinit".into()]);
+        assert_eq!(
+            convert(no_noinit.clone()).unwrap(),
+            [1, 2, 3, 4, 5, 6, 0, 0, 7, 8, 9, 9]
+        );
+        assert_eq!(
+            convert(no_noinit.clone().gap_fill(0xff).keep_data(false)).unwrap(),
+            [1, 2, 3, 4, 5, 6, 0xff, 0xff, 7, 8]
+        );
+        assert_eq!(
+            convert(ConversionOptions::default().only_sections(vec![".text".into()])).unwrap(),
+            [1, 2, 3, 4, 5, 6]
+        );
+        assert!(convert(ConversionOptions::default().only_sections(vec![".bss".into()])).is_err());
+
+        assert_eq!(
+            no_noinit.gap_fill(0xff).keep_data(false).objcopy_args(),
+            [
+                "--gap-fill=0xff",
+                "--remove-section=.noinit",
+                "--remove-section=.data"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pinned_image_hash() {
+        // if this changes, so does every binary that was made with these options
+        let options = ConversionOptions::default()
+            .gap_fill(0xff)
+            .remove_sections(vec![".noinit".into()]);
+        let bin = elf_to_bin(&sections_fixture(), 0x0001_8000, &options).unwrap();
+        assert_eq!(
+            sha256_hex(&bin),
+            "6d691fe5c1ae4de0ede025e60ca84ac2cd7be3221aa7759359ccb2540844bb90"
+        );
+
+        // without `.data` the image is 10 bytes, so it is padded to 12 before it is hashed
+        let bin = elf_to_bin(&sections_fixture(), 0x0001_8000, &options.keep_data(false)).unwrap();
+        let image = PreparedImage::new(bin, &UploadConfig::default());
+        assert_eq!(image.bytes().len(), 12);
+        assert_eq!(
+            image.sha256,
+            "671c430dcaf3e080395d9fa39029b289c6846678149ead86beb6ae14c5415f83"
+        );
+    }
+}
@@ -0,0 +1,667 @@
+//! An in-process stand-in for the serial DFU bootloader on the drone boards, so the
+//! protocol code can be exercised without any hardware attached, by the tests and by
+//! [`PortSelector::Simulated`](crate::PortSelector::Simulated).
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use serial2::FlowControl;
+
+use crate::clock::{Clock, FakeClock};
+use crate::crc::calc_crc16_default;
+use crate::hci::{DFU_RESPONSE, LINK_CONTROL_PACKET, VENDOR_PACKET};
+use crate::slip::unescape;
+use crate::transport::{ControlLine, Transport};
+use crate::SERIAL_TIMEOUT;
+
+struct State {
+    /// Escaped bytes of the frame currently being received.
+    frame: Vec<u8>,
+    /// Bytes waiting to be read by the host.
+    outgoing: VecDeque<u8>,
+    /// The sequence number of the next packet we accept. Unknown until the first packet arrives.
+    expected_seq: Option<u8>,
+    /// Indices (counting every frame received) of frames that get lost on the way.
+    drop_frames: HashSet<usize>,
+    /// Frames that arrive and are handled, but whose ack gets lost on the way back.
+    drop_ack_frames: HashSet<usize>,
+    /// Frames that are answered with a link control packet, asking for them again.
+    nack_frames: HashSet<usize>,
+    /// Frames that are acknowledged twice, like an ack that was sent again late.
+    duplicate_ack_frames: HashSet<usize>,
+    /// Frames whose ack is preceded by a copy of it that was garbled on the line.
+    corrupt_ack_frames: HashSet<usize>,
+    /// Packets with this opcode are answered with a DFU response with this result code.
+    reject: Option<(u32, u32)>,
+    /// After the stop packet, answer with a DFU response, with this CRC of the image in it
+    /// or with only the opcode, the request and the result.
+    stop_response: Option<Option<u16>>,
+    /// After the init packet, say that it is ready for the data packets in a DFU response.
+    init_response: bool,
+    frames_received: usize,
+    /// Everything the host ever wrote, exactly as it arrived.
+    written: Vec<u8>,
+    /// The most bytes a single `read` hands out, to split responses over several reads.
+    max_read: Option<usize>,
+    /// Printed by the "application" once the stop packet was received.
+    banner: Vec<u8>,
+    /// Log output that is sent before every ack.
+    noise: Vec<u8>,
+
+    image_size: Option<u32>,
+    init_packet: Option<Vec<u8>>,
+    image: Vec<u8>,
+    stopped: bool,
+    /// Whether the application was started with an activate packet after the stop packet.
+    activated: bool,
+
+    /// When set, reads wait on this clock for responses, up to the read timeout.
+    clock: Option<Arc<FakeClock>>,
+    read_timeout: Duration,
+    /// Every `(read, write)` timeout the host set, in order.
+    timeouts: Vec<(Duration, Duration)>,
+    /// The latency timer the host set last.
+    latency_timer: Option<Duration>,
+    /// The line settings the host set last, after the upload.
+    line_settings: Option<(u32, FlowControl)>,
+    /// How long it takes before an ack can be read.
+    response_time: Duration,
+    /// The acks that are still on their way: when they can be read, and where their first byte
+    /// is, counting every byte that was ever sent.
+    in_transit: VecDeque<(Instant, usize)>,
+    /// How many bytes the host read, or threw away.
+    taken: usize,
+    /// Never answer anything, like a port that has something else than a drone on it.
+    unresponsive: bool,
+    /// Stop being unresponsive when reset through DTR.
+    reset_by_dtr: bool,
+    /// Every change of a control line, in order.
+    control_lines: Vec<(ControlLine, bool)>,
+    /// Send back everything that is written, like an adapter with TX connected to RX.
+    loopback: bool,
+    /// How long erasing the flash takes after a start packet. Needs a clock.
+    erase_time: Duration,
+    /// Until when frames are lost because the flash is being erased.
+    busy_until: Option<Instant>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            frame: Vec::new(),
+            outgoing: VecDeque::new(),
+            expected_seq: None,
+            drop_frames: HashSet::new(),
+            drop_ack_frames: HashSet::new(),
+            nack_frames: HashSet::new(),
+            duplicate_ack_frames: HashSet::new(),
+            corrupt_ack_frames: HashSet::new(),
+            reject: None,
+            stop_response: None,
+            init_response: false,
+            frames_received: 0,
+            written: Vec::new(),
+            max_read: None,
+            banner: Vec::new(),
+            noise: Vec::new(),
+            image_size: None,
+            init_packet: None,
+            image: Vec::new(),
+            stopped: false,
+            activated: false,
+            clock: None,
+            read_timeout: SERIAL_TIMEOUT,
+            timeouts: Vec::new(),
+            latency_timer: None,
+            line_settings: None,
+            response_time: Duration::ZERO,
+            in_transit: VecDeque::new(),
+            taken: 0,
+            unresponsive: false,
+            reset_by_dtr: false,
+            control_lines: Vec::new(),
+            loopback: false,
+            erase_time: Duration::ZERO,
+            busy_until: None,
+        }
+    }
+}
+
+/// Cloning an emulator gives another handle to the same bootloader, so a test can keep one
+/// to inspect what was flashed while the other is owned by a [`Serial`].
+#[derive(Clone, Default)]
+pub struct Emulator {
+    state: Arc<Mutex<State>>,
+}
+
+impl Emulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The board of [`PortSelector::Simulated`](crate::PortSelector::Simulated), which says when
+    /// it is ready for the data packets, so uploads to it don't wait for the flash to be erased.
+    pub fn simulated() -> Self {
+        Self::new().init_response()
+    }
+
+    /// Pretend the frame with this index (counting from 0) never arrived.
+    pub fn drop_frame(self, index: usize) -> Self {
+        self.state.lock().unwrap().drop_frames.insert(index);
+        self
+    }
+
+    /// Handle the frame with this index (counting from 0), but lose its ack, so the host sends
+    /// a packet again that was already written.
+    pub fn drop_ack(self, index: usize) -> Self {
+        self.state.lock().unwrap().drop_ack_frames.insert(index);
+        self
+    }
+
+    /// Ask for the frame with this index (counting from 0) again, instead of acknowledging it.
+    pub fn nack_frame(self, index: usize) -> Self {
+        self.state.lock().unwrap().nack_frames.insert(index);
+        self
+    }
+
+    /// Acknowledge the frame with this index (counting from 0) twice.
+    pub fn duplicate_ack(self, index: usize) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .duplicate_ack_frames
+            .insert(index);
+        self
+    }
+
+    /// Send a copy of the ack for the frame with this index (counting from 0) with a bit flipped
+    /// in its header before the ack itself.
+    pub fn corrupt_ack(self, index: usize) -> Self {
+        self.state.lock().unwrap().corrupt_ack_frames.insert(index);
+        self
+    }
+
+    /// Answer packets with this opcode with an error `result`, instead of accepting them.
+    pub fn reject(self, opcode: u32, result: u32) -> Self {
+        self.state.lock().unwrap().reject = Some((opcode, result));
+        self
+    }
+
+    /// Answer the stop packet with a DFU response that says the image has this CRC, like the
+    /// bootloader builds that report it. Sent after the ack of the stop packet.
+    pub fn stop_response(self, crc: u16) -> Self {
+        self.state.lock().unwrap().stop_response = Some(Some(crc));
+        self
+    }
+
+    /// Answer the stop packet with a plain DFU response without a CRC in it, like the Nordic
+    /// bootloader builds that don't report one.
+    pub fn stop_response_without_crc(self) -> Self {
+        self.state.lock().unwrap().stop_response = Some(None);
+        self
+    }
+
+    /// Answer the init packet with a DFU response once it is ready for the data packets, like
+    /// the bootloader builds that announce that. Sent after the ack of the init packet.
+    pub fn init_response(self) -> Self {
+        self.state.lock().unwrap().init_response = true;
+        self
+    }
+
+    /// Hand out at most this many bytes per `read`.
+    pub fn max_read(self, max_read: usize) -> Self {
+        self.state.lock().unwrap().max_read = Some(max_read);
+        self
+    }
+
+    /// Bytes the flashed application prints when it starts, after the activate packet that
+    /// follows the stop packet.
+    pub fn banner(self, banner: &[u8]) -> Self {
+        self.state.lock().unwrap().banner = banner.to_vec();
+        self
+    }
+
+    /// Print this before every ack, like a board that still has a debug task running.
+    pub fn noise(self, noise: &[u8]) -> Self {
+        self.state.lock().unwrap().noise = noise.to_vec();
+        self
+    }
+
+    /// Let reads wait for responses on this clock (up to the read timeout) instead of failing
+    /// right away when nothing is there.
+    pub fn clock(self, clock: Arc<FakeClock>) -> Self {
+        self.state.lock().unwrap().clock = Some(clock);
+        self
+    }
+
+    /// Take this long before every ack can be read. Needs a [`clock`](Self::clock).
+    pub fn response_time(self, response_time: Duration) -> Self {
+        self.state.lock().unwrap().response_time = response_time;
+        self
+    }
+
+    /// Never answer anything.
+    pub fn unresponsive(self) -> Self {
+        self.state.lock().unwrap().unresponsive = true;
+        self
+    }
+
+    /// Ignore everything until DTR is pulsed, like a board that is still running the
+    /// application and has DTR wired to its reset pin.
+    pub fn running_application(self) -> Self {
+        let mut state = self.state.lock().unwrap();
+        state.unresponsive = true;
+        state.reset_by_dtr = true;
+        drop(state);
+        self
+    }
+
+    /// Take this long to erase the flash after a start packet, losing every frame that arrives
+    /// in the meantime. Needs a [`clock`](Self::clock).
+    pub fn erase_time(self, erase_time: Duration) -> Self {
+        self.state.lock().unwrap().erase_time = erase_time;
+        self
+    }
+
+    /// Echo everything back instead of answering it.
+    pub fn loopback(self) -> Self {
+        self.state.lock().unwrap().loopback = true;
+        self
+    }
+
+    /// Start out in the middle of an upload that the host gave up on, still waiting for the next
+    /// data packet. Only a packet with the right sequence number, like a stop packet or the start
+    /// of a new upload, gets it out of there.
+    pub fn mid_transfer(self) -> Self {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.expected_seq = Some(5);
+            state.image_size = Some(0x1000);
+            state.image = vec![0xaa; 0x400];
+        }
+        self
+    }
+
+    /// The bytes written to flash by data packets.
+    pub fn image(&self) -> Vec<u8> {
+        self.state.lock().unwrap().image.clone()
+    }
+
+    /// The image size announced in the start packet.
+    pub fn image_size(&self) -> Option<u32> {
+        self.state.lock().unwrap().image_size
+    }
+
+    pub fn init_packet(&self) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().init_packet.clone()
+    }
+
+    pub fn written(&self) -> Vec<u8> {
+        self.state.lock().unwrap().written.clone()
+    }
+
+    pub fn timeouts(&self) -> Vec<(Duration, Duration)> {
+        self.state.lock().unwrap().timeouts.clone()
+    }
+
+    pub fn latency_timer(&self) -> Option<Duration> {
+        self.state.lock().unwrap().latency_timer
+    }
+
+    pub fn control_lines(&self) -> Vec<(ControlLine, bool)> {
+        self.state.lock().unwrap().control_lines.clone()
+    }
+
+    pub fn line_settings(&self) -> Option<(u32, FlowControl)> {
+        self.state.lock().unwrap().line_settings
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.state.lock().unwrap().stopped
+    }
+}
+
+impl State {
+    fn receive_frame(&mut self, escaped: &[u8]) {
+        let index = self.frames_received;
+        self.frames_received += 1;
+        if self.unresponsive || self.drop_frames.contains(&index) || self.erasing() {
+            return;
+        }
+
+        let Ok(frame) = unescape(escaped) else {
+            return;
+        };
+        if frame.len() < 6 || frame[..4].iter().fold(0u8, |a, &b| a.wrapping_add(b)) != 0 {
+            return;
+        }
+
+        let seq = frame[0] & 0x07;
+        let len = (frame[1] >> 4) as usize | (frame[2] as usize) << 4;
+        if frame.len() != 4 + len + 2 {
+            return;
+        }
+        let crc = u16::from_le_bytes([frame[4 + len], frame[5 + len]]);
+        if calc_crc16_default(&frame[..4 + len]) != crc {
+            return;
+        }
+
+        if self.nack_frames.contains(&index) {
+            let ack = self.expected_seq.unwrap_or(seq);
+            self.send_frame(ack << 3, LINK_CONTROL_PACKET, &[]);
+            return;
+        }
+
+        let packet = &frame[4..4 + len];
+        let opcode = packet
+            .get(..4)
+            .map(|o| u32::from_le_bytes(o.try_into().unwrap()));
+        if let Some((rejected, result)) = self.reject.filter(|&(op, _)| Some(op) == opcode) {
+            let response: Vec<u8> = [DFU_RESPONSE, rejected, result]
+                .iter()
+                .flat_map(|w| w.to_le_bytes())
+                .collect();
+            self.send_frame(seq << 3 | 0x40, VENDOR_PACKET, &response);
+            return;
+        }
+
+        let in_order = self.expected_seq.is_none_or(|expected| expected == seq);
+        if in_order {
+            self.handle_packet(packet);
+            self.expected_seq = Some((seq + 1) % 8);
+        }
+
+        // Out of order packets are answered with the ack we sent last,
+        // telling the host which packet we are still waiting for.
+        if let Some(expected) = self
+            .expected_seq
+            .filter(|_| !self.drop_ack_frames.contains(&index))
+        {
+            if self.corrupt_ack_frames.contains(&index) {
+                let start = self.outgoing.len();
+                self.send_frame(expected << 3, 0, &[]);
+                // the ack number, which is never escaped, now doesn't match the checksum
+                self.outgoing[start + 1] ^= 0x08;
+            }
+            self.send_ack(expected);
+            if self.duplicate_ack_frames.contains(&index) {
+                self.send_ack(expected);
+            }
+        }
+
+        if in_order && self.init_response && opcode == Some(1) {
+            let response: Vec<u8> = [DFU_RESPONSE, 1, 1]
+                .iter()
+                .flat_map(|w| w.to_le_bytes())
+                .collect();
+            self.send_frame(0x40, VENDOR_PACKET, &response);
+        }
+        if self.stopped {
+            if let Some(crc) = self.stop_response.take() {
+                let response: Vec<u8> = [DFU_RESPONSE, 5, 1]
+                    .into_iter()
+                    .chain(crc.map(u32::from))
+                    .flat_map(|w| w.to_le_bytes())
+                    .collect();
+                self.send_frame(0x40, VENDOR_PACKET, &response);
+            }
+            // ready for the next upload, which starts counting all over again
+            self.expected_seq = None;
+        }
+        if self.activated {
+            let banner = std::mem::take(&mut self.banner);
+            self.outgoing.extend(banner);
+            // the application is running now, which ignores DFU frames until the board is
+            // reset back into the bootloader
+            self.unresponsive = true;
+            self.reset_by_dtr = true;
+        }
+    }
+
+    fn handle_packet(&mut self, packet: &[u8]) {
+        let Some(opcode) = packet.get(..4) else {
+            return;
+        };
+        match u32::from_le_bytes(opcode.try_into().unwrap()) {
+            3 => {
+                if let Some(clock) = &self.clock {
+                    self.busy_until = Some(clock.now() + self.erase_time);
+                }
+                // the sizes of the SoftDevice, bootloader and application
+                self.image_size = packet.get(8..20).map(|sizes| {
+                    sizes
+                        .chunks(4)
+                        .map(|s| u32::from_le_bytes(s.try_into().unwrap()))
+                        .sum()
+                });
+                self.image.clear();
+                self.stopped = false;
+                self.activated = false;
+            }
+            1 => self.init_packet = Some(packet[4..].to_vec()),
+            4 => self.image.extend_from_slice(&packet[4..]),
+            5 => self.stopped = true,
+            6 => self.activated = self.stopped,
+            _ => {}
+        }
+    }
+
+    fn erasing(&self) -> bool {
+        match (&self.clock, self.busy_until) {
+            (Some(clock), Some(until)) => clock.now() < until,
+            _ => false,
+        }
+    }
+
+    fn send_ack(&mut self, ack: u8) {
+        if let Some(clock) = &self.clock {
+            let start = self.taken + self.outgoing.len();
+            self.in_transit
+                .push_back((clock.now() + self.response_time, start));
+        }
+
+        let noise = self.noise.clone();
+        self.outgoing.extend(noise);
+        self.send_frame(ack << 3, 0, &[]);
+    }
+
+    /// Queue a frame of `packet_type` with the first header byte `b1`, and a CRC when its bit is set.
+    fn send_frame(&mut self, b1: u8, packet_type: u8, payload: &[u8]) {
+        let b2 = packet_type | ((payload.len() & 0x0f) << 4) as u8;
+        let b3 = (payload.len() >> 4) as u8;
+        let mut frame = vec![
+            b1,
+            b2,
+            b3,
+            (!b1.wrapping_add(b2).wrapping_add(b3)).wrapping_add(1),
+        ];
+        frame.extend_from_slice(payload);
+        if b1 & 0x40 != 0 {
+            let crc = calc_crc16_default(&frame);
+            frame.extend_from_slice(&crc.to_le_bytes());
+        }
+
+        self.outgoing.push_back(0xc0);
+        for b in frame {
+            match b {
+                0xc0 => self.outgoing.extend([0xdb, 0xdc]),
+                0xdb => self.outgoing.extend([0xdb, 0xdd]),
+                b => self.outgoing.push_back(b),
+            }
+        }
+        self.outgoing.push_back(0xc0);
+    }
+
+    /// How many bytes can be read now: everything before the first ack that is still on its way.
+    fn readable(&mut self) -> usize {
+        if let Some(clock) = &self.clock {
+            let now = clock.now();
+            self.in_transit.retain(|&(t, _)| t > now);
+        }
+        match self.in_transit.front() {
+            Some(&(_, start)) => start - self.taken,
+            None => self.outgoing.len(),
+        }
+    }
+
+    /// Wait until `needed` bytes can be read, for as long as the read timeout allows.
+    fn wait_for(&mut self, needed: usize) -> bool {
+        let Some(clock) = self.clock.clone() else {
+            return self.outgoing.len() >= needed;
+        };
+
+        if self.readable() < needed {
+            // when enough of the acks on their way have arrived
+            let arrival = (0..self.in_transit.len()).find_map(|i| {
+                let end = self
+                    .in_transit
+                    .get(i + 1)
+                    .map_or(self.outgoing.len(), |&(_, start)| start - self.taken);
+                (end >= needed).then_some(self.in_transit[i].0)
+            });
+            let now = clock.now();
+            match arrival {
+                Some(t) if t - now <= self.read_timeout => clock.sleep(t - now),
+                _ => clock.sleep(self.read_timeout),
+            }
+        }
+        self.readable() >= needed
+    }
+
+    fn take(&mut self, n: usize) -> impl Iterator<Item = u8> + '_ {
+        self.taken += n;
+        self.outgoing.drain(..n)
+    }
+}
+
+impl Transport for Emulator {
+    fn read_all(&mut self, buf: &mut [u8]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.wait_for(buf.len()) {
+            bail!("timed out waiting for the bootloader to respond");
+        }
+        let n = buf.len();
+        for (b, byte) in buf.iter_mut().zip(state.take(n)) {
+            *b = byte;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if !state.wait_for(1) {
+            return Ok(0);
+        }
+        let n = state
+            .readable()
+            .min(buf.len())
+            .min(state.max_read.unwrap_or(usize::MAX));
+        for (b, byte) in buf.iter_mut().zip(state.take(n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.written.extend_from_slice(buf);
+        if state.loopback {
+            state.outgoing.extend(buf);
+            return Ok(());
+        }
+        for &b in buf {
+            if b == 0xc0 {
+                let frame = std::mem::take(&mut state.frame);
+                if !frame.is_empty() {
+                    state.receive_frame(&frame);
+                }
+            } else {
+                state.frame.push(b);
+            }
+        }
+        Ok(())
+    }
+
+    fn clear_input(&mut self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let n = state.outgoing.len();
+        state.take(n).for_each(drop);
+        state.in_transit.clear();
+        Ok(())
+    }
+
+    fn set_timeouts(&mut self, read: Duration, write: Duration) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.read_timeout = read;
+        state.timeouts.push((read, write));
+        Ok(())
+    }
+
+    fn set_latency_timer(&mut self, timer: Duration) -> Result<()> {
+        self.state.lock().unwrap().latency_timer = Some(timer);
+        Ok(())
+    }
+
+    fn set_control_line(&mut self, line: ControlLine, active: bool) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.control_lines.push((line, active));
+        if line == ControlLine::Dtr && !active && state.reset_by_dtr {
+            state.unresponsive = false;
+        }
+        Ok(())
+    }
+
+    fn reconfigure(&mut self, baud_rate: u32, flow_control: FlowControl) -> Result<()> {
+        self.state.lock().unwrap().line_settings = Some((baud_rate, flow_control));
+        Ok(())
+    }
+}
+
+/// A [`Serial`](crate::serial::Serial) talking to (another handle to) this emulator, for the
+/// tests. It waits on the emulator's [`clock`](Emulator::clock), or on a fake clock of its own.
+#[cfg(test)]
+pub fn emulator_serial(emulator: &Emulator) -> crate::serial::Serial {
+    let clock = emulator.state.lock().unwrap().clock.clone();
+    crate::serial::Serial::with_transport(
+        std::path::PathBuf::from("/dev/emulator"),
+        Box::new(emulator.clone()),
+        clock.unwrap_or_else(|| Arc::new(FakeClock::new())),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{emulator_serial, Emulator};
+    use crate::config::UploadConfig;
+
+    #[test]
+    fn test_upload_to_emulator() {
+        let image: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+        let emulator = Emulator::new();
+        let report = emulator_serial(&emulator)
+            .try_do_upload(&image, &UploadConfig::default())
+            .unwrap();
+        assert_eq!(report.retries, 0);
+        assert_eq!(emulator.image_size(), Some(2000));
+        assert!(emulator.init_packet().is_some());
+        assert_eq!(emulator.image(), image);
+        assert!(emulator.stopped());
+    }
+
+    #[test]
+    fn test_lost_packet() {
+        // a data packet, after the start and init packets, is sent again
+        let image = [0x42; 2048];
+        let emulator = Emulator::new().drop_frame(3);
+        let config = UploadConfig::default().window_size(2);
+        let report = emulator_serial(&emulator)
+            .try_do_upload(&image, &config)
+            .unwrap();
+        assert!(report.retries > 0);
+        assert_eq!(emulator.image(), image);
+        assert!(emulator.stopped());
+    }
+}
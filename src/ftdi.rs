@@ -0,0 +1,550 @@
+//! The FTDI backend: talks to the chip on the board through the D2XX driver, instead of the
+//! serial port the operating system makes for it. Enabled by the `ftdi` feature, which is on by
+//! default.
+
+use std::fs::{canonicalize, read_to_string};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::{Help, Result};
+use libftd2xx::{list_devices, Ftdi, FtdiCommon};
+use serial2::FlowControl;
+
+use crate::board::check_baud_rate;
+use crate::doctor::DRIVER_INSTALL;
+use crate::transport::{BitsPerWord, ControlLine, LineSettings, Parity, StopBits, Transport};
+use crate::SERIAL_TIMEOUT;
+
+/// Ports of FTDI devices that were picked by D2XX instead of by the operating system get a path
+/// with this prefix and their serial number, like `ftdi:DK0F3GQL`.
+pub(crate) const PATH_PREFIX: &str = "ftdi:";
+
+/// The bytes that pause and resume the other side with software flow control.
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+
+/// Which FTDI device D2XX talks to for a serial port, see [`resolve_ftdi`](crate::resolve_ftdi).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FtdiIdentity {
+    /// The position of the device in the list of D2XX, as in
+    /// [`PortSelector::DeviceIndex`](crate::PortSelector::DeviceIndex).
+    pub index: usize,
+    /// The serial number of the device, which D2XX opens it by.
+    pub serial_number: String,
+    /// The description in the EEPROM of the device.
+    pub description: String,
+}
+
+impl Transport for Ftdi {
+    fn read_all(&mut self, buf: &mut [u8]) -> Result<()> {
+        FtdiCommon::read_all(self, buf)?;
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        // D2XX waits until the whole buffer is filled or the read timeout expires, so only ask
+        // for what is already there, or a single byte to wait for when nothing is
+        let queued = self.queue_status()?;
+        let n = queued.max(1).min(buf.len());
+        Ok(FtdiCommon::read(self, &mut buf[..n])?)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        FtdiCommon::write_all(self, buf)?;
+        Ok(())
+    }
+
+    fn set_timeouts(&mut self, read: Duration, write: Duration) -> Result<()> {
+        FtdiCommon::set_timeouts(self, read, write)?;
+        Ok(())
+    }
+
+    fn set_latency_timer(&mut self, timer: Duration) -> Result<()> {
+        FtdiCommon::set_latency_timer(self, timer)?;
+        Ok(())
+    }
+
+    fn clear_input(&mut self) -> Result<()> {
+        self.purge_rx()?;
+        Ok(())
+    }
+
+    fn clear_output(&mut self) -> Result<()> {
+        self.purge_tx()?;
+        Ok(())
+    }
+
+    fn reconfigure(&mut self, baud_rate: u32, flow_control: FlowControl) -> Result<()> {
+        check_baud_rate(baud_rate)?;
+        self.set_baud_rate(baud_rate).wrap_err_with(|| {
+            format!("the FTDI device doesn't support a baud rate of {baud_rate}")
+        })?;
+        match flow_control {
+            FlowControl::None => self.set_flow_control_none()?,
+            FlowControl::XonXoff => self.set_flow_control_xon_xoff(XON, XOFF)?,
+            FlowControl::RtsCts => self.set_flow_control_rts_cts()?,
+        }
+        Ok(())
+    }
+
+    fn set_control_line(&mut self, line: ControlLine, active: bool) -> Result<()> {
+        match (line, active) {
+            (ControlLine::Dtr, true) => self.set_dtr()?,
+            (ControlLine::Dtr, false) => self.clear_dtr()?,
+            (ControlLine::Rts, true) => self.set_rts()?,
+            (ControlLine::Rts, false) => self.clear_rts()?,
+        }
+        Ok(())
+    }
+
+    fn serial_number(&mut self) -> Option<String> {
+        self.device_info().ok().map(|i| i.serial_number)
+    }
+
+    fn as_ftdi_mut(&mut self) -> Option<&mut Ftdi> {
+        Some(self)
+    }
+
+    fn into_ftdi(self: Box<Self>) -> Option<Ftdi> {
+        Some(*self)
+    }
+}
+
+/// Open the FTDI chip behind the serial port at `path`, found with [`resolve`].
+pub(crate) fn open(path: &Path) -> Result<Ftdi> {
+    let serial_number = resolve(path)?.serial_number;
+    Ftdi::with_serial_number(&serial_number)
+        .wrap_err_with(|| format!("failed to open the FTDI device {serial_number} for {path:?}"))
+}
+
+/// Set up an opened FTDI chip for the bootloader at `baud_rate` and with `line`.
+pub(crate) fn configure(port: &mut Ftdi, baud_rate: u32, line: LineSettings) -> Result<()> {
+    let bits = match line.bits {
+        BitsPerWord::Seven => libftd2xx::BitsPerWord::Bits7,
+        BitsPerWord::Eight => libftd2xx::BitsPerWord::Bits8,
+    };
+    let stop_bits = match line.stop_bits {
+        StopBits::One => libftd2xx::StopBits::Bits1,
+        StopBits::Two => libftd2xx::StopBits::Bits2,
+    };
+    let parity = match line.parity {
+        Parity::None => libftd2xx::Parity::No,
+        Parity::Odd => libftd2xx::Parity::Odd,
+        Parity::Even => libftd2xx::Parity::Even,
+    };
+    port.set_data_characteristics(bits, stop_bits, parity)?;
+    port.set_baud_rate(baud_rate)
+        .wrap_err_with(|| format!("the FTDI device doesn't support a baud rate of {baud_rate}"))?;
+    port.set_flow_control_rts_cts()?;
+    FtdiCommon::set_timeouts(port, SERIAL_TIMEOUT, SERIAL_TIMEOUT)?;
+    port.purge_all()?;
+    Ok(())
+}
+
+/// The serial numbers and descriptions of the connected FTDI devices, in the order D2XX lists
+/// them.
+fn devices() -> Result<Vec<(String, String)>> {
+    Ok(list_devices()
+        .map_err(|e| eyre!("failed to list the FTDI devices: {e}"))
+        .suggestion(DRIVER_INSTALL)?
+        .into_iter()
+        .map(|d| (d.serial_number, d.description))
+        .collect())
+}
+
+/// The version of the D2XX library, which fails when the driver doesn't work.
+pub(crate) fn library_version() -> Result<String> {
+    Ok(libftd2xx::library_version()
+        .map_err(|e| eyre!("the D2XX library doesn't respond: {e}"))?
+        .to_string())
+}
+
+/// The serial numbers and descriptions of the FTDI devices D2XX lists, for
+/// [`check_drivers`](crate::check_drivers).
+pub(crate) fn device_names() -> Result<Vec<String>> {
+    Ok(devices()?
+        .into_iter()
+        .map(|(serial, description)| format!("{serial} ({description})"))
+        .collect())
+}
+
+/// The serial numbers of the connected FTDI devices, in the order D2XX lists them.
+fn serial_numbers() -> Result<Vec<String>> {
+    Ok(devices()?.into_iter().map(|(serial, _)| serial).collect())
+}
+
+/// The FTDI device behind the serial port at `path`. This is how every path turns into a
+/// device, so the port an upload reports is the one that was flashed.
+///
+/// D2XX doesn't know about the serial ports of the operating system, so the chip is found by
+/// its serial number: macOS puts it in the name of the port, and on Linux it is in sysfs. When
+/// the serial number can't be found out, the only FTDI chip that is connected is used.
+pub(crate) fn resolve(path: &Path) -> Result<FtdiIdentity> {
+    identify(path, serial_number_of_port(path), &devices()?)
+}
+
+/// Drop the paths that are the same FTDI device as a path before them, like the `cu.` and
+/// `tty.` ports macOS makes for every device, so every device is opened at most once. Paths that
+/// can't be matched to a device are kept, opening them says what is wrong.
+pub(crate) fn distinct_devices(paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    Ok(distinct(paths, &devices()?))
+}
+
+fn distinct(paths: Vec<PathBuf>, devices: &[(String, String)]) -> Vec<PathBuf> {
+    let mut seen: Vec<(usize, PathBuf)> = Vec::new();
+    let mut distinct = Vec::new();
+    for path in paths {
+        if let Ok(identity) = identify(&path, serial_number_of_port(&path), devices) {
+            if let Some((_, first)) = seen.iter().find(|(i, _)| *i == identity.index) {
+                eprintln!("skipping {path:?}, it is the same FTDI device as {first:?}");
+                continue;
+            }
+            seen.push((identity.index, path.clone()));
+        }
+        distinct.push(path);
+    }
+    distinct
+}
+
+fn identify(
+    path: &Path,
+    serial_number: Option<String>,
+    devices: &[(String, String)],
+) -> Result<FtdiIdentity> {
+    let serial_numbers: Vec<_> = devices.iter().map(|(serial, _)| serial.clone()).collect();
+    let serial_number = match_ftdi_device(path, serial_number, &serial_numbers)?;
+    let index = serial_numbers
+        .iter()
+        .position(|s| *s == serial_number)
+        .expect("the serial number is one of the devices");
+    Ok(FtdiIdentity {
+        index,
+        description: devices[index].1.clone(),
+        serial_number,
+    })
+}
+
+/// The path of the FTDI device at `index` in the list of D2XX, see
+/// [`PortSelector::DeviceIndex`](crate::PortSelector::DeviceIndex).
+pub(crate) fn path_of_device(index: usize) -> Result<PathBuf> {
+    let devices = serial_numbers()?;
+    let serial_number = device_at(index, &devices)?;
+    Ok(PathBuf::from(format!("{PATH_PREFIX}{serial_number}")))
+}
+
+/// The path of the FTDI device with this serial number, see
+/// [`PortSelector::BySerialNumber`](crate::PortSelector::BySerialNumber).
+pub(crate) fn path_of_serial_number(serial_number: &str) -> Result<PathBuf> {
+    path_with_serial_number(serial_number, &serial_numbers()?)
+}
+
+fn path_with_serial_number(serial_number: &str, devices: &[String]) -> Result<PathBuf> {
+    if !devices.iter().any(|d| d == serial_number) {
+        return Err(eyre!(
+            "no FTDI device has serial number {serial_number}, {}",
+            connected_devices(devices)
+        )
+        .suggestion("Make sure the board is plugged in"));
+    }
+    Ok(PathBuf::from(format!("{PATH_PREFIX}{serial_number}")))
+}
+
+/// The path of the FTDI device with this description, see
+/// [`PortSelector::ByDescription`](crate::PortSelector::ByDescription).
+pub(crate) fn path_of_description(description: &str) -> Result<PathBuf> {
+    let devices = devices()?;
+    let serial_number = device_with_description(description, &devices)?;
+    Ok(PathBuf::from(format!("{PATH_PREFIX}{serial_number}")))
+}
+
+/// The serial number of the device, out of (serial number, description) pairs, whose
+/// description is `description`, or else the only one whose description contains it.
+fn device_with_description<'a>(
+    description: &str,
+    devices: &'a [(String, String)],
+) -> Result<&'a str> {
+    if let Some((serial, _)) = devices.iter().find(|(_, d)| d == description) {
+        return Ok(serial);
+    }
+
+    let matching: Vec<_> = devices
+        .iter()
+        .filter(|(_, d)| d.contains(description))
+        .collect();
+    match matching.as_slice() {
+        [(serial, _)] => Ok(serial),
+        [] => {
+            let connected = if devices.is_empty() {
+                "no FTDI devices are connected".to_string()
+            } else {
+                format!(
+                    "the connected FTDI devices are {}",
+                    with_descriptions(devices)
+                )
+            };
+            Err(
+                eyre!("no FTDI device has a description containing {description:?}, {connected}")
+                    .suggestion("Make sure the board is plugged in"),
+            )
+        }
+        _ => Err(eyre!(
+            "several FTDI devices have a description containing {description:?}: {}",
+            with_descriptions(matching)
+        )
+        .suggestion("Select the board by its serial number instead")),
+    }
+}
+
+/// A list of devices like `DK0F3GQL ("ES-Drone v2"), A10KXQ2C ("ES-Drone v3")`.
+fn with_descriptions<'a>(devices: impl IntoIterator<Item = &'a (String, String)>) -> String {
+    devices
+        .into_iter()
+        .map(|(serial, description)| format!("{serial} ({description:?})"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Which FTDI devices are connected, for in an error message.
+fn connected_devices(devices: &[String]) -> String {
+    if devices.is_empty() {
+        "no FTDI devices are connected".to_string()
+    } else {
+        format!("the connected FTDI devices are {}", devices.join(", "))
+    }
+}
+
+fn device_at(index: usize, devices: &[String]) -> Result<&str> {
+    devices.get(index).map(String::as_str).ok_or_else(|| {
+        let found = match devices.len() {
+            0 => "no FTDI devices are connected".to_string(),
+            1 => "only 1 FTDI device is connected, device 0".to_string(),
+            n => format!(
+                "only {n} FTDI devices are connected, devices 0 to {}",
+                n - 1
+            ),
+        };
+        eyre!("there is no FTDI device {index}, {found}")
+            .suggestion("Make sure all the boards are plugged in")
+    })
+}
+
+/// The serial number of the USB device behind a serial port, if the operating system tells.
+pub(crate) fn serial_number_of_port(path: &Path) -> Option<String> {
+    if let Some(serial) = path.to_str()?.strip_prefix(PATH_PREFIX) {
+        return Some(serial.to_string());
+    }
+    let name = path.file_name()?.to_str()?;
+    if let Some(serial) = serial_number_in_name(name) {
+        return Some(serial.to_string());
+    }
+
+    // /sys/class/tty/ttyUSB0/device is the usb interface, and its parent the usb device
+    let interface = canonicalize(Path::new("/sys/class/tty").join(name).join("device")).ok()?;
+    let serial = read_to_string(interface.parent()?.parent()?.join("serial")).ok()?;
+    Some(serial.trim().to_string())
+}
+
+/// The macOS driver of FTDI chips names its ports after their serial number, like `cu.usbserial-DK0F3GQL`.
+fn serial_number_in_name(name: &str) -> Option<&str> {
+    name.strip_prefix("cu.usbserial-")
+        .or_else(|| name.strip_prefix("tty.usbserial-"))
+        .filter(|s| !s.is_empty())
+}
+
+/// Which of the connected FTDI devices (by serial number) is the one at `path`.
+fn match_ftdi_device(
+    path: &Path,
+    serial_number: Option<String>,
+    devices: &[String],
+) -> Result<String> {
+    match serial_number {
+        Some(serial) => devices
+            .iter()
+            // chips with several ports add a letter per port to the serial number
+            .find(|d| {
+                **d == serial
+                    || serial
+                        .strip_prefix(d.as_str())
+                        .is_some_and(|s| s.len() == 1)
+            })
+            .cloned()
+            .ok_or_else(|| {
+                eyre!(
+                    "no FTDI device corresponds to {path:?} (serial number {serial}), {}",
+                    connected_devices(devices)
+                )
+                .suggestion("Is the port the one of the FTDI chip on the board?")
+            }),
+        None => match devices {
+            [only] => Ok(only.clone()),
+            _ => Err(eyre!(
+                "can't tell which FTDI device corresponds to {path:?}, {}",
+                connected_devices(devices)
+            )
+            .suggestion("Only connect the board you want to upload to")),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{
+        device_at, device_with_description, distinct, identify, match_ftdi_device,
+        path_with_serial_number, serial_number_in_name, serial_number_of_port, FtdiIdentity,
+    };
+
+    #[test]
+    fn test_match_ftdi_device() {
+        let devices = ["DK0F3GQL".to_string(), "A10KXQ2C".to_string()];
+        let matched = |path: &str, serial: Option<&str>, devices: &[String]| {
+            match_ftdi_device(Path::new(path), serial.map(String::from), devices)
+        };
+
+        assert_eq!(
+            serial_number_in_name("cu.usbserial-A10KXQ2C"),
+            Some("A10KXQ2C")
+        );
+        assert_eq!(
+            serial_number_in_name("tty.usbserial-DK0F3GQL"),
+            Some("DK0F3GQL")
+        );
+        assert_eq!(serial_number_in_name("cu.usbserial-"), None);
+        assert_eq!(serial_number_in_name("ttyUSB0"), None);
+
+        // the second board, not just the first one that is connected
+        let path = "/dev/cu.usbserial-A10KXQ2C";
+        assert_eq!(
+            matched(path, Some("A10KXQ2C"), &devices).unwrap(),
+            "A10KXQ2C"
+        );
+        assert_eq!(
+            matched(path, Some("A10KXQ2CB"), &devices).unwrap(),
+            "A10KXQ2C"
+        );
+        let err = matched(path, Some("FT000001"), &devices).unwrap_err();
+        assert!(err.to_string().contains("DK0F3GQL, A10KXQ2C"));
+
+        // when the port doesn't tell, only a single device is unambiguous
+        assert_eq!(matched("COM3", None, &devices[..1]).unwrap(), "DK0F3GQL");
+        assert!(matched("COM3", None, &devices).is_err());
+        assert!(matched("COM3", None, &[]).is_err());
+    }
+
+    #[test]
+    fn test_path_with_serial_number() {
+        let devices = ["DK0F3GQL".to_string(), "A10KXQ2C".to_string()];
+        assert_eq!(
+            path_with_serial_number("A10KXQ2C", &devices).unwrap(),
+            Path::new("ftdi:A10KXQ2C")
+        );
+        assert_eq!(
+            path_with_serial_number("A10KXQ2D", &devices)
+                .unwrap_err()
+                .to_string(),
+            "no FTDI device has serial number A10KXQ2D, the connected FTDI devices are DK0F3GQL, A10KXQ2C"
+        );
+    }
+
+    #[test]
+    fn test_device_with_description() {
+        let devices = [
+            ("DK0F3GQL".to_string(), "ES-Drone v2".to_string()),
+            ("A10KXQ2C".to_string(), "ES-Drone v3".to_string()),
+            ("FT5XJ0AB".to_string(), "ES-Drone".to_string()),
+            ("A600BX1Q".to_string(), "FT232R USB UART".to_string()),
+        ];
+        // an exact match wins over the descriptions that contain it
+        assert_eq!(
+            device_with_description("ES-Drone", &devices).unwrap(),
+            "FT5XJ0AB"
+        );
+        assert_eq!(device_with_description("v3", &devices).unwrap(), "A10KXQ2C");
+        assert_eq!(
+            device_with_description("ES-Drone v", &devices)
+                .unwrap_err()
+                .to_string(),
+            "several FTDI devices have a description containing \"ES-Drone v\": \
+             DK0F3GQL (\"ES-Drone v2\"), A10KXQ2C (\"ES-Drone v3\")"
+        );
+        assert_eq!(
+            device_with_description("es-drone", &devices[3..])
+                .unwrap_err()
+                .to_string(),
+            "no FTDI device has a description containing \"es-drone\", \
+             the connected FTDI devices are A600BX1Q (\"FT232R USB UART\")"
+        );
+        assert!(device_with_description("ES-Drone", &[]).is_err());
+    }
+
+    #[test]
+    fn test_identify() {
+        let devices = [
+            ("DK0F3GQL".to_string(), "ES-Drone v2".to_string()),
+            ("FT4QX1B".to_string(), "Dual RS232-HS".to_string()),
+        ];
+        let identity = identify(
+            Path::new("/dev/cu.usbserial-FT4QX1BB"),
+            Some("FT4QX1BB".to_string()),
+            &devices,
+        )
+        .unwrap();
+        assert_eq!(
+            identity,
+            FtdiIdentity {
+                index: 1,
+                serial_number: "FT4QX1B".to_string(),
+                description: "Dual RS232-HS".to_string(),
+            }
+        );
+        assert!(identify(Path::new("/dev/ttyUSB0"), None, &devices).is_err());
+        assert_eq!(
+            identify(Path::new("/dev/ttyUSB0"), None, &devices[..1])
+                .unwrap()
+                .serial_number,
+            "DK0F3GQL"
+        );
+    }
+
+    #[test]
+    fn test_distinct() {
+        let devices = [
+            ("DK0F3GQL".to_string(), "ES-Drone v2".to_string()),
+            ("A10KXQ2C".to_string(), "ES-Drone v3".to_string()),
+        ];
+        let paths = [
+            "/dev/cu.usbserial-DK0F3GQL",
+            "/dev/tty.usbserial-DK0F3GQL",
+            "/dev/cu.usbserial-A10KXQ2C",
+            "ftdi:DK0F3GQL",
+            "/dev/cu.usbserial-A10KXQ2C",
+            "/dev/cu.usbserial-XXXXXXXX",
+        ]
+        .map(PathBuf::from);
+        assert_eq!(
+            distinct(paths.to_vec(), &devices),
+            [paths[0].clone(), paths[2].clone(), paths[5].clone()]
+        );
+    }
+
+    #[test]
+    fn test_device_at() {
+        let devices = ["DK0F3GQL".to_string(), "A10KXQ2C".to_string()];
+        assert_eq!(device_at(1, &devices).unwrap(), "A10KXQ2C");
+        assert_eq!(
+            device_at(2, &devices).unwrap_err().to_string(),
+            "there is no FTDI device 2, only 2 FTDI devices are connected, devices 0 to 1"
+        );
+        assert_eq!(
+            device_at(0, &[]).unwrap_err().to_string(),
+            "there is no FTDI device 0, no FTDI devices are connected"
+        );
+
+        assert_eq!(
+            serial_number_of_port(Path::new("ftdi:A10KXQ2C")).as_deref(),
+            Some("A10KXQ2C")
+        );
+    }
+}
@@ -0,0 +1,264 @@
+//! Decoding the HCI frames the bootloader sends back.
+//!
+//! Most of them only acknowledge a packet, but the bootloader can also ask for a packet again
+//! with a link control packet, and answer a DFU packet with a response that says what went wrong.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use color_eyre::Result;
+
+use crate::dfu::{
+    DFU_ACTIVATE_AND_RESET, DFU_DATA_PACKET, DFU_INIT_PACKET, DFU_START_PACKET,
+    DFU_STOP_DATA_PACKET,
+};
+use crate::slip::{decode_unescaped, DecodedFrame};
+
+/// Opcode of a response of the bootloader to one of our DFU packets.
+pub(crate) const DFU_RESPONSE: u32 = 16;
+
+/// Frames with only a header, which acknowledge a packet.
+const ACK_PACKET: u8 = 0;
+/// Frames with a DFU packet in them, in both directions.
+pub(crate) const VENDOR_PACKET: u8 = 14;
+/// Link control, sent by the bootloader when it wants a packet again.
+pub(crate) const LINK_CONTROL_PACKET: u8 = 15;
+
+/// A frame the bootloader sent back, with its header decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AckFrame {
+    /// The sequence number of the frame itself.
+    pub seq: u8,
+    /// The sequence number of the packet the bootloader expects next.
+    pub ack: u8,
+    /// Whether the bootloader wants this frame acknowledged.
+    pub reliable: bool,
+    /// 0 for a plain ack, 14 for a DFU packet and 15 for link control.
+    pub packet_type: u8,
+    /// The bytes after the header, without the CRC.
+    pub payload: Vec<u8>,
+}
+
+/// A decoded frame from the bootloader.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Received {
+    pub(crate) frame: AckFrame,
+    pub(crate) packet: Packet,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Packet {
+    Ack,
+    /// The bootloader wants the packet again.
+    Nack,
+    Dfu(DfuResponse),
+    /// A packet type we don't know, or a vendor packet that isn't a DFU response.
+    Other {
+        packet_type: u8,
+    },
+}
+
+/// What the bootloader thought of one of our DFU packets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct DfuResponse {
+    /// The opcode of the packet this is a response to.
+    pub(crate) request: u32,
+    pub(crate) result: DfuResult,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DfuResult {
+    Success,
+    InvalidState,
+    NotSupported,
+    DataSizeExceedsLimit,
+    CrcError,
+    OperationFailed,
+    Unknown(u32),
+}
+
+impl From<u32> for DfuResult {
+    fn from(code: u32) -> Self {
+        match code {
+            1 => Self::Success,
+            2 => Self::InvalidState,
+            3 => Self::NotSupported,
+            4 => Self::DataSizeExceedsLimit,
+            5 => Self::CrcError,
+            6 => Self::OperationFailed,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+impl Display for DfuResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Success => f.write_str("success"),
+            Self::InvalidState => f.write_str("invalid state"),
+            Self::NotSupported => f.write_str("not supported"),
+            Self::DataSizeExceedsLimit => f.write_str("size too large"),
+            Self::CrcError => f.write_str("CRC error"),
+            Self::OperationFailed => f.write_str("operation failed"),
+            Self::Unknown(code) => write!(f, "unknown result code {code}"),
+        }
+    }
+}
+
+impl Received {
+    /// Decode an unescaped frame, without the 0xc0 bytes around it.
+    pub(crate) fn decode(frame: &[u8]) -> Result<Self> {
+        let DecodedFrame { header, payload } = decode_unescaped(frame)?;
+
+        let packet_type = header.packet_type;
+        let packet = match packet_type {
+            ACK_PACKET => Packet::Ack,
+            LINK_CONTROL_PACKET => Packet::Nack,
+            VENDOR_PACKET => match parse_dfu_response(&payload) {
+                Some(response) => Packet::Dfu(response),
+                None => Packet::Other { packet_type },
+            },
+            _ => Packet::Other { packet_type },
+        };
+        let frame = AckFrame {
+            seq: header.seq,
+            ack: header.ack,
+            reliable: header.reliable,
+            packet_type,
+            payload,
+        };
+
+        Ok(Self { frame, packet })
+    }
+}
+
+pub(crate) fn parse_dfu_response(payload: &[u8]) -> Option<DfuResponse> {
+    let word = |i: usize| {
+        payload
+            .get(i * 4..i * 4 + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    };
+    if word(0)? != DFU_RESPONSE {
+        return None;
+    }
+    Some(DfuResponse {
+        request: word(1)?,
+        result: word(2)?.into(),
+    })
+}
+
+/// What the DFU packet with this opcode is called.
+pub(crate) fn opcode_name(opcode: u32) -> &'static str {
+    match opcode {
+        DFU_START_PACKET => "start",
+        DFU_INIT_PACKET => "init",
+        DFU_DATA_PACKET => "data",
+        DFU_STOP_DATA_PACKET => "stop",
+        DFU_ACTIVATE_AND_RESET => "activate",
+        DFU_RESPONSE => "response",
+        _ => "unknown",
+    }
+}
+
+/// The bootloader answered a packet with an error. This is never worth sending the packet again for.
+#[derive(Debug)]
+pub(crate) struct Rejected(pub(crate) DfuResponse);
+
+impl Display for Rejected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the bootloader rejected the {} packet: {}",
+            opcode_name(self.0.request),
+            self.0.result
+        )
+    }
+}
+
+impl Error for Rejected {}
+
+/// The bootloader asked for the packet again.
+#[derive(Debug)]
+pub(crate) struct Nacked;
+
+impl Display for Nacked {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("the bootloader asked for the packet again")
+    }
+}
+
+impl Error for Nacked {}
+
+#[cfg(test)]
+mod tests {
+    use super::{AckFrame, DfuResponse, DfuResult, Packet, Received, Rejected};
+    use crate::crc::calc_crc16_default;
+
+    fn frame(b1: u8, packet_type: u8, payload: &[u8]) -> Vec<u8> {
+        let b2 = packet_type | ((payload.len() & 0x0f) << 4) as u8;
+        let b3 = (payload.len() >> 4) as u8;
+        let mut frame = vec![
+            b1,
+            b2,
+            b3,
+            (!b1.wrapping_add(b2).wrapping_add(b3)).wrapping_add(1),
+        ];
+        frame.extend_from_slice(payload);
+        if b1 & 0x40 != 0 {
+            let crc = calc_crc16_default(&frame);
+            frame.extend_from_slice(&crc.to_le_bytes());
+        }
+        frame
+    }
+
+    #[test]
+    fn test_decode() {
+        let ack = Received::decode(&frame(3 << 3, 0, &[])).unwrap();
+        assert_eq!((ack.frame.ack, ack.packet), (3, Packet::Ack));
+
+        let nack = Received::decode(&frame(5 << 3, 15, &[])).unwrap();
+        assert_eq!(nack.packet, Packet::Nack);
+
+        let payload: Vec<u8> = [16u32, 3, 4].iter().flat_map(|w| w.to_le_bytes()).collect();
+        let response = Received::decode(&frame(1 << 3 | 0x40, 14, &payload)).unwrap();
+        let Packet::Dfu(response) = response.packet else {
+            panic!("not a DFU response: {response:?}");
+        };
+        assert_eq!(
+            response,
+            DfuResponse {
+                request: 3,
+                result: DfuResult::DataSizeExceedsLimit
+            }
+        );
+        assert_eq!(
+            Rejected(response).to_string(),
+            "the bootloader rejected the start packet: size too large"
+        );
+
+        // vendor packets that aren't responses, and packet types we don't know
+        let other = Received::decode(&frame(0x80 | 0x40 | 2 << 3 | 6, 14, &[4, 0, 0, 0])).unwrap();
+        assert_eq!(other.packet, Packet::Other { packet_type: 14 });
+        assert_eq!(
+            other.frame,
+            AckFrame {
+                seq: 6,
+                ack: 2,
+                reliable: true,
+                packet_type: 14,
+                payload: vec![4, 0, 0, 0],
+            }
+        );
+        let other = Received::decode(&frame(0, 7, &[])).unwrap();
+        assert_eq!(other.packet, Packet::Other { packet_type: 7 });
+
+        let mut corrupted = frame(1 << 3 | 0x40, 14, &payload);
+        corrupted[6] ^= 1;
+        assert!(Received::decode(&corrupted).is_err());
+        let mut bad_header = frame(3 << 3, 0, &[]);
+        bad_header[3] ^= 1;
+        assert!(Received::decode(&bad_header).is_err());
+        assert!(Received::decode(&[0, 0]).is_err());
+        assert!(Received::decode(&frame(0, 0, &[1, 2])[..5]).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! An append-only log of upload attempts, so it can be traced afterwards what was flashed where.
+
+use std::env;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{create_dir_all, read_to_string, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::image::{short_hash, PreparedImage};
+
+/// One upload attempt, as recorded in the history file when
+/// [`UploadConfig::record_history`](crate::UploadConfig::record_history) is enabled.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Seconds since the unix epoch.
+    pub timestamp: u64,
+    /// The serial number of the USB serial adapter, if it could be read.
+    pub adapter_serial: Option<String>,
+    pub port: PathBuf,
+    /// SHA-256 of the uploaded image, in hex.
+    pub sha256: String,
+    pub size: usize,
+    #[serde(rename = "duration_ms", with = "crate::report::duration_ms")]
+    pub duration: Duration,
+    /// Why the attempt failed, or `None` if it succeeded.
+    pub error: Option<String>,
+}
+
+impl HistoryEntry {
+    pub(crate) fn new(port: &Path, image: &PreparedImage) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            adapter_serial: None,
+            port: port.to_path_buf(),
+            sha256: image.sha256.clone(),
+            size: image.bytes().len(),
+            duration: Duration::ZERO,
+            error: None,
+        }
+    }
+}
+
+impl Display for HistoryEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}  {:<24} {:<12} {:>8} {}  {:>6.1}s  {}",
+            format_timestamp(self.timestamp),
+            self.port.display(),
+            self.adapter_serial.as_deref().unwrap_or("-"),
+            self.size,
+            short_hash(&self.sha256),
+            self.duration.as_secs_f64(),
+            self.error.as_deref().unwrap_or("ok"),
+        )
+    }
+}
+
+/// Where the history is kept: a file in the user data directory of the platform.
+fn history_path() -> Option<PathBuf> {
+    let data_dir = if cfg!(target_os = "macos") {
+        PathBuf::from(env::var_os("HOME")?).join("Library/Application Support")
+    } else if cfg!(windows) {
+        PathBuf::from(env::var_os("APPDATA")?)
+    } else {
+        env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".local/share")))?
+    };
+
+    Some(data_dir.join("tudelft-serial-upload").join("history.jsonl"))
+}
+
+/// Append an entry to the history at `path`, or else the one in the user data directory. This
+/// never fails the upload, problems are only warned about.
+pub(crate) fn record(entry: &HistoryEntry, path: Option<&Path>) {
+    let res = path
+        .map(Path::to_path_buf)
+        .or_else(history_path)
+        .ok_or_else(|| eyre!("couldn't find a directory to store it in"))
+        .and_then(|path| append(&path, entry));
+
+    if let Err(e) = res {
+        eprintln!("WARNING: failed to record the upload in the history: {e}");
+    }
+}
+
+fn append(path: &Path, entry: &HistoryEntry) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir).wrap_err_with(|| format!("failed to create {dir:?}"))?;
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .wrap_err_with(|| format!("failed to open {path:?}"))?;
+
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+
+    // when a previous write was cut off halfway, start on a fresh line so only that one is lost
+    if file.seek(SeekFrom::End(0))? > 0 {
+        let mut last = [0];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            line.insert(0, '\n');
+        }
+    }
+
+    file.write_all(line.as_bytes())
+        .wrap_err_with(|| format!("failed to write to {path:?}"))
+}
+
+/// Read back the last `limit` upload attempts from the history, oldest first.
+/// Lines that can't be read (for example because a write was cut off) are skipped.
+pub fn upload_history(limit: usize) -> Result<Vec<HistoryEntry>> {
+    let path = history_path().ok_or_else(|| eyre!("couldn't find the history directory"))?;
+    read_history(&path, limit)
+}
+
+pub(crate) fn read_history(path: &Path, limit: usize) -> Result<Vec<HistoryEntry>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let contents = read_to_string(path).wrap_err_with(|| format!("failed to read {path:?}"))?;
+    let mut entries: Vec<HistoryEntry> = contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+
+    entries.drain(..entries.len().saturating_sub(limit));
+    Ok(entries)
+}
+
+/// Formats a unix timestamp as a UTC date and time.
+fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{remove_file, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::{append, format_timestamp, read_history, HistoryEntry};
+    use crate::image::PreparedImage;
+
+    #[test]
+    fn test_history_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("tudelft-history-{}.jsonl", std::process::id()));
+        let _ = remove_file(&path);
+
+        let image = PreparedImage::unpadded(b"firmware".to_vec());
+        let mut entry = HistoryEntry::new(&PathBuf::from("/dev/ttyUSB0"), &image);
+        entry.duration = Duration::from_millis(1500);
+        append(&path, &entry).unwrap();
+
+        // a write that got cut off halfway
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"timestamp\": 12")
+            .unwrap();
+
+        let mut failed = entry.clone();
+        failed.error = Some("timed out".to_string());
+        append(&path, &failed).unwrap();
+
+        assert_eq!(read_history(&path, 10).unwrap(), [entry, failed.clone()]);
+        assert_eq!(read_history(&path, 1).unwrap(), [failed]);
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(1_709_251_199), "2024-02-29 23:59:59");
+    }
+}
@@ -0,0 +1,144 @@
+use std::ops::RangeInclusive;
+
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use sha2::{Digest, Sha256};
+
+use crate::config::UploadConfig;
+
+/// RAM of the nRF51 on the lab boards. The end is included, since the stack
+/// pointer usually starts right past the last byte of RAM.
+pub const RAM: RangeInclusive<u32> = 0x2000_0000..=0x2000_8000;
+
+/// An image the way it is sent to the board: padded to the [alignment](UploadConfig::pad_to)
+/// of the config, with its SHA-256 computed once, for the report and every entry in the history.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreparedImage {
+    bytes: Vec<u8>,
+    /// SHA-256 of the bytes that are sent, in hex.
+    pub sha256: String,
+}
+
+impl PreparedImage {
+    /// Pad `image` with 0xff to a multiple of [`UploadConfig::pad_to`] bytes, before anything
+    /// is sent about its size, CRC or hash.
+    pub fn new(image: impl Into<Vec<u8>>, config: &UploadConfig) -> Self {
+        let mut bytes = image.into();
+        let padded_len = bytes.len().next_multiple_of(config.pad_to.max(1));
+        bytes.resize(padded_len, 0xff);
+        Self::unpadded(bytes)
+    }
+
+    /// The image exactly as it is, for uploads of images that were prepared already.
+    pub(crate) fn unpadded(bytes: Vec<u8>) -> Self {
+        Self {
+            sha256: sha256_hex(&bytes),
+            bytes,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// The SHA-256 of an image, in hex. Unlike the CRC16 the bootloader checks, this identifies
+/// exactly which firmware was flashed.
+pub fn sha256_hex(image: &[u8]) -> String {
+    Sha256::digest(image)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// The first 12 characters of a hash, which is plenty to tell firmwares apart in a log.
+pub fn short_hash(hash: &str) -> &str {
+    &hash[..hash.len().min(12)]
+}
+
+/// Check that the image starts with something that looks like a Cortex-M vector table: the initial
+/// stack pointer has to lie in RAM, and the reset vector has to point to Thumb code in the application's flash.
+///
+/// Images that fail this are accepted by the bootloader just fine, after which the board sits there doing nothing.
+pub fn check_vector_table(image: &[u8], config: &UploadConfig) -> Result<()> {
+    const HINT: &str = "Is this a binary of your program, created from the elf file by objcopy? \
+                        Use UploadConfig::force to upload it anyway.";
+
+    let Some(header) = image.get(..8) else {
+        bail!(
+            "the image is only {} bytes, which is too short to even contain a vector table. {HINT}",
+            image.len()
+        );
+    };
+    let stack_pointer = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let reset_vector = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+    let ram = &config.board.ram;
+    if !ram.contains(&stack_pointer) {
+        bail!(
+            "the image starts with an initial stack pointer of 0x{stack_pointer:08x}, which is not in RAM (0x{:08x}-0x{:08x}). {HINT}",
+            ram.start(),
+            ram.end()
+        );
+    }
+
+    let flash = config.app_start()..config.board.bootloader_start;
+    if reset_vector & 1 == 0 || !flash.contains(&reset_vector) {
+        bail!(
+            "the image has a reset vector of 0x{reset_vector:08x}, which is not an odd (thumb) address in the application's flash (0x{:08x}-0x{:08x}). {HINT}",
+            flash.start,
+            flash.end
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_vector_table, PreparedImage};
+    use crate::config::UploadConfig;
+    use crate::elf::test_binary;
+
+    #[test]
+    fn test_vector_table() {
+        let config = UploadConfig::default();
+
+        assert!(check_vector_table(&test_binary(0x2000_4000, 0x0001_80c1, 32), &config).is_ok());
+        assert!(check_vector_table(&test_binary(0x2000_8000, 0x0001_9001, 32), &config).is_ok());
+
+        // stack pointer in flash
+        assert!(check_vector_table(&test_binary(0x0001_8000, 0x0001_80c1, 32), &config).is_err());
+        // even reset vector
+        assert!(check_vector_table(&test_binary(0x2000_4000, 0x0001_80c0, 32), &config).is_err());
+        // reset vector in the softdevice
+        assert!(check_vector_table(&test_binary(0x2000_4000, 0x0000_10c1, 32), &config).is_err());
+        // too short
+        assert!(check_vector_table(&[0; 4], &config).is_err());
+
+        let config = config.app_start_address(0);
+        assert!(check_vector_table(&test_binary(0x2000_4000, 0x0000_10c1, 32), &config).is_ok());
+    }
+
+    #[test]
+    fn test_prepared_image() {
+        let config = UploadConfig::default();
+        assert_eq!(PreparedImage::new([1; 8], &config).bytes().len(), 8);
+        assert_eq!(PreparedImage::new([1; 9], &config).bytes().len(), 12);
+        assert_eq!(
+            PreparedImage::new([1; 9], &config.clone().pad_to(16))
+                .bytes()
+                .len(),
+            16
+        );
+
+        // the hash is of the padded image, which is what ends up in the flash
+        let image = PreparedImage::new([1, 2, 3, 4, 5], &config);
+        assert_eq!(image.bytes(), [1, 2, 3, 4, 5, 0xff, 0xff, 0xff]);
+        assert_eq!(image, PreparedImage::unpadded(image.bytes().to_vec()));
+        assert_ne!(
+            image.sha256,
+            PreparedImage::unpadded(vec![1, 2, 3, 4, 5]).sha256
+        );
+    }
+}
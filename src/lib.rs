@@ -1,15 +1,76 @@
 extern crate core;
 
+#[cfg(feature = "async")]
+mod async_upload;
+mod bench;
+mod board;
+mod clock;
+mod config;
 mod crc;
+#[cfg(feature = "protocol")]
+pub mod dfu;
+#[cfg(not(feature = "protocol"))]
+mod dfu;
+mod doctor;
+mod elf;
+#[cfg_attr(not(test), allow(dead_code))]
+mod emulator;
+#[cfg(feature = "ftdi")]
+mod ftdi;
+mod hci;
+mod history;
+mod image;
+mod progress;
+mod recording;
+mod report;
 mod selector;
 mod serial;
+#[cfg(feature = "protocol")]
+pub mod slip;
+#[cfg(not(feature = "protocol"))]
+#[allow(dead_code)]
+mod slip;
+mod trace;
+mod transport;
 mod upload;
+mod watcher;
 
 use std::time::Duration;
 
+#[cfg(feature = "async")]
+pub use async_upload::{upload_async, ProgressEvents, UploadTask};
+pub use bench::{
+    benchmark, benchmark_throughput, BenchmarkOptions, BenchmarkReport, BenchmarkRun,
+    ThroughputStats,
+};
+pub use board::{BoardProfile, Protocol, UsbId};
 pub use color_eyre;
-pub use selector::PortSelector;
-pub use upload::{upload, upload_file, upload_file_or_stop, upload_or_stop};
+pub use config::UploadConfig;
+pub use crc::{calc_crc16, calc_crc16_default, calc_crc32, Crc16, Crc32};
+pub use dfu::{ImageSizes, ImageType, InitPacket, IntegrityCheck};
+pub use doctor::{check_drivers, D2xxReport, DriverReport};
+pub use elf::ConversionOptions;
+#[cfg(feature = "ftdi")]
+pub use ftdi::FtdiIdentity;
+pub use hci::AckFrame;
+pub use history::{upload_history, HistoryEntry};
+pub use image::{short_hash, PreparedImage};
+#[cfg(feature = "ftdi")]
+pub use libftd2xx;
+pub use progress::ProgressEvent;
+pub use report::{Phase, PhaseTiming, UploadReport};
+#[cfg(feature = "ftdi")]
+pub use selector::resolve_ftdi;
+pub use selector::{MultipleMatches, PortSelector, SIMULATED_PORT, SIMULATE_VAR};
+pub use serial::{Cancelled, DeadlineExceeded, Serial};
 pub use serial2;
+pub use trace::{Direction, TracedFrame};
+pub use transport::{BitsPerWord, ControlLine, LineSettings, Parity, StopBits, Transport};
+pub use upload::{
+    abort_dfu, erase, loopback_test, replay, upload, upload_file, upload_file_or_stop,
+    upload_file_with_config, upload_keep_open, upload_or_stop, upload_over_port,
+    upload_with_config, upload_with_report,
+};
+pub use watcher::{ChangeSet, PortWatcher};
 
 const SERIAL_TIMEOUT: Duration = Duration::from_secs(5);
@@ -0,0 +1,36 @@
+//! Events about how far an upload is, for callers that show progress themselves.
+
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+
+/// A step of an upload, handed to the callback set with
+/// [`UploadConfig::on_progress`](crate::UploadConfig::on_progress).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// The port is open and an upload attempt begins. After an [`Error`](Self::Error), this
+    /// comes again for every further attempt allowed by
+    /// [`UploadConfig::max_upload_attempts`](crate::UploadConfig::max_upload_attempts).
+    Started,
+    /// The bootloader acknowledged the start packet.
+    StartDfuSent,
+    /// The bootloader accepted the init packet and erased the flash.
+    InitSent,
+    /// The bootloader acknowledged the data packet with this index.
+    Chunk { index: usize, total: usize },
+    /// The image was uploaded.
+    Finished,
+    /// The upload failed with this error.
+    Error(String),
+}
+
+type ProgressFn = dyn Fn(ProgressEvent) + Send + Sync;
+
+/// Where progress events go, set with [`UploadConfig::on_progress`](crate::UploadConfig::on_progress).
+#[derive(Clone)]
+pub(crate) struct ProgressSink(pub(crate) Arc<ProgressFn>);
+
+impl Debug for ProgressSink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressSink")
+    }
+}
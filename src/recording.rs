@@ -0,0 +1,329 @@
+//! Recording everything that goes over the port during an upload, and replaying it later.
+//!
+//! A recording is a file of JSON lines, one for every write and every read, with the bytes in
+//! hex and the time since the line before. Reads that timed out have no bytes. Replaying it feeds
+//! the received bytes back to the protocol code, so a failed upload can be reproduced without
+//! the board it failed on.
+
+use std::collections::VecDeque;
+use std::fs::{read_to_string, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+#[cfg(feature = "ftdi")]
+use libftd2xx::Ftdi;
+use serde::{Deserialize, Serialize};
+use serial2::FlowControl;
+
+use crate::clock::{Clock, FakeClock};
+use crate::transport::{ControlLine, Transport};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Dir {
+    Tx,
+    Rx,
+}
+
+/// A line of a recording.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Event {
+    dir: Dir,
+    /// Microseconds since the line before.
+    dt_us: u64,
+    hex: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// A recording that is being written, see [`UploadConfig::record_to`](crate::UploadConfig::record_to).
+pub(crate) struct Recording {
+    out: BufWriter<File>,
+    /// The clock of the upload, which is also the one a replay waits on.
+    clock: Arc<dyn Clock>,
+    last: Instant,
+}
+
+impl Recording {
+    pub(crate) fn create(path: &Path, clock: Arc<dyn Clock>) -> Result<Self> {
+        let file = File::create(path)
+            .wrap_err_with(|| format!("failed to create the recording {path:?}"))?;
+        Ok(Self {
+            out: BufWriter::new(file),
+            last: clock.now(),
+            clock,
+        })
+    }
+
+    fn record(&mut self, dir: Dir, bytes: &[u8]) {
+        let now = self.clock.now();
+        let event = Event {
+            dir,
+            dt_us: (now - self.last).as_micros() as u64,
+            hex: to_hex(bytes),
+        };
+        self.last = now;
+        // a recording that can't be written shouldn't stop the upload it is for
+        if let Ok(line) = serde_json::to_string(&event) {
+            let _ = writeln!(self.out, "{line}");
+        }
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/// The transport of a [`Serial`](crate::Serial), which writes everything that goes through it
+/// to a recording while there is one.
+pub(crate) struct Recorded {
+    pub(crate) inner: Box<dyn Transport + Send>,
+    pub(crate) recording: Option<Recording>,
+}
+
+impl Recorded {
+    pub(crate) fn new(inner: Box<dyn Transport + Send>) -> Self {
+        Self {
+            inner,
+            recording: None,
+        }
+    }
+
+    fn record(&mut self, dir: Dir, bytes: &[u8]) {
+        if let Some(recording) = &mut self.recording {
+            recording.record(dir, bytes);
+        }
+    }
+}
+
+impl Transport for Recorded {
+    fn read_all(&mut self, buf: &mut [u8]) -> Result<()> {
+        let res = self.inner.read_all(buf);
+        // what a read that failed got before it timed out is lost, as it is for the upload
+        let read = if res.is_ok() { &buf[..] } else { &[] };
+        self.record(Dir::Rx, read);
+        res
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.record(Dir::Rx, &buf[..n]);
+        Ok(n)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        self.record(Dir::Tx, buf);
+        self.inner.write_all(buf)
+    }
+
+    fn set_timeouts(&mut self, read: Duration, write: Duration) -> Result<()> {
+        self.inner.set_timeouts(read, write)
+    }
+
+    fn set_latency_timer(&mut self, timer: Duration) -> Result<()> {
+        self.inner.set_latency_timer(timer)
+    }
+
+    fn reconfigure(&mut self, baud_rate: u32, flow_control: FlowControl) -> Result<()> {
+        self.inner.reconfigure(baud_rate, flow_control)
+    }
+
+    fn set_control_line(&mut self, line: ControlLine, active: bool) -> Result<()> {
+        self.inner.set_control_line(line, active)
+    }
+
+    fn clear_input(&mut self) -> Result<()> {
+        self.inner.clear_input()
+    }
+
+    fn clear_output(&mut self) -> Result<()> {
+        self.inner.clear_output()
+    }
+
+    fn serial_number(&mut self) -> Option<String> {
+        self.inner.serial_number()
+    }
+
+    #[cfg(feature = "ftdi")]
+    fn as_ftdi_mut(&mut self) -> Option<&mut Ftdi> {
+        self.inner.as_ftdi_mut()
+    }
+
+    #[cfg(feature = "ftdi")]
+    fn into_ftdi(self: Box<Self>) -> Option<Ftdi> {
+        self.inner.into_ftdi()
+    }
+}
+
+/// Plays back a recording: reads get the bytes that were received, at the time they were
+/// received on `clock`, and writes are checked against what was sent.
+pub(crate) struct Replay {
+    events: VecDeque<(Dir, Duration, Vec<u8>)>,
+    clock: Arc<FakeClock>,
+}
+
+impl Replay {
+    pub(crate) fn open(path: &Path, clock: Arc<FakeClock>) -> Result<Self> {
+        let text = read_to_string(path)
+            .wrap_err_with(|| format!("failed to read the recording {path:?}"))?;
+        Self::parse(&text, clock).wrap_err_with(|| format!("invalid recording {path:?}"))
+    }
+
+    fn parse(text: &str, clock: Arc<FakeClock>) -> Result<Self> {
+        let events = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                let event: Event =
+                    serde_json::from_str(line).wrap_err_with(|| format!("on line {}", i + 1))?;
+                let bytes =
+                    from_hex(&event.hex).ok_or_else(|| eyre!("invalid hex on line {}", i + 1))?;
+                Ok((event.dir, Duration::from_micros(event.dt_us), bytes))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { events, clock })
+    }
+
+    /// Take the next event in `dir`, letting the time pass that the events before it took.
+    fn next(&mut self, dir: Dir) -> Option<Vec<u8>> {
+        loop {
+            let (d, dt, bytes) = self.events.pop_front()?;
+            self.clock.sleep(dt);
+            if d == dir {
+                return Some(bytes);
+            }
+            if dir == Dir::Tx {
+                // a write without a read after the last one, keep the read for later
+                self.events.push_front((d, Duration::ZERO, bytes));
+                return None;
+            }
+        }
+    }
+}
+
+impl Transport for Replay {
+    fn read_all(&mut self, buf: &mut [u8]) -> Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = self.read(&mut buf[filled..])?;
+            if n == 0 {
+                bail!("timed out waiting for the bootloader to respond");
+            }
+            filled += n;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let Some(mut bytes) = self.next(Dir::Rx) else {
+            bail!("the recording ends here");
+        };
+        if bytes.len() > buf.len() {
+            let rest = bytes.split_off(buf.len());
+            self.events.push_front((Dir::Rx, Duration::ZERO, rest));
+        }
+        buf[..bytes.len()].copy_from_slice(&bytes);
+        Ok(bytes.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        match self.next(Dir::Tx) {
+            Some(sent) if sent != buf => bail!(
+                "the replayed upload sent {}, where the recorded one sent {}, was it replayed with another image or config?",
+                to_hex(buf),
+                to_hex(&sent)
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_control_line(&mut self, _line: ControlLine, _active: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn clear_input(&mut self) -> Result<()> {
+        // what was thrown away during the recording wasn't recorded either
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{from_hex, to_hex, Dir, Recorded, Recording, Replay};
+    use crate::clock::FakeClock;
+    use crate::emulator::Emulator;
+    use crate::transport::Transport;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0xc0, 0x01, 0xab]), "c001ab");
+        assert_eq!(from_hex("c001ab"), Some(vec![0xc0, 0x01, 0xab]));
+        assert_eq!(from_hex(""), Some(vec![]));
+        assert_eq!(from_hex("c0a"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("recording-{}.jsonl", std::process::id()));
+        let emulator = Emulator::new().loopback();
+        let mut port = Recorded::new(Box::new(emulator));
+        port.recording = Some(Recording::create(&path, Arc::new(FakeClock::new())).unwrap());
+        port.write_all(b"hello").unwrap();
+        let mut buf = [0; 3];
+        port.read_all(&mut buf).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(port.read(&mut buf).unwrap(), 2);
+        assert_eq!(port.read(&mut buf).unwrap(), 0);
+        port.recording = None;
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(text.lines().count(), 4);
+        assert!(text.starts_with(r#"{"dir":"tx","dt_us":"#));
+        assert!(text.lines().nth(1).unwrap().ends_with(r#""hex":"68656c"}"#));
+
+        let clock = Arc::new(FakeClock::new());
+        let mut replay = Replay::parse(&text, clock.clone()).unwrap();
+        assert_eq!(replay.events[0].0, Dir::Tx);
+        replay.write_all(b"hello").unwrap();
+        // the bytes come back in other pieces than they were read in
+        let mut buf = [0; 4];
+        replay.read_all(&mut buf).unwrap();
+        assert_eq!(&buf, b"hell");
+        assert_eq!(replay.read(&mut buf).unwrap(), 1);
+        assert_eq!(replay.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            replay.read(&mut buf).unwrap_err().to_string(),
+            "the recording ends here"
+        );
+
+        let mut replay = Replay::parse(&text, clock).unwrap();
+        let err = replay.write_all(b"bye").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("the replayed upload sent 627965,"));
+        assert!(Replay::parse("{\"dir\":\"rx\"}", Arc::new(FakeClock::new())).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::clock::Clock;
+
+/// What happened during an upload.
+#[derive(Clone, Debug, Serialize)]
+pub struct UploadReport {
+    /// The serial port the upload happened over. This path can be used to communicate with the board.
+    pub port: PathBuf,
+    /// Number of bytes of the image that were sent.
+    pub bytes: usize,
+    /// SHA-256 (in hex) of exactly the bytes that were sent.
+    pub sha256: String,
+    /// CRC-16 of the bytes that were sent, which the init packet has unless it was configured
+    /// to have a CRC-32 instead.
+    pub crc16: u16,
+    /// Number of data packets the image was split into.
+    pub chunks: usize,
+    /// The sizes the data packets had, in the order they were used: only the configured one,
+    /// unless it was changed by [`UploadConfig::adaptive_packet_size`](crate::UploadConfig::adaptive_packet_size).
+    pub packet_sizes: Vec<usize>,
+    /// Number of packets that had to be sent again.
+    pub retries: usize,
+    /// Time from the start packet until the stop packet was acknowledged.
+    #[serde(rename = "duration_ms", with = "duration_ms")]
+    pub duration: Duration,
+    /// Number of received bytes that were not part of the protocol, like log output of the board.
+    pub discarded_bytes: usize,
+    /// Whether the application printed its banner after the upload, when one was
+    /// configured with [`UploadConfig::expect_banner`](crate::UploadConfig::expect_banner).
+    pub banner_seen: Option<bool>,
+    /// Whether the bootloader confirmed it received the image intact, when that was asked for
+    /// with [`UploadConfig::verify`](crate::UploadConfig::verify). `Some(false)` means the
+    /// bootloader doesn't report that, an image that didn't arrive intact fails the upload.
+    pub verified: Option<bool>,
+    /// How long every phase of the upload took, in order.
+    pub phases: Vec<PhaseTiming>,
+}
+
+/// A part of the upload that is timed separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Turning the ELF file into a binary.
+    Convert,
+    /// Finding the serial port(s) to use.
+    Select,
+    /// Opening the serial port(s).
+    Open,
+    /// The hook set with [`UploadConfig::before_reset`](crate::UploadConfig::before_reset).
+    BeforeReset,
+    /// Resetting the board with a control line, see [`UploadConfig::reset_before_upload`](crate::UploadConfig::reset_before_upload).
+    Reset,
+    /// The ping and the start packet, and the wait after it.
+    Start,
+    /// The init packet, and the wait after it.
+    Init,
+    /// All data packets.
+    Data,
+    /// The stop packet.
+    Stop,
+    /// Waiting for the bootloader to confirm the CRC of the image.
+    Verify,
+    /// Telling the bootloader to start the application.
+    Activate,
+    /// Waiting for the banner of the application.
+    Banner,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PhaseTiming {
+    pub phase: Phase,
+    #[serde(rename = "duration_ms", with = "duration_ms")]
+    pub duration: Duration,
+}
+
+pub(crate) mod duration_ms {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        u64::deserialize(d).map(Duration::from_millis)
+    }
+}
+
+/// Measures one phase after the other.
+pub(crate) struct PhaseTimer {
+    clock: Arc<dyn Clock>,
+    last: Instant,
+    phases: Vec<PhaseTiming>,
+}
+
+impl PhaseTimer {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            last: clock.now(),
+            clock,
+            phases: Vec::new(),
+        }
+    }
+
+    /// Record that `phase` ended now, and started when the previous one ended.
+    pub(crate) fn lap(&mut self, phase: Phase) {
+        let now = self.clock.now();
+        self.phases.push(PhaseTiming {
+            phase,
+            duration: now - self.last,
+        });
+        self.last = now;
+    }
+
+    pub(crate) fn finish(self) -> Vec<PhaseTiming> {
+        self.phases
+    }
+}
+
+impl UploadReport {
+    pub(crate) fn new(port: PathBuf) -> Self {
+        Self {
+            port,
+            bytes: 0,
+            sha256: String::new(),
+            crc16: 0,
+            chunks: 0,
+            packet_sizes: Vec::new(),
+            retries: 0,
+            duration: Duration::ZERO,
+            discarded_bytes: 0,
+            banner_seen: None,
+            verified: None,
+            phases: Vec::new(),
+        }
+    }
+
+    /// Effective upload speed in bytes per second, including all protocol waits.
+    pub fn throughput(&self) -> f64 {
+        if self.duration.is_zero() {
+            return 0.0;
+        }
+        self.bytes as f64 / self.duration.as_secs_f64()
+    }
+
+    /// The time every phase took as a small table, one line per phase.
+    pub fn phase_table(&self) -> String {
+        let total: Duration = self.phases.iter().map(|p| p.duration).sum();
+        let mut table = String::new();
+        for PhaseTiming { phase, duration } in &self.phases {
+            let share = if total.is_zero() {
+                0.0
+            } else {
+                duration.as_secs_f64() / total.as_secs_f64() * 100.0
+            };
+            let _ = writeln!(
+                table,
+                "{:<14} {:>8.3}s {share:>5.1}%",
+                format!("{phase:?}"),
+                duration.as_secs_f64()
+            );
+        }
+        let _ = writeln!(table, "{:<14} {:>8.3}s", "Total", total.as_secs_f64());
+        table
+    }
+}
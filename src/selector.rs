@@ -1,15 +1,30 @@
-use std::io::{stdin, stdout, Write};
+use std::cell::RefCell;
+use std::fmt::{self, Display, Formatter};
+use std::io::{stderr, stdin, IsTerminal, Write};
+use std::path::PathBuf;
 
-use color_eyre::{eyre::eyre, Help, Result};
+use color_eyre::{
+    eyre::{eyre, Report},
+    Help, Result,
+};
 use crossterm::{
     execute,
     style::{Color, Print, ResetColor, SetForegroundColor},
     terminal::{Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use serial_enumerator::{get_serial_list, SerialInfo};
+use serial_enumerator::SerialInfo;
+
+use crate::config::UploadConfig;
+
+/// The path of the simulated board of [`PortSelector::Simulated`].
+pub const SIMULATED_PORT: &str = "/dev/null-sim0";
 
-#[derive(Default)]
-pub enum PortSelector<'a> {
+/// When this environment variable is set (to anything but `0`), uploads go to the simulated
+/// board of [`PortSelector::Simulated`], whatever port they were asked to use.
+pub const SIMULATE_VAR: &str = "TUDELFT_UPLOAD_SIMULATE";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum PortSelector {
     /// Automatically upload based on the USB Product ID and Vendor ID of the serial chip that is on
     /// the drone boards used in the Embedded Systems Lab
     #[default]
@@ -25,86 +40,503 @@ pub enum PortSelector<'a> {
     /// Interactively choose which serial port you want to upload to
     ChooseInteractive,
 
+    /// Like [`ChooseInteractive`](Self::ChooseInteractive), but only list the ports whose name,
+    /// product or manufacturer contains this string, ignoring case. The filter can be changed
+    /// while choosing, by typing `f` followed by the new filter (or nothing, to clear it).
+    ChooseInteractiveFiltered(String),
+
     /// Choose to a specific, named serial port
     /// Note that a conversion from strings exists for this
     /// variant, so you can just write `upload("/dev/ttyUSB0", ...)` for example.
-    Named(&'a str),
+    ///
+    /// The name may also be a glob pattern like `/dev/cu.usbserial-*`, which is matched against
+    /// the names of the serial ports that are found (`*`, `?` and `[...]` are supported).
+    /// When more than one port matches, [`UploadConfig::multiple_matches`] says which is used.
+    Named(String),
+
+    /// Use the serial port named in this environment variable, like [`Named`](Self::Named) does.
+    /// Finds nothing when the variable isn't set, or is empty.
+    Env(String),
+
+    /// The FTDI device at this index in the list of the D2XX driver, which stays the same when
+    /// the operating system names the ports differently. Its path is `ftdi:` and the serial
+    /// number of the device.
+    #[cfg(feature = "ftdi")]
+    DeviceIndex(usize),
+
+    /// The FTDI device with this serial number, which is the same on every computer and after
+    /// every reboot. [`ChooseInteractive`](Self::ChooseInteractive) shows the serial numbers.
+    #[cfg(feature = "ftdi")]
+    BySerialNumber(String),
+
+    /// The FTDI device whose description, which is programmed in its EEPROM, is exactly this.
+    /// When none is, the only device whose description contains this, so `"ES-Drone"` finds an
+    /// `"ES-Drone v2"`. It is an error when several devices match. The comparison is case
+    /// sensitive.
+    #[cfg(feature = "ftdi")]
+    ByDescription(String),
+
+    /// A simulated board instead of a real one, at the path [`SIMULATED_PORT`]. Uploads to it go
+    /// through the same framing and chunking as to a board, and it acknowledges every packet,
+    /// so programs can be checked without hardware, in CI for example. Setting the environment
+    /// variable [`SIMULATE_VAR`] uses it instead of every other selector.
+    Simulated,
+
+    /// Try each of these in order, and use the first one that finds a serial port.
+    /// For example, `Chain(vec![Env("DRONE_PORT".into()), AutoManufacturer, ChooseInteractive])`
+    /// uses the port in `DRONE_PORT` if it is set, and otherwise looks for a drone board, only
+    /// asking which port to use when no board is found.
+    Chain(Vec<PortSelector>),
 }
 
-impl<'a, T: AsRef<str>> From<&'a T> for PortSelector<'a> {
-    fn from(s: &'a T) -> Self {
-        Self::Named(s.as_ref())
+impl<T: AsRef<str> + ?Sized> From<&T> for PortSelector {
+    fn from(s: &T) -> Self {
+        Self::Named(s.as_ref().to_string())
     }
 }
 
-pub fn all_serial_ports() -> impl Iterator<Item = String> {
-    get_serial_list()
-        .into_iter()
-        .filter(|i| i.usb_info.is_some())
-        .map(|i| i.name)
+impl From<String> for PortSelector {
+    fn from(s: String) -> Self {
+        Self::Named(s)
+    }
+}
+
+impl Display for PortSelector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AutoManufacturer => write!(f, "auto"),
+            Self::SearchFirst => write!(f, "first"),
+            Self::SearchAll => write!(f, "all"),
+            Self::ChooseInteractive => write!(f, "interactive"),
+            Self::ChooseInteractiveFiltered(filter) => write!(f, "interactive:{filter}"),
+            Self::Named(n) => write!(f, "{n}"),
+            Self::Env(var) => write!(f, "env:{var}"),
+            Self::Simulated => write!(f, "simulated"),
+            #[cfg(feature = "ftdi")]
+            Self::DeviceIndex(index) => write!(f, "index:{index}"),
+            #[cfg(feature = "ftdi")]
+            Self::BySerialNumber(serial) => write!(f, "serial:{serial}"),
+            #[cfg(feature = "ftdi")]
+            Self::ByDescription(description) => write!(f, "description:{description}"),
+            Self::Chain(selectors) => {
+                for (i, selector) in selectors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{selector}")?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
-pub fn choose_interactive() -> Result<String> {
-    internal_choose_interactive(get_serial_list())
+/// What a single [`PortSelector`] found.
+enum Found {
+    /// The ports to try, and whether to stop after the first one that fails.
+    Ports(Vec<PathBuf>, bool),
+    /// No candidates at all, and why.
+    Nothing(Report),
 }
 
-pub fn find_available_serial_port_by_id() -> Result<String> {
-    let mut ports: Vec<_> = get_serial_list()
+/// Find the ports a [`PortSelector`] wants us to try, and whether to stop after the first one that fails.
+///
+/// Ports that match one of the [excluded ports](UploadConfig::exclude_ports) are never found, but can still
+/// be named explicitly. The available ports and environment variables are looked up through
+/// `ports` and `var`, so tests can fake them. [`SIMULATE_VAR`] overrides the selector.
+pub(crate) fn select(
+    selector: &PortSelector,
+    config: &UploadConfig,
+    ports: &dyn Fn() -> Vec<SerialInfo>,
+    var: &dyn Fn(&str) -> Option<String>,
+) -> Result<(Vec<PathBuf>, bool)> {
+    if var(SIMULATE_VAR).is_some_and(|v| !v.is_empty() && v != "0") {
+        if !matches!(selector, PortSelector::Simulated) {
+            eprintln!(
+                "NOTE: {SIMULATE_VAR} is set, uploading to a simulated board instead of {selector}"
+            );
+        }
+        return Ok((vec![PathBuf::from(SIMULATED_PORT)], true));
+    }
+
+    let excluded = RefCell::new(Vec::new());
+    let ports = || {
+        let (skipped, ports): (Vec<_>, Vec<_>) = ports()
+            .into_iter()
+            .partition(|p| is_excluded(&p.name, &config.exclude_ports));
+        *excluded.borrow_mut() = skipped.into_iter().map(|p| p.name).collect();
+        ports
+    };
+
+    match find(selector, config, &ports, var)? {
+        Found::Ports(paths, stop_after_first_error) => Ok((paths, stop_after_first_error)),
+        Found::Nothing(e) if !excluded.borrow().is_empty() => Err(eyre!(
+            "{e}, after skipping the excluded ports {}",
+            excluded.borrow().join(", ")
+        )
+        .suggestion(
+            "Remove the port from UploadConfig::exclude_ports if it is the board after all",
+        )),
+        Found::Nothing(e) => Err(e),
+    }
+}
+
+fn is_excluded(name: &str, exclude: &[String]) -> bool {
+    exclude.iter().any(|pattern| {
+        if is_glob(pattern) {
+            glob_match(pattern.as_bytes(), name.as_bytes())
+        } else {
+            pattern == name
+        }
+    })
+}
+
+fn find(
+    selector: &PortSelector,
+    config: &UploadConfig,
+    ports: &dyn Fn() -> Vec<SerialInfo>,
+    var: &dyn Fn(&str) -> Option<String>,
+) -> Result<Found> {
+    Ok(match selector {
+        PortSelector::SearchFirst | PortSelector::SearchAll => {
+            let paths: Vec<_> = ports()
+                .into_iter()
+                .filter(|i| i.usb_info.is_some())
+                .map(|i| PathBuf::from(i.name))
+                .collect();
+
+            if paths.is_empty() {
+                Found::Nothing(
+                    eyre!("No usb serial port found").suggestion("Make sure the usb is plugged in"),
+                )
+            } else if matches!(selector, PortSelector::SearchFirst) {
+                if let Some(message) = first_of_several(&paths) {
+                    if config.strict_search_first {
+                        return Err(eyre!("{message}").suggestion(
+                            "Use PortSelector::AutoManufacturer to find the drone board, PortSelector::Named for a specific port, or PortSelector::ChooseInteractive to pick one",
+                        ));
+                    }
+                    eprintln!("WARNING: {message}");
+                }
+                Found::Ports(paths, true)
+            } else {
+                Found::Ports(paths, false)
+            }
+        }
+        PortSelector::ChooseInteractive => chosen(ports(), "")?,
+        PortSelector::ChooseInteractiveFiltered(filter) => chosen(ports(), filter)?,
+        PortSelector::Named(n) if is_glob(n) => glob(n, ports(), config)?,
+        PortSelector::Named(n) => Found::Ports(vec![PathBuf::from(n)], false),
+        PortSelector::Env(name) => match var(name).filter(|v| !v.is_empty()) {
+            Some(value) => find(&PortSelector::Named(value), config, ports, var)?,
+            None => Found::Nothing(eyre!("The environment variable {name} is not set")),
+        },
+        PortSelector::AutoManufacturer => by_id(ports(), config)?,
+        PortSelector::Simulated => Found::Ports(vec![PathBuf::from(SIMULATED_PORT)], true),
+        #[cfg(feature = "ftdi")]
+        PortSelector::DeviceIndex(index) => match crate::ftdi::path_of_device(*index) {
+            Ok(path) => Found::Ports(vec![path], true),
+            Err(e) => Found::Nothing(e),
+        },
+        #[cfg(feature = "ftdi")]
+        PortSelector::BySerialNumber(serial) => match crate::ftdi::path_of_serial_number(serial) {
+            Ok(path) => Found::Ports(vec![path], true),
+            Err(e) => Found::Nothing(e),
+        },
+        #[cfg(feature = "ftdi")]
+        PortSelector::ByDescription(description) => {
+            match crate::ftdi::path_of_description(description) {
+                Ok(path) => Found::Ports(vec![path], true),
+                Err(e) => Found::Nothing(e),
+            }
+        }
+        PortSelector::Chain(selectors) => {
+            let mut reasons = Vec::new();
+            for selector in selectors {
+                match find(selector, config, ports, var)? {
+                    Found::Ports(paths, stop_after_first_error) => {
+                        return Ok(Found::Ports(paths, stop_after_first_error))
+                    }
+                    Found::Nothing(e) => reasons.push(format!("\n  {selector}: {e}")),
+                }
+            }
+
+            Found::Nothing(eyre!(
+                "None of the port selectors found a serial port, tried:{}",
+                reasons.concat()
+            ))
+        }
+    })
+}
+
+/// When [`PortSelector::SearchFirst`] found several ports, which could each be the board,
+/// a message saying which one it uses.
+fn first_of_several(paths: &[PathBuf]) -> Option<String> {
+    let (chosen, others) = paths.split_first()?;
+    if others.is_empty() {
+        return None;
+    }
+
+    let others: Vec<_> = others.iter().map(|p| p.display().to_string()).collect();
+    Some(format!(
+        "found {} usb serial ports, using the first one: {}. The others are {}",
+        paths.len(),
+        chosen.display(),
+        others.join(", ")
+    ))
+}
+
+/// Let the user pick one of the ports, if there are any, initially only showing the ones matching `filter`.
+fn chosen(ports: Vec<SerialInfo>, filter: &str) -> Result<Found> {
+    if ports.is_empty() {
+        return Ok(Found::Nothing(
+            eyre!("No serial port to choose from").suggestion("Make sure the usb is plugged in"),
+        ));
+    }
+    Ok(Found::Ports(
+        vec![PathBuf::from(internal_choose_interactive(ports, filter)?)],
+        true,
+    ))
+}
+
+/// What to do when a selector finds several ports, set with [`UploadConfig::multiple_matches`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MultipleMatches {
+    /// Let the user choose one of the ports, like [`PortSelector::ChooseInteractive`] does.
+    /// When stdin is not a terminal, this fails like [`Fail`](Self::Fail).
+    #[default]
+    Choose,
+    /// Use the first of the ports, in the order the operating system lists them, with a warning.
+    First,
+    /// Fail with the list of ports that were found.
+    Fail,
+}
+
+/// The policy to use for several ports, when whether stdin is a terminal is `interactive`.
+fn multiple_matches_policy(config: &UploadConfig, interactive: bool) -> MultipleMatches {
+    match config.multiple_matches {
+        MultipleMatches::Choose if !interactive => MultipleMatches::Fail,
+        policy => policy,
+    }
+}
+
+/// Use the port if there is only one, and otherwise pick one the way the config says.
+fn single_or_chosen(mut ports: Vec<SerialInfo>, config: &UploadConfig) -> Result<Found> {
+    if ports.len() < 2 {
+        return match ports.pop() {
+            Some(port) => Ok(Found::Ports(vec![PathBuf::from(port.name)], true)),
+            None => chosen(ports, ""),
+        };
+    }
+
+    let names: Vec<_> = ports.iter().map(|p| p.name.as_str()).collect();
+    match multiple_matches_policy(config, stdin().is_terminal()) {
+        MultipleMatches::Choose => chosen(ports, ""),
+        MultipleMatches::First => {
+            eprintln!(
+                "WARNING: found {} serial ports, using the first one: {}",
+                names.len(),
+                names.join(", ")
+            );
+            Ok(Found::Ports(vec![PathBuf::from(&ports[0].name)], true))
+        }
+        MultipleMatches::Fail => Err(eyre!(
+            "found {} serial ports, and can't choose between them: {}",
+            names.len(),
+            names.join(", ")
+        )
+        .suggestion(
+            "Name one of them with PortSelector::Named, or use the first one with UploadConfig::multiple_matches(MultipleMatches::First)",
+        )),
+    }
+}
+
+fn by_id(ports: Vec<SerialInfo>, config: &UploadConfig) -> Result<Found> {
+    let ports: Vec<_> = ports
         .into_iter()
         .filter(|a| {
             if let Some(usb_info) = &a.usb_info {
-                (usb_info.vid == "403" || usb_info.vid == "0403") && usb_info.pid == "6015"
+                config.board.matches_usb_id(&usb_info.vid, &usb_info.pid)
+            } else if let Some(name) = product_name_match(a, config.usb_product_names()) {
+                eprintln!(
+                    "no usb ids known for {}, but matched it by its name {name:?}",
+                    a.name
+                );
+                true
             } else {
                 false
             }
         })
         .collect();
 
-    if ports.is_empty() {
-        Err(eyre!("No serial port to choose from").suggestion("Make sure the usb is plugged in"))
-    } else if ports.len() > 1 {
-        internal_choose_interactive(ports)
-    } else {
-        ports
-            .pop()
-            .ok_or_else(|| eyre!("Error getting serial port"))
-            .map(|info| info.name)
+    single_or_chosen(ports, config)
+}
+
+/// The product or manufacturer string of the port that contains one of `product_names`, ignoring case.
+fn product_name_match<'p>(port: &'p SerialInfo, product_names: &[String]) -> Option<&'p str> {
+    [&port.product, &port.vendor]
+        .into_iter()
+        .flatten()
+        .find(|s| {
+            let s = s.to_lowercase();
+            product_names.iter().any(|n| s.contains(&n.to_lowercase()))
+        })
+        .map(String::as_str)
+}
+
+/// Which FTDI device [`Serial::open`](crate::Serial::open) talks to for the serial port at
+/// `path`, like `/dev/ttyUSB0` or `/dev/cu.usbserial-DK0F3GQL` from
+/// [`PortSelector::SearchAll`]. The device is matched by the USB serial number of the port, and
+/// when that can't be found out, it is the only FTDI device that is connected.
+#[cfg(feature = "ftdi")]
+pub fn resolve_ftdi(path: &std::path::Path) -> Result<crate::FtdiIdentity> {
+    crate::ftdi::resolve(path)
+}
+
+/// Whether a port name should be treated as a glob pattern.
+pub fn is_glob(name: &str) -> bool {
+    name.contains(['*', '?', '['])
+}
+
+fn glob(pattern: &str, ports: Vec<SerialInfo>, config: &UploadConfig) -> Result<Found> {
+    let available: Vec<_> = ports.iter().map(|p| p.name.clone()).collect();
+    let matching = ports_matching_glob(pattern, ports);
+
+    Ok(match matching.len() {
+        0 if available.is_empty() => Found::Nothing(
+            eyre!("No serial port matches {pattern:?}")
+                .suggestion("No serial ports were found at all, make sure the usb is plugged in"),
+        ),
+        0 => Found::Nothing(eyre!(
+            "No serial port matches {pattern:?}, the available ports are: {}",
+            available.join(", ")
+        )),
+        _ => single_or_chosen(matching, config)?,
+    })
+}
+
+fn ports_matching_glob(pattern: &str, ports: Vec<SerialInfo>) -> Vec<SerialInfo> {
+    ports
+        .into_iter()
+        .filter(|p| glob_match(pattern.as_bytes(), p.name.as_bytes()))
+        .collect()
+}
+
+/// Match `name` against a glob pattern supporting `*`, `?`, and character classes like `[0-9]` or `[!a]`.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        Some((b'?', rest)) => !name.is_empty() && glob_match(rest, &name[1..]),
+        Some((b'[', rest)) => {
+            let Some((&c, name_rest)) = name.split_first() else {
+                return false;
+            };
+            let (negated, rest) = match rest.split_first() {
+                Some((b'!' | b'^', rest)) => (true, rest),
+                _ => (false, rest),
+            };
+            // a `]` right at the start is part of the class, not the end of it
+            let Some(end) = rest.iter().skip(1).position(|&b| b == b']').map(|i| i + 1) else {
+                // no closing bracket, so this is just a literal `[`
+                return c == b'[' && glob_match(&pattern[1..], name_rest);
+            };
+
+            let class = &rest[..end];
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= (class[i]..=class[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+
+            matched != negated && glob_match(&rest[end + 1..], name_rest)
+        }
+        Some((&p, rest)) => name.first() == Some(&p) && glob_match(rest, &name[1..]),
     }
 }
 
-fn internal_choose_interactive(mut ports: Vec<SerialInfo>) -> Result<String> {
+/// The ports whose name, product or manufacturer contains `filter`, ignoring case.
+/// An empty filter matches every port.
+fn filter_ports<'p>(ports: &'p [SerialInfo], filter: &str) -> Vec<&'p SerialInfo> {
+    let filter = filter.to_lowercase();
+    ports
+        .iter()
+        .filter(|p| {
+            [Some(&p.name), p.product.as_ref(), p.vendor.as_ref()]
+                .into_iter()
+                .flatten()
+                .any(|s| s.to_lowercase().contains(&filter))
+        })
+        .collect()
+}
+
+fn internal_choose_interactive(ports: Vec<SerialInfo>, filter: &str) -> Result<String> {
     if ports.is_empty() {
         return Err(
             eyre!("No serial port to choose from").suggestion("Make sure the usb is plugged in")
         );
     }
 
-    execute!(stdout(), EnterAlternateScreen, Clear(ClearType::All))?;
-    let index = loop {
-        println!("Please choose a Serial Device (by number):\n");
-        for (index, port) in ports.iter().enumerate() {
-            print!("\t{index}: {}", port.name);
+    let mut filter = filter.to_string();
+    execute!(stderr(), EnterAlternateScreen, Clear(ClearType::All))?;
+    let name = loop {
+        let shown = filter_ports(&ports, &filter);
+
+        if shown.is_empty() {
+            eprintln!(
+                "No serial port matches {filter:?}, type `f` to clear the filter or `f <filter>` to change it\n"
+            );
+        } else {
+            if !filter.is_empty() {
+                eprintln!("Showing the ports matching {filter:?}, type `f` to clear the filter\n");
+            }
+            eprintln!("Please choose a Serial Device (by number):\n");
+        }
+        for (index, port) in shown.iter().enumerate() {
+            eprint!("\t{index}: {}", port.name);
             if let Some(product) = &port.product {
-                print!(", {product}");
+                eprint!(", {product}");
             }
             if let Some(usb_info) = &port.usb_info {
-                print!(", pid: {}, vid: {}", usb_info.pid, usb_info.vid);
+                eprint!(", pid: {}, vid: {}", usb_info.pid, usb_info.vid);
+            }
+            #[cfg(feature = "ftdi")]
+            if let Some(serial) =
+                crate::ftdi::serial_number_of_port(std::path::Path::new(&port.name))
+            {
+                eprint!(", serial: {serial}");
             }
-            println!();
+            eprintln!();
         }
 
-        print!("\n >>> ");
+        eprint!("\n >>> ");
 
-        stdout().flush()?;
+        stderr().flush()?;
         let mut buf = String::new();
         stdin().read_line(&mut buf)?;
+        let input = buf.trim();
 
-        if let Ok(i) = buf.trim().parse::<usize>() {
-            if i < ports.len() {
-                break i;
+        if let Some(new_filter) = input
+            .strip_prefix('f')
+            .filter(|f| f.is_empty() || f.starts_with(' '))
+        {
+            filter = new_filter.trim().to_string();
+            execute!(stderr(), Clear(ClearType::All))?;
+            continue;
+        }
+
+        if let Ok(i) = input.parse::<usize>() {
+            if let Some(port) = shown.get(i) {
+                break port.name.clone();
             }
             execute!(
-                stdout(),
+                stderr(),
                 Clear(ClearType::All),
                 SetForegroundColor(Color::Red),
                 Print("Index out of range".to_owned()),
@@ -112,7 +544,7 @@ fn internal_choose_interactive(mut ports: Vec<SerialInfo>) -> Result<String> {
             )?;
         } else {
             execute!(
-                stdout(),
+                stderr(),
                 Clear(ClearType::All),
                 SetForegroundColor(Color::Red),
                 Print("Please enter a valid number".to_owned()),
@@ -120,30 +552,424 @@ fn internal_choose_interactive(mut ports: Vec<SerialInfo>) -> Result<String> {
             )?;
         }
 
-        println!();
+        eprintln!();
     };
 
-    execute!(stdout(), LeaveAlternateScreen)?;
-    // swap_remove is safe because we checked i < ports.len() earlier
-    // and i != 0 at the start of this function
-    Ok(ports.swap_remove(index).name)
+    execute!(stderr(), LeaveAlternateScreen)?;
+    Ok(name)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::selector::choose_interactive;
+    use std::path::PathBuf;
+
+    use serial_enumerator::{get_serial_list, SerialInfo, UsbInfo};
+
+    use crate::config::UploadConfig;
+
+    use super::{
+        filter_ports, first_of_several, glob_match, internal_choose_interactive, is_glob,
+        multiple_matches_policy, ports_matching_glob, select, MultipleMatches, PortSelector,
+        SIMULATED_PORT,
+    };
+
+    fn ports(names: &[&str]) -> Vec<SerialInfo> {
+        names
+            .iter()
+            .map(|&name| SerialInfo {
+                name: name.to_string(),
+                vendor: None,
+                product: None,
+                driver: None,
+                usb_info: None,
+            })
+            .collect()
+    }
 
-    use super::{find_available_serial_port_by_id, internal_choose_interactive};
+    fn usb(name: &str, pid: &str) -> SerialInfo {
+        SerialInfo {
+            usb_info: Some(UsbInfo {
+                vid: "0403".to_string(),
+                pid: pid.to_string(),
+            }),
+            ..ports(&[name]).remove(0)
+        }
+    }
+
+    /// Select with a fake list of ports, and only `DRONE_PORT` set in the environment.
+    fn select_with(
+        selector: PortSelector,
+        available: impl Fn() -> Vec<SerialInfo>,
+        drone_port: Option<&str>,
+    ) -> color_eyre::Result<Vec<PathBuf>> {
+        let var = |name: &str| {
+            drone_port
+                .filter(|_| name == "DRONE_PORT")
+                .map(String::from)
+        };
+        select(&selector, &UploadConfig::default(), &available, &var).map(|(paths, _)| paths)
+    }
 
     #[test]
     fn test_no_ports() {
-        assert!(internal_choose_interactive(Vec::new()).is_err());
+        assert!(internal_choose_interactive(Vec::new(), "").is_err());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let matches = |p: &str, n: &str| glob_match(p.as_bytes(), n.as_bytes());
+
+        assert!(matches("/dev/cu.usbserial-*", "/dev/cu.usbserial-DK0F3GQL"));
+        assert!(matches("/dev/cu.usbserial-*", "/dev/cu.usbserial-"));
+        assert!(!matches(
+            "/dev/cu.usbserial-*",
+            "/dev/tty.usbserial-DK0F3GQL"
+        ));
+        assert!(matches("/dev/ttyUSB?", "/dev/ttyUSB3"));
+        assert!(!matches("/dev/ttyUSB?", "/dev/ttyUSB12"));
+        assert!(matches("/dev/ttyUSB[0-2]", "/dev/ttyUSB1"));
+        assert!(!matches("/dev/ttyUSB[0-2]", "/dev/ttyUSB3"));
+        assert!(matches("/dev/ttyUSB[!0]", "/dev/ttyUSB3"));
+        assert!(!matches("/dev/ttyUSB[!0]", "/dev/ttyUSB0"));
+        assert!(matches("*[", "COM["));
+        assert!(matches("COM*", "COM3"));
+
+        assert!(is_glob("/dev/cu.*"));
+        assert!(!is_glob("/dev/ttyUSB0"));
+    }
+
+    #[test]
+    fn test_find_port_by_glob() {
+        let available = [
+            "/dev/cu.Bluetooth",
+            "/dev/cu.usbserial-A1",
+            "/dev/tty.usbserial-A1",
+        ];
+
+        assert_eq!(
+            select_with(
+                PortSelector::Named("/dev/cu.usbserial-*".into()),
+                || ports(&available),
+                None
+            )
+            .unwrap(),
+            [PathBuf::from("/dev/cu.usbserial-A1")]
+        );
+
+        let err = select_with(
+            PortSelector::Named("/dev/ttyUSB*".into()),
+            || ports(&available),
+            None,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("/dev/cu.Bluetooth, /dev/cu.usbserial-A1"));
+
+        let matching = ports_matching_glob("/dev/*.usbserial-*", ports(&available));
+        assert_eq!(matching.len(), 2);
+    }
+
+    #[test]
+    fn test_several_matches_without_choosing() {
+        let available = || ports(&["/dev/cu.usbserial-A1", "/dev/cu.usbserial-B2"]);
+        let glob = PortSelector::Named("/dev/cu.usbserial-*".into());
+        let no_var = |_: &str| None;
+
+        let first = UploadConfig::default().multiple_matches(MultipleMatches::First);
+        assert_eq!(
+            select(&glob, &first, &available, &no_var).unwrap(),
+            (vec![PathBuf::from("/dev/cu.usbserial-A1")], true)
+        );
+
+        let fail = UploadConfig::default().multiple_matches(MultipleMatches::Fail);
+        let err = select(&glob, &fail, &available, &no_var).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "found 2 serial ports, and can't choose between them: /dev/cu.usbserial-A1, /dev/cu.usbserial-B2"
+        );
+        // the same for several drone boards
+        let boards = || vec![usb("/dev/ttyUSB0", "6015"), usb("/dev/ttyUSB1", "6015")];
+        let auto = PortSelector::AutoManufacturer;
+        assert!(select(&auto, &fail, &boards, &no_var).is_err());
+        assert_eq!(
+            select(&auto, &first, &boards, &no_var).unwrap().0,
+            [PathBuf::from("/dev/ttyUSB0")]
+        );
+
+        // choosing needs someone at a terminal to choose
+        let choose = UploadConfig::default();
+        assert_eq!(
+            multiple_matches_policy(&choose, true),
+            MultipleMatches::Choose
+        );
+        assert_eq!(
+            multiple_matches_policy(&choose, false),
+            MultipleMatches::Fail
+        );
+        assert_eq!(
+            multiple_matches_policy(&first, false),
+            MultipleMatches::First
+        );
+    }
+
+    #[test]
+    fn test_chain_falls_through() {
+        let stored = PortSelector::Chain(vec![
+            PortSelector::Env("DRONE_PORT".into()),
+            PortSelector::AutoManufacturer,
+            PortSelector::ChooseInteractive,
+        ]);
+        let chain = || stored.clone();
+        // some other FTDI chip, that isn't on a drone board
+        let others = || vec![usb("/dev/ttyUSB3", "6001")];
+        let with_drone = || vec![usb("/dev/ttyUSB3", "6001"), usb("/dev/ttyUSB0", "6015")];
+
+        // the environment variable wins, even when a board is connected
+        assert_eq!(
+            select_with(chain(), with_drone, Some("/dev/ttyUSB7")).unwrap(),
+            [PathBuf::from("/dev/ttyUSB7")]
+        );
+        // unset or empty, so auto detection is next
+        for drone_port in [None, Some("")] {
+            assert_eq!(
+                select_with(chain(), with_drone, drone_port).unwrap(),
+                [PathBuf::from("/dev/ttyUSB0")]
+            );
+        }
+        // the variable may be a glob too, which finds nothing here
+        assert_eq!(
+            select_with(chain(), with_drone, Some("/dev/cu.*")).unwrap(),
+            [PathBuf::from("/dev/ttyUSB0")]
+        );
+
+        let err = select_with(chain(), Vec::new, None).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("env:DRONE_PORT: The environment variable DRONE_PORT is not set"));
+        assert!(message.contains("auto: No serial port to choose from"));
+        assert!(message.contains("interactive: No serial port to choose from"));
+
+        assert!(select_with(PortSelector::Chain(Vec::new()), others, None).is_err());
+        assert_eq!(
+            select_with(
+                PortSelector::Chain(vec![
+                    PortSelector::AutoManufacturer,
+                    PortSelector::SearchAll
+                ]),
+                others,
+                None
+            )
+            .unwrap(),
+            [PathBuf::from("/dev/ttyUSB3")]
+        );
+
+        // owned, so a chain can be kept around and handed to another thread
+        let handle = std::thread::spawn(move || stored.to_string());
+        assert_eq!(handle.join().unwrap(), "env:DRONE_PORT,auto,interactive");
+    }
+
+    #[test]
+    fn test_simulated() {
+        let drone = || vec![usb("/dev/ttyUSB0", "6015")];
+        let simulated = [PathBuf::from(SIMULATED_PORT)];
+        assert_eq!(
+            select_with(PortSelector::Simulated, drone, None).unwrap(),
+            simulated
+        );
+
+        // the environment variable overrides what the program asks for
+        let select_simulating = |value: &str| {
+            let var = |name: &str| (name == "TUDELFT_UPLOAD_SIMULATE").then(|| value.to_string());
+            select(
+                &PortSelector::AutoManufacturer,
+                &UploadConfig::default(),
+                &drone,
+                &var,
+            )
+            .unwrap()
+            .0
+        };
+        assert_eq!(select_simulating("1"), simulated);
+        assert_eq!(select_simulating("0"), [PathBuf::from("/dev/ttyUSB0")]);
+        assert_eq!(select_simulating(""), [PathBuf::from("/dev/ttyUSB0")]);
+    }
+
+    #[test]
+    fn test_exclude_ports() {
+        let config = UploadConfig::default().exclude_ports(vec![
+            "/dev/cu.debug-*".to_string(),
+            "/dev/ttyUSB1".to_string(),
+        ]);
+        let available = || {
+            vec![
+                usb("/dev/cu.debug-console", "6015"),
+                usb("/dev/ttyUSB0", "6015"),
+                usb("/dev/ttyUSB1", "6015"),
+            ]
+        };
+        let select_excluding = |selector: PortSelector| {
+            select(&selector, &config, &available, &|_| None).map(|(paths, _)| paths)
+        };
+
+        for selector in [
+            PortSelector::AutoManufacturer,
+            PortSelector::SearchFirst,
+            PortSelector::SearchAll,
+            PortSelector::Named("/dev/*".into()),
+            PortSelector::Chain(vec![
+                PortSelector::Env("DRONE_PORT".into()),
+                PortSelector::SearchAll,
+            ]),
+        ] {
+            assert_eq!(
+                select_excluding(selector).unwrap(),
+                [PathBuf::from("/dev/ttyUSB0")]
+            );
+        }
+
+        // naming it explicitly still works
+        assert_eq!(
+            select_excluding(PortSelector::Named("/dev/ttyUSB1".into())).unwrap(),
+            [PathBuf::from("/dev/ttyUSB1")]
+        );
+
+        for selector in [
+            PortSelector::Named("/dev/cu.*".into()),
+            PortSelector::ChooseInteractive,
+        ] {
+            let only_debug = || vec![usb("/dev/cu.debug-console", "6015")];
+            let err = select(&selector, &config, &only_debug, &|_| None).unwrap_err();
+            assert!(err
+                .to_string()
+                .contains("after skipping the excluded ports /dev/cu.debug-console"));
+        }
+    }
+
+    #[test]
+    fn test_search_first_among_several() {
+        let candidates = |n: usize| {
+            move || {
+                (0..n)
+                    .map(|i| usb(&format!("/dev/ttyUSB{i}"), "6001"))
+                    .collect()
+            }
+        };
+        let strict = UploadConfig::default().strict_search_first(true);
+
+        // a single port is used without any fuss, also when strict
+        let paths = select_with(PortSelector::SearchFirst, candidates(1), None).unwrap();
+        assert_eq!(paths, [PathBuf::from("/dev/ttyUSB0")]);
+        assert_eq!(first_of_several(&paths), None);
+        let found = select(&PortSelector::SearchFirst, &strict, &candidates(1), &|_| {
+            None
+        });
+        assert!(found.is_ok());
+
+        let paths = select_with(PortSelector::SearchFirst, candidates(2), None).unwrap();
+        assert_eq!(
+            first_of_several(&paths).unwrap(),
+            "found 2 usb serial ports, using the first one: /dev/ttyUSB0. The others are /dev/ttyUSB1"
+        );
+
+        let paths = select_with(PortSelector::SearchFirst, candidates(5), None).unwrap();
+        assert_eq!(
+            first_of_several(&paths).unwrap(),
+            "found 5 usb serial ports, using the first one: /dev/ttyUSB0. The others are \
+             /dev/ttyUSB1, /dev/ttyUSB2, /dev/ttyUSB3, /dev/ttyUSB4"
+        );
+
+        for n in [2, 5] {
+            let err = select(&PortSelector::SearchFirst, &strict, &candidates(n), &|_| {
+                None
+            })
+            .unwrap_err();
+            assert!(err
+                .to_string()
+                .starts_with(&format!("found {n} usb serial ports")));
+        }
+        // only SearchFirst picks silently, SearchAll tries them all anyway
+        assert!(select(&PortSelector::SearchAll, &strict, &candidates(5), &|_| None).is_ok());
+    }
+
+    #[test]
+    fn test_filter_ports() {
+        let available = vec![
+            SerialInfo {
+                product: Some("FT231X USB UART".to_string()),
+                vendor: Some("FTDI".to_string()),
+                ..usb("/dev/ttyUSB0", "6015")
+            },
+            SerialInfo {
+                vendor: Some("Dell".to_string()),
+                ..ports(&["/dev/ttyACM0"]).remove(0)
+            },
+            ports(&["/dev/cu.Bluetooth-Incoming-Port"]).remove(0),
+        ];
+        let names = |filter: &str| -> Vec<String> {
+            filter_ports(&available, filter)
+                .into_iter()
+                .map(|p| p.name.clone())
+                .collect()
+        };
+
+        assert_eq!(names("").len(), 3);
+        assert_eq!(names("uart"), ["/dev/ttyUSB0"]);
+        assert_eq!(names("ftdi"), ["/dev/ttyUSB0"]);
+        assert_eq!(names("DELL"), ["/dev/ttyACM0"]);
+        assert_eq!(names("/dev/tty"), ["/dev/ttyUSB0", "/dev/ttyACM0"]);
+        assert!(names("arduino").is_empty());
+    }
+
+    #[test]
+    fn test_match_by_product_name() {
+        let named = |name: &str, product: &str, vendor: Option<&str>| SerialInfo {
+            product: Some(product.to_string()),
+            vendor: vendor.map(String::from),
+            ..ports(&[name]).remove(0)
+        };
+
+        // no usb info at all, so only the product name can tell
+        let available = || {
+            vec![
+                named("/dev/ttyS0", "16550A", None),
+                named("/dev/ttyUSB0", "FT231X USB UART", Some("FTDI")),
+            ]
+        };
+        assert_eq!(
+            select_with(PortSelector::AutoManufacturer, available, None).unwrap(),
+            [PathBuf::from("/dev/ttyUSB0")]
+        );
+
+        // the usb ids are known and say this isn't the drone, whatever it's called
+        let other_chip = || {
+            vec![SerialInfo {
+                product: Some("FT231X USB UART".to_string()),
+                ..usb("/dev/ttyUSB0", "6001")
+            }]
+        };
+        assert!(select_with(PortSelector::AutoManufacturer, other_chip, None).is_err());
+
+        let config = UploadConfig::default().product_names(vec!["drone uart".to_string()]);
+        let custom = || vec![named("/dev/ttyACM0", "Custom", Some("Drone UART Inc."))];
+        let found = select(&PortSelector::AutoManufacturer, &config, &custom, &|_| None);
+        assert_eq!(found.unwrap().0, [PathBuf::from("/dev/ttyACM0")]);
+        assert!(select_with(PortSelector::AutoManufacturer, custom, None).is_err());
     }
 
     #[test]
     #[ignore]
     fn test_find_serial_port_by_manufacturer() {
-        assert_eq!(find_available_serial_port_by_id().unwrap(), "/dev/ttyUSB0");
+        assert_eq!(
+            select(
+                &PortSelector::AutoManufacturer,
+                &UploadConfig::default(),
+                &get_serial_list,
+                &|_| None
+            )
+            .unwrap()
+            .0,
+            [PathBuf::from("/dev/ttyUSB0")]
+        );
     }
 
     #[test]
@@ -151,6 +977,9 @@ mod tests {
     fn test_choose_interactive() {
         // To run this test, please do:
         // cargo test --package tudelft-serial-upload --lib -- selector::tests::test_choose_interactive --exact --nocapture --ignored
-        assert_eq!(choose_interactive().unwrap(), "/dev/ttyUSB0");
+        assert_eq!(
+            internal_choose_interactive(get_serial_list(), "").unwrap(),
+            "/dev/ttyUSB0"
+        );
     }
 }
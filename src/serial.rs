@@ -1,58 +1,594 @@
-use color_eyre::eyre::{bail, WrapErr};
-use libftd2xx::{BitsPerWord, Ftdi, FtdiCommon, Parity, StopBits};
-use std::io::{stdout, Write};
+use color_eyre::eyre::{bail, eyre, Report, WrapErr};
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::hash::Hasher;
+use std::io::{stderr, Write};
 use std::path::PathBuf;
-use std::sync::mpsc::channel;
-use std::thread::{sleep, spawn};
-use std::time::Duration;
-
+use std::sync::mpsc::sync_channel;
+use std::sync::Arc;
+use std::thread::scope;
+use std::time::{Duration, Instant};
 
+use crate::bench::{test_image, ThroughputStats};
+use crate::board::{check_baud_rate, DEFAULT_BAUD_RATE};
+use crate::clock::{Clock, SystemClock};
+use crate::config::{
+    UploadConfig, DEFAULT_MAX_RETRIES, DEFAULT_OPEN_ATTEMPTS, DEFAULT_PACKET_DELAY,
+    DEFAULT_PACKET_SIZE, DEFAULT_RETRY_BACKOFF, DEFAULT_RETRY_BACKOFF_FACTOR, MAX_WINDOW_SIZE,
+};
 use crate::crc::calc_crc16_default;
+use crate::dfu::{
+    activate_payload, data_payload, stop_payload, DfuSession, IntegrityCheck, DFU_DATA_PACKET,
+    DFU_INIT_PACKET, DFU_STOP_DATA_PACKET,
+};
+use crate::hci::{parse_dfu_response, AckFrame, DfuResult, Nacked, Packet, Received, Rejected};
+use crate::image::{short_hash, PreparedImage};
+use crate::progress::ProgressEvent;
+use crate::recording::{Recorded, Recording};
+use crate::report::{Phase, PhaseTimer, UploadReport};
+use crate::slip::{
+    escape, frame_into, unescape, unescape_into, Decoded, SlipDecoder, END, ESC, ESC_END, ESC_ESC,
+};
+use crate::trace::{Direction, FrameLog};
+use crate::transport::{
+    open_port, open_with_retries, open_with_timeout, ControlLine, DeadlineTransport, LineSettings,
+    Transport,
+};
 use crate::SERIAL_TIMEOUT;
-use color_eyre::Result;
+use color_eyre::{Help, Result};
+#[cfg(feature = "ftdi")]
+use libftd2xx::Ftdi;
+use serial2::FlowControl;
+
+const BANNER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long the bootloader takes to start listening after a reset. It doesn't announce itself.
+const BOOTLOADER_START_TIME: Duration = Duration::from_millis(100);
+/// How long to wait for the first ack when the board may still be busy, see [`Serial::send_data_when_ready`].
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// The shortest read timeout that is set to end a wait at the deadline of an upload, a zero
+/// timeout doesn't mean the same to every driver.
+const MIN_READ_TIMEOUT: Duration = Duration::from_millis(1);
+/// How long the bootloader gets to answer a ping, see [`Serial::ping`].
+const PING_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long the bootloader gets to report the CRC of the image, see [`Serial::verify_image`].
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long the bootloader gets to acknowledge the activate packet, see [`Serial::send_activate_and_reset`].
+const ACTIVATE_TIMEOUT: Duration = Duration::from_millis(200);
+/// How many bytes are read from the port at once, at most.
+const RX_CHUNK_SIZE: usize = 64;
+/// How long to wait before reading again after a read returned nothing.
+const EMPTY_READ_BACKOFF: Duration = Duration::from_millis(10);
+/// After this many bytes outside of frames, we warn that the board seems to be printing things.
+const NOISE_WARNING_THRESHOLD: usize = 32;
+const NOISE_SAMPLE_SIZE: usize = 64;
+/// How many bytes can arrive without a frame that decodes before the line is taken to be
+/// garbage, far more than the largest frame the bootloader sends.
+const MAX_GARBAGE: usize = 4096;
+/// How many of those are shown in the error.
+const GARBAGE_SAMPLE_SIZE: usize = 16;
+/// After this many acks in a row for the same packet we didn't send, we go on with the sequence
+/// number the board expects.
+const SEQUENCE_RESYNC_AFTER: usize = 2;
+
+const START_ERROR_HINT: &str = "the bootloader didn't accept the start packet. If an earlier upload was interrupted, it may still be waiting for the rest of that one: run `tudelft-upload abort` (or `abort_dfu`) and reset the board";
+const ACK_ERROR_HINT: &str = "waiting for message acknowledgement. If this is due to a timeout, try resetting your board, or turning it off and on again";
+
+/// The upload was stopped with the [cancel flag](UploadConfig::cancel_flag).
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("the upload was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// The upload didn't finish before the [deadline](UploadConfig::deadline).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineExceeded {
+    /// How many data packets the bootloader acknowledged by then.
+    pub chunks_sent: usize,
+    /// How many data packets the image is sent in.
+    pub total_chunks: usize,
+}
+
+impl Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the upload didn't finish before its deadline, after {}/{} data packets",
+            self.chunks_sent, self.total_chunks
+        )
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// We received a frame we sent ourselves, so the port is looped back instead of connected to a board.
+#[derive(Debug)]
+struct Echoed;
+
+impl Display for Echoed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "the data we sent is being echoed back: check the wiring of the serial adapter (TX may be connected to RX), and that you selected the right port")
+    }
+}
+
+impl std::error::Error for Echoed {}
+
+/// No complete frame arrived from the board within this read timeout.
+#[derive(Debug)]
+struct TimedOut(Duration);
+
+impl Display for TimedOut {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "timed out after {:.1}s waiting for a response from the board",
+            self.0.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// We received a lot of bytes without a frame in them, starting with these. A wrong baud rate
+/// turns everything into such garbage, and so does a board that is running an application
+/// which prints a lot.
+#[derive(Debug)]
+struct Garbage {
+    len: usize,
+    start: Vec<u8>,
+}
+
+impl Display for Garbage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "received {} bytes of continuous garbage, starting with",
+            self.len
+        )?;
+        for b in &self.start {
+            write!(f, " {b:02x}")?;
+        }
+        write!(
+            f,
+            ": check that the board is in bootloader mode and that the baud rate matches"
+        )
+    }
+}
+
+impl std::error::Error for Garbage {}
+
+/// The board keeps acknowledging a packet we didn't send, so it counts sequence numbers from
+/// another starting point than we do, like after an earlier upload was interrupted.
+#[derive(Debug)]
+struct OutOfSync {
+    ack: u8,
+}
+
+impl Display for OutOfSync {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the board keeps waiting for the packet with sequence number {}, which isn't the next one we send",
+            self.ack
+        )
+    }
+}
+
+impl std::error::Error for OutOfSync {}
+
+/// Whether `e` is about what is on the other end of the port, which sending the packet again
+/// won't change.
+fn is_line_problem(e: &Report) -> bool {
+    e.is::<Echoed>() || e.is::<Garbage>()
+}
+
+/// Add a hint about what could be wrong to `e`, unless it already says exactly what is wrong.
+fn with_hint(e: Report, hint: &'static str) -> Report {
+    if is_line_problem(&e) || e.is::<Rejected>() {
+        e
+    } else {
+        e.wrap_err(hint)
+    }
+}
+
+/// Hash of a frame as it went over the wire, escaped and without the ENDs around it, so the
+/// frames we send can be hashed as they are written.
+fn escaped_frame_hash(escaped: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    escaped.iter().for_each(|&b| hasher.write_u8(b));
+    hasher.finish()
+}
 
-const DFU_INIT_PACKET: u32 = 1;
-const DFU_START_PACKET: u32 = 3;
-const DFU_DATA_PACKET: u32 = 4;
-const DFU_STOP_DATA_PACKET: u32 = 5;
-const DFU_MAX_PACKET_SIZE: usize = 512;
-const SEND_START_DFU_WAIT_TIME: Duration = Duration::from_secs(2);
-const SEND_INIT_PACKET_WAIT_TIME: Duration = Duration::from_secs(1);
+/// [`escaped_frame_hash`] of an unescaped frame that was received, escaping it on the way.
+fn received_frame_hash(unescaped: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for &b in unescaped {
+        match b {
+            END => [ESC, ESC_END].iter().for_each(|&b| hasher.write_u8(b)),
+            ESC => [ESC, ESC_ESC].iter().for_each(|&b| hasher.write_u8(b)),
+            b => hasher.write_u8(b),
+        }
+    }
+    hasher.finish()
+}
 
+/// A connection to the bootloader on a drone board.
+///
+/// A `Serial` is [`Send`], so it can be moved to a worker thread to upload from there, while
+/// for example a user interface keeps running. Progress can be followed from the other thread
+/// with the sink of [`UploadConfig::trace`], and the upload stopped with
+/// [`UploadConfig::cancel_flag`].
 pub struct Serial {
-    port: Ftdi,
+    port: Recorded,
     pub(crate) path: PathBuf,
     sequence_number: u8,
+    clock: Arc<dyn Clock>,
+    /// Bytes that were read from the port, but not looked at yet.
+    rx_buffer: VecDeque<u8>,
+    decoder: SlipDecoder,
+    /// Number of received bytes that weren't part of any frame.
+    discarded_bytes: usize,
+    /// The first few of those, to show in the warning about them.
+    noise_sample: Vec<u8>,
+    /// Number of bytes that were received since the last frame that decoded.
+    garbage: usize,
+    /// The first few of those, to show in the error when there are too many.
+    garbage_sample: Vec<u8>,
+    /// Hashes of the last frames we sent, as they were written, to notice them coming back.
+    recent_frames: VecDeque<u64>,
+    /// A shorter timeout for the start packet, used while searching for the right port.
+    pub(crate) handshake_timeout: Option<Duration>,
+    /// When the upload has to be done, see [`UploadConfig::deadline`]. Waits for an ack end
+    /// there at the latest.
+    pub(crate) deadline: Option<Instant>,
+    /// How many of how many data packets were acknowledged, for [`DeadlineExceeded`].
+    data_progress: (usize, usize),
+    /// How long to wait for a frame from the board.
+    read_timeout: Duration,
+    write_timeout: Duration,
+    /// The read timeout set with [`set_timeouts`](Self::set_timeouts), unlike `read_timeout` not
+    /// changed for a while by [`with_read_timeout`](Self::with_read_timeout). Waiting this long
+    /// for an ack prints a warning.
+    ack_warning_after: Duration,
+    /// Whether that warning was printed already.
+    warned_about_timeout: bool,
+    /// How long to wait after writing a packet before waiting for its ack.
+    packet_delay: Duration,
+    /// How long to wait before sending a packet again the first time, and how much longer
+    /// every further time.
+    retry_backoff: (Duration, u32),
+    /// How often [`send_data`](Self::send_data) sends a packet again when it isn't acknowledged,
+    /// [`DEFAULT_MAX_RETRIES`] until an upload sets it from its config.
+    max_retries: usize,
+    /// How often it had to.
+    retries: usize,
+    /// For the packets that aren't data packets, those get an encoder of their own.
+    encoder: FrameEncoder,
+    /// The last frames that were sent and received, for the error when the upload fails.
+    frame_log: FrameLog,
+    /// Whether the port was closed with [`close`](Self::close), or taken out with
+    /// [`into_inner`](Self::into_inner), so that dropping it leaves the port alone.
+    closed: bool,
+}
+
+/// Encodes frames, reusing the buffer for the unescaped frame from one frame to the next.
+#[derive(Default)]
+struct FrameEncoder {
+    unescaped: Vec<u8>,
+}
+
+impl FrameEncoder {
+    /// Encode a frame with `parts` one after the other as its payload, into `out`, which is
+    /// cleared first.
+    fn encode_into(&mut self, seq_nr: u8, parts: &[&[u8]], out: &mut Vec<u8>) {
+        let len = parts.iter().map(|p| p.len()).sum();
+        self.unescaped.clear();
+        frame_into(seq_nr, parts, &mut self.unescaped);
+
+        out.clear();
+        out.reserve(max_frame_size(len));
+        escape(&self.unescaped, out);
+    }
+
+    /// Like [`encode_into`](Self::encode_into), into a new buffer that is allocated only once.
+    fn encode(&mut self, seq_nr: u8, parts: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(seq_nr, parts, &mut out);
+        out
+    }
+}
+
+/// The most bytes a frame with `payload_len` bytes of payload takes: with every byte of the
+/// header, the payload and the CRC escaped, and an END on either side.
+fn max_frame_size(payload_len: usize) -> usize {
+    2 * (4 + payload_len + 2) + 2
+}
+
+/// With [`UploadConfig::adaptive_packet_size`], how often packets may have to be sent again
+/// before the packets are made smaller.
+const SHRINK_AFTER_RETRIES: usize = 2;
+/// How many packets in a row have to get through the first time before they are made bigger.
+const GROW_AFTER_CLEAN_PACKETS: usize = 32;
+/// How small the packets can get.
+const MIN_ADAPTIVE_PACKET_SIZE: usize = 64;
+
+/// Picks the size of the next data packet from how the ones before got through: half as big
+/// after a few had to be sent again, and twice as big after a streak that didn't, never
+/// bigger than the configured size (which fits the length field of the header) and a multiple
+/// of 4 bytes like the words the flash is written in.
+struct PacketSizer {
+    max: usize,
+    size: usize,
+    /// Packets sent again since the size changed last.
+    retries: usize,
+    /// Packets in a row that got through the first time.
+    clean: usize,
+}
+
+impl PacketSizer {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            size: max,
+            retries: 0,
+            clean: 0,
+        }
+    }
+
+    /// Count a packet that had to be sent again `retries` times, and return the new size when
+    /// that changes it.
+    fn after_packet(&mut self, retries: usize) -> Option<usize> {
+        let size = if retries == 0 {
+            self.clean += 1;
+            if self.clean < GROW_AFTER_CLEAN_PACKETS || self.size == self.max {
+                return None;
+            }
+            (self.size * 2).min(self.max)
+        } else {
+            self.clean = 0;
+            self.retries += retries;
+            if self.retries < SHRINK_AFTER_RETRIES {
+                return None;
+            }
+            (self.size / 2 / 4 * 4).max(MIN_ADAPTIVE_PACKET_SIZE)
+        };
+
+        self.retries = 0;
+        self.clean = 0;
+        if size >= self.size && retries > 0 {
+            // as small as they get
+            return None;
+        }
+        self.size = size;
+        Some(size)
+    }
+}
+
+/// A data packet that was sent while pipelining, but not acknowledged yet.
+struct InFlight {
+    expected_ack: u8,
+    packet: Vec<u8>,
 }
 
 impl Serial {
     pub fn open(path: PathBuf) -> Result<Self> {
-        // let mut port = SerialPort::open(&path, |mut s: Settings| {
-        //     s.set_raw();
-        //     s.set_baud_rate(921_600)?;
-        //     s.set_flow_control(FlowControl::RtsCts);
-        //     Ok(s)
-        // })
-        // .wrap_err("failed to open serial port")?;
-        //
-        // port.set_read_timeout(SERIAL_TIMEOUT).wrap_err("failed to set read timeout")?;
-        // port.set_write_timeout(SERIAL_TIMEOUT)
-        //     .wrap_err("failed to set write timeout")?;
-        //
-        // port.discard_buffers().wrap_err("flush")?;
-
-        let mut port = Ftdi::new()?;
-        port.set_data_characteristics(BitsPerWord::Bits8, StopBits::Bits1, Parity::No)?;
-        port.set_baud_rate(921_600)?;
-        port.set_flow_control_rts_cts()?;
-        port.set_timeouts(SERIAL_TIMEOUT, SERIAL_TIMEOUT)?;
-        port.purge_all()?;
-
-         Ok(Self {
-            port,
+        Self::open_with_baud_rate(path, DEFAULT_BAUD_RATE)
+    }
+
+    /// Open the port at `baud_rate`. While it is busy, opening it is tried as often as
+    /// [`UploadConfig::open_attempts`] does by default.
+    pub fn open_with_baud_rate(path: PathBuf, baud_rate: u32) -> Result<Self> {
+        check_baud_rate(baud_rate)?;
+        let port = open_with_retries(&path, DEFAULT_OPEN_ATTEMPTS, &SystemClock, || {
+            open_port(&path, baud_rate, LineSettings::default())
+        })?;
+        Ok(Self::with_transport(path, port, Arc::new(SystemClock)))
+    }
+
+    /// Open the FTDI device at `index` in the list of the D2XX driver, which doesn't depend on
+    /// the names the operating system gives the ports.
+    #[cfg(feature = "ftdi")]
+    pub fn open_by_index(index: usize) -> Result<Self> {
+        Self::open(crate::ftdi::path_of_device(index)?)
+    }
+
+    /// Open the FTDI device with this serial number, which is the same whichever USB port the
+    /// board is plugged into.
+    #[cfg(feature = "ftdi")]
+    pub fn open_by_serial_number(serial_number: &str) -> Result<Self> {
+        Self::open(crate::ftdi::path_of_serial_number(serial_number)?)
+    }
+
+    /// Open the FTDI device whose EEPROM description is `description`, or else the only one whose
+    /// description contains it, see [`PortSelector::ByDescription`](crate::PortSelector::ByDescription).
+    #[cfg(feature = "ftdi")]
+    pub fn open_by_description(description: &str) -> Result<Self> {
+        Self::open(crate::ftdi::path_of_description(description)?)
+    }
+
+    /// Open the port with other line settings than 8N1, for bootloaders that were built with those.
+    pub fn open_with(path: PathBuf, settings: LineSettings) -> Result<Self> {
+        let port = open_with_retries(&path, DEFAULT_OPEN_ATTEMPTS, &SystemClock, || {
+            open_port(&path, DEFAULT_BAUD_RATE, settings)
+        })?;
+        Ok(Self::with_transport(path, port, Arc::new(SystemClock)))
+    }
+
+    /// Open the port at the baud rate and with the line settings of `config`, and give up on an
+    /// attempt when that takes longer than its open timeout. Busy ports get the open attempts
+    /// of the config.
+    pub(crate) fn open_with_config(path: PathBuf, config: &UploadConfig) -> Result<Self> {
+        let (baud_rate, line) = (config.baud(), config.line_settings);
+        check_baud_rate(baud_rate)?;
+        let port = open_with_retries(&path, config.open_attempts, &SystemClock, || {
+            let opened = path.clone();
+            open_with_timeout(&path, config.open_timeout, move || {
+                open_port(&opened, baud_rate, line)
+            })
+        })?;
+        Ok(Self::with_transport(path, port, Arc::new(SystemClock)))
+    }
+
+    /// Speak the protocol over any [`Transport`], instead of the FTDI chip on the board. It has
+    /// to be [`Send`], like the `Serial` itself.
+    #[cfg(feature = "protocol")]
+    pub fn from_transport(path: PathBuf, port: Box<dyn Transport + Send>) -> Self {
+        Self::with_transport(path, port, Arc::new(SystemClock))
+    }
+
+    pub(crate) fn with_transport(
+        path: PathBuf,
+        port: Box<dyn Transport + Send>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            port: Recorded::new(port),
             path,
             sequence_number: 0,
-        })
+            clock,
+            rx_buffer: VecDeque::new(),
+            decoder: SlipDecoder::new(),
+            discarded_bytes: 0,
+            noise_sample: Vec::new(),
+            garbage: 0,
+            garbage_sample: Vec::new(),
+            recent_frames: VecDeque::new(),
+            handshake_timeout: None,
+            deadline: None,
+            data_progress: (0, 0),
+            read_timeout: SERIAL_TIMEOUT,
+            write_timeout: SERIAL_TIMEOUT,
+            ack_warning_after: SERIAL_TIMEOUT,
+            warned_about_timeout: false,
+            packet_delay: DEFAULT_PACKET_DELAY,
+            retry_backoff: (DEFAULT_RETRY_BACKOFF, DEFAULT_RETRY_BACKOFF_FACTOR),
+            max_retries: DEFAULT_MAX_RETRIES,
+            retries: 0,
+            encoder: FrameEncoder::default(),
+            frame_log: FrameLog::default(),
+            closed: false,
+        }
+    }
+
+    /// Take over the connection to the board, for example to talk to the uploaded program with
+    /// a framing of your own. The sequence number of the DFU protocol is lost, as well as
+    /// anything that was received but not [read](Self::read) yet.
+    pub fn into_inner(mut self) -> Box<dyn Transport + Send> {
+        self.closed = true;
+        std::mem::replace(&mut self.port.inner, Box::new(Taken))
+    }
+
+    /// Like [`into_inner`](Self::into_inner), for the D2XX handle of the FTDI chip, to change
+    /// its baud rate for example. Gives the `Serial` back when it doesn't talk to the board
+    /// through D2XX, like when it was created with a transport of your own.
+    #[cfg(feature = "ftdi")]
+    pub fn into_ftdi(mut self) -> Result<Ftdi, Box<Self>> {
+        if self.port.as_ftdi_mut().is_none() {
+            return Err(Box::new(self));
+        }
+        self.closed = true;
+        Ok(std::mem::replace(&mut self.port.inner, Box::new(Taken))
+            .into_ftdi()
+            .expect("the transport is a D2XX handle"))
+    }
+
+    /// Leave the port in a defined state for whatever opens it next, and close it: everything
+    /// that is still buffered is thrown away and both control lines are released. Dropping a
+    /// `Serial` does the same, without reporting what failed. The macOS driver in particular
+    /// can keep a device busy that wasn't left like this.
+    pub fn close(mut self) -> Result<()> {
+        self.shut_down()
+    }
+
+    fn shut_down(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+
+        let purged = self
+            .port
+            .clear_input()
+            .and_then(|_| self.port.clear_output())
+            .wrap_err("failed to purge the buffers of the serial port");
+        for line in [ControlLine::Dtr, ControlLine::Rts] {
+            // transports without control lines have nothing to release
+            let _ = self.port.set_control_line(line, false);
+        }
+        // the port itself is closed when it is dropped right after
+        purged
+    }
+
+    /// The D2XX handle of the FTDI chip, when the board is connected through D2XX. Anything
+    /// done with it directly bypasses the DFU protocol state of this `Serial`.
+    #[cfg(feature = "ftdi")]
+    pub fn ftdi_mut(&mut self) -> Option<&mut Ftdi> {
+        self.port.as_ftdi_mut()
+    }
+
+    /// The serial number of the USB serial adapter, if it can be read.
+    pub fn adapter_serial(&mut self) -> Option<String> {
+        self.port.serial_number()
+    }
+
+    /// Change how long reads and writes wait for the board before they time out.
+    /// They start out at 5 seconds. The warning that the board doesn't acknowledge anything is
+    /// printed after the read timeout too.
+    pub fn set_timeouts(&mut self, read: Duration, write: Duration) -> Result<()> {
+        self.apply_timeouts(read, write)?;
+        self.ack_warning_after = read;
+        Ok(())
+    }
+
+    fn apply_timeouts(&mut self, read: Duration, write: Duration) -> Result<()> {
+        self.port
+            .set_timeouts(read, write)
+            .wrap_err("failed to set the serial port timeouts")?;
+        self.read_timeout = read;
+        self.write_timeout = write;
+        Ok(())
+    }
+
+    /// Run `f` with a different read timeout, and restore the previous one afterwards,
+    /// also when `f` fails.
+    pub(crate) fn with_read_timeout<T>(
+        &mut self,
+        timeout: Duration,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let previous = self.read_timeout;
+        self.apply_timeouts(timeout, self.write_timeout)?;
+        let res = f(self);
+        let restored = self.apply_timeouts(previous, self.write_timeout);
+        res.and_then(|v| restored.map(|()| v))
+    }
+
+    /// The time on the clock of this port.
+    pub(crate) fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Wait on the clock of this port, which tests can fake.
+    pub(crate) fn sleep(&self, duration: Duration) {
+        self.clock.sleep(duration);
+    }
+
+    /// Start counting sequence numbers from the beginning, like the bootloader does after it was
+    /// reset or finished an upload. For flows that reset the board themselves.
+    pub fn reset_sequence(&mut self) {
+        self.sequence_number = 0;
+    }
+
+    /// Give the next packet the sequence number that `ack` asks for.
+    fn adopt_sequence(&mut self, ack: u8) {
+        self.sequence_number = (ack + 7) % 8;
     }
 
     fn next_sequence_number(&mut self) -> u8 {
@@ -60,224 +596,2620 @@ impl Serial {
         self.sequence_number
     }
 
-    /// For a description of the SLIP header go to:
-    /// http://developer.nordicsemi.com/nRF51_SDK/doc/7.2.0/s110/html/a00093.html
-    fn create_slip_header(&mut self, pkt_len: usize) -> ([u8; 4], u8) {
-        assert!(pkt_len < 0x1000);
+    fn create_packet(&mut self, data: &[u8]) -> (Vec<u8>, u8) {
+        let seq_nr = self.next_sequence_number();
+        (self.encoder.encode(seq_nr, &[data]), seq_nr)
+    }
+
+    pub fn send_data(&mut self, data: &[u8]) -> Result<()> {
+        self.send_data_with_response(data).map(|_| ())
+    }
+
+    /// Like [`send_data`](Self::send_data), but returns the frame that acknowledged the packet,
+    /// with anything the bootloader sent back in it.
+    ///
+    /// When the board keeps asking for another packet than this one, the packet is sent once
+    /// more with the sequence number it asks for, and the packets after it follow from there.
+    pub fn send_data_with_response(&mut self, data: &[u8]) -> Result<AckFrame> {
+        let (packet, seq_nr) = self.create_packet(data);
+        match self.send_packet(&packet, seq_nr) {
+            Err(e) if e.is::<OutOfSync>() => {
+                let ack = e.downcast_ref::<OutOfSync>().unwrap().ack;
+                eprintln!("the board expects sequence number {ack}, going on from there");
+                self.adopt_sequence(ack);
+                let (packet, seq_nr) = self.create_packet(data);
+                self.send_packet(&packet, seq_nr)
+                    .wrap_err("failed after resynchronizing the sequence numbers")
+            }
+            res => res,
+        }
+    }
 
-        // sequence number
-        let seq = self.next_sequence_number();
-        // data integrity check (yes we always have a CRC)
-        let dip = true as u8;
-        // reliable packet (yes, our (USB) connection is reliable)
-        let rp = true as u8;
+    /// Send an already encoded packet and wait for the board to acknowledge it. When the ack
+    /// doesn't come or is for another packet, the same frame is sent again after a wait that
+    /// grows with every attempt (see [`UploadConfig::retry_backoff`]), at most `max_retries` times.
+    ///
+    /// Fails with [`OutOfSync`] when [`SEQUENCE_RESYNC_AFTER`] acks in a row are for the same
+    /// packet another than this one. An ack proves that a bootloader is listening, so those
+    /// attempts are made even when `max_retries` is lower.
+    fn send_packet(&mut self, packet: &[u8], seq_nr: u8) -> Result<AckFrame> {
+        self.send_packet_covering(packet, seq_nr, &[])
+    }
 
-        // we always send HCI packet, pkt type 14.
-        let pkt_type = 14;
+    /// Like [`send_packet`](Self::send_packet), but also done when the ack is one of `later`,
+    /// the acks of packets that were sent after this one and that it covers.
+    fn send_packet_covering(
+        &mut self,
+        packet: &[u8],
+        seq_nr: u8,
+        later: &[u8],
+    ) -> Result<AckFrame> {
+        let mut attempt = 0;
+        let mut mismatches: Option<(u8, usize)> = None;
+        loop {
+            self.write_frame(packet)?;
+            if !self.packet_delay.is_zero() {
+                self.clock.sleep(self.packet_delay);
+            }
 
-        let b1 = seq | (((seq + 1) % 8) << 3) | (dip << 6) | (rp << 7);
-        let b2 = pkt_type | ((pkt_len & 0x00f) << 4) as u8;
-        let b3 = ((pkt_len & 0xff0) >> 4) as u8;
+            let err = match self.wait_for_ack_frame() {
+                Ok(frame) if frame.ack == (seq_nr + 1) % 8 || later.contains(&frame.ack) => {
+                    return Ok(frame)
+                }
+                // still waiting for this packet, which sending it again is the fix for
+                Ok(frame) if frame.ack == seq_nr => {
+                    mismatches = None;
+                    eyre!("received invalid sequence number, retry transmission")
+                }
+                Ok(frame) => {
+                    let count = match mismatches {
+                        Some((ack, count)) if ack == frame.ack => count + 1,
+                        _ => 1,
+                    };
+                    if count == SEQUENCE_RESYNC_AFTER {
+                        return Err(OutOfSync { ack: frame.ack }.into());
+                    }
+                    mismatches = Some((frame.ack, count));
+                    eyre!("received invalid sequence number, retry transmission")
+                }
+                Err(e) if is_line_problem(&e) || e.is::<Rejected>() => return Err(e),
+                Err(e) if e.is::<Nacked>() => e,
+                Err(e) => with_hint(e, ACK_ERROR_HINT),
+            };
+            let max_retries = match mismatches {
+                Some(_) => self.max_retries.max(SEQUENCE_RESYNC_AFTER - 1),
+                None => self.max_retries,
+            };
+            if attempt >= max_retries || self.deadline_passed() {
+                return Err(if attempt == 0 {
+                    err
+                } else {
+                    err.wrap_err(format!("the packet was sent {} times", attempt + 1))
+                });
+            }
 
-        (
-            [
-                b1,
-                b2,
-                b3,
-                (!b1.wrapping_add(b2).wrapping_add(b3)).wrapping_add(1),
-            ],
-            seq,
-        )
+            attempt += 1;
+            self.retries += 1;
+            let (base, factor) = self.retry_backoff;
+            self.clock
+                .sleep(base.saturating_mul(factor.saturating_pow(attempt as u32 - 1)));
+            // a late ack for the previous attempt would be taken for the one of the next
+            self.purge()?;
+        }
+    }
+
+    /// Write an encoded frame, and remember it so we recognize it when it gets echoed back.
+    fn write_frame(&mut self, frame: &[u8]) -> Result<()> {
+        if self.recent_frames.len() > MAX_WINDOW_SIZE {
+            self.recent_frames.pop_front();
+        }
+        let escaped = &frame[1..frame.len() - 1];
+        self.recent_frames.push_back(escaped_frame_hash(escaped));
+        // only a trace sink gets all of the frame unescaped, the frames that are kept anyway
+        // only need what the header and the opcode say
+        if self.frame_log.sink.is_some() {
+            self.frame_log
+                .record(Direction::Sent, &unescape(escaped)?, frame);
+        } else {
+            let mut head = [0; 8];
+            let len = unescape_into(escaped, &mut head);
+            self.frame_log.record(Direction::Sent, &head[..len], frame);
+        }
+
+        self.port
+            .write_all(frame)
+            .wrap_err("failed to write to serial port")
     }
 
-    fn encode_int(i: u32) -> [u8; 4] {
-        i.to_le_bytes()
+    pub fn wait_for_ack(&mut self) -> Result<u8> {
+        self.wait_for_ack_frame().map(|frame| frame.ack)
     }
 
-    fn create_packet(&mut self, data: &[u8]) -> (Vec<u8>, u8) {
-        let mut temp_res = Vec::new();
-
-        let (bytes, seq_nr) = self.create_slip_header(data.len());
-        // create header
-        temp_res.extend_from_slice(&bytes);
-        // add data
-        temp_res.extend_from_slice(data);
-        // add crc
-        temp_res.extend_from_slice(&calc_crc16_default(&temp_res).to_le_bytes());
-
-        (Self::escape(&temp_res), seq_nr)
-    }
-
-    fn escape(unescaped: &[u8]) -> Vec<u8> {
-        let mut res = vec![0xc0];
-        for &i in unescaped {
-            match i {
-                0xc0 => res.extend_from_slice(&[0xdb, 0xdc]),
-                0xdb => res.extend_from_slice(&[0xdb, 0xdd]),
-                a => res.push(a),
-            }
+    /// Like [`read_ack_frame`](Self::read_ack_frame), but when it fails after waiting at least
+    /// as long as the read timeout, print some advice about it. Only once per port.
+    fn wait_for_ack_frame(&mut self) -> Result<AckFrame> {
+        let start = self.clock.now();
+        let res = self.read_ack_frame();
+
+        if res.is_err()
+            && !self.warned_about_timeout
+            && self.clock.now() - start >= self.ack_warning_after
+        {
+            self.warned_about_timeout = true;
+            eprintln!("Your read operation seems to be timing out. Make sure you reset your board before uploading a program");
+            eprintln!("and try turning it off and on again. We'll keep trying to send data, but most likely the upload has failed now.");
         }
-        res.push(0xc0);
         res
     }
 
-    fn unescape(unescaped: &[u8]) -> Result<Vec<u8>> {
-        let mut res = vec![];
+    /// Read the next frame from the board, and return the sequence number it acknowledges.
+    fn read_ack(&mut self) -> Result<u8> {
+        self.read_ack_frame().map(|frame| frame.ack)
+    }
 
-        let mut iter = unescaped.iter();
-        while let Some(&byte) = iter.next() {
-            res.push(match byte {
-                0xdb => match iter.next() {
-                    Some(0xdc) => 0xc0,
-                    Some(0xdd) => 0xdb,
-                    i => bail!("encountered invalid byte '{i:?}' after escape character"),
-                },
-                i => i,
-            });
+    /// Read the next frame from the board. Fails with [`Nacked`] when the board asks for the
+    /// packet again, and with [`Rejected`] when it answers with an error.
+    ///
+    /// Near the [deadline](UploadConfig::deadline), the read timeout of the port is shortened to end
+    /// there, and restored afterwards.
+    fn read_ack_frame(&mut self) -> Result<AckFrame> {
+        let remaining = self.deadline.map(|d| {
+            d.saturating_duration_since(self.clock.now())
+                .max(MIN_READ_TIMEOUT)
+        });
+        match remaining {
+            Some(remaining) if remaining < self.read_timeout => {
+                self.with_read_timeout(remaining, Self::read_ack_frame_in_time)
+            }
+            _ => self.read_ack_frame_in_time(),
+        }
+    }
+
+    /// Like [`read_ack_frame`](Self::read_ack_frame), within the read timeout as it is.
+    fn read_ack_frame_in_time(&mut self) -> Result<AckFrame> {
+        let deadline = self.clock.now() + self.read_timeout;
+        loop {
+            let frame = self.read_frame(deadline)?;
+            if self.recent_frames.contains(&received_frame_hash(&frame)) {
+                return Err(Echoed.into());
+            }
+
+            let Ok(received) = Received::decode(&frame) else {
+                // log output between two frames, that happened to contain a 0xc0
+                frame.into_iter().for_each(|b| self.discard(b));
+                continue;
+            };
+            self.garbage = 0;
+            self.garbage_sample.clear();
+            return match received.packet {
+                Packet::Nack => Err(Nacked.into()),
+                Packet::Dfu(response) if response.result != DfuResult::Success => {
+                    Err(Rejected(response).into())
+                }
+                _ => Ok(received.frame),
+            };
         }
+    }
+
+    /// Check that the bootloader is listening, by sending it a frame without a DFU packet in it,
+    /// and waiting a short time for any ack. The start packet is answered only after the whole
+    /// serial timeout when nothing is listening, which takes a lot longer to find out.
+    ///
+    /// Any answer of the bootloader will do, also one that asks for the frame again. Only when
+    /// nothing answers, or what we sent comes back, the board is not in bootloader mode.
+    pub fn ping(&mut self) -> Result<()> {
+        let timeout = self
+            .handshake_timeout
+            .unwrap_or(PING_TIMEOUT)
+            .min(PING_TIMEOUT)
+            .min(self.read_timeout);
+        let (packet, _) = self.create_packet(&[]);
 
-        Ok(res)
+        let res = self.with_read_timeout(timeout, |s| {
+            s.write_frame(&packet)?;
+            s.read_ack()
+        });
+        match res {
+            // also when it is still waiting for another packet, then it's at least listening
+            Ok(_) => Ok(()),
+            Err(e) if e.is::<Nacked>() || e.is::<Rejected>() => Ok(()),
+            Err(e) if e.is::<TimedOut>() || e.is::<Echoed>() => Err(e
+                .wrap_err("the board is not in bootloader mode")
+                .suggestion("press the reset button of the board, or turn it off and on again")),
+            Err(e) => Err(e),
+        }
     }
 
-    pub fn send_data(&mut self, data: &[u8]) -> Result<()> {
+    /// Send a packet that the board may not be ready for yet, sending it again (with the same
+    /// sequence number) while it isn't acknowledged, for at most `max_wait`. The wait for an
+    /// ack starts short and doubles every attempt. Returns whether it was sent more than once.
+    pub(crate) fn send_data_when_ready(&mut self, data: &[u8], max_wait: Duration) -> Result<bool> {
         let (packet, seq_nr) = self.create_packet(data);
+        let expected_ack = (seq_nr + 1) % 8;
+        let deadline = self.clock.now() + max_wait;
+        let mut poll = READY_POLL_INTERVAL;
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+            let res = self.with_read_timeout(poll, |s| {
+                s.write_frame(&packet)?;
+                s.read_ack()
+            });
+            match res {
+                Ok(ack) if ack == expected_ack => return Ok(attempts > 1),
+                // answered as out of order, so it is still waiting for this packet
+                Ok(ack) if ack == seq_nr => {}
+                Ok(_) => bail!("received invalid sequence number, retry transmission"),
+                Err(e) if is_line_problem(&e) || e.is::<Rejected>() || self.deadline_passed() => {
+                    return Err(e)
+                }
+                Err(e) if self.clock.now() >= deadline => {
+                    return Err(e.wrap_err(format!(
+                        "the board wasn't ready after {:.1}s",
+                        max_wait.as_secs_f64()
+                    )))
+                }
+                Err(_) => {}
+            }
+
+            let remaining = deadline.saturating_duration_since(self.clock.now());
+            poll = (poll * 2).min(remaining).max(READY_POLL_INTERVAL);
+        }
+    }
+
+    /// Wait for a successful DFU response to the packet with opcode `request`, for at most
+    /// `max_wait`, throwing away any other frames that arrive. Returns whether it arrived.
+    pub(crate) fn wait_for_response(&mut self, request: u32, max_wait: Duration) -> Result<bool> {
+        let deadline = self.clock.now() + max_wait;
+        loop {
+            let remaining = deadline.saturating_duration_since(self.clock.now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+            match self.with_read_timeout(remaining, |s| s.read_ack_frame()) {
+                Ok(frame)
+                    if parse_dfu_response(&frame.payload).is_some_and(|r| r.request == request) =>
+                {
+                    return Ok(true)
+                }
+                Ok(_) => {}
+                Err(e) if is_line_problem(&e) || e.is::<Rejected>() => return Err(e),
+                // nothing more arrived before the deadline
+                Err(_) => return Ok(false),
+            }
+        }
+    }
 
-        // println!("send: {:?}", packet.iter().map(|i| format!("{:02x}", i).chars().collect::<Vec<_>>()).flatten().collect::<String>());
+    /// Throw away everything that was received but not read yet, like the acks for packets
+    /// that were sent more than once.
+    pub(crate) fn clear_input(&mut self) -> Result<()> {
+        self.rx_buffer.clear();
+        self.decoder.reset();
+        self.garbage = 0;
+        self.garbage_sample.clear();
+        self.port
+            .clear_input()
+            .wrap_err("failed to drain the serial port")
+    }
 
+    /// Throw away everything that was received but not read yet, and everything that was
+    /// written but not sent yet, so nothing of an earlier exchange with the board gets mixed up
+    /// with the next one.
+    pub fn purge(&mut self) -> Result<()> {
+        self.clear_input()?;
         self.port
-            .write_all(&packet)
-            .wrap_err("failed to write to serial port")?;
-        sleep(Duration::from_millis(40));
+            .clear_output()
+            .wrap_err("failed to clear the output of the serial port")
+    }
+
+    /// Read the next frame before `deadline`, unescaped and without the 0xc0 bytes around it.
+    ///
+    /// Anything that arrives outside of a frame, like log output of an application that is still
+    /// running or of the bootloader itself, is thrown away.
+    ///
+    /// Fails when no complete frame arrived in time. Reads that time out don't always fail
+    /// themselves: D2XX reports them as successfully reading nothing. Also fails, without
+    /// waiting for the deadline, when [`MAX_GARBAGE`] bytes arrived since the last frame that
+    /// decoded, so the frame that is being collected can't grow without bound.
+    fn read_frame(&mut self, deadline: Instant) -> Result<Vec<u8>> {
+        loop {
+            if self.garbage >= MAX_GARBAGE {
+                let start = std::mem::take(&mut self.garbage_sample);
+                let len = std::mem::take(&mut self.garbage);
+                self.decoder.reset();
+                return Err(Garbage { len, start }.into());
+            }
 
-        let res = self.wait_for_ack()
-            .wrap_err("waiting for message acknowledgement. If this is due to a timeout, try resetting your board, or turning it off and on again")?;
+            let Some(byte) = self.rx_buffer.pop_front() else {
+                if self.clock.now() >= deadline {
+                    return Err(TimedOut(self.read_timeout).into());
+                }
+                self.fill_rx_buffer(self.decoder.bytes_missing())?;
+                continue;
+            };
+            self.garbage += 1;
+            if self.garbage_sample.len() < GARBAGE_SAMPLE_SIZE {
+                self.garbage_sample.push(byte);
+            }
 
-        if res != (seq_nr + 1) % 8 {
-            bail!("received invalid sequence number, retry transmission")
+            match self.decoder.push(byte) {
+                Some(Decoded::Frame(frame)) => {
+                    self.frame_log.record(Direction::Received, &frame, &frame);
+                    return Ok(frame);
+                }
+                Some(Decoded::Noise(b)) => self.discard(b),
+                Some(Decoded::Invalid(raw)) => raw.into_iter().for_each(|b| self.discard(b)),
+                None => {}
+            }
         }
+    }
 
+    /// Read at most `max` bytes of whatever arrived into the receive buffer.
+    fn fill_rx_buffer(&mut self, max: usize) -> Result<()> {
+        let mut buf = [0u8; RX_CHUNK_SIZE];
+        let max = max.clamp(1, RX_CHUNK_SIZE);
+        let n = self
+            .port
+            .read(&mut buf[..max])
+            .wrap_err("failed to read from serial port")?;
+        if n == 0 {
+            self.clock.sleep(EMPTY_READ_BACKOFF);
+        }
+        self.rx_buffer.extend(&buf[..n]);
         Ok(())
     }
 
-    pub fn wait_for_ack(&mut self) -> Result<u8> {
-        let (tx, rx) = channel();
+    fn discard(&mut self, byte: u8) {
+        self.discarded_bytes += 1;
+        if self.noise_sample.len() < NOISE_SAMPLE_SIZE {
+            self.noise_sample.push(byte);
+        }
 
-        spawn(move || {
-            if rx.recv_timeout(SERIAL_TIMEOUT).is_err() {
-                println!("Your read operation seems to be timing out. Make sure you reset your board before uploading a program");
-                println!("and try turning it off and on again. We'll keep trying to send data, but most likely the upload has failed now.");
-            }
-        });
+        if self.discarded_bytes == NOISE_WARNING_THRESHOLD {
+            eprintln!();
+            eprintln!(
+                "WARNING: the board appears to be printing over the upload connection: {:?}",
+                String::from_utf8_lossy(&self.noise_sample)
+            );
+            eprintln!(
+                "Is the program still running, instead of the bootloader? Those bytes are ignored."
+            );
+        }
+    }
 
-        let mut response = Vec::new();
+    /// Check that bytes come back unchanged at the baud rate of the port, with TX connected to
+    /// RX of the adapter instead of to a board. This tells whether the adapter can really do a
+    /// baud rate, before uploading at it.
+    pub fn loopback_test(&mut self) -> Result<()> {
+        let pattern: Vec<u8> = (0..=255).collect();
+        self.clear_input()?;
+        self.port
+            .write_all(&pattern)
+            .wrap_err("failed to write to serial port")?;
 
-        while response.iter().filter(|&&i| i == 0xc0).count() < 2 {
-            let mut temp = [0u8; 6];
-            self.port
-                .read_all(&mut temp)
-                .wrap_err("failed to read from serial port")?;
-            response.extend_from_slice(&temp);
+        let deadline = self.clock.now() + self.read_timeout;
+        while self.rx_buffer.len() < pattern.len() && self.clock.now() < deadline {
+            self.fill_rx_buffer(pattern.len() - self.rx_buffer.len())?;
+        }
+        let received: Vec<u8> = self.rx_buffer.drain(..).collect();
+
+        if received.is_empty() {
+            return Err(eyre!("nothing came back in the loopback test")
+                .suggestion("connect TX to RX of the serial adapter, without a board"));
+        }
+        let different = pattern
+            .iter()
+            .zip(&received)
+            .filter(|(a, b)| a != b)
+            .count();
+        if different > 0 || received.len() != pattern.len() {
+            bail!(
+                "{} bytes were sent in the loopback test, but {} came back, {different} of them different: the serial adapter can't do this baud rate reliably",
+                pattern.len(),
+                received.len()
+            );
         }
+        Ok(())
+    }
+
+    /// Get a bootloader that is still waiting for the data packets of an interrupted upload out
+    /// of that state by sending it a stop packet. Returns whether the bootloader responded.
+    pub fn abort(&mut self) -> Result<bool> {
+        self.clear_input()?;
 
-        // ignore error, if the thread died then that's too bad.
-        let _ = tx.send(());
+        let stop = stop_payload();
+        for _ in 0..2 {
+            let (packet, seq_nr) = self.create_packet(&stop);
+            self.write_frame(&packet)?;
 
-        let unescaped = Self::unescape(&response)?;
+            let ack = match self.wait_for_ack() {
+                Ok(ack) => ack,
+                Err(e) if is_line_problem(&e) => return Err(e),
+                Err(_) => return Ok(false),
+            };
+            if ack == (seq_nr + 1) % 8 {
+                return Ok(true);
+            }
 
-        // remove 0xc0 at the start and end
-        let message = &unescaped[1..unescaped.len() - 1];
+            // A packet the bootloader didn't expect is answered with the ack for the one it is
+            // waiting for, so the next stop packet gets exactly that sequence number.
+            self.adopt_sequence(ack);
+        }
 
-        Ok(message[0] >> 3 & 0x07)
+        bail!("the bootloader responded, but didn't accept the stop packet")
     }
 
-    pub fn send_start_dfu(&mut self, file_size: u32) -> Result<()> {
-        let mut res = Vec::new();
+    /// [`abort`](Self::abort) with the serial timeout of `config`, and when the bootloader
+    /// stopped, reset the board back into the bootloader if the config
+    /// [resets](UploadConfig::reset_before_upload) it.
+    pub(crate) fn abort_with_config(&mut self, config: &UploadConfig) -> Result<bool> {
+        self.set_timeouts(config.serial_timeout, config.serial_timeout)?;
+        let stopped = self.abort()?;
+        if let Some((line, pulse)) = config.reset.filter(|_| stopped) {
+            eprintln!("resetting the board...");
+            self.pulse_reset(line, pulse)?;
+        }
+        Ok(stopped)
+    }
 
-        res.extend_from_slice(&Self::encode_int(DFU_START_PACKET));
-        res.extend_from_slice(&Self::encode_int(4));
-        res.extend_from_slice(&Self::encode_int(0));
-        res.extend_from_slice(&Self::encode_int(0));
-        res.extend_from_slice(&Self::encode_int(file_size));
+    /// Wipe the application on the board without uploading a new one: start an upload as large
+    /// as all the flash for the application, which the bootloader erases, and stop it right away.
+    /// The board is [reset](UploadConfig::reset_before_upload) into the bootloader first if the
+    /// config says so.
+    pub fn erase(&mut self, config: &UploadConfig) -> Result<()> {
+        config.validate()?;
+        self.set_timeouts(config.serial_timeout, config.serial_timeout)?;
+        if let Some((line, pulse)) = config.reset {
+            eprintln!("resetting the board...");
+            self.pulse_reset(line, pulse)?;
+        }
 
-        self.send_data(&res)?;
+        eprintln!("starting connection...");
+        if config.ping {
+            self.ping()?;
+        }
+        // like for an upload, the start packet isn't sent again
+        self.max_retries = 0;
+        let started = DfuSession::new(self).send_start(config.available_flash() as u32);
+        self.max_retries = config.max_retries;
+        started.map_err(|e| with_hint(e, START_ERROR_HINT))?;
 
-        Ok(())
+        eprintln!("erasing the application...");
+        self.purge()?;
+        match self.send_data_when_ready(&stop_payload(), config.erase_timeout) {
+            Ok(_) => Ok(()),
+            // without an image there is nothing to check, but the application is gone either way
+            Err(e) if e.is::<Rejected>() => Ok(()),
+            Err(e) => Err(e.wrap_err("the bootloader didn't finish erasing the application")),
+        }
     }
 
-    pub fn send_init_packet(&mut self, file: &[u8]) -> Result<()> {
-        let mut res = vec![];
+    /// Send the whole image as data packets, encoding them either inline or one frame ahead
+    /// on a separate thread, depending on the config.
+    fn send_all_data_packets(
+        &mut self,
+        file: &[u8],
+        config: &UploadConfig,
+        report: &mut UploadReport,
+    ) -> Result<()> {
+        let progress = Progress {
+            total_chunks: Cell::new(file.len().div_ceil(config.packet_size)),
+            packet_size: Cell::new(config.packet_size),
+            resized_at: Cell::new((0, 0)),
+            started: self.clock.now(),
+            reported: Cell::new(0),
+        };
+        if config.adaptive_packet_size {
+            return self.send_data_packets_adaptively(file, &progress, config, report);
+        }
+        report.chunks = progress.total_chunks.get();
+        report.packet_sizes = vec![config.packet_size];
 
-        res.extend_from_slice(&Self::encode_int(DFU_INIT_PACKET));
-        res.extend_from_slice(&[
-            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 0x00, 0xfe, 0xff,
-        ]);
-        res.extend_from_slice(&calc_crc16_default(file).to_le_bytes());
-        // padding required as per the python reference implementation. No further docs found on this
-        res.extend_from_slice(&[0, 0]);
+        // Sequence numbers are handed out in order, so the frames can be
+        // encoded without access to the sequence state in `self`.
+        let first_seq = self.sequence_number as usize + 1;
+        let mut encoder = FrameEncoder::default();
+        let opcode = DFU_DATA_PACKET.to_le_bytes();
+        let encode = move |(index, chunk): (usize, &[u8])| {
+            let seq_nr = ((first_seq + index) % 8) as u8;
+            (encoder.encode(seq_nr, &[&opcode, chunk]), seq_nr)
+        };
+        let frames = file.chunks(config.packet_size).enumerate();
+
+        if !config.encode_ahead {
+            return self.send_frames(frames.map(encode), &progress, config, report);
+        }
+
+        scope(|s| {
+            // A rendezvous channel: the encoder works on frame N+1 while frame N is
+            // being written, and then waits until frame N+1 is taken.
+            let (tx, rx) = sync_channel(0);
+            s.spawn(move || {
+                for frame in frames.map(encode) {
+                    if tx.send(frame).is_err() {
+                        // the upload failed, nobody wants our frames anymore
+                        break;
+                    }
+                }
+            });
+
+            self.send_frames(rx.into_iter(), &progress, config, report)
+        })
+    }
+
+    /// Send the data packets one at a time, with the packet size that [`PacketSizer`] picks
+    /// after every packet for the rest of the image.
+    fn send_data_packets_adaptively(
+        &mut self,
+        file: &[u8],
+        progress: &Progress,
+        config: &UploadConfig,
+        report: &mut UploadReport,
+    ) -> Result<()> {
+        let mut sizer = PacketSizer::new(config.packet_size);
+        report.packet_sizes = vec![sizer.size];
+        let mut sent = 0;
+        while sent < file.len() {
+            self.check_stop(config)?;
+            let chunk = &file[sent..file.len().min(sent + sizer.size)];
+            let retries = self.retries;
+            self.send_data(&data_payload(chunk))?;
+            sent += chunk.len();
+            report.chunks += 1;
 
-        self.send_data(&res)?;
+            if let Some(size) = sizer.after_packet(self.retries - retries) {
+                report.packet_sizes.push(size);
+                progress.resize(report.chunks, sent, file.len() - sent, size);
+            }
+            self.chunks_done(report.chunks, progress, config);
+        }
 
         Ok(())
     }
 
-    pub fn send_stop_packet(&mut self) -> Result<()> {
-        let mut res = vec![];
+    fn send_frames(
+        &mut self,
+        frames: impl Iterator<Item = (Vec<u8>, u8)>,
+        progress: &Progress,
+        config: &UploadConfig,
+        report: &mut UploadReport,
+    ) -> Result<()> {
+        if config.window_size > 1 {
+            return self.send_frames_windowed(frames, progress, config, report);
+        }
 
-        res.extend_from_slice(&Self::encode_int(DFU_STOP_DATA_PACKET));
-        self.send_data(&res)?;
+        for (index, (packet, seq_nr)) in frames.enumerate() {
+            self.check_stop(config)?;
+            self.sequence_number = seq_nr;
+            self.send_packet(&packet, seq_nr)?;
+            self.chunks_done(index + 1, progress, config);
+        }
 
         Ok(())
     }
 
-    pub fn send_data_packet(&mut self, data: &[u8]) -> Result<()> {
-        let mut res = vec![];
+    /// Send all data packets while keeping up to `window_size` of them unacknowledged.
+    /// Acks are cumulative, so an ack also covers every packet sent before the one it is for.
+    ///
+    /// When an ack arrives that doesn't belong to anything in flight, the board must have missed
+    /// a packet. We then stop pipelining and resend the outstanding packets one at a time.
+    fn send_frames_windowed(
+        &mut self,
+        frames: impl Iterator<Item = (Vec<u8>, u8)>,
+        progress: &Progress,
+        config: &UploadConfig,
+        report: &mut UploadReport,
+    ) -> Result<()> {
+        let mut in_flight = VecDeque::new();
+        let mut acked = 0;
+        let mut window = config.window_size;
 
-        res.extend_from_slice(&Self::encode_int(DFU_DATA_PACKET));
-        res.extend_from_slice(data);
+        for (packet, seq_nr) in frames {
+            self.check_stop(config)?;
+            while in_flight.len() >= window {
+                acked += self.wait_for_window_ack(&mut in_flight, &mut window, report)?;
+                self.chunks_done(acked, progress, config);
+            }
+
+            self.sequence_number = seq_nr;
+            self.write_frame(&packet)?;
+            in_flight.push_back(InFlight {
+                expected_ack: (seq_nr + 1) % 8,
+                packet,
+            });
+        }
 
-        self.send_data(&res)?;
+        while !in_flight.is_empty() {
+            acked += self.wait_for_window_ack(&mut in_flight, &mut window, report)?;
+            self.chunks_done(acked, progress, config);
+        }
 
         Ok(())
     }
 
-    pub fn try_do_upload(&mut self, file: &[u8]) -> Result<()> {
-        println!("starting connection...");
-        self.send_start_dfu(file.len() as u32)?;
-        // wait before we actually send data to the board after
-        // we send the start_dfu message
-        sleep(SEND_START_DFU_WAIT_TIME);
+    /// Count `done` data packets as acknowledged, and show it.
+    fn chunks_done(&mut self, done: usize, progress: &Progress, config: &UploadConfig) {
+        self.data_progress = (done, progress.total_chunks.get());
+        progress.update(done, self.clock.now(), config);
+    }
 
-        println!("initializing upload...");
-        self.send_init_packet(file)?;
+    /// Fail when the upload was cancelled or its deadline passed, before the next data packet.
+    fn check_stop(&self, config: &UploadConfig) -> Result<()> {
+        if config.cancelled() {
+            return Err(Cancelled.into());
+        }
+        self.check_deadline()
+    }
 
-        // wait before we actually send data to the board after
-        // we send the init_packet message
-        sleep(SEND_INIT_PACKET_WAIT_TIME);
+    fn deadline_passed(&self) -> bool {
+        self.deadline.is_some_and(|d| self.clock.now() >= d)
+    }
 
-        let total_chunks = (file.len() + DFU_MAX_PACKET_SIZE - 1) / DFU_MAX_PACKET_SIZE;
+    fn deadline_exceeded(&self) -> DeadlineExceeded {
+        let (chunks_sent, total_chunks) = self.data_progress;
+        DeadlineExceeded {
+            chunks_sent,
+            total_chunks,
+        }
+    }
 
-        println!(
-            "uploading in {total_chunks} chunks ({}kb)...",
-            file.len() as f64 / 1024.0
-        );
-        for (index, i) in file.chunks(DFU_MAX_PACKET_SIZE).enumerate() {
-            if let Err(e) = self.send_data_packet(i) {
-                println!();
-                return Err(e);
+    /// Fail with [`DeadlineExceeded`] when the deadline of the upload passed.
+    pub(crate) fn check_deadline(&self) -> Result<()> {
+        if self.deadline_passed() {
+            return Err(self.deadline_exceeded().into());
+        }
+        Ok(())
+    }
+
+    /// Blame `e` on the deadline when that passed, like a wait for an ack that was cut short
+    /// by it.
+    fn past_deadline(&self, e: Report) -> Report {
+        if !self.deadline_passed() || e.is::<DeadlineExceeded>() || e.is::<Cancelled>() {
+            return e;
+        }
+        e.wrap_err(self.deadline_exceeded())
+    }
+
+    /// Send `bytes` of generated data in data packets, one at a time through the same path with
+    /// the default retransmissions as an upload, and measure how fast they are acknowledged. For comparing
+    /// computers, cables and settings like the latency timer.
+    ///
+    /// A bootloader that isn't in the middle of an upload acknowledges the packets without
+    /// writing them to flash, but don't run this while the board runs an application that
+    /// listens on the port.
+    pub fn benchmark(&mut self, bytes: usize) -> Result<ThroughputStats> {
+        let data = test_image(bytes);
+        self.max_retries = DEFAULT_MAX_RETRIES;
+        let retries = self.retries;
+        let started = self.clock.now();
+        let mut latencies = Vec::new();
+        for chunk in data.chunks(DEFAULT_PACKET_SIZE) {
+            let sent = self.clock.now();
+            self.send_data(&data_payload(chunk))?;
+            latencies.push(self.clock.now() - sent);
+        }
+        Ok(ThroughputStats::new(
+            bytes,
+            self.clock.now() - started,
+            latencies,
+            self.retries - retries,
+        ))
+    }
+
+    /// Wait for the next ack while pipelining, returning how many packets it acknowledged.
+    ///
+    /// When no ack arrives in time, the board asks for a packet again, or an ack arrives that
+    /// doesn't belong to anything in flight, a packet or an ack got lost. We then stop
+    /// pipelining and send the outstanding packets again one at a time, with the retries of
+    /// [`send_packet`](Self::send_packet).
+    fn wait_for_window_ack(
+        &mut self,
+        in_flight: &mut VecDeque<InFlight>,
+        window: &mut usize,
+        report: &mut UploadReport,
+    ) -> Result<usize> {
+        match self.wait_for_ack() {
+            Ok(ack) => {
+                if let Some(pos) = in_flight.iter().position(|p| p.expected_ack == ack) {
+                    in_flight.drain(..=pos);
+                    return Ok(pos + 1);
+                }
             }
-            print!(
-                "\rframes uploaded: {}/{total_chunks} = {:.1}%",
-                index + 1,
-                ((index + 1) as f64 / total_chunks as f64) * 100.0
-            );
-            stdout().flush().unwrap();
+            Err(e) if is_line_problem(&e) || e.is::<Rejected>() || self.deadline_passed() => {
+                return Err(with_hint(e, ACK_ERROR_HINT))
+            }
+            Err(_) => {}
         }
-        println!();
 
-        println!("finalizing upload...");
-        self.send_stop_packet()?;
+        *window = 1;
+        // late acks for the packets in flight would be taken for those of the packets sent again
+        self.purge()?;
+        let resent = in_flight.len();
+        while let Some(InFlight {
+            expected_ack,
+            packet,
+        }) = in_flight.front()
+        {
+            report.retries += 1;
+            let seq_nr = (expected_ack + 7) % 8;
+            // an ack for a packet after this one means the board has those too
+            let later: Vec<u8> = in_flight.iter().skip(1).map(|p| p.expected_ack).collect();
+            let ack = self.send_packet_covering(packet, seq_nr, &later)?.ack;
+            let pos = in_flight
+                .iter()
+                .position(|p| p.expected_ack == ack)
+                .unwrap_or(0);
+            in_flight.drain(..=pos);
+        }
 
-        println!("done");
-        Ok(())
+        Ok(resent)
+    }
+
+    /// Upload `file` over this port. When that fails, the error has the last frames that were
+    /// sent and received in a section of its own. With [`UploadConfig::record_to`], everything
+    /// that goes over the port is recorded.
+    ///
+    /// The [deadline](UploadConfig::deadline) counts from here, unless the upload is one
+    /// attempt of several that share it.
+    pub fn try_do_upload(&mut self, file: &[u8], config: &UploadConfig) -> Result<UploadReport> {
+        self.try_upload_prepared(&PreparedImage::unpadded(file.to_vec()), config)
+    }
+
+    /// Upload an image that was [prepared](PreparedImage::new) already, like [`try_do_upload`](Self::try_do_upload).
+    pub(crate) fn try_upload_prepared(
+        &mut self,
+        image: &PreparedImage,
+        config: &UploadConfig,
+    ) -> Result<UploadReport> {
+        if let Some(path) = &config.record {
+            self.port.recording = Some(Recording::create(path, self.clock.clone())?);
+        }
+        let own_deadline = self.deadline.is_none();
+        if own_deadline {
+            self.deadline = config.deadline.map(|d| self.clock.now() + d);
+        }
+        self.frame_log.sink = config.trace.clone();
+        config.report_progress(ProgressEvent::Started);
+        let res = self
+            .upload_phases(image, config)
+            .map_err(|e| self.past_deadline(e));
+        self.frame_log.sink = None;
+        self.port.recording = None;
+        if own_deadline {
+            self.deadline = None;
+        }
+        match &res {
+            Ok(_) => config.report_progress(ProgressEvent::Finished),
+            Err(e) => config.report_progress(ProgressEvent::Error(e.to_string())),
+        }
+        res.map_err(|e| self.frame_log.attach_to(e))
+    }
+
+    fn upload_phases(
+        &mut self,
+        image: &PreparedImage,
+        config: &UploadConfig,
+    ) -> Result<UploadReport> {
+        config.validate()?;
+        let file = image.bytes();
+        let total_chunks = file.len().div_ceil(config.packet_size);
+        self.data_progress = (0, total_chunks);
+        self.check_deadline()?;
+        self.set_timeouts(config.serial_timeout, config.serial_timeout)?;
+        self.packet_delay = config.packet_delay;
+        self.retry_backoff = config.retry_backoff;
+        self.port
+            .set_latency_timer(config.latency_timer)
+            .wrap_err("failed to set the latency timer of the serial port")?;
+        let mut report = UploadReport::new(self.path.clone());
+        let mut timer = PhaseTimer::new(self.clock.clone());
+        if config.before_reset.is_some() {
+            self.run_before_reset_hook(config)?;
+            timer.lap(Phase::BeforeReset);
+        }
+        if let Some((line, pulse)) = config.reset {
+            eprintln!("resetting the board...");
+            self.pulse_reset(line, pulse)?;
+            timer.lap(Phase::Reset);
+        }
+        let start = self.clock.now();
+
+        eprintln!("starting connection...");
+        if config.ping {
+            self.ping()?;
+        }
+        let sizes = config.image_type.sizes(file.len() as u32);
+        // a start packet that isn't acknowledged means there is no bootloader, so it isn't sent
+        // again, but every packet after it is
+        self.max_retries = 0;
+        let started = match self.handshake_timeout {
+            Some(timeout) => self.with_read_timeout(timeout, |s| {
+                DfuSession::new(s).send_start_typed(config.image_type, sizes)
+            }),
+            None => DfuSession::new(self).send_start_typed(config.image_type, sizes),
+        };
+        self.max_retries = config.max_retries;
+        started.map_err(|e| with_hint(e, START_ERROR_HINT))?;
+        timer.lap(Phase::Start);
+        config.report_progress(ProgressEvent::StartDfuSent);
+
+        eprintln!("initializing upload...");
+        self.purge()?;
+        DfuSession::new(self)
+            .erase_timeout(config.erase_timeout)
+            .init_wait(config.init_wait)
+            .init_packet(config.init_packet.clone())
+            .integrity_check(config.integrity_check)
+            .send_init(file)
+            .map_err(|e| match e.downcast_ref::<Rejected>() {
+                Some(Rejected(response)) if response.request == DFU_INIT_PACKET => {
+                    let other = match config.integrity_check {
+                        IntegrityCheck::Crc16 => IntegrityCheck::Crc32,
+                        IntegrityCheck::Crc32 => IntegrityCheck::Crc16,
+                    };
+                    e.suggestion(format!(
+                        "the bootloader may expect another init packet, try UploadConfig::integrity_check(IntegrityCheck::{other:?})"
+                    ))
+                }
+                _ => e,
+            })?;
+        timer.lap(Phase::Init);
+        config.report_progress(ProgressEvent::InitSent);
+
+        eprintln!(
+            "uploading in {total_chunks} chunks ({}kb)...",
+            file.len() as f64 / 1024.0
+        );
+        self.purge()?;
+        let res = self
+            .send_all_data_packets(file, config, &mut report)
+            .map_err(|e| self.past_deadline(e));
+        eprintln!();
+        if matches!(&res, Err(e) if e.is::<Cancelled>() || e.is::<DeadlineExceeded>()) {
+            eprintln!("cancelling upload...");
+            // best effort, the board can also be reset to get it out of the upload. Past the
+            // deadline the stop packet isn't waited for, and whatever is still on its way
+            // is purged, so the port can be used or closed right away
+            let _ = self.abort();
+            self.purge()?;
+        }
+        res?;
+        timer.lap(Phase::Data);
+
+        eprintln!("finalizing upload...");
+        let stop_ack = self.send_data_with_response(&stop_payload())?;
+        timer.lap(Phase::Stop);
+        if config.verify {
+            report.verified = Some(self.verify_image(&stop_ack, file)?);
+            timer.lap(Phase::Verify);
+        }
+        if config.reset_after_upload {
+            self.send_activate_and_reset()?;
+            timer.lap(Phase::Activate);
+        }
+
+        report.bytes = file.len();
+        report.sha256 = image.sha256.clone();
+        report.crc16 = calc_crc16_default(file);
+        report.duration = self.clock.now() - start;
+        report.discarded_bytes = self.discarded_bytes;
+        report.retries += self.retries;
+        eprintln!(
+            "done, uploaded firmware {} at {:.1}kB/s",
+            short_hash(&report.sha256),
+            report.throughput() / 1024.0
+        );
+        if report.retries > 0 {
+            eprintln!(
+                "{} packets had to be sent again, check the cable if this keeps happening",
+                report.retries
+            );
+        }
+        if report.packet_sizes.len() > 1 {
+            let sizes: Vec<_> = report.packet_sizes.iter().map(usize::to_string).collect();
+            eprintln!(
+                "the packets had to be made smaller, their sizes were {} bytes",
+                sizes.join(", ")
+            );
+        }
+
+        if let Some((banner, timeout)) = &config.banner {
+            eprintln!("waiting for the application to start...");
+            let seen = self.wait_for_banner(banner, *timeout)?;
+            if seen {
+                eprintln!("application started");
+            } else {
+                eprintln!(
+                    "WARNING: firmware flashed but no banner seen within {:.1}s, the application might not have started",
+                    timeout.as_secs_f64()
+                );
+            }
+            report.banner_seen = Some(seen);
+            timer.lap(Phase::Banner);
+        }
+
+        report.phases = timer.finish();
+        Ok(report)
+    }
+
+    /// Tell the bootloader to start the application that was just uploaded. The board resets
+    /// right away, often before its ack is out, so getting no ack within 200ms counts as
+    /// success, and so does a bootloader that doesn't know the packet.
+    pub fn send_activate_and_reset(&mut self) -> Result<()> {
+        let (packet, _) = self.create_packet(&activate_payload());
+        let res = self.with_read_timeout(ACTIVATE_TIMEOUT, |s| {
+            s.write_frame(&packet)?;
+            s.read_ack()
+        });
+        match res {
+            Err(e) if is_line_problem(&e) => Err(e),
+            Err(e)
+                if e.downcast_ref::<Rejected>()
+                    .is_some_and(|r| r.0.result != DfuResult::NotSupported) =>
+            {
+                Err(e)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the CRC the bootloader reports for the image it received, in the ack of the stop
+    /// packet or in a response right after it. Returns whether it reported one, bootloaders that
+    /// don't stay silent until [`VERIFY_TIMEOUT`], or answer without a CRC.
+    fn verify_image(&mut self, stop_ack: &AckFrame, file: &[u8]) -> Result<bool> {
+        let is_stop_response = |payload: &[u8]| {
+            parse_dfu_response(payload).is_some_and(|r| r.request == DFU_STOP_DATA_PACKET)
+        };
+        let payload = if is_stop_response(&stop_ack.payload) {
+            stop_ack.payload.clone()
+        } else {
+            match self.with_read_timeout(VERIFY_TIMEOUT, |s| s.read_ack_frame()) {
+                Ok(frame) if is_stop_response(&frame.payload) => frame.payload,
+                Ok(_) => Vec::new(),
+                Err(e) if e.is::<Rejected>() => return Err(e.wrap_err("image verification failed")),
+                Err(e) if is_line_problem(&e) => return Err(e),
+                Err(_) => Vec::new(),
+            }
+        };
+        // after the opcode, the request and the result
+        let Some(crc) = payload.get(12..14) else {
+            eprintln!(
+                "verification unsupported, the bootloader didn't report the CRC of the image"
+            );
+            return Ok(false);
+        };
+
+        let crc = u16::from_le_bytes([crc[0], crc[1]]);
+        let expected = calc_crc16_default(file);
+        if crc != expected {
+            bail!("image verification failed: the bootloader received an image with CRC 0x{crc:04x}, but the one that was sent has CRC 0x{expected:04x}");
+        }
+        eprintln!("verified the image on the board");
+        Ok(true)
+    }
+
+    /// Run the hook set with [`UploadConfig::before_reset`], giving it raw access to the port.
+    fn run_before_reset_hook(&mut self, config: &UploadConfig) -> Result<()> {
+        let Some(hook) = &config.before_reset else {
+            return Ok(());
+        };
+
+        eprintln!("running pre-upload hook...");
+        let res = self
+            .with_read_timeout(config.before_reset_timeout, |s| {
+                let mut port = DeadlineTransport {
+                    inner: &mut s.port,
+                    clock: &*s.clock,
+                    deadline: s.clock.now() + config.before_reset_timeout,
+                };
+                let mut hook = hook.0.lock().unwrap_or_else(|e| e.into_inner());
+                hook(&mut port)
+            })
+            .wrap_err("the pre-upload hook failed");
+
+        match res {
+            Err(e) if config.ignore_before_reset_errors => {
+                eprintln!("WARNING: {e:?}");
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    /// Reset the board by asserting `line` for `pulse`, on boards that have it wired to the reset
+    /// pin of the chip. Afterwards, waits for the bootloader to start and throws away what the
+    /// board sent before.
+    pub fn pulse_reset(&mut self, line: ControlLine, pulse: Duration) -> Result<()> {
+        self.port
+            .set_control_line(line, true)
+            .wrap_err_with(|| format!("failed to assert {line} to reset the board"))?;
+        self.clock.sleep(pulse);
+        self.port
+            .set_control_line(line, false)
+            .wrap_err_with(|| format!("failed to release {line} after resetting the board"))?;
+
+        self.clock.sleep(BOOTLOADER_START_TIME);
+        self.clear_input()
+    }
+
+    /// Read from the port until `banner` shows up, or the timeout expires.
+    /// Returns whether the banner was seen.
+    pub fn wait_for_banner(&mut self, banner: &[u8], timeout: Duration) -> Result<bool> {
+        let deadline = self.clock.now() + timeout;
+        let mut matcher = PatternMatcher::new(banner);
+        let mut buf = [0u8; 64];
+
+        let buffered: Vec<u8> = self.rx_buffer.drain(..).collect();
+        if matcher.feed(&buffered) {
+            return Ok(true);
+        }
+        while self.clock.now() < deadline {
+            let n = self
+                .port
+                .read(&mut buf)
+                .wrap_err("failed to read from serial port")?;
+            if matcher.feed(&buf[..n]) {
+                return Ok(true);
+            }
+            if n == 0 {
+                self.clock.sleep(BANNER_POLL_INTERVAL);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Switch the port over to the line settings of the program that was uploaded, after
+    /// throwing away whatever was received at the old ones.
+    pub fn reconfigure(&mut self, baud_rate: u32, flow_control: FlowControl) -> Result<()> {
+        check_baud_rate(baud_rate)?;
+        self.clear_input()?;
+        self.port
+            .reconfigure(baud_rate, flow_control)
+            .wrap_err("failed to reconfigure the serial port")
+    }
+
+    /// Read whatever the board sent, like the output of the program that was just uploaded.
+    /// Returns the number of bytes read, which is 0 when nothing arrived before the read timeout.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.rx_buffer.is_empty() {
+            let n = self.rx_buffer.len().min(buf.len());
+            for (b, byte) in buf.iter_mut().zip(self.rx_buffer.drain(..n)) {
+                *b = byte;
+            }
+            return Ok(n);
+        }
+
+        self.port
+            .read(buf)
+            .wrap_err("failed to read from serial port")
+    }
+
+    /// Write bytes to the board as they are, outside of any frame.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.port
+            .write_all(data)
+            .wrap_err("failed to write to serial port")
+    }
+}
+
+impl Drop for Serial {
+    fn drop(&mut self) {
+        let _ = self.shut_down();
+    }
+}
+
+/// What is left in a [`Serial`] after its transport was taken out of it.
+struct Taken;
+
+impl Transport for Taken {
+    fn read_all(&mut self, _buf: &mut [u8]) -> Result<()> {
+        bail!("the transport was taken out of this port")
+    }
+
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize> {
+        bail!("the transport was taken out of this port")
+    }
+
+    fn write_all(&mut self, _buf: &[u8]) -> Result<()> {
+        bail!("the transport was taken out of this port")
+    }
+}
+
+/// Finds a byte pattern in data that arrives in pieces, even when the
+/// pattern is split over several of them.
+struct PatternMatcher<'a> {
+    pattern: &'a [u8],
+    // the end of what we've seen so far, which may be the start of the pattern
+    tail: Vec<u8>,
+}
+
+impl<'a> PatternMatcher<'a> {
+    fn new(pattern: &'a [u8]) -> Self {
+        Self {
+            pattern,
+            tail: Vec::new(),
+        }
+    }
+
+    fn feed(&mut self, data: &[u8]) -> bool {
+        self.tail.extend_from_slice(data);
+        if self
+            .tail
+            .windows(self.pattern.len())
+            .any(|w| w == self.pattern)
+        {
+            return true;
+        }
+
+        let keep = self.pattern.len() - 1;
+        if self.tail.len() > keep {
+            self.tail.drain(..self.tail.len() - keep);
+        }
+        false
+    }
+}
+
+/// How far sending the data packets is.
+struct Progress {
+    total_chunks: Cell<usize>,
+    packet_size: Cell<usize>,
+    /// How many chunks and bytes were sent before the packet size changed last.
+    resized_at: Cell<(usize, usize)>,
+    started: Instant,
+    /// How many chunks were handed to the progress callback.
+    reported: Cell<usize>,
+}
+
+impl Progress {
+    /// Count the `remaining` bytes after `done` chunks of `sent` bytes in chunks of `packet_size`.
+    fn resize(&self, done: usize, sent: usize, remaining: usize, packet_size: usize) {
+        self.resized_at.set((done, sent));
+        self.packet_size.set(packet_size);
+        self.total_chunks
+            .set(done + remaining.div_ceil(packet_size));
+    }
+
+    /// Print how far the upload is, and tell the progress callback about the chunks that were
+    /// acknowledged since the last time. With a window, one ack can cover several.
+    fn update(&self, done: usize, now: Instant, config: &UploadConfig) {
+        for index in self.reported.replace(done)..done {
+            config.report_progress(ProgressEvent::Chunk {
+                index,
+                total: self.total_chunks.get(),
+            });
+        }
+        self.print(done, now);
+    }
+
+    fn print(&self, done: usize, now: Instant) {
+        let elapsed = (now - self.started).as_secs_f64();
+        let (resized_at, sent_before) = self.resized_at.get();
+        let sent = sent_before + (done - resized_at) * self.packet_size.get();
+        let speed = if elapsed > 0.0 {
+            sent as f64 / elapsed / 1024.0
+        } else {
+            0.0
+        };
+        let total_chunks = self.total_chunks.get();
+        eprint!(
+            "\rframes uploaded: {done}/{total_chunks} = {:.1}% ({speed:.1}kB/s)",
+            (done as f64 / total_chunks as f64) * 100.0
+        );
+        stderr().flush().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use color_eyre::eyre::bail;
+    use serial2::FlowControl;
+
+    use super::{
+        escaped_frame_hash, max_frame_size, received_frame_hash, Cancelled, DeadlineExceeded,
+        Echoed, FrameEncoder, Garbage, PacketSizer, PatternMatcher, Serial,
+        GROW_AFTER_CLEAN_PACKETS, MAX_GARBAGE,
+    };
+    use crate::clock::FakeClock;
+    use crate::config::{UploadConfig, DEFAULT_PACKET_DELAY};
+    use crate::crc::{calc_crc16_default, calc_crc32};
+    use crate::dfu::{data_payload, DfuSession, ImageType, InitPacket, IntegrityCheck};
+    use crate::emulator::{emulator_serial, Emulator};
+    use crate::hci::AckFrame;
+    use crate::progress::ProgressEvent;
+    use crate::report::Phase;
+    use crate::slip::escape;
+    use crate::trace::Direction;
+    use crate::transport::{ControlLine, Transport};
+    use crate::SERIAL_TIMEOUT;
+
+    /// Hands out exactly these pieces of a byte stream, one per read at most.
+    struct Pieces(VecDeque<Vec<u8>>);
+
+    impl Transport for Pieces {
+        fn read_all(&mut self, buf: &mut [u8]) -> color_eyre::Result<()> {
+            let mut filled = 0;
+            while filled < buf.len() {
+                match self.read(&mut buf[filled..])? {
+                    0 => bail!("timed out waiting for the bootloader to respond"),
+                    n => filled += n,
+                }
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> color_eyre::Result<usize> {
+            let Some(piece) = self.0.front_mut() else {
+                return Ok(0);
+            };
+            let n = piece.len().min(buf.len());
+            buf[..n].copy_from_slice(&piece[..n]);
+            piece.drain(..n);
+            if piece.is_empty() {
+                self.0.pop_front();
+            }
+            Ok(n)
+        }
+
+        fn write_all(&mut self, _buf: &[u8]) -> color_eyre::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn serial_reading(pieces: &[&[u8]]) -> Serial {
+        let pieces = Pieces(pieces.iter().map(|p| p.to_vec()).collect());
+        Serial::with_transport(
+            PathBuf::from("/dev/pieces"),
+            Box::new(pieces),
+            Arc::new(FakeClock::new()),
+        )
+    }
+
+    fn upload_to_emulator(image: &[u8], config: &UploadConfig) -> Emulator {
+        let emulator = Emulator::new();
+        emulator_serial(&emulator)
+            .try_do_upload(image, config)
+            .unwrap();
+        emulator
+    }
+
+    #[test]
+    fn test_upload_on_another_thread() {
+        fn assert_send<T: Send>() {}
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send::<Serial>();
+        assert_send_sync::<UploadConfig>();
+
+        let emulator = Emulator::new();
+        let mut serial = emulator_serial(&emulator);
+        let config = UploadConfig::default();
+        let report = std::thread::spawn(move || serial.try_do_upload(&[0x55; 1000], &config))
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.bytes, 1000);
+        assert_eq!(emulator.image(), [0x55; 1000]);
+    }
+
+    #[test]
+    fn test_encode_ahead_sends_identical_bytes() {
+        let image: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+
+        for window_size in [1, 3] {
+            let config = UploadConfig::default().window_size(window_size);
+            let inline = upload_to_emulator(&image, &config);
+            let ahead = upload_to_emulator(&image, &config.encode_ahead(true));
+
+            assert_eq!(ahead.image(), image);
+            assert_eq!(inline.written(), ahead.written());
+        }
+    }
+
+    #[test]
+    fn test_pattern_matcher_across_reads() {
+        let mut matcher = PatternMatcher::new(b"drone v1");
+        assert!(!matcher.feed(b"garbage dr"));
+        assert!(!matcher.feed(b"o"));
+        assert!(!matcher.feed(b"ne v"));
+        assert!(matcher.feed(b"1.0\r\n"));
+
+        let mut matcher = PatternMatcher::new(b"aab");
+        assert!(!matcher.feed(b"aa"));
+        assert!(matcher.feed(b"ab"));
+    }
+
+    #[test]
+    fn test_reset_before_upload() {
+        let image = [0x55; 1000];
+        let emulator = Emulator::new().running_application();
+        assert!(emulator_serial(&emulator)
+            .try_do_upload(&image, &UploadConfig::default())
+            .is_err());
+
+        let config = UploadConfig::default()
+            .reset_before_upload(ControlLine::Dtr, Duration::from_millis(10));
+        let serial = &mut emulator_serial(&emulator);
+        let lines_before = emulator.control_lines().len();
+        let report = serial.try_do_upload(&image, &config).unwrap();
+        assert_eq!(
+            emulator.control_lines()[lines_before..],
+            [(ControlLine::Dtr, true), (ControlLine::Dtr, false)]
+        );
+        assert_eq!(emulator.image(), image);
+        assert_eq!(report.phases[0].phase, Phase::Reset);
+
+        // transports without control lines can't reset the board
+        let mut serial = serial_reading(&[]);
+        assert!(serial.try_do_upload(&image, &config).is_err());
+    }
+
+    #[test]
+    fn test_banner_after_upload() {
+        let image = vec![0x42; 1000];
+        let banner = b"quadrupel firmware v2.0".to_vec();
+        let config = UploadConfig::default().expect_banner(banner.clone(), Duration::from_secs(1));
+
+        let emulator = Emulator::new()
+            .max_read(3)
+            .banner(b"booting...\r\nquadrupel firmware v2.0\r\n");
+        let report = emulator_serial(&emulator)
+            .try_do_upload(&image, &config)
+            .unwrap();
+        assert_eq!(report.banner_seen, Some(true));
+
+        let emulator = Emulator::new().banner(b"quadrupel firmware v1.9\r\n");
+        let report = emulator_serial(&emulator)
+            .try_do_upload(&image, &config)
+            .unwrap();
+        assert_eq!(report.banner_seen, Some(false));
+    }
+
+    #[test]
+    fn test_before_reset_hook_runs_first() {
+        let image = vec![0x42; 1000];
+        let calls = Arc::new(Mutex::new(0));
+        let config = {
+            let calls = calls.clone();
+            UploadConfig::default().before_reset(move |port| {
+                *calls.lock().unwrap() += 1;
+                port.write_all(b"disarm\n")
+            })
+        };
+
+        let emulator = Emulator::new();
+        emulator_serial(&emulator)
+            .try_do_upload(&image, &config)
+            .unwrap();
+        assert_eq!(*calls.lock().unwrap(), 1);
+        assert!(emulator.written().starts_with(b"disarm\n\xc0"));
+        assert_eq!(emulator.image(), image);
+    }
+
+    #[test]
+    fn test_before_reset_hook_failure() {
+        let image = vec![0x42; 1000];
+        let config = UploadConfig::default().before_reset(|_| bail!("motors still spinning"));
+
+        let emulator = Emulator::new();
+        assert!(emulator_serial(&emulator)
+            .try_do_upload(&image, &config)
+            .is_err());
+        assert!(emulator.written().is_empty());
+
+        let config = config.ignore_before_reset_errors(true);
+        emulator_serial(&emulator)
+            .try_do_upload(&image, &config)
+            .unwrap();
+        assert_eq!(emulator.image(), image);
+
+        // the hook doesn't get to use the port after its time is up
+        let config = UploadConfig::default()
+            .before_reset_timeout(Duration::ZERO)
+            .before_reset(|port| port.write_all(b"disarm\n"));
+        assert!(emulator_serial(&Emulator::new())
+            .try_do_upload(&image, &config)
+            .is_err());
+    }
+
+    #[test]
+    fn test_noise_between_frames() {
+        let image = vec![0x42; 3000];
+        let noise = b"[debug] motors: 0 0 0 0\r\n";
+
+        for window_size in [1, 4] {
+            let emulator = Emulator::new().noise(noise);
+            let report = emulator_serial(&emulator)
+                .try_do_upload(&image, &UploadConfig::default().window_size(window_size))
+                .unwrap();
+
+            assert_eq!(emulator.image(), image);
+            // the ping, start, init, 6 data packets, stop and activate all get an ack with noise
+            // in front of it
+            assert_eq!(report.discarded_bytes, 11 * noise.len());
+        }
+    }
+
+    #[test]
+    fn test_wait_for_erase() {
+        let upload = |erase_time: Duration, config: &UploadConfig| {
+            let clock = Arc::new(FakeClock::new());
+            let emulator = Emulator::new().clock(clock.clone()).erase_time(erase_time);
+            let mut serial = emulator_serial(&emulator);
+            let res = serial.try_do_upload(&[7; 3000], config);
+            (res, emulator)
+        };
+        let init_time = |erase_time: Duration| {
+            let (res, emulator) = upload(erase_time, &UploadConfig::default());
+            assert_eq!(emulator.image(), [7; 3000]);
+            let report = res.unwrap();
+            let init = report.phases.iter().find(|p| p.phase == Phase::Init);
+            init.unwrap().duration
+        };
+
+        // a board that is ready right away doesn't wait for the erase at all
+        let fast = init_time(Duration::ZERO);
+        assert!(fast < Duration::from_millis(1100), "{fast:?}");
+
+        for erase_time in [Duration::from_millis(300), Duration::from_millis(2500)] {
+            let slow = init_time(erase_time);
+            assert!(slow >= erase_time + Duration::from_secs(1), "{slow:?}");
+            assert!(slow < erase_time * 2 + Duration::from_secs(1), "{slow:?}");
+        }
+
+        let config = UploadConfig::default().erase_timeout(Duration::from_secs(1));
+        let (res, emulator) = upload(Duration::from_secs(3), &config);
+        assert!(format!("{:?}", res.unwrap_err()).contains("wasn't ready after 1.0s"));
+        assert!(emulator.init_packet().is_none());
+    }
+
+    #[test]
+    fn test_pipelining_with_slow_acks() {
+        let image: Vec<u8> = (0..8192u32).map(|i| (i % 233) as u8).collect();
+        let data_phase = |window_size: usize| {
+            let clock = Arc::new(FakeClock::new());
+            let emulator = Emulator::new()
+                .clock(clock.clone())
+                .response_time(Duration::from_millis(20));
+            let mut serial = emulator_serial(&emulator);
+            let config = UploadConfig::default()
+                .window_size(window_size)
+                .packet_delay(Duration::ZERO);
+            let report = serial.try_do_upload(&image, &config).unwrap();
+            assert_eq!(emulator.image(), image);
+            assert_eq!(report.retries, 0);
+            report.phases[2].duration
+        };
+
+        // 16 data packets, every ack takes 20ms to arrive
+        let stop_and_wait = data_phase(1);
+        assert_eq!(stop_and_wait, Duration::from_millis(16 * 20));
+        let pipelined = data_phase(4);
+        assert!(pipelined * 3 <= stop_and_wait, "{pipelined:?}");
+    }
+
+    #[test]
+    fn test_loopback_is_detected() {
+        for window_size in [1, 4] {
+            let emulator = Emulator::new().loopback();
+            // past the ping, which says the board is not in bootloader mode
+            let config = UploadConfig::default().ping(false).window_size(window_size);
+            // with bytes that have to be escaped, which the echo comes back with
+            let err = emulator_serial(&emulator)
+                .try_do_upload(&[0xc0, 0xdb, 1, 2].repeat(750), &config)
+                .unwrap_err();
+            assert!(err.to_string().contains("being echoed back"), "{err:?}");
+        }
+
+        let err = emulator_serial(&Emulator::new().loopback())
+            .abort()
+            .unwrap_err();
+        assert!(err.to_string().contains("being echoed back"));
+
+        // a frame that comes back is the frame that was written, once it is unescaped
+        let unescaped = [0x01, 0xc0, 0x02, 0xdb, 0xdc];
+        let mut written = Vec::new();
+        escape(&unescaped, &mut written);
+        let escaped = &written[1..written.len() - 1];
+        assert_eq!(escaped_frame_hash(escaped), received_frame_hash(&unescaped));
+        assert_ne!(escaped_frame_hash(escaped), received_frame_hash(escaped));
+    }
+
+    #[test]
+    fn test_activate_after_upload() {
+        let image = [0x55; 100];
+        // the ping, start, init, data and stop, and then the activate packet
+        let frames =
+            |emulator: &Emulator| emulator.written().iter().filter(|&&b| b == 0xc0).count() / 2;
+
+        let emulator = upload_to_emulator(&image, &UploadConfig::default());
+        assert_eq!(frames(&emulator), 6);
+        let emulator =
+            upload_to_emulator(&image, &UploadConfig::default().reset_after_upload(false));
+        assert_eq!(frames(&emulator), 5);
+
+        // the board reset before it acknowledged the activate packet, or doesn't know it
+        let config = UploadConfig::default();
+        for emulator in [Emulator::new().drop_frame(5), Emulator::new().reject(6, 3)] {
+            emulator_serial(&emulator)
+                .try_do_upload(&image, &config)
+                .unwrap();
+        }
+        let err = emulator_serial(&Emulator::new().reject(6, 6))
+            .try_do_upload(&image, &config)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the bootloader rejected the activate packet: operation failed"
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let traced = Arc::new(Mutex::new(Vec::new()));
+        let sink = traced.clone();
+        let config = UploadConfig::default()
+            .reset_after_upload(false)
+            .trace(move |frame| sink.lock().unwrap().push(frame.clone()));
+        let emulator = upload_to_emulator(&[0x55; 100], &config);
+
+        // every frame we sent, as it was written, each followed by its ack
+        let traced = traced.lock().unwrap();
+        let sent: Vec<_> = traced
+            .iter()
+            .filter(|f| f.direction == Direction::Sent)
+            .collect();
+        assert_eq!(
+            sent.iter().map(|f| f.opcode).collect::<Vec<_>>(),
+            [None, Some(3), Some(1), Some(4), Some(5)]
+        );
+        assert_eq!(
+            sent.iter()
+                .flat_map(|f| f.bytes.clone())
+                .collect::<Vec<_>>(),
+            emulator.written()
+        );
+        for pair in traced.chunks(2) {
+            assert_eq!(pair[1].direction, Direction::Received);
+            assert_eq!(pair[1].ack, (pair[0].seq + 1) % 8);
+        }
+    }
+
+    #[test]
+    fn test_progress_events() {
+        for window in [1, 4] {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let sink = events.clone();
+            let config = UploadConfig::default()
+                .packet_size(100)
+                .window_size(window)
+                .on_progress(move |event| sink.lock().unwrap().push(event));
+            upload_to_emulator(&[0x55; 1000], &config);
+
+            let mut expected = vec![
+                ProgressEvent::Started,
+                ProgressEvent::StartDfuSent,
+                ProgressEvent::InitSent,
+            ];
+            expected.extend((0..10).map(|index| ProgressEvent::Chunk { index, total: 10 }));
+            expected.push(ProgressEvent::Finished);
+            assert_eq!(*events.lock().unwrap(), expected, "window {window}");
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let config =
+            UploadConfig::default().on_progress(move |event| sink.lock().unwrap().push(event));
+        let emulator = Emulator::new().reject(3, 6);
+        assert!(emulator_serial(&emulator)
+            .try_do_upload(&[0x55; 100], &config)
+            .is_err());
+        assert!(matches!(
+            events.lock().unwrap().as_slice(),
+            [ProgressEvent::Started, ProgressEvent::Error(e)] if e.contains("start packet")
+        ));
+    }
+
+    #[test]
+    fn test_verify() {
+        let image = [0x55; 1000];
+        let crc = calc_crc16_default(&image);
+        let config = UploadConfig::default().verify(true);
+
+        let emulator = Emulator::new().stop_response(crc);
+        let report = emulator_serial(&emulator)
+            .try_do_upload(&image, &config)
+            .unwrap();
+        assert_eq!(report.verified, Some(true));
+        assert!(report.phases.iter().any(|p| p.phase == Phase::Verify));
+
+        let emulator = Emulator::new().stop_response(crc ^ 1);
+        let err = emulator_serial(&emulator)
+            .try_do_upload(&image, &config)
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("image verification failed"),
+            "{err:?}"
+        );
+
+        // a bootloader that doesn't report the CRC
+        let report = emulator_serial(&Emulator::new())
+            .try_do_upload(&image, &config)
+            .unwrap();
+        assert_eq!(report.verified, Some(false));
+        // or answers the stop packet without one
+        let report = emulator_serial(&Emulator::new().stop_response_without_crc())
+            .try_do_upload(&image, &config)
+            .unwrap();
+        assert_eq!(report.verified, Some(false));
+        let report = emulator_serial(&Emulator::new())
+            .try_do_upload(&image, &UploadConfig::default())
+            .unwrap();
+        assert_eq!(report.verified, None);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let cancel = Arc::new(AtomicBool::new(true));
+        for window_size in [1, 3] {
+            let emulator = Emulator::new();
+            let config = UploadConfig::default()
+                .window_size(window_size)
+                .cancel_flag(cancel.clone());
+            let err = emulator_serial(&emulator)
+                .try_do_upload(&[0x55; 2000], &config)
+                .unwrap_err();
+            assert!(err.is::<Cancelled>(), "{err:?}");
+            assert!(emulator.image().is_empty());
+            // told to stop, so it doesn't wait for the rest of the image
+            assert!(emulator.stopped());
+        }
+    }
+
+    #[test]
+    fn test_deadline() {
+        let clock = Arc::new(FakeClock::new());
+        // the ack of the third data packet gets lost
+        let emulator = Emulator::new()
+            .clock(clock.clone())
+            .response_time(Duration::from_millis(20))
+            .drop_ack(5);
+        let mut serial = emulator_serial(&emulator);
+        let config = UploadConfig::default().deadline(Duration::from_secs(3));
+        let err = serial.try_do_upload(&[0x55; 8192], &config).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DeadlineExceeded>(),
+            Some(&DeadlineExceeded {
+                chunks_sent: 2,
+                total_chunks: 16
+            }),
+            "{err:?}"
+        );
+        // the wait for the lost ack ends at the deadline, not after the serial timeout
+        assert!(clock.elapsed() < Duration::from_millis(3100));
+        // the third packet was written all the same, only its ack is missing
+        assert_eq!(emulator.image().len(), 3 * 512);
+        assert!(emulator.stopped());
+        // with the timeouts of the port as they were
+        assert_eq!(
+            emulator.timeouts().last(),
+            Some(&(SERIAL_TIMEOUT, SERIAL_TIMEOUT))
+        );
+
+        // nothing is sent once the deadline passed
+        let emulator = Emulator::new();
+        let config = UploadConfig::default().deadline(Duration::ZERO);
+        let err = emulator_serial(&emulator)
+            .try_do_upload(&[0x55; 8192], &config)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the upload didn't finish before its deadline, after 0/16 data packets"
+        );
+        assert!(emulator.written().is_empty());
+    }
+
+    #[test]
+    fn test_loopback_test() {
+        emulator_serial(&Emulator::new().loopback())
+            .loopback_test()
+            .unwrap();
+
+        // a board instead of a loopback, which throws the bytes away
+        let err = emulator_serial(&Emulator::new())
+            .loopback_test()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "nothing came back in the loopback test");
+    }
+
+    #[test]
+    fn test_erase() {
+        // still in the bootloader after the upload, instead of running the application
+        let emulator = Emulator::new();
+        emulator_serial(&emulator)
+            .try_do_upload(
+                &[0x55; 100],
+                &UploadConfig::default().reset_after_upload(false),
+            )
+            .unwrap();
+
+        emulator_serial(&emulator)
+            .erase(&UploadConfig::default())
+            .unwrap();
+        assert_eq!(emulator.image_size(), Some(0x0002_4000));
+        assert!(emulator.image().is_empty());
+        assert!(emulator.stopped());
+
+        // a bootloader that refuses to stop without an image has erased it all the same
+        let emulator = Emulator::new().reject(5, 2);
+        emulator_serial(&emulator)
+            .erase(&UploadConfig::default())
+            .unwrap();
+
+        let err = emulator_serial(&Emulator::new().unresponsive())
+            .erase(&UploadConfig::default())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "the board is not in bootloader mode");
+
+        let emulator = Emulator::new().running_application();
+        let config = UploadConfig::default()
+            .reset_before_upload(ControlLine::Dtr, Duration::from_millis(10));
+        emulator_serial(&emulator).erase(&config).unwrap();
+        assert_eq!(
+            emulator.control_lines()[..2],
+            [(ControlLine::Dtr, true), (ControlLine::Dtr, false)]
+        );
+        assert!(emulator.stopped());
+    }
+
+    #[test]
+    fn test_abort_mid_transfer() {
+        let image: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+        let emulator = Emulator::new().mid_transfer();
+
+        assert!(emulator_serial(&emulator).abort().unwrap());
+        assert!(emulator.stopped());
+
+        emulator_serial(&emulator)
+            .try_do_upload(&image, &UploadConfig::default())
+            .unwrap();
+        assert_eq!(emulator.image(), image);
+    }
+
+    #[test]
+    fn test_sequence_resync() {
+        let image: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+
+        // the start packet is answered with acks for the data packet the board still waits for,
+        // so it is sent with that sequence number instead
+        for window_size in [1, 4] {
+            let emulator = Emulator::new().mid_transfer();
+            let config = UploadConfig::default().window_size(window_size);
+            emulator_serial(&emulator)
+                .try_do_upload(&image, &config)
+                .unwrap();
+            assert_eq!(emulator.image(), image);
+        }
+
+        // a flow of its own starts over after the board finished an upload
+        let emulator = Emulator::new();
+        let mut serial = emulator_serial(&emulator);
+        serial
+            .try_do_upload(&image, &UploadConfig::default())
+            .unwrap();
+        serial.reset_sequence();
+        assert_eq!(serial.create_packet(&[]).1, 1);
+    }
+
+    #[test]
+    fn test_lost_packet_is_sent_again() {
+        let image: Vec<u8> = (0..2000u32).map(|i| (i % 241) as u8).collect();
+
+        // the second data packet, after the ping, start and init
+        let emulator = Emulator::new().drop_frame(4);
+        let report = emulator_serial(&emulator)
+            .try_do_upload(&image, &UploadConfig::default())
+            .unwrap();
+        assert_eq!(report.retries, 1);
+        assert_eq!(emulator.image(), image);
+        // sent again as it was, with the same sequence number
+        let written = emulator.written();
+        let frames: Vec<&[u8]> = written
+            .split(|&b| b == 0xc0)
+            .filter(|f| !f.is_empty())
+            .collect();
+        assert_eq!(frames[4], frames[5]);
+        assert_ne!(frames[5], frames[6]);
+
+        let emulator = (4..8).fold(Emulator::new(), Emulator::drop_frame);
+        let err = emulator_serial(&emulator)
+            .try_do_upload(&image, &UploadConfig::default())
+            .unwrap_err();
+        assert!(format!("{err:?}").contains("sent 4 times"));
+
+        let emulator = Emulator::new().drop_frame(4);
+        let config = UploadConfig::default().max_retries(0);
+        assert!(emulator_serial(&emulator)
+            .try_do_upload(&image, &config)
+            .is_err());
+
+        // also without an upload setting it
+        let emulator = Emulator::new().drop_frame(0);
+        let mut serial = emulator_serial(&emulator);
+        serial.send_data(&image[..64]).unwrap();
+        assert_eq!(serial.retries, 1);
+    }
+
+    #[test]
+    fn test_lost_packet_while_pipelining() {
+        let image: Vec<u8> = (0..2048u32).map(|i| (i % 241) as u8).collect();
+        let config = UploadConfig::default()
+            .window_size(4)
+            .serial_timeout(Duration::from_millis(200));
+
+        // the four data packets are frames 3 to 6, after the ping, start and init
+        for (emulator, retries) in [
+            // the last data packet, which no later ack covers
+            (Emulator::new().drop_frame(6), 1),
+            // its ack
+            (Emulator::new().drop_ack(6), 1),
+            // the second data packet, and the first time it is sent again, after which the
+            // two packets behind it are sent again too
+            (Emulator::new().drop_frame(4).drop_frame(7), 4),
+        ] {
+            let report = emulator_serial(&emulator)
+                .try_do_upload(&image, &config)
+                .unwrap();
+            assert_eq!(emulator.image(), image);
+            assert_eq!(report.retries, retries);
+        }
+
+        // with as many retries as without a window
+        let emulator = (6..11).fold(Emulator::new(), Emulator::drop_frame);
+        let err = emulator_serial(&emulator)
+            .try_do_upload(&image, &config)
+            .unwrap_err();
+        assert!(format!("{err:?}").contains("sent 4 times"), "{err:?}");
+    }
+
+    #[test]
+    fn test_adaptive_packet_size() {
+        let image: Vec<u8> = (0..4096u32).map(|i| (i % 241) as u8).collect();
+        let config = UploadConfig::default()
+            .packet_size(1024)
+            .adaptive_packet_size(true);
+
+        // the first data packet only gets through the third time
+        let emulator = Emulator::new().drop_frame(3).drop_frame(4);
+        let report = emulator_serial(&emulator)
+            .try_do_upload(&image, &config)
+            .unwrap();
+        assert_eq!(emulator.image(), image);
+        assert_eq!(report.packet_sizes, [1024, 512]);
+        assert_eq!((report.chunks, report.retries), (7, 2));
+
+        // without trouble, the packets stay as they are
+        let emulator = Emulator::new();
+        let report = emulator_serial(&emulator)
+            .try_do_upload(&image, &config)
+            .unwrap();
+        assert_eq!(
+            (report.packet_sizes.as_slice(), report.chunks),
+            (&[1024][..], 4)
+        );
+
+        assert!(config.window_size(2).validate().is_err());
+    }
+
+    #[test]
+    fn test_packet_sizer() {
+        let mut sizer = PacketSizer::new(1024);
+        assert_eq!(sizer.after_packet(1), None);
+        assert_eq!(sizer.after_packet(1), Some(512));
+        assert_eq!(sizer.after_packet(3), Some(256));
+        // a clean streak makes up for it, one step at a time
+        for _ in 1..GROW_AFTER_CLEAN_PACKETS {
+            assert_eq!(sizer.after_packet(0), None);
+        }
+        assert_eq!(sizer.after_packet(0), Some(512));
+        // a retry starts the streak over
+        for _ in 1..GROW_AFTER_CLEAN_PACKETS {
+            assert_eq!(sizer.after_packet(0), None);
+        }
+        assert_eq!(sizer.after_packet(1), None);
+        for _ in 1..GROW_AFTER_CLEAN_PACKETS {
+            assert_eq!(sizer.after_packet(0), None);
+        }
+        assert_eq!(sizer.after_packet(0), Some(1024));
+        for _ in 0..2 * GROW_AFTER_CLEAN_PACKETS {
+            assert_eq!(sizer.after_packet(0), None);
+        }
+
+        // sizes stay multiples of 4, and don't go below the minimum
+        let mut sizer = PacketSizer::new(1000);
+        let sizes: Vec<_> = (0..6).filter_map(|_| sizer.after_packet(2)).collect();
+        assert_eq!(sizes, [500, 248, 124, 64]);
+        for _ in 0..GROW_AFTER_CLEAN_PACKETS * 5 {
+            sizer.after_packet(0);
+        }
+        assert_eq!(sizer.size, 1000);
+        assert_eq!(PacketSizer::new(32).after_packet(5), None);
+    }
+
+    #[test]
+    fn test_stale_acks_are_purged() {
+        let image = [0x55; 2000];
+        // the start packet and the init packet are acknowledged twice
+        let emulator = Emulator::new().duplicate_ack(1).duplicate_ack(2);
+        let report = emulator_serial(&emulator)
+            .try_do_upload(&image, &UploadConfig::default())
+            .unwrap();
+        assert_eq!(emulator.image(), image);
+        assert_eq!(report.retries, 0);
+        // nothing had to be sent again
+        let frames = emulator.written().iter().filter(|&&b| b == 0xc0).count() / 2;
+        assert_eq!(frames, 3 + report.chunks + 2);
+    }
+
+    #[test]
+    fn test_nack_and_rejection() {
+        let image = [0u8; 2000];
+
+        // asked for the second data packet again
+        let emulator = Emulator::new().nack_frame(4);
+        let report = emulator_serial(&emulator)
+            .try_do_upload(&image, &UploadConfig::default())
+            .unwrap();
+        assert_eq!(report.retries, 1);
+        assert_eq!(emulator.image(), image);
+
+        // data size exceeds limit, which is never retried
+        let emulator = Emulator::new().reject(3, 4);
+        let err = emulator_serial(&emulator)
+            .try_do_upload(&image, &UploadConfig::default())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the bootloader rejected the start packet: size too large"
+        );
+        let emulator = Emulator::new().reject(4, 6);
+        let err = emulator_serial(&emulator)
+            .try_do_upload(&image, &UploadConfig::default())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the bootloader rejected the data packet: operation failed"
+        );
+        assert_eq!(emulator.written().iter().filter(|&&b| b == 0xc0).count(), 8);
+    }
+
+    #[test]
+    fn test_init_nack_and_lost_ack() {
+        let image: Vec<u8> = (0..2000u32).map(|i| (i % 241) as u8).collect();
+        let frames = |emulator: &Emulator| -> Vec<Vec<u8>> {
+            emulator
+                .written()
+                .split(|&b| b == 0xc0)
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_vec())
+                .collect()
+        };
+
+        // asked for the init packet again, after the ping and start, while the board may still
+        // be erasing, so it isn't counted as a retry
+        let emulator = Emulator::new().nack_frame(2);
+        let report = emulator_serial(&emulator)
+            .try_do_upload(&image, &UploadConfig::default())
+            .unwrap();
+        assert_eq!(report.retries, 0);
+        assert_eq!(frames(&emulator)[2], frames(&emulator)[3]);
+        assert!(emulator.init_packet().is_some());
+        assert_eq!(emulator.image(), image);
+
+        // the first data packet arrived, but its ack didn't: the packet is sent again with the
+        // same sequence number, and the bootloader doesn't write it twice
+        let emulator = Emulator::new().drop_ack(3);
+        let report = emulator_serial(&emulator)
+            .try_do_upload(&image, &UploadConfig::default())
+            .unwrap();
+        assert_eq!(report.retries, 1);
+        assert_eq!(emulator.image(), image);
+        assert_eq!(frames(&emulator)[3], frames(&emulator)[4]);
+    }
+
+    #[test]
+    fn test_frames_in_pieces() {
+        // acks for 3 and 5, with a header checksum of 0xe8 and 0xd8
+        let ack3: &[u8] = &[0xc0, 0x18, 0, 0, 0xe8, 0xc0];
+        let ack5: &[u8] = &[0xc0, 0x28, 0, 0, 0xd8, 0xc0];
+        // a vendor frame acknowledging 3, with escaped bytes in the payload
+        let escaped = FrameEncoder::default().encode(2, &[&[0xc0, 0xdb, 1]]);
+        assert!(escaped.windows(2).any(|w| w == [0xdb, 0xdc]));
+
+        // split everywhere, also between the two bytes of an escape
+        let split = escaped.iter().position(|&b| b == 0xdb).unwrap() + 1;
+        let mut serial = serial_reading(&[
+            &ack3[..2],
+            &ack3[2..5],
+            &ack3[5..],
+            &escaped[..split],
+            &escaped[split..],
+        ]);
+        assert_eq!(serial.read_ack().unwrap(), 3);
+        assert_eq!(serial.read_ack().unwrap(), 3);
+
+        // back to back in a single read, with log output before and after
+        let together = [b"log\n".as_slice(), ack3, ack5, &escaped, b"hello"].concat();
+        let mut serial = serial_reading(&[&together]);
+        assert_eq!(serial.read_ack().unwrap(), 3);
+        assert_eq!(serial.read_ack().unwrap(), 5);
+        assert_eq!(serial.read_ack().unwrap(), 3);
+        // nothing after the last frame was read along with it
+        assert!(serial.rx_buffer.is_empty());
+        assert!(serial
+            .wait_for_banner(b"hello", Duration::from_secs(1))
+            .unwrap());
+        assert_eq!(serial.discarded_bytes, 4);
+
+        // when the rest of the escape never arrives, that's a timeout, not an invalid escape
+        let mut serial = serial_reading(&[&escaped[..split]]);
+        let err = serial.read_ack().unwrap_err();
+        assert!(err.to_string().starts_with("timed out"), "{err:?}");
+    }
+
+    #[test]
+    fn test_boot_text_before_ack() {
+        let ack3: &[u8] = &[0xc0, 0x18, 0, 0, 0xe8, 0xc0];
+        let boot = b"Bootloader v1.0\r\n";
+
+        let mut serial = serial_reading(&[&[boot.as_slice(), ack3].concat()]);
+        assert_eq!(serial.wait_for_ack().unwrap(), 3);
+        assert_eq!(serial.discarded_bytes, boot.len());
+        assert_eq!(serial.noise_sample, boot);
+
+        // also when the text comes in its own reads, or has a stray 0xc0 in it that opens a
+        // frame the ack then seems to close
+        let stray = [b"\xc0boot".as_slice(), boot].concat();
+        let mut serial = serial_reading(&[&stray[..3], &stray[3..], ack3]);
+        assert_eq!(serial.wait_for_ack().unwrap(), 3);
+        assert_eq!(serial.discarded_bytes, stray.len() - 1);
+    }
+
+    #[test]
+    fn test_continuous_garbage() {
+        let ack3: &[u8] = &[0xc0, 0x18, 0, 0, 0xe8, 0xc0];
+        let noise = vec![0x78; 2 * MAX_GARBAGE];
+
+        // it fails as soon as there is too much, instead of when the read times out
+        let mut serial = serial_reading(&[&noise]);
+        let err = serial.wait_for_ack().unwrap_err();
+        assert!(err.is::<Garbage>(), "{err:?}");
+        assert!(err
+            .to_string()
+            .starts_with("received 4096 bytes of continuous garbage, starting with 78 78"));
+
+        // also when it all goes into a frame that is never closed
+        let mut serial = serial_reading(&[&[0xc0], &noise]);
+        assert!(serial.wait_for_ack().unwrap_err().is::<Garbage>());
+
+        // and it isn't sent again for that
+        let mut serial = serial_reading(&[&noise]);
+        let err = serial.send_data(&[1]).unwrap_err();
+        assert!(
+            err.to_string().starts_with("received 4096 bytes"),
+            "{err:?}"
+        );
+
+        // a little less is fine, when a frame comes after it
+        let some = &noise[..MAX_GARBAGE - 100];
+        let mut serial = serial_reading(&[some, ack3, some, ack3]);
+        assert_eq!(serial.wait_for_ack().unwrap(), 3);
+        assert_eq!(serial.wait_for_ack().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_frame_encoder() {
+        let mut encoder = FrameEncoder::default();
+        let chunk = [1, 0xc0, 2, 0xdb];
+        let opcode = 4u32.to_le_bytes();
+        assert_eq!(
+            encoder.encode(3, &[&opcode, &chunk]),
+            encoder.encode(3, &[&data_payload(&chunk)])
+        );
+
+        // the worst case fits in what is reserved, so the buffer is never grown while encoding
+        let mut out = Vec::new();
+        encoder.encode_into(1, &[&[0xc0; 16]], &mut out);
+        assert!(out.len() > 2 * 16 && out.len() <= max_frame_size(16));
+        let (capacity, ptr) = (out.capacity(), out.as_ptr());
+        encoder.encode_into(2, &[&[7; 16]], &mut out);
+        assert_eq!((out.capacity(), out.as_ptr()), (capacity, ptr));
+        assert_eq!(out, encoder.encode(2, &[&[7; 16]]));
+    }
+
+    #[test]
+    fn test_ack_with_response() {
+        // the first packet has sequence number 1, acknowledged by a frame with status bytes
+        let reply = FrameEncoder::default().encode(1, &[&[0xaa, 0x55]]);
+        let mut serial = serial_reading(&[&reply]);
+
+        let frame = serial.send_data_with_response(&[4, 0, 0, 0]).unwrap();
+        assert_eq!(
+            frame,
+            AckFrame {
+                seq: 1,
+                ack: 2,
+                reliable: true,
+                packet_type: 14,
+                payload: vec![0xaa, 0x55],
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_and_write_after_upload() {
+        let emulator = Emulator::new();
+        let mut serial = emulator_serial(&emulator);
+        serial
+            .try_do_upload(&[0x55; 100], &UploadConfig::default())
+            .unwrap();
+        serial.reconfigure(115_200, FlowControl::None).unwrap();
+        assert_eq!(emulator.line_settings(), Some((115_200, FlowControl::None)));
+        serial.write(b"ping").unwrap();
+        assert!(emulator.written().ends_with(b"ping"));
+        assert!(serial.reconfigure(100, FlowControl::None).is_err());
+
+        // bytes that were already received but not looked at come first
+        let ack3: &[u8] = &[0xc0, 0x18, 0, 0, 0xe8, 0xc0];
+        let mut serial = serial_reading(&[ack3, b"world"]);
+        assert_eq!(serial.read_ack().unwrap(), 3);
+        serial.rx_buffer.extend(b"hello ");
+        let mut buf = [0; 16];
+        assert_eq!(serial.read(&mut buf).unwrap(), 6);
+        assert_eq!(&buf[..6], b"hello ");
+        assert_eq!(serial.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let emulator = Emulator::new();
+        #[cfg(feature = "ftdi")]
+        let serial = {
+            let mut serial = emulator_serial(&emulator);
+            assert!(serial.ftdi_mut().is_none());
+            *serial.into_ftdi().unwrap_err()
+        };
+        #[cfg(not(feature = "ftdi"))]
+        let serial = emulator_serial(&emulator);
+
+        let mut port = serial.into_inner();
+        port.write_all(b"ping").unwrap();
+        assert_eq!(emulator.written(), b"ping");
+        // the port is left as it is for whoever took it
+        drop(port);
+        assert!(emulator.control_lines().is_empty());
+    }
+
+    #[test]
+    fn test_close() {
+        let released = [(ControlLine::Dtr, false), (ControlLine::Rts, false)];
+
+        // an upload that fails before it sent anything still leaves the port behind cleanly
+        let emulator = Emulator::new().unresponsive();
+        assert!(emulator_serial(&emulator)
+            .try_do_upload(&[0; 100], &UploadConfig::default())
+            .is_err());
+        assert_eq!(emulator.control_lines(), released);
+
+        // and closing it explicitly doesn't do that again when it is dropped
+        let emulator = Emulator::new();
+        emulator_serial(&emulator).close().unwrap();
+        assert_eq!(emulator.control_lines(), released);
+
+        // transports without control lines are closed all the same
+        serial_reading(&[b"leftover"]).close().unwrap();
+    }
+
+    #[test]
+    fn test_acks_in_one_read() {
+        // acks for the first two packets, which have sequence numbers 1 and 2
+        let acks: &[u8] = &[0xc0, 0x10, 0, 0, 0xf0, 0xc0, 0xc0, 0x18, 0, 0, 0xe8, 0xc0];
+        let clock = Arc::new(FakeClock::new());
+        let mut serial = Serial::with_transport(
+            PathBuf::from("/dev/pieces"),
+            Box::new(Pieces([acks.to_vec()].into())),
+            clock.clone(),
+        );
+
+        // the second ack has already arrived when the first one is read, and isn't lost
+        serial.send_data(&[1]).unwrap();
+        serial.send_data(&[2]).unwrap();
+        assert_eq!(clock.elapsed(), 2 * DEFAULT_PACKET_DELAY);
+
+        // also not when it was read along with the first, like from a driver that hands out
+        // everything it has
+        let mut serial = serial_reading(&[]);
+        serial.rx_buffer.extend(acks);
+        serial.send_data(&[1]).unwrap();
+        assert_eq!(serial.rx_buffer.len(), 6);
+        serial.send_data(&[2]).unwrap();
+    }
+
+    #[test]
+    fn test_packet_size() {
+        let image: Vec<u8> = (0..5000u32).map(|i| (i % 239) as u8).collect();
+        let config = UploadConfig::default().packet_size(1024);
+        let emulator = Emulator::new();
+        let report = emulator_serial(&emulator)
+            .try_do_upload(&image, &config)
+            .unwrap();
+        assert_eq!(report.chunks, 5);
+        assert_eq!(report.bytes, 5000);
+        assert_eq!(report.retries, 0);
+        assert_eq!(
+            report.crc16.to_le_bytes(),
+            InitPacket::parse(&emulator.init_packet().unwrap())
+                .unwrap()
+                .1[..2]
+        );
+        assert_eq!(emulator.image(), image);
+
+        // the opcode and the chunk have to fit the 12-bit length in the header
+        assert!(UploadConfig::default().packet_size(4091).validate().is_ok());
+        assert!(UploadConfig::default()
+            .packet_size(4092)
+            .validate()
+            .is_err());
+        assert!(UploadConfig::default().packet_size(0).validate().is_err());
+    }
+
+    #[test]
+    fn test_corrupted_frames_are_skipped() {
+        // a frame with a CRC acknowledging 3, one bit of its payload flipped
+        let mut corrupted = FrameEncoder::default().encode(2, &[&[1, 2, 3]]);
+        corrupted[6] ^= 0x01;
+        // an ack for 5 with a flipped sequence bit, which breaks the header checksum instead
+        let flipped: &[u8] = &[0xc0, 0x28 ^ 0x08, 0, 0, 0xd8, 0xc0];
+        let ack5: &[u8] = &[0xc0, 0x28, 0, 0, 0xd8, 0xc0];
+
+        let mut serial = serial_reading(&[&corrupted, flipped, ack5]);
+        assert_eq!(serial.read_ack().unwrap(), 5);
+        assert_eq!(
+            serial.discarded_bytes,
+            corrupted.len() - 2 + flipped.len() - 2
+        );
+
+        // and when only garbage arrives, it's a timeout like when nothing arrives
+        let mut serial = serial_reading(&[&corrupted]);
+        let err = serial.read_ack().unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err:?}");
+
+        // an upload doesn't send anything again for them
+        let image = vec![0x42; 3000];
+        for window_size in [1, 4] {
+            let emulator = Emulator::new().corrupt_ack(1).corrupt_ack(4);
+            let report = emulator_serial(&emulator)
+                .try_do_upload(&image, &UploadConfig::default().window_size(window_size))
+                .unwrap();
+            assert_eq!(emulator.image(), image);
+            assert_eq!(report.retries, 0);
+            assert_eq!(report.discarded_bytes, 2 * 4);
+        }
+    }
+
+    #[test]
+    fn test_abort_without_response() {
+        let emulator = Emulator::new().drop_frame(0);
+        assert!(!emulator_serial(&emulator).abort().unwrap());
+    }
+
+    #[test]
+    fn test_abort_with_config() {
+        let timeout = Duration::from_millis(300);
+        let config = UploadConfig::default()
+            .serial_timeout(timeout)
+            .reset_before_upload(ControlLine::Dtr, Duration::from_millis(10));
+
+        let emulator = Emulator::new().mid_transfer();
+        assert!(emulator_serial(&emulator)
+            .abort_with_config(&config)
+            .unwrap());
+        assert_eq!(emulator.timeouts()[0], (timeout, timeout));
+        assert_eq!(
+            emulator.control_lines()[..2],
+            [(ControlLine::Dtr, true), (ControlLine::Dtr, false)]
+        );
+
+        // nothing to reset when no bootloader answered
+        let emulator = Emulator::new().drop_frame(0);
+        assert!(!emulator_serial(&emulator)
+            .abort_with_config(&config)
+            .unwrap());
+        assert!(!emulator.control_lines().contains(&(ControlLine::Dtr, true)));
+    }
+
+    #[test]
+    fn test_empty_reads_time_out() {
+        let clock = Arc::new(FakeClock::new());
+        // reads return immediately with nothing, like D2XX does after its read timeout
+        let emulator = Emulator::new().unresponsive();
+        let mut serial = Serial::with_transport(
+            PathBuf::from("/dev/emulator"),
+            Box::new(emulator),
+            clock.clone(),
+        );
+
+        let config = UploadConfig::default().ping(false);
+        let err = serial.try_do_upload(&[0; 100], &config).unwrap_err();
+        assert!(format!("{err:?}").contains("timed out after 5.0s"));
+        assert!(clock.elapsed() < SERIAL_TIMEOUT + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_upload_bootloader() {
+        let image: Vec<u8> = (0..3000u32).map(|i| (i % 247) as u8).collect();
+        let config = UploadConfig::default().image_type(ImageType::Bootloader);
+        let emulator = upload_to_emulator(&image, &config);
+        assert_eq!(emulator.image_size(), Some(3000));
+        assert_eq!(emulator.image(), image);
+        // the mode word of the start packet
+        assert!(emulator
+            .written()
+            .windows(8)
+            .any(|w| w == [3, 0, 0, 0, 2, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_custom_init_packet() {
+        let packet = InitPacket {
+            device_type: 0x0052,
+            device_revision: 1,
+            app_version: 7,
+            ..InitPacket::default()
+        };
+        let config = UploadConfig::default().init_packet(packet.clone());
+        let emulator = upload_to_emulator(&[0x55; 100], &config);
+        let sent = emulator.init_packet().unwrap();
+        assert_eq!(InitPacket::parse(&sent).unwrap().0, packet);
+    }
+
+    #[test]
+    fn test_integrity_check() {
+        let image = [0x55; 100];
+        let config = UploadConfig::default().integrity_check(IntegrityCheck::Crc32);
+        let emulator = upload_to_emulator(&image, &config);
+        let sent = emulator.init_packet().unwrap();
+        assert_eq!(
+            InitPacket::parse(&sent).unwrap().1,
+            calc_crc32(&image).to_le_bytes()
+        );
+
+        let emulator = Emulator::new().reject(1, 5);
+        let err = emulator_serial(&emulator)
+            .try_do_upload(&image, &UploadConfig::default())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the bootloader rejected the init packet: CRC error"
+        );
+    }
+
+    #[test]
+    fn test_ping() {
+        let clock = Arc::new(FakeClock::new());
+        let mut serial = Serial::with_transport(
+            PathBuf::from("/dev/emulator"),
+            Box::new(Emulator::new().unresponsive()),
+            clock.clone(),
+        );
+        let err = serial
+            .try_do_upload(&[0; 100], &UploadConfig::default())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "the board is not in bootloader mode");
+        assert!(clock.elapsed() < Duration::from_secs(1));
+
+        // a bootloader that is waiting for the rest of an earlier upload answers as well
+        emulator_serial(&Emulator::new().mid_transfer())
+            .ping()
+            .unwrap();
+        // and so does one that asks for the frame again
+        emulator_serial(&Emulator::new().nack_frame(0))
+            .ping()
+            .unwrap();
+
+        // what comes back over a loopback isn't a bootloader either
+        let err = emulator_serial(&Emulator::new().loopback())
+            .ping()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "the board is not in bootloader mode");
+        assert!(err.is::<Echoed>());
+    }
+
+    #[test]
+    fn test_configured_timeout() {
+        let clock = Arc::new(FakeClock::new());
+        let emulator = Emulator::new().unresponsive();
+        let mut serial = Serial::with_transport(
+            PathBuf::from("/dev/emulator"),
+            Box::new(emulator.clone()),
+            clock.clone(),
+        );
+        let timeout = Duration::from_secs(1);
+
+        let config = UploadConfig::default().serial_timeout(timeout).ping(false);
+        let err = serial.try_do_upload(&[0; 100], &config).unwrap_err();
+        assert!(format!("{err:?}").contains("timed out after 1.0s"));
+        assert!(clock.elapsed() < timeout * 2);
+        assert_eq!(emulator.timeouts(), [(timeout, timeout)]);
+        assert_eq!(serial.ack_warning_after, timeout);
+        assert!(serial.warned_about_timeout);
+
+        // a temporarily shorter timeout doesn't make the warning come earlier
+        serial.warned_about_timeout = false;
+        let res = serial.with_read_timeout(Duration::from_millis(10), |s| s.wait_for_ack());
+        assert!(res.is_err());
+        assert_eq!(serial.ack_warning_after, timeout);
+        assert!(!serial.warned_about_timeout);
+    }
+
+    #[test]
+    fn test_latency_timer() {
+        let emulator = upload_to_emulator(&[0; 100], &UploadConfig::default());
+        assert_eq!(emulator.latency_timer(), Some(Duration::from_millis(2)));
+
+        let config = UploadConfig::default().latency_timer(Duration::from_millis(16));
+        let emulator = upload_to_emulator(&[0; 100], &config);
+        assert_eq!(emulator.latency_timer(), Some(Duration::from_millis(16)));
+
+        let config = UploadConfig::default().latency_timer(Duration::from_millis(300));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_read_timeout_is_restored() {
+        let emulator = Emulator::new();
+        let mut serial = emulator_serial(&emulator);
+        let short = Duration::from_millis(200);
+
+        serial
+            .with_read_timeout(short, |s| DfuSession::new(s).send_start(100))
+            .unwrap();
+        // no response, but the timeout is restored all the same
+        let err = serial.with_read_timeout(short, |s| {
+            s.port.write_all(b"nothing")?;
+            s.wait_for_ack()
+        });
+        assert!(err.is_err());
+
+        serial
+            .set_timeouts(Duration::from_secs(1), SERIAL_TIMEOUT)
+            .unwrap();
+        assert_eq!(
+            emulator.timeouts(),
+            [
+                (short, SERIAL_TIMEOUT),
+                (SERIAL_TIMEOUT, SERIAL_TIMEOUT),
+                (short, SERIAL_TIMEOUT),
+                (SERIAL_TIMEOUT, SERIAL_TIMEOUT),
+                (Duration::from_secs(1), SERIAL_TIMEOUT),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_phase_timings() {
+        let clock = Arc::new(FakeClock::new());
+        let emulator = Emulator::new().banner(b"hello");
+        let mut serial = Serial::with_transport(
+            PathBuf::from("/dev/emulator"),
+            Box::new(emulator),
+            clock.clone(),
+        );
+
+        let config =
+            UploadConfig::default().expect_banner(b"hello".to_vec(), Duration::from_secs(1));
+        let report = serial.try_do_upload(&[0; 2000], &config).unwrap();
+
+        let phases: Vec<_> = report.phases.iter().map(|p| p.phase).collect();
+        assert_eq!(
+            phases,
+            [
+                Phase::Start,
+                Phase::Init,
+                Phase::Data,
+                Phase::Stop,
+                Phase::Activate,
+                Phase::Banner
+            ]
+        );
+        // the fixed wait after the init packet
+        assert!(report.phases[1].duration >= Duration::from_secs(1));
+        assert_eq!(
+            report.phases.iter().map(|p| p.duration).sum::<Duration>(),
+            clock.elapsed()
+        );
+        assert_eq!(report.phase_table().lines().count(), 7);
+    }
+
+    #[test]
+    fn test_init_wait() {
+        let init_phase_on = |emulator: &Emulator, config: &UploadConfig| {
+            let mut serial = emulator_serial(emulator);
+            let report = serial.try_do_upload(&[0; 100], config).unwrap();
+            assert_eq!(report.phases[1].phase, Phase::Init);
+            report.phases[1].duration
+        };
+        let init_phase = |config: &UploadConfig| init_phase_on(&Emulator::new(), config);
+
+        assert_eq!(init_phase(&UploadConfig::default()), Duration::from_secs(1));
+        let wait = Duration::from_millis(50);
+        assert_eq!(init_phase(&UploadConfig::default().init_wait(wait)), wait);
+        assert_eq!(
+            init_phase(&UploadConfig::default().init_wait(Duration::ZERO)),
+            Duration::ZERO
+        );
+
+        // a bootloader that says when it is ready isn't waited for any longer
+        let config = UploadConfig::default();
+        let emulator = Emulator::new().init_response();
+        assert_eq!(init_phase_on(&emulator, &config), Duration::ZERO);
+        assert_eq!(emulator.image(), [0; 100]);
+    }
+
+    #[test]
+    fn test_packet_delay() {
+        let data_phase = |config: &UploadConfig| {
+            let mut serial = emulator_serial(&Emulator::new());
+            let report = serial.try_do_upload(&[0; 2048], config).unwrap();
+            report.phases[2].duration
+        };
+
+        // by default it waits after every one of the 4 data packets
+        assert_eq!(
+            data_phase(&UploadConfig::default()),
+            DEFAULT_PACKET_DELAY * 4
+        );
+        let delay = Duration::from_millis(5);
+        assert_eq!(
+            data_phase(&UploadConfig::default().packet_delay(delay)),
+            delay * 4
+        );
+        // and with no delay the data packets are sent as soon as the previous one is acknowledged
+        assert_eq!(
+            data_phase(&UploadConfig::default().packet_delay(Duration::ZERO)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let image = [0x5a; 2048];
+        // the second data packet only gets through the third time
+        let data_phase = |config: &UploadConfig| {
+            let emulator = Emulator::new().drop_frame(4).drop_frame(5);
+            let mut serial = emulator_serial(&emulator);
+            let report = serial.try_do_upload(&image, config).unwrap();
+            assert_eq!(emulator.image(), image);
+            assert_eq!(report.retries, 2);
+            report.phases[2].duration
+        };
+
+        // 20ms before the second attempt and 80ms before the third, on top of the timeouts
+        let config = UploadConfig::default().serial_timeout(Duration::from_millis(200));
+        let right_away = data_phase(&config.clone().retry_backoff(Duration::ZERO, 4));
+        assert_eq!(data_phase(&config) - right_away, Duration::from_millis(100));
+        assert_eq!(
+            data_phase(&config.clone().retry_backoff(Duration::from_millis(10), 2)) - right_away,
+            Duration::from_millis(30)
+        );
+
+        assert!(config.retry_backoff(Duration::ZERO, 0).validate().is_err());
     }
 }
@@ -0,0 +1,596 @@
+//! The SLIP framing of the HCI packets that go to and from the bootloader.
+//!
+//! Every frame is a 4 byte [`Header`], the payload and a CRC16 of both, escaped and between two
+//! [`END`]s. [`encode_frame`] builds the frames the host sends and [`decode_frame`] checks and
+//! takes apart any frame, for tools and tests that need to speak the same framing.
+//!
+//! For a description of the header go to:
+//! <http://developer.nordicsemi.com/nRF51_SDK/doc/7.2.0/s110/html/a00093.html>
+
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+
+use crate::crc::calc_crc16_default;
+use crate::hci::VENDOR_PACKET;
+
+/// Frames start and end with this byte.
+pub const END: u8 = 0xc0;
+/// Starts an escape sequence, for an [`END`] or [`ESC`] in a frame.
+pub const ESC: u8 = 0xdb;
+/// After an [`ESC`]: an [`END`] that is part of the frame.
+pub const ESC_END: u8 = 0xdc;
+/// After an [`ESC`]: an [`ESC`] that is part of the frame.
+pub const ESC_ESC: u8 = 0xdd;
+
+/// The longest payload the 12 bit length in the header can describe.
+pub const MAX_PAYLOAD_SIZE: usize = 0xfff;
+
+/// The size of the smallest frame, an ack: a 4 byte header between two [`END`]s.
+const MIN_FRAME_SIZE: usize = 6;
+
+/// The bit fields of the 4 byte header in front of every frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// The sequence number of the frame, 0 to 7.
+    pub seq: u8,
+    /// The sequence number of the frame the sender expects next, 0 to 7.
+    pub ack: u8,
+    /// Whether a CRC16 follows the payload.
+    pub data_integrity: bool,
+    /// Whether the frame has to be acknowledged.
+    pub reliable: bool,
+    /// 0 for a plain ack, 14 for a DFU packet and 15 for link control.
+    pub packet_type: u8,
+    /// The number of bytes of payload, at most [`MAX_PAYLOAD_SIZE`].
+    pub len: usize,
+}
+
+impl Header {
+    /// The header of the frames the host sends: a reliable DFU packet with a CRC, which
+    /// acknowledges the frame after itself. The bootloader doesn't look at that ack number,
+    /// and the reference implementation derives it from `seq` like this as well, instead of
+    /// from what the bootloader sent.
+    pub fn for_packet(seq: u8, len: usize) -> Self {
+        Self {
+            seq,
+            ack: (seq + 1) % 8,
+            data_integrity: true,
+            reliable: true,
+            packet_type: VENDOR_PACKET,
+            len,
+        }
+    }
+
+    /// The header as it is sent, with the checksum that makes its bytes add up to 0.
+    ///
+    /// # Panics
+    ///
+    /// When a field doesn't fit in its bits.
+    pub fn to_bytes(self) -> [u8; 4] {
+        assert!(self.seq < 8 && self.ack < 8 && self.packet_type < 16);
+        assert!(self.len <= MAX_PAYLOAD_SIZE);
+
+        let b1 = self.seq
+            | self.ack << 3
+            | (self.data_integrity as u8) << 6
+            | (self.reliable as u8) << 7;
+        let b2 = self.packet_type | ((self.len & 0x00f) << 4) as u8;
+        let b3 = ((self.len & 0xff0) >> 4) as u8;
+
+        [
+            b1,
+            b2,
+            b3,
+            (!b1.wrapping_add(b2).wrapping_add(b3)).wrapping_add(1),
+        ]
+    }
+
+    /// Read a header, which fails when its checksum is wrong.
+    pub fn parse(bytes: [u8; 4]) -> Result<Self> {
+        if bytes.iter().fold(0u8, |a, &b| a.wrapping_add(b)) != 0 {
+            bail!("received a frame with an invalid header checksum");
+        }
+        Ok(Self {
+            seq: bytes[0] & 0x07,
+            ack: bytes[0] >> 3 & 0x07,
+            data_integrity: bytes[0] & 0x40 != 0,
+            reliable: bytes[0] & 0x80 != 0,
+            packet_type: bytes[1] & 0x0f,
+            len: (bytes[1] >> 4) as usize | (bytes[2] as usize) << 4,
+        })
+    }
+}
+
+/// A frame that was taken apart by [`decode_frame`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedFrame {
+    pub header: Header,
+    /// The bytes after the header, without the CRC.
+    pub payload: Vec<u8>,
+}
+
+/// Append `unescaped` to `out`, escaped and between two [`END`]s.
+pub fn escape(unescaped: &[u8], out: &mut Vec<u8>) {
+    out.push(END);
+    for &b in unescaped {
+        match b {
+            END => out.extend_from_slice(&[ESC, ESC_END]),
+            ESC => out.extend_from_slice(&[ESC, ESC_ESC]),
+            b => out.push(b),
+        }
+    }
+    out.push(END);
+}
+
+/// Undo the escaping of the bytes between two [`END`]s, which fails on an [`ESC`] that isn't
+/// followed by [`ESC_END`] or [`ESC_ESC`].
+pub fn unescape(escaped: &[u8]) -> Result<Vec<u8>> {
+    let mut res = Vec::with_capacity(escaped.len());
+
+    let mut iter = escaped.iter();
+    while let Some(&byte) = iter.next() {
+        res.push(match byte {
+            ESC => match iter.next() {
+                Some(&ESC_END) => END,
+                Some(&ESC_ESC) => ESC,
+                i => bail!("encountered invalid byte '{i:?}' after escape character"),
+            },
+            i => i,
+        });
+    }
+
+    Ok(res)
+}
+
+/// Undo the escaping of as much of the start of `escaped` as fits in `out`, and return how many
+/// bytes that is. For reading the header of a frame without unescaping (and copying) all of it.
+/// Stops early at an [`ESC`] that isn't followed by [`ESC_END`] or [`ESC_ESC`].
+pub(crate) fn unescape_into(escaped: &[u8], out: &mut [u8]) -> usize {
+    let mut iter = escaped.iter();
+    let mut n = 0;
+    while n < out.len() {
+        out[n] = match iter.next() {
+            Some(&ESC) => match iter.next() {
+                Some(&ESC_END) => END,
+                Some(&ESC_ESC) => ESC,
+                _ => break,
+            },
+            Some(&b) => b,
+            None => break,
+        };
+        n += 1;
+    }
+    n
+}
+
+/// Append the frame with `parts` one after the other as its payload to `out`, with the header
+/// and the CRC like [`encode_frame`], but not escaped.
+pub(crate) fn frame_into(seq: u8, parts: &[&[u8]], out: &mut Vec<u8>) {
+    let len = parts.iter().map(|p| p.len()).sum();
+    out.extend_from_slice(&Header::for_packet(seq, len).to_bytes());
+    for part in parts {
+        out.extend_from_slice(part);
+    }
+    let crc = calc_crc16_default(out);
+    out.extend_from_slice(&crc.to_le_bytes());
+}
+
+/// The frame with sequence number `seq` that carries `payload` to the bootloader, as it goes over
+/// the wire: with the [header](Header::for_packet) and CRC, escaped and between two [`END`]s.
+///
+/// # Panics
+///
+/// When `seq` is 8 or more, or the payload is longer than [`MAX_PAYLOAD_SIZE`].
+pub fn encode_frame(seq: u8, payload: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(4 + payload.len() + 2);
+    frame_into(seq, &[payload], &mut unescaped);
+    let mut out = Vec::with_capacity(2 * unescaped.len() + 2);
+    escape(&unescaped, &mut out);
+    out
+}
+
+/// Check and take apart a frame as it went over the wire, with or without the [`END`]s around it.
+/// Fails when the escaping, the header checksum, the length or the CRC is wrong.
+pub fn decode_frame(frame: &[u8]) -> Result<DecodedFrame> {
+    let frame = frame.strip_prefix(&[END]).unwrap_or(frame);
+    let frame = frame.strip_suffix(&[END]).unwrap_or(frame);
+    decode_unescaped(&unescape(frame)?)
+}
+
+/// Like [`decode_frame`], for a frame that was unescaped already.
+pub(crate) fn decode_unescaped(frame: &[u8]) -> Result<DecodedFrame> {
+    let Some((header, rest)) = frame.split_first_chunk::<4>() else {
+        bail!("received a frame of only {} bytes", frame.len());
+    };
+    let header = Header::parse(*header)?;
+
+    let len = header.len;
+    let crc_len = if header.data_integrity { 2 } else { 0 };
+    if rest.len() != len + crc_len {
+        bail!(
+            "received a frame with {} bytes after the header, which says it has {len}",
+            rest.len()
+        );
+    }
+    if header.data_integrity {
+        let crc = u16::from_le_bytes([rest[len], rest[len + 1]]);
+        if calc_crc16_default(&frame[..4 + len]) != crc {
+            bail!("received a frame with an invalid CRC");
+        }
+    }
+
+    Ok(DecodedFrame {
+        header,
+        payload: rest[..len].to_vec(),
+    })
+}
+
+/// What a byte that was fed to a [`SlipDecoder`] completed.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Decoded {
+    /// A byte from before the first [`END`], which can't be part of a frame.
+    Noise(u8),
+    /// The unescaped contents of a frame.
+    Frame(Vec<u8>),
+    /// Bytes between two [`END`]s with an invalid escape sequence in them.
+    Invalid(Vec<u8>),
+}
+
+/// Decodes SLIP frames from bytes that arrive in any pieces.
+///
+/// Every [`END`] ends the frame before it and starts the next one, so after log output that
+/// happens to contain an [`END`], the decoder is back in sync at the next frame. What comes
+/// between two frames is then returned as a frame as well, which is for the caller to reject.
+#[derive(Debug, Default)]
+pub(crate) struct SlipDecoder {
+    /// Whether an [`END`] was seen since the decoder was created or reset.
+    synced: bool,
+    /// Whether the last byte was an [`ESC`].
+    escaped: bool,
+    /// Whether the frame being decoded had an invalid escape sequence.
+    invalid: bool,
+    /// The frame being decoded, unescaped.
+    frame: Vec<u8>,
+    /// The frame being decoded, as it was received.
+    raw: Vec<u8>,
+}
+
+impl SlipDecoder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget everything that was fed before, like after the input of the port was cleared.
+    pub(crate) fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Feed the next byte. Empty frames, between two [`END`]s in a row, are skipped.
+    pub(crate) fn push(&mut self, byte: u8) -> Option<Decoded> {
+        if !self.synced {
+            if byte == END {
+                self.synced = true;
+                return None;
+            }
+            return Some(Decoded::Noise(byte));
+        }
+
+        if byte == END {
+            let frame = std::mem::take(&mut self.frame);
+            let raw = std::mem::take(&mut self.raw);
+            let invalid = self.invalid || self.escaped;
+            self.escaped = false;
+            self.invalid = false;
+            return match (raw.is_empty(), invalid) {
+                (true, _) => None,
+                (false, true) => Some(Decoded::Invalid(raw)),
+                (false, false) => Some(Decoded::Frame(frame)),
+            };
+        }
+
+        self.raw.push(byte);
+        if self.escaped {
+            self.escaped = false;
+            match byte {
+                ESC_END => self.frame.push(END),
+                ESC_ESC => self.frame.push(ESC),
+                _ => self.invalid = true,
+            }
+        } else if byte == ESC {
+            self.escaped = true;
+        } else {
+            self.frame.push(byte);
+        }
+        None
+    }
+
+    /// The fewest bytes that can still be missing from the frame being decoded, counting the
+    /// [`END`] after it. Reading no more than this never reads into whatever follows the frame.
+    pub(crate) fn bytes_missing(&self) -> usize {
+        if !self.synced {
+            return MIN_FRAME_SIZE;
+        }
+
+        let size = match self.frame.get(..4) {
+            Some(header) => {
+                let len = (header[1] >> 4) as usize | (header[2] as usize) << 4;
+                let crc = if header[0] & 0x40 != 0 { 2 } else { 0 };
+                4 + len + crc
+            }
+            None => 4,
+        };
+        size.saturating_sub(self.frame.len()) + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_frame, decode_unescaped, encode_frame, escape, unescape, unescape_into, Decoded,
+        Header, SlipDecoder, END, ESC, ESC_END, ESC_ESC, MAX_PAYLOAD_SIZE,
+    };
+    use crate::bench::xorshift;
+    use crate::crc::calc_crc16_default;
+    use crate::hci::Received;
+
+    fn escaped(unescaped: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        escape(unescaped, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_header() {
+        for seq in 0..8 {
+            for ack in 0..8 {
+                for packet_type in [0, 14, 15] {
+                    for len in [0, 1, 15, 16, 0x123, MAX_PAYLOAD_SIZE] {
+                        let header = Header {
+                            seq,
+                            ack,
+                            data_integrity: len % 2 == 0,
+                            reliable: seq % 2 == 0,
+                            packet_type,
+                            len,
+                        };
+                        let bytes = header.to_bytes();
+                        assert_eq!(bytes.iter().fold(0u8, |a, &b| a.wrapping_add(b)), 0);
+                        assert_eq!(Header::parse(bytes).unwrap(), header);
+                    }
+                }
+            }
+        }
+
+        // a start packet with sequence number 1, as the host sends it
+        assert_eq!(
+            Header::for_packet(1, 20).to_bytes(),
+            [0xd1, 0x4e, 0x01, 0xe0]
+        );
+        assert_eq!(Header::for_packet(7, 0).ack, 0);
+
+        let mut bytes = Header::for_packet(3, 5).to_bytes();
+        bytes[2] ^= 0x10;
+        assert_eq!(
+            Header::parse(bytes).unwrap_err().to_string(),
+            "received a frame with an invalid header checksum"
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_header_too_long() {
+        Header::for_packet(0, MAX_PAYLOAD_SIZE + 1).to_bytes();
+    }
+
+    #[test]
+    fn test_unescape_into() {
+        let escaped = [1, ESC, ESC_END, 2, ESC, ESC_ESC, 3];
+        let mut out = [0; 8];
+        assert_eq!(unescape_into(&escaped, &mut out), 5);
+        assert_eq!(out[..5], [1, END, 2, ESC, 3]);
+
+        // only as much as fits, and nothing past an escape sequence that got cut off
+        let mut head = [0; 2];
+        assert_eq!(unescape_into(&escaped, &mut head), 2);
+        assert_eq!(head, [1, END]);
+        assert_eq!(unescape_into(&[1, ESC], &mut out), 1);
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escaped(&[]), [END, END]);
+        assert_eq!(escaped(&[1, 2, 3]), [END, 1, 2, 3, END]);
+        assert_eq!(escaped(&[END]), [END, ESC, ESC_END, END]);
+        assert_eq!(escaped(&[ESC]), [END, ESC, ESC_ESC, END]);
+        // the bytes of an escape sequence themselves, which aren't escaped on their own
+        assert_eq!(
+            escaped(&[ESC, ESC_END, ESC_ESC, END, END]),
+            [END, ESC, ESC_ESC, ESC_END, ESC_ESC, ESC, ESC_END, ESC, ESC_END, END]
+        );
+
+        // every byte survives the round trip, in any order
+        let all: Vec<u8> = (0..=255).chain((0..=255).rev()).collect();
+        let out = escaped(&all);
+        assert_eq!(out.len(), all.len() + 4 + 2);
+        assert!(!out[1..out.len() - 1].contains(&END));
+        assert_eq!(unescape(&out[1..out.len() - 1]).unwrap(), all);
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape(&[]).unwrap(), [0u8; 0]);
+        assert_eq!(unescape(&[ESC, ESC_END, ESC, ESC_ESC]).unwrap(), [END, ESC]);
+        // only directly after an ESC, ESC_END and ESC_ESC mean something
+        assert_eq!(unescape(&[ESC_END, ESC_ESC]).unwrap(), [ESC_END, ESC_ESC]);
+
+        assert!(unescape(&[ESC, 0x01]).is_err());
+        assert!(unescape(&[ESC, ESC]).is_err());
+        assert!(unescape(&[0x01, ESC]).is_err());
+    }
+
+    #[test]
+    fn test_encode_and_decode_frame() {
+        let payload = [3, 0, 0, 0, END, ESC, 0xaa];
+        let frame = encode_frame(5, &payload);
+        assert_eq!((frame[0], frame[frame.len() - 1]), (END, END));
+        assert_eq!(frame.iter().filter(|&&b| b == END).count(), 2);
+
+        let decoded = decode_frame(&frame).unwrap();
+        assert_eq!(decoded.header, Header::for_packet(5, payload.len()));
+        assert_eq!(decoded.payload, payload);
+        // or without the ENDs, like the frames the decoder splits off
+        assert_eq!(decode_frame(&frame[1..frame.len() - 1]).unwrap(), decoded);
+
+        // a header and CRC that need escaping themselves: find a payload whose CRC has an END
+        // and an ESC in it
+        let payload = (0..=u16::MAX)
+            .map(|i| i.to_le_bytes())
+            .find(|p| {
+                let mut unescaped = Header::for_packet(0, 2).to_bytes().to_vec();
+                unescaped.extend_from_slice(p);
+                let crc = calc_crc16_default(&unescaped).to_le_bytes();
+                crc.contains(&END) && crc.contains(&ESC)
+            })
+            .unwrap();
+        let frame = encode_frame(0, &payload);
+        assert_eq!(
+            frame.len(),
+            2 + 4 + 2 + 2 * 2 + payload.iter().filter(|&&b| b == END || b == ESC).count()
+        );
+        assert_eq!(decode_frame(&frame).unwrap().payload, payload);
+
+        for len in [0, 1, 512, MAX_PAYLOAD_SIZE] {
+            let payload: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+            assert_eq!(
+                decode_frame(&encode_frame(2, &payload)).unwrap().payload,
+                payload
+            );
+        }
+    }
+
+    #[test]
+    fn test_arbitrary_input_never_panics() {
+        // the starting corpus of the fuzz targets, and then short inputs made mostly of the bytes
+        // that mean something to the framing
+        let mut inputs: Vec<Vec<u8>> = vec![vec![], vec![END], vec![END, END], vec![END, ESC]];
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = || xorshift(&mut state);
+        for _ in 0..20_000 {
+            let len = random() as usize % 40;
+            let symbols = [END, ESC, ESC_END, ESC_ESC, random() as u8];
+            inputs.push(
+                (0..len)
+                    .map(|_| symbols[random() as usize % symbols.len()])
+                    .collect(),
+            );
+        }
+
+        for input in inputs {
+            let _ = decode_frame(&input);
+            let mut decoder = SlipDecoder::new();
+            for &b in &input {
+                decoder.bytes_missing();
+                if let Some(Decoded::Frame(frame)) = decoder.push(b) {
+                    let _ = Received::decode(&frame);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_invalid_frames() {
+        let frame = encode_frame(1, &[1, 2, 3]);
+        let error = |frame: &[u8]| decode_frame(frame).unwrap_err().to_string();
+
+        let mut corrupted = frame.clone();
+        corrupted[6] ^= 1;
+        assert_eq!(error(&corrupted), "received a frame with an invalid CRC");
+        let mut corrupted = frame.clone();
+        corrupted[2] ^= 1;
+        assert_eq!(
+            error(&corrupted),
+            "received a frame with an invalid header checksum"
+        );
+        assert_eq!(
+            error(&frame[..frame.len() - 2]),
+            "received a frame with 4 bytes after the header, which says it has 3"
+        );
+        assert_eq!(error(&[END, 1, 2, END]), "received a frame of only 2 bytes");
+        assert!(error(&[END, 1, ESC, 2, 3, 4, END]).contains("after escape character"));
+
+        // frames without a CRC, like the acks of the bootloader
+        let ack = Header {
+            seq: 0,
+            ack: 3,
+            data_integrity: false,
+            reliable: false,
+            packet_type: 0,
+            len: 0,
+        };
+        let decoded = decode_unescaped(&ack.to_bytes()).unwrap();
+        assert_eq!((decoded.header, decoded.payload), (ack, vec![]));
+        let mut with_crc = ack.to_bytes().to_vec();
+        with_crc.extend_from_slice(&[0, 0]);
+        assert!(decode_unescaped(&with_crc).is_err());
+    }
+
+    fn decode(decoder: &mut SlipDecoder, bytes: &[u8]) -> Vec<Decoded> {
+        bytes.iter().filter_map(|&b| decoder.push(b)).collect()
+    }
+
+    #[test]
+    fn test_pathological_streams() {
+        let mut decoder = SlipDecoder::new();
+        assert_eq!(
+            decode(&mut decoder, b"ok\xc0\x01\x02\xc0\xc0\xc0\x03\xc0"),
+            [
+                Decoded::Noise(b'o'),
+                Decoded::Noise(b'k'),
+                Decoded::Frame(vec![1, 2]),
+                Decoded::Frame(vec![3]),
+            ]
+        );
+
+        // an escape sequence split over two pieces
+        assert_eq!(decode(&mut decoder, b"\x04\xdb"), []);
+        assert_eq!(
+            decode(&mut decoder, b"\xdc\xdb\xdd\xc0"),
+            [Decoded::Frame(vec![4, 0xc0, 0xdb])]
+        );
+
+        // log output with an END in it turns into a frame of its own, after which the decoder
+        // is in sync again
+        assert_eq!(
+            decode(&mut decoder, b"boot\xc0\x05\xc0"),
+            [Decoded::Frame(b"boot".to_vec()), Decoded::Frame(vec![5])]
+        );
+
+        // invalid escapes, and a frame that ends while an escape is pending
+        assert_eq!(
+            decode(&mut decoder, b"\x06\xdb\x07\xc0\x08\xdb\xc0\x09\xc0"),
+            [
+                Decoded::Invalid(vec![6, 0xdb, 7]),
+                Decoded::Invalid(vec![8, 0xdb]),
+                Decoded::Frame(vec![9]),
+            ]
+        );
+
+        decoder.reset();
+        assert_eq!(decode(&mut decoder, b"\x0a\xc0"), [Decoded::Noise(10)]);
+    }
+
+    #[test]
+    fn test_bytes_missing() {
+        let mut decoder = SlipDecoder::new();
+        assert_eq!(decoder.bytes_missing(), 6);
+
+        decoder.push(0xc0);
+        assert_eq!(decoder.bytes_missing(), 5);
+
+        // a header with a CRC and 3 bytes of payload, of which one escaped byte has arrived
+        for b in [0x4a, 0x3e, 0x00, 0x78, 0xdb, 0xdc] {
+            decoder.push(b);
+        }
+        assert_eq!(decoder.bytes_missing(), 2 + 2 + 1);
+    }
+}
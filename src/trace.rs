@@ -0,0 +1,203 @@
+//! Tracing the frames that go over the port, for debugging the protocol.
+//!
+//! Every frame can be handed to a sink set with [`UploadConfig::trace`](crate::UploadConfig::trace),
+//! and the last few are always kept, to add to the error when an upload fails.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Debug, Display, Formatter, Write};
+use std::sync::Arc;
+
+use color_eyre::{Report, Section, SectionExt};
+
+use crate::hci::{opcode_name, VENDOR_PACKET};
+
+/// How many frames are kept for the error of a failed upload.
+const HISTORY_SIZE: usize = 8;
+/// How many bytes of every frame are kept for that, enough for the header and the opcode.
+const HISTORY_SAMPLE_SIZE: usize = 24;
+
+/// Which way a frame went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A frame that was sent to or received from the board.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TracedFrame {
+    pub direction: Direction,
+    /// The sequence number in the header of the frame.
+    pub seq: u8,
+    /// The acknowledgement number in the header of the frame.
+    pub ack: u8,
+    /// The opcode of the DFU packet in the frame, if it has one.
+    pub opcode: Option<u32>,
+    /// The frame as it went over the wire for frames we sent, so escaped and between two ENDs,
+    /// and unescaped for frames we received.
+    pub bytes: Vec<u8>,
+    /// How long the frame was, `bytes` may only be the start of it.
+    pub len: usize,
+}
+
+impl TracedFrame {
+    /// Annotate a frame with what its (unescaped) header and payload say.
+    pub(crate) fn new(direction: Direction, unescaped: &[u8], bytes: &[u8]) -> Self {
+        let (seq, ack, opcode) = match unescaped {
+            [b0, b1, _, _, payload @ ..] => {
+                let opcode = payload
+                    .first_chunk::<4>()
+                    .filter(|_| b1 & 0x0f == VENDOR_PACKET)
+                    .map(|o| u32::from_le_bytes(*o));
+                (b0 & 0x07, b0 >> 3 & 0x07, opcode)
+            }
+            _ => (0, 0, None),
+        };
+        Self {
+            direction,
+            seq,
+            ack,
+            opcode,
+            bytes: bytes.to_vec(),
+            len: bytes.len(),
+        }
+    }
+
+    /// The same frame, with only the start of the bytes.
+    fn sample(&self) -> Self {
+        Self {
+            bytes: self.bytes[..self.len.min(HISTORY_SAMPLE_SIZE)].to_vec(),
+            ..self.clone()
+        }
+    }
+}
+
+impl Display for TracedFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let arrow = match self.direction {
+            Direction::Sent => "->",
+            Direction::Received => "<-",
+        };
+        write!(f, "{arrow} seq {} ack {}", self.seq, self.ack)?;
+        match self.opcode {
+            Some(opcode) => write!(f, " {:<8}", opcode_name(opcode))?,
+            None => write!(f, " {:<8}", "")?,
+        }
+        for b in &self.bytes {
+            write!(f, " {b:02x}")?;
+        }
+        if self.bytes.len() < self.len {
+            write!(f, " .. ({} bytes)", self.len)?;
+        }
+        Ok(())
+    }
+}
+
+type SinkFn = dyn Fn(&TracedFrame) + Send + Sync;
+
+/// Where traced frames go, set with [`UploadConfig::trace`](crate::UploadConfig::trace).
+#[derive(Clone)]
+pub(crate) struct TraceSink(pub(crate) Arc<SinkFn>);
+
+impl Debug for TraceSink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("TraceSink")
+    }
+}
+
+/// The last few frames, and where to trace all of them to.
+#[derive(Default)]
+pub(crate) struct FrameLog {
+    pub(crate) sink: Option<TraceSink>,
+    recent: VecDeque<TracedFrame>,
+}
+
+impl FrameLog {
+    pub(crate) fn record(&mut self, direction: Direction, unescaped: &[u8], bytes: &[u8]) {
+        // only the start of the frame is copied when there is no sink to hand all of it to
+        let sample = &bytes[..bytes.len().min(HISTORY_SAMPLE_SIZE)];
+        let frame = match &self.sink {
+            Some(TraceSink(sink)) => {
+                let frame = TracedFrame::new(direction, unescaped, bytes);
+                sink(&frame);
+                frame.sample()
+            }
+            None => TracedFrame {
+                len: bytes.len(),
+                ..TracedFrame::new(direction, unescaped, sample)
+            },
+        };
+
+        if self.recent.len() == HISTORY_SIZE {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(frame);
+    }
+
+    /// Add the last frames to the error of a failed upload.
+    pub(crate) fn attach_to(&self, e: Report) -> Report {
+        if self.recent.is_empty() {
+            return e;
+        }
+        let mut frames = String::new();
+        for frame in &self.recent {
+            let _ = writeln!(frames, "{frame}");
+        }
+        e.section(frames.trim_end().to_string().header("Last frames:"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Direction, FrameLog, TraceSink, TracedFrame, HISTORY_SIZE};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_traced_frame() {
+        // a start packet with sequence number 1, and the ack for it
+        let unescaped = [0xc9, 0x4e, 0x01, 0xe8, 3, 0, 0, 0, 4];
+        let frame = TracedFrame::new(Direction::Sent, &unescaped, &[0xc0, 0xc9, 0x4e, 0xc0]);
+        assert_eq!((frame.seq, frame.ack, frame.opcode), (1, 1, Some(3)));
+        assert_eq!(frame.to_string(), "-> seq 1 ack 1 start    c0 c9 4e c0");
+
+        let ack = TracedFrame::new(
+            Direction::Received,
+            &[0x10, 0, 0, 0xf0],
+            &[0x10, 0, 0, 0xf0],
+        );
+        assert_eq!(ack.to_string(), "<- seq 0 ack 2          10 00 00 f0");
+
+        let long = TracedFrame {
+            len: 100,
+            ..ack.clone()
+        };
+        assert_eq!(
+            long.to_string(),
+            "<- seq 0 ack 2          10 00 00 f0 .. (100 bytes)"
+        );
+    }
+
+    #[test]
+    fn test_frame_log() {
+        let traced = Arc::new(Mutex::new(Vec::new()));
+        let sink = traced.clone();
+        let mut log = FrameLog {
+            sink: Some(TraceSink(Arc::new(move |f: &TracedFrame| {
+                sink.lock().unwrap().push(f.len)
+            }))),
+            ..FrameLog::default()
+        };
+        for len in 4..20 {
+            log.record(Direction::Received, &[0; 4], &vec![0; len * 4]);
+        }
+
+        // the sink gets every frame, whole
+        assert_eq!(
+            *traced.lock().unwrap(),
+            (4..20).map(|l| l * 4).collect::<Vec<_>>()
+        );
+        // only the last few are kept, with the start of the bytes
+        assert_eq!(log.recent.len(), HISTORY_SIZE);
+        assert!(log.recent.iter().all(|f| f.bytes.len() == 24 && f.len > 24));
+    }
+}
@@ -0,0 +1,549 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{bail, WrapErr};
+use color_eyre::{Help, Report, Result};
+#[cfg(feature = "ftdi")]
+use libftd2xx::{FtStatus, Ftdi};
+use serial2::{CharSize, FlowControl, SerialPort};
+
+use crate::clock::Clock;
+use crate::emulator::Emulator;
+use crate::selector::SIMULATED_PORT;
+use crate::SERIAL_TIMEOUT;
+
+/// A modem control line of the serial adapter, which some boards have wired to the reset pin
+/// of the chip, see [`UploadConfig::reset_before_upload`](crate::UploadConfig::reset_before_upload).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlLine {
+    Dtr,
+    /// Also used for the flow control the bootloader needs, so only use this when the board
+    /// doesn't.
+    Rts,
+}
+
+impl Display for ControlLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dtr => f.write_str("DTR"),
+            Self::Rts => f.write_str("RTS"),
+        }
+    }
+}
+
+/// How many data bits there are in a character on the serial line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BitsPerWord {
+    Seven,
+    #[default]
+    Eight,
+}
+
+/// The parity bit after the data bits of a character.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Parity {
+    #[default]
+    None,
+    Odd,
+    Even,
+}
+
+/// How many stop bits end a character.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StopBits {
+    #[default]
+    One,
+    Two,
+}
+
+/// The framing of the characters on the serial line, which has to be what the bootloader was
+/// built with. Defaults to the 8N1 of the bootloaders on the lab boards.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LineSettings {
+    pub bits: BitsPerWord,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Display for LineSettings {
+    /// The usual short form, like `8N1` or `8E1`.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let bits = match self.bits {
+            BitsPerWord::Seven => 7,
+            BitsPerWord::Eight => 8,
+        };
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        write!(f, "{bits}{parity}{stop_bits}")
+    }
+}
+
+/// The byte-level connection a [`Serial`](crate::serial::Serial) speaks the DFU protocol over.
+///
+/// On real hardware this is the FTDI chip on the drone board, either through the D2XX driver
+/// (the `ftdi` feature, the default) or through the serial port of the operating system when
+/// the feature is disabled. Anything that can move bytes (like the bootloader emulator used in
+/// tests) can stand in for it.
+pub trait Transport {
+    /// Fill the whole buffer, or fail when the read timeout expires first.
+    fn read_all(&mut self, buf: &mut [u8]) -> Result<()>;
+
+    /// Read whatever arrives before the read timeout expires, up to the size of the buffer.
+    /// Returns the number of bytes read, which is 0 when nothing arrived at all.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+    /// Write the whole buffer, or fail when the write timeout expires first.
+    fn write_all(&mut self, buf: &[u8]) -> Result<()>;
+
+    /// Change how long reads and writes wait before they time out.
+    /// Transports that never time out can ignore this.
+    fn set_timeouts(&mut self, _read: Duration, _write: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    /// Change how long the USB serial adapter holds on to received bytes before passing on a
+    /// packet that isn't full. Transports that don't have such a timer ignore this.
+    fn set_latency_timer(&mut self, _timer: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    /// Change the baud rate and flow control, like for the program that was uploaded. The other
+    /// line settings stay as they are. Transports without line settings can ignore this.
+    fn reconfigure(&mut self, _baud_rate: u32, _flow_control: FlowControl) -> Result<()> {
+        Ok(())
+    }
+
+    /// Assert (when `active`) or release a modem control line. Fails for transports that don't
+    /// have them.
+    fn set_control_line(&mut self, line: ControlLine, _active: bool) -> Result<()> {
+        bail!("the port has no {line} line")
+    }
+
+    /// Throw away everything that was received but not read yet.
+    fn clear_input(&mut self) -> Result<()> {
+        let mut buf = [0u8; 64];
+        while self.read(&mut buf)? > 0 {}
+        Ok(())
+    }
+
+    /// Throw away everything that was written but not sent yet. Transports that send everything
+    /// before a write returns can ignore this.
+    fn clear_output(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The serial number of the USB serial adapter, when the transport knows it.
+    fn serial_number(&mut self) -> Option<String> {
+        None
+    }
+
+    /// The D2XX handle, when this transport is one.
+    #[cfg(feature = "ftdi")]
+    fn as_ftdi_mut(&mut self) -> Option<&mut Ftdi> {
+        None
+    }
+
+    /// Turn the transport back into the D2XX handle, when it is one.
+    #[cfg(feature = "ftdi")]
+    fn into_ftdi(self: Box<Self>) -> Option<Ftdi> {
+        None
+    }
+}
+
+/// A serial port of the operating system, like the virtual COM port driver of the FTDI chip.
+impl Transport for SerialPort {
+    fn read_all(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match SerialPort::read(self, buf) {
+            Ok(n) => Ok(n),
+            Err(e) if e.kind() == ErrorKind::TimedOut => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        SerialPort::write_all(self, buf)?;
+        Ok(())
+    }
+
+    fn set_timeouts(&mut self, read: Duration, write: Duration) -> Result<()> {
+        self.set_read_timeout(read)?;
+        self.set_write_timeout(write)?;
+        Ok(())
+    }
+
+    fn reconfigure(&mut self, baud_rate: u32, flow_control: FlowControl) -> Result<()> {
+        let mut settings = self
+            .get_configuration()
+            .wrap_err("failed to read the settings of the serial port")?;
+        settings.set_baud_rate(baud_rate).wrap_err_with(|| {
+            format!("the serial port doesn't support a baud rate of {baud_rate}")
+        })?;
+        settings.set_flow_control(flow_control);
+        self.set_configuration(&settings)
+            .wrap_err("failed to configure the serial port")
+    }
+
+    fn set_control_line(&mut self, line: ControlLine, active: bool) -> Result<()> {
+        match line {
+            ControlLine::Dtr => self.set_dtr(active)?,
+            ControlLine::Rts => self.set_rts(active)?,
+        }
+        Ok(())
+    }
+
+    fn clear_input(&mut self) -> Result<()> {
+        self.discard_input_buffer()?;
+        Ok(())
+    }
+
+    fn clear_output(&mut self) -> Result<()> {
+        self.discard_output_buffer()?;
+        Ok(())
+    }
+}
+
+/// Open the serial port at `path` for an upload at `baud_rate` and with `line`, with the backend picked by the
+/// `ftdi` feature: the D2XX driver when it is enabled, and the serial port of the operating
+/// system (which needs no driver from FTDI) when it isn't.
+///
+/// When D2XX can't find or open the device behind a serial port of the operating system, like
+/// when the driver isn't installed or the kernel driver has the device, that port is used
+/// instead, after a notice.
+///
+/// The path [`SIMULATED_PORT`] opens a simulated board.
+pub(crate) fn open_port(
+    path: &Path,
+    baud_rate: u32,
+    line: LineSettings,
+) -> Result<Box<dyn Transport + Send>> {
+    if path == Path::new(SIMULATED_PORT) {
+        return Ok(Box::new(Emulator::simulated()));
+    }
+
+    #[cfg(feature = "ftdi")]
+    let port: Box<dyn Transport + Send> = match crate::ftdi::open(path) {
+        Ok(mut port) => {
+            crate::ftdi::configure(&mut port, baud_rate, line)?;
+            Box::new(port)
+        }
+        Err(e) if can_fall_back(path) => match open_serial_port(path, baud_rate, line) {
+            Ok(port) => {
+                eprintln!(
+                    "NOTE: {e}, using the serial port of the operating system instead, which may not do hardware flow control"
+                );
+                Box::new(port)
+            }
+            Err(fallback) => {
+                return Err(e
+                    .note(format!(
+                        "opening it as a serial port failed too: {fallback}"
+                    ))
+                    .suggestion(crate::doctor::DRIVER_INSTALL))
+            }
+        },
+        Err(e) => return Err(e),
+    };
+
+    #[cfg(not(feature = "ftdi"))]
+    let port = Box::new(open_serial_port(path, baud_rate, line)?);
+
+    Ok(port)
+}
+
+/// Whether a port D2XX failed to open can be opened as a serial port of the operating system
+/// instead, which the `ftdi:` paths of devices picked by D2XX aren't.
+#[cfg(feature = "ftdi")]
+fn can_fall_back(path: &Path) -> bool {
+    let picked_by_d2xx = path
+        .to_str()
+        .is_some_and(|p| p.starts_with(crate::ftdi::PATH_PREFIX));
+    !picked_by_d2xx && path.exists()
+}
+
+fn open_serial_port(path: &Path, baud_rate: u32, line: LineSettings) -> Result<SerialPort> {
+    let mut port = SerialPort::open(path, baud_rate)
+        .wrap_err_with(|| format!("failed to open serial port {path:?}"))?;
+    configure_serial_port(&mut port, baud_rate, line)?;
+    Transport::set_timeouts(&mut port, SERIAL_TIMEOUT, SERIAL_TIMEOUT)?;
+    port.discard_buffers()
+        .wrap_err("failed to clear the buffers of the serial port")?;
+    Ok(port)
+}
+
+/// How long to wait before opening a busy port again, doubling for every attempt after that.
+const OPEN_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Whether opening a port failed because something still has it open, which can pass by
+/// itself: the driver holds on to a device for a moment after the program that had it exited.
+pub(crate) fn is_busy(e: &Report) -> bool {
+    e.chain().any(|cause| {
+        #[cfg(feature = "ftdi")]
+        if let Some(status) = cause.downcast_ref::<FtStatus>() {
+            return matches!(
+                status,
+                FtStatus::DEVICE_NOT_OPENED | FtStatus::IO_ERROR | FtStatus::INSUFFICIENT_RESOURCES
+            );
+        }
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == ErrorKind::ResourceBusy)
+    })
+}
+
+/// Run `open`, and while it fails because the port is busy, again after a short wait, for at
+/// most `attempts` attempts in total.
+pub(crate) fn open_with_retries<T>(
+    path: &Path,
+    attempts: usize,
+    clock: &dyn Clock,
+    mut open: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut delay = OPEN_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match open() {
+            Err(e) if is_busy(&e) && attempt < attempts => {
+                clock.sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) if is_busy(&e) => {
+                return Err(e
+                    .wrap_err(format!("{path:?} was still busy after {attempts} attempts to open it"))
+                    .suggestion("another program may have the port open, like a serial monitor or an upload that is still running: close it, or unplug the board and plug it back in"))
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Run `open` on a thread of its own, and give up on it after `timeout`. A driver that hangs
+/// while opening a device in a bad state then only costs the timeout. The thread is left to
+/// finish by itself, and closes the port again if it still gets it.
+pub(crate) fn open_with_timeout<T: Send + 'static>(
+    path: &Path,
+    timeout: Duration,
+    open: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let _ = tx.send(open());
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(res) => res,
+        Err(RecvTimeoutError::Timeout) => bail!(
+            "opening {path:?} didn't finish within {:.1}s, the device may be stuck: unplug it and plug it back in",
+            timeout.as_secs_f64()
+        ),
+        Err(RecvTimeoutError::Disconnected) => bail!("opening {path:?} panicked"),
+    }
+}
+
+/// Configure a serial port the way the bootloader expects: raw at `baud_rate` and with `line`
+/// (8N1 by default), with RTS/CTS flow control. Fails when the port doesn't take those settings.
+pub(crate) fn configure_serial_port(
+    port: &mut SerialPort,
+    baud_rate: u32,
+    line: LineSettings,
+) -> Result<()> {
+    let char_size = match line.bits {
+        BitsPerWord::Seven => CharSize::Bits7,
+        BitsPerWord::Eight => CharSize::Bits8,
+    };
+    let parity = match line.parity {
+        Parity::None => serial2::Parity::None,
+        Parity::Odd => serial2::Parity::Odd,
+        Parity::Even => serial2::Parity::Even,
+    };
+    let stop_bits = match line.stop_bits {
+        StopBits::One => serial2::StopBits::One,
+        StopBits::Two => serial2::StopBits::Two,
+    };
+
+    let mut settings = port
+        .get_configuration()
+        .wrap_err("failed to read the settings of the serial port")?;
+    settings.set_raw();
+    settings
+        .set_baud_rate(baud_rate)
+        .wrap_err_with(|| format!("the serial port doesn't support a baud rate of {baud_rate}"))?;
+    settings.set_char_size(char_size);
+    settings.set_stop_bits(stop_bits);
+    settings.set_parity(parity);
+    settings.set_flow_control(FlowControl::RtsCts);
+    port.set_configuration(&settings)
+        .wrap_err("failed to configure the serial port")?;
+
+    // some drivers accept settings they don't support, and quietly use something else
+    let applied = port
+        .get_configuration()
+        .wrap_err("failed to read the settings of the serial port")?;
+    let applied_baud_rate = applied.get_baud_rate()?;
+    if applied_baud_rate != baud_rate {
+        bail!("the serial port didn't accept a baud rate of {baud_rate}, it is set to {applied_baud_rate} instead");
+    }
+    if applied.get_char_size()? != char_size
+        || applied.get_parity()? != parity
+        || applied.get_stop_bits()? != stop_bits
+    {
+        bail!("the serial port didn't accept {line} line settings");
+    }
+    if applied.get_flow_control()? != FlowControl::RtsCts {
+        bail!("the serial port didn't accept RTS/CTS flow control, which the bootloader needs");
+    }
+    Ok(())
+}
+
+/// Passes everything on to another transport, until a deadline passes.
+pub(crate) struct DeadlineTransport<'a> {
+    pub(crate) inner: &'a mut dyn Transport,
+    pub(crate) clock: &'a dyn Clock,
+    pub(crate) deadline: Instant,
+}
+
+impl DeadlineTransport<'_> {
+    fn check(&self) -> Result<()> {
+        if self.clock.now() >= self.deadline {
+            bail!("timed out");
+        }
+        Ok(())
+    }
+}
+
+impl Transport for DeadlineTransport<'_> {
+    fn read_all(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.check()?;
+        self.inner.read_all(buf)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.check()?;
+        self.inner.read(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        self.check()?;
+        self.inner.write_all(buf)
+    }
+
+    fn set_timeouts(&mut self, read: Duration, write: Duration) -> Result<()> {
+        self.inner.set_timeouts(read, write)
+    }
+
+    fn set_control_line(&mut self, line: ControlLine, active: bool) -> Result<()> {
+        self.check()?;
+        self.inner.set_control_line(line, active)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, ErrorKind};
+    use std::path::Path;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    use color_eyre::eyre::{bail, WrapErr};
+
+    use super::{open_with_retries, open_with_timeout, LineSettings, Parity, StopBits};
+    use crate::clock::FakeClock;
+
+    #[test]
+    fn test_open_with_timeout() {
+        let path = Path::new("/dev/ttyUSB0");
+        assert_eq!(
+            open_with_timeout(path, Duration::from_secs(1), || Ok(5)).unwrap(),
+            5
+        );
+
+        // a driver that never returns from opening the device
+        let (_never, wait) = channel::<()>();
+        let err = open_with_timeout(path, Duration::from_millis(100), move || {
+            let _ = wait.recv();
+            Ok(())
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "opening \"/dev/ttyUSB0\" didn't finish within 0.1s, the device may be stuck: unplug it and plug it back in"
+        );
+    }
+
+    #[test]
+    fn test_open_with_retries() {
+        let path = Path::new("/dev/ttyUSB0");
+        let clock = FakeClock::new();
+        let busy = || Error::from(ErrorKind::ResourceBusy);
+
+        // busy for a moment, with waits of 100, 200 and 400ms in between
+        let mut attempts = 0;
+        let opened = open_with_retries(path, 5, &clock, || {
+            attempts += 1;
+            if attempts < 4 {
+                return Err(busy()).wrap_err("failed to open serial port");
+            }
+            Ok(attempts)
+        });
+        assert_eq!(opened.unwrap(), 4);
+        assert_eq!(clock.elapsed(), Duration::from_millis(700));
+
+        let err = open_with_retries(path, 3, &clock, || Err::<(), _>(busy().into())).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "\"/dev/ttyUSB0\" was still busy after 3 attempts to open it"
+        );
+
+        // anything else won't pass by waiting
+        let mut attempts = 0;
+        let err = open_with_retries(path, 5, &clock, || -> color_eyre::Result<()> {
+            attempts += 1;
+            bail!("no such device")
+        })
+        .unwrap_err();
+        assert_eq!((err.to_string().as_str(), attempts), ("no such device", 1));
+
+        #[cfg(feature = "ftdi")]
+        {
+            let status = Err::<(), _>(libftd2xx::FtStatus::DEVICE_NOT_OPENED);
+            assert!(super::is_busy(&status.wrap_err("failed").unwrap_err()));
+        }
+    }
+
+    #[cfg(all(feature = "ftdi", unix))]
+    #[test]
+    fn test_can_fall_back() {
+        use super::can_fall_back;
+
+        assert!(can_fall_back(Path::new("/dev/null")));
+        assert!(!can_fall_back(Path::new("/dev/ttyUSB-not-there")));
+        assert!(!can_fall_back(Path::new("ftdi:DK0F3GQL")));
+    }
+
+    #[test]
+    fn test_line_settings() {
+        assert_eq!(LineSettings::default().to_string(), "8N1");
+        let settings = LineSettings {
+            parity: Parity::Even,
+            stop_bits: StopBits::Two,
+            ..LineSettings::default()
+        };
+        assert_eq!(settings.to_string(), "8E2");
+    }
+}
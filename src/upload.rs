@@ -1,31 +1,48 @@
-use crate::serial::Serial;
+use crate::clock::{Clock, FakeClock, SystemClock};
+use crate::config::UploadConfig;
+use crate::dfu::ImageType;
+use crate::elf::{elf_to_bin, objcopy_base, verify_bin, ConversionOptions};
+use crate::history::{self, HistoryEntry};
+use crate::image::{check_vector_table, PreparedImage};
+use crate::recording::Replay;
+use crate::report::{Phase, PhaseTimer, UploadReport};
+use crate::serial::{Cancelled, DeadlineExceeded, Serial};
+use crate::transport::configure_serial_port;
 use crate::{selector, PortSelector};
-use color_eyre::eyre::{bail, eyre, Context};
-use color_eyre::Result;
-use std::fs::read;
+use color_eyre::eyre::{bail, eyre, Context, Report};
+use color_eyre::{Help, Result};
+use serial2::SerialPort;
+use serial_enumerator::get_serial_list;
+use std::env;
+use std::fs::{metadata, read, remove_file};
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::process::{exit, Command};
+use std::sync::Arc;
 
-fn copy_object(source: &Path, target: &Path) -> Result<()> {
-    if Command::new("rust-objcopy").output().is_err() {
-        bail!(
-            "rust-objcopy not found, try installing cargo-binutils or refer to the course website"
-        );
-    }
+/// What [`UploadReport::port`] says for uploads over a port that was opened by the caller.
+const OPEN_PORT_PATH: &str = "<already open port>";
 
-    let op = Command::new("rust-objcopy")
+fn copy_object(
+    objcopy: &str,
+    source: &Path,
+    target: &Path,
+    options: &ConversionOptions,
+) -> Result<()> {
+    let op = Command::new(objcopy)
         .arg("-O")
         .arg("binary")
+        .args(options.objcopy_args())
         .arg(source)
         .arg(target)
         .output()
-        .wrap_err("failed to run rust-objcopy")?;
+        .wrap_err_with(|| format!("failed to run {objcopy}"))?;
 
-    println!("creating binary file at {target:?}");
+    eprintln!("creating binary file at {target:?}");
 
     if !op.status.success() {
         bail!(
-            "running rust-objcopy failed: {}",
+            "running {objcopy} failed: {}",
             String::from_utf8_lossy(&op.stderr)
         );
     }
@@ -33,15 +50,39 @@ fn copy_object(source: &Path, target: &Path) -> Result<()> {
     Ok(())
 }
 
-fn read_file(file: &Path) -> Result<Vec<u8>> {
+fn read_file(file: &Path, config: &UploadConfig) -> Result<Vec<u8>> {
+    if Command::new("rust-objcopy").output().is_err() {
+        eprintln!("rust-objcopy not found, converting elf file to bin file without it");
+        let elf = read(file).wrap_err("failed to read elf file")?;
+        return elf_to_bin(&elf, config.app_start(), &config.conversion);
+    }
+
+    let elf = read(file).wrap_err("failed to read elf file")?;
     let mut target = file.to_path_buf();
     target.set_extension("bin");
+    // so a binary left over from an earlier build is never sent
+    match remove_file(&target) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).wrap_err_with(|| format!("failed to remove old binary file {target:?}"));
+        }
+        _ => {}
+    }
 
-    println!("converting elf file to bin file");
-    copy_object(file, &target)?;
+    eprintln!("converting elf file to bin file");
+    copy_object("rust-objcopy", file, &target, &config.conversion)?;
+
+    if !metadata(&target).is_ok_and(|m| m.len() > 0) {
+        bail!("rust-objcopy didn't create {target:?}, or left it empty");
+    }
 
-    println!("reading binary file");
-    read(target).wrap_err("failed to read converted binary file to send to board")
+    eprintln!("reading binary file");
+    let bin = read(&target).wrap_err("failed to read converted binary file to send to board")?;
+
+    let base = objcopy_base(&elf, &config.conversion)?.unwrap_or(config.app_start());
+    verify_bin(&elf, &bin, base, &config.conversion)
+        .wrap_err_with(|| format!("the binary rust-objcopy created at {target:?} is wrong"))?;
+
+    Ok(bin)
 }
 
 /// Upload a file to a connected board. Select which serial port the board is on with the [`PortSelector`].
@@ -51,7 +92,7 @@ fn read_file(file: &Path) -> Result<Vec<u8>> {
 /// Returns a path to a serial port over which uploading happened. This path can be used to communicate with the board.
 pub fn upload_file_or_stop(port: PortSelector, file: Option<impl AsRef<Path>>) -> PathBuf {
     if let Some(file) = file {
-        match read_file(file.as_ref())
+        match read_file(file.as_ref(), &UploadConfig::default())
             .wrap_err_with(|| format!("failed to read from file {:?}", file.as_ref()))
         {
             Ok(i) => upload_or_stop(port, i, false),
@@ -75,7 +116,7 @@ pub fn upload_file(port: PortSelector, file: Option<impl AsRef<Path>>) -> Result
         port,
         file.as_ref()
             .map(|f| {
-                read_file(f.as_ref())
+                read_file(f.as_ref(), &UploadConfig::default())
                     .wrap_err_with(|| format!("failed to read from file {:?}", f.as_ref()))
             })
             .transpose()?
@@ -84,6 +125,25 @@ pub fn upload_file(port: PortSelector, file: Option<impl AsRef<Path>>) -> Result
     )
 }
 
+/// Upload a file to a connected board, like [`upload_file`], but with the upload tuned by an [`UploadConfig`].
+/// The file is expected to be the compiled `.elf` file created by cargo/rustc
+/// Returns an error when the upload fails.
+///
+/// Returns an [`UploadReport`] describing the upload, which includes the path to the serial port over which uploading happened.
+pub fn upload_file_with_config(
+    port: PortSelector,
+    file: impl AsRef<Path>,
+    config: &UploadConfig,
+) -> Result<UploadReport> {
+    let file = file.as_ref();
+    let mut timer = PhaseTimer::new(Arc::new(SystemClock));
+    let bin =
+        read_file(file, config).wrap_err_with(|| format!("failed to read from file {:?}", file))?;
+    timer.lap(Phase::Convert);
+
+    upload_internal(port, &bin, false, config, timer).map(|(r, _)| r)
+}
+
 /// Upload (already read) bytes to a connected board. Select which serial port the board is on with the [`PortSelector`]
 /// The bytes are the exact bytes that are uploaded to the board. That means it should be a binary file, and *not* contain
 /// ELF headers or similar
@@ -107,44 +167,317 @@ pub fn upload_or_stop(port: PortSelector, file: impl AsRef<[u8]>, dry_run: bool)
 ///
 /// Returns a path to a serial port over which uploading happened. This path can be used to communicate with the board.
 pub fn upload(port: PortSelector, file: impl AsRef<[u8]>, dry_run: bool) -> Result<PathBuf> {
-    upload_internal(port, file.as_ref(), dry_run)
+    let config = UploadConfig::default();
+    upload_internal(port, file.as_ref(), dry_run, &config, system_timer()).map(|(r, _)| r.port)
+}
+
+/// Upload (already read) bytes to a connected board, like [`upload`], but with the upload tuned by an [`UploadConfig`].
+/// Returns an error when the upload fails.
+///
+/// Returns an [`UploadReport`] describing the upload, which includes the path to the serial port over which uploading happened.
+pub fn upload_with_config(
+    port: PortSelector,
+    file: impl AsRef<[u8]>,
+    config: &UploadConfig,
+) -> Result<UploadReport> {
+    upload_internal(port, file.as_ref(), false, config, system_timer()).map(|(r, _)| r)
+}
+
+/// Upload (already read) bytes to a connected board, like [`upload`], but return an
+/// [`UploadReport`] with how long it took, how many chunks were sent and how often they had
+/// to be sent again instead of only the path to the port.
+pub fn upload_with_report(port: PortSelector, file: impl AsRef<[u8]>) -> Result<UploadReport> {
+    upload_with_config(port, file, &UploadConfig::default())
+}
+
+/// Upload (already read) bytes to a connected board, like [`upload_with_config`], but keep the
+/// port open afterwards instead of closing it.
+///
+/// Returns the open [`Serial`] together with the path of its port, to communicate with the
+/// program that was just uploaded. Opening the same FTDI device again right after closing it
+/// fails now and then on macOS, which this avoids.
+pub fn upload_keep_open(
+    port: PortSelector,
+    file: impl AsRef<[u8]>,
+    config: &UploadConfig,
+) -> Result<(Serial, PathBuf)> {
+    let (_, serial) = upload_internal(port, file.as_ref(), false, config, system_timer())?;
+    let path = serial.path.clone();
+    Ok((serial, path))
+}
+
+/// Wipe the application on a connected board, without uploading a new one, to return it to a
+/// clean state. The board stays in the bootloader until a new application is uploaded.
+///
+/// The [`UploadConfig`] picks the board, whose whole application flash is erased, and how to
+/// reach its bootloader.
+///
+/// Returns the path to the serial port of the board that was erased.
+pub fn erase(port: PortSelector, config: &UploadConfig) -> Result<PathBuf> {
+    config.validate()?;
+    let (paths, stop_after_first_error) = select_ports(port, config)?;
+
+    for path in paths {
+        let res =
+            Serial::open_with_config(path.clone(), config).and_then(|mut port| port.erase(config));
+        match res {
+            Ok(()) => {
+                eprintln!("erased the application on {path:?}");
+                return Ok(path);
+            }
+            Err(e) => eprintln!(
+                "WARNING: {:?}",
+                e.wrap_err(format!("failed to erase on {path:?}"))
+            ),
+        }
+
+        if stop_after_first_error {
+            break;
+        }
+    }
+
+    Err(eyre!("No board was erased (see previous warnings)")
+        .suggestion("Make sure the board is in the bootloader, or turn it off and on again"))
+}
+
+/// Check that the serial adapter can really talk at `baud_rate`, by sending bytes to itself.
+/// TX has to be connected to RX of the adapter for this, instead of to a board.
+///
+/// Returns the path to the serial port that passed the test.
+pub fn loopback_test(port: PortSelector, baud_rate: u32) -> Result<PathBuf> {
+    let config = UploadConfig::default().baud_rate(baud_rate);
+    config.validate()?;
+    let (paths, _) = select_ports(port, &config)?;
+    let Some(path) = paths.into_iter().next() else {
+        bail!("No serial port to test");
+    };
+
+    Serial::open_with_config(path.clone(), &config)
+        .and_then(|mut port| port.loopback_test())
+        .wrap_err_with(|| format!("the loopback test at {baud_rate} baud failed on {path:?}"))?;
+    eprintln!("the loopback test at {baud_rate} baud passed on {path:?}");
+    Ok(path)
+}
+
+/// Recover a board whose bootloader is stuck in an upload that was interrupted halfway. Such a
+/// bootloader ignores new start packets until it is turned off and on again, unless it is told
+/// to stop the old upload first, which is what this does.
+///
+/// The port is opened, and waited for, with the settings and timeouts of `config`. With
+/// [`UploadConfig::reset_before_upload`], the board is reset back into the bootloader once it
+/// stopped, otherwise it has to be reset by hand for the next upload.
+///
+/// Returns the path to the serial port on which the bootloader responded.
+pub fn abort_dfu(port: PortSelector, config: &UploadConfig) -> Result<PathBuf> {
+    config.validate()?;
+    let (paths, stop_after_first_error) = select_ports(port, config)?;
+
+    for path in paths {
+        let res = Serial::open_with_config(path.clone(), config)
+            .and_then(|mut port| port.abort_with_config(config));
+        match res {
+            Ok(true) => {
+                eprintln!("the bootloader on {path:?} stopped the interrupted upload");
+                return Ok(path);
+            }
+            Ok(false) => eprintln!("WARNING: no response from a bootloader on {path:?}"),
+            Err(e) => eprintln!(
+                "WARNING: {:?}",
+                e.wrap_err(format!("failed to abort on {path:?}"))
+            ),
+        }
+
+        if stop_after_first_error {
+            break;
+        }
+    }
+
+    Err(eyre!("No bootloader responded to the stop packet")
+        .suggestion("Make sure the board is in the bootloader, or turn it off and on again"))
+}
+
+/// [`selector::select`] on the ports of this machine and its environment variables.
+pub(crate) fn select_ports(
+    port: PortSelector,
+    config: &UploadConfig,
+) -> Result<(Vec<PathBuf>, bool)> {
+    let (paths, stop_after_first_error) =
+        selector::select(&port, config, &get_serial_list, &|name| env::var(name).ok())?;
+
+    // several paths can be the same FTDI device, which can only be opened once at a time
+    #[cfg(feature = "ftdi")]
+    let paths = match paths.len() {
+        0 | 1 => paths,
+        _ => crate::ftdi::distinct_devices(paths)?,
+    };
+
+    Ok((paths, stop_after_first_error))
 }
 
-fn upload_internal(port: PortSelector<'_>, file: &[u8], dry_run: bool) -> Result<PathBuf> {
+fn system_timer() -> PhaseTimer {
+    PhaseTimer::new(Arc::new(SystemClock))
+}
+
+/// Upload to the port(s) `port` selects. The phases `timer` measured before, like the
+/// conversion of an ELF file, go in front of the ones of the upload in the report.
+fn upload_internal(
+    port: PortSelector,
+    file: &[u8],
+    dry_run: bool,
+    config: &UploadConfig,
+    mut timer: PhaseTimer,
+) -> Result<(UploadReport, Serial)> {
     if dry_run && matches!(port, PortSelector::SearchAll) {
         bail!("can't use dry_run in SearchAll mode");
     }
+    check_image(file, dry_run, config)?;
+    let image = &PreparedImage::new(file, config);
 
-    let (ports_to_try, stop_after_first_error): (Vec<Result<Serial>>, bool) = match port {
-        PortSelector::SearchFirst => (
-            selector::all_serial_ports()
-                .map(PathBuf::from)
-                .map(Serial::open)
-                .collect(),
-            true,
-        ),
-        PortSelector::SearchAll => (
-            selector::all_serial_ports()
-                .map(PathBuf::from)
-                .map(Serial::open)
-                .collect(),
-            false,
-        ),
-        PortSelector::ChooseInteractive => (
-            vec![Serial::open(PathBuf::from(selector::choose_interactive()?))],
-            true,
-        ),
-        PortSelector::Named(n) => (vec![Serial::open(Path::new(n).to_path_buf())], false),
-        PortSelector::AutoManufacturer => (
-            vec![Serial::open(PathBuf::from(
-                selector::find_available_serial_port_by_id()?,
-            ))],
-            true,
-        ),
+    let (mut paths, stop_after_first_error) = select_ports(port, config)?;
+    timer.lap(Phase::Select);
+    if let Some(max_ports) = config.max_ports {
+        for path in paths.iter().skip(max_ports) {
+            eprintln!("skipping {path:?}, only {max_ports} ports are tried");
+        }
+        paths.truncate(max_ports);
+    }
+
+    let searching = !stop_after_first_error && paths.len() > 1;
+    let open = |path: &Path| Serial::open_with_config(path.to_path_buf(), config);
+    let ports_to_try: Vec<Result<Serial>> = paths.iter().map(|path| open(path)).collect();
+    timer.lap(Phase::Open);
+    let (mut report, serial) = upload_to_ports(
+        ports_to_try,
+        stop_after_first_error,
+        searching,
+        image,
+        dry_run,
+        config,
+        &open,
+    )?;
+    report.phases.splice(0..0, timer.finish());
+    Ok((report, serial))
+}
+
+/// Check the config, and that the image fits and looks like an application.
+fn check_image(file: &[u8], dry_run: bool, config: &UploadConfig) -> Result<()> {
+    config.validate()?;
+
+    if !config.force && file.len() > config.image_size_limit() {
+        return Err(eyre!(
+            "the image is {} bytes, but at most {} bytes fit in the flash for the application starting at 0x{:08x}",
+            file.len(),
+            config.image_size_limit(),
+            config.app_start()
+        )
+        .suggestion(
+            "build with `--release` for the target of the board (thumbv6m-none-eabi), debug builds and builds for your own computer are much larger",
+        ));
+    }
+
+    if let ImageType::SoftDeviceAndBootloader { softdevice_size } = config.image_type {
+        if softdevice_size as usize > file.len() {
+            bail!(
+                "the SoftDevice is {softdevice_size} bytes, but the whole image only {} bytes",
+                file.len()
+            );
+        }
+    }
+
+    if !dry_run && !config.force && config.image_type == ImageType::Application {
+        check_vector_table(file, config)?;
+    }
+
+    Ok(())
+}
+
+/// Play back a recording made with [`UploadConfig::record_to`], to reproduce a failed upload
+/// without the board it failed on. The protocol code gets the bytes that were received back then,
+/// at the times they were received, and fails where the recorded upload failed. Use the same
+/// (already read) image and config as the recorded upload, writes that differ from the recorded
+/// ones are an error. Nothing is waited for, the time of the recording passes on a fake clock.
+pub fn replay(
+    recording: impl AsRef<Path>,
+    file: impl AsRef<[u8]>,
+    config: &UploadConfig,
+) -> Result<UploadReport> {
+    let recording = recording.as_ref();
+    let clock = Arc::new(FakeClock::new());
+    let port = Replay::open(recording, clock.clone())?;
+    let image = PreparedImage::new(file.as_ref(), config);
+    // not recorded again over the recording that is played back
+    let config = UploadConfig {
+        record: None,
+        ..config.clone()
     };
+    Serial::with_transport(recording.to_path_buf(), Box::new(port), clock)
+        .try_upload_prepared(&image, &config)
+}
+
+/// Upload (already read) bytes over a serial port that is already open, for example because a
+/// command was sent over it to the running application first. The port is configured the way the
+/// bootloader expects (raw, at the [baud rate](UploadConfig::baud_rate), with RTS/CTS
+/// flow control), which fails when the port doesn't support that.
+///
+/// Returns the port when the upload succeeded, still configured for the bootloader, so it can
+/// be used to communicate with the board.
+pub fn upload_over_port(
+    port: SerialPort,
+    file: impl AsRef<[u8]>,
+    config: &UploadConfig,
+) -> Result<SerialPort> {
+    upload_over_port_with_clock(port, file.as_ref(), config, Arc::new(SystemClock))
+}
+
+fn upload_over_port_with_clock(
+    mut port: SerialPort,
+    file: &[u8],
+    config: &UploadConfig,
+    clock: Arc<dyn Clock>,
+) -> Result<SerialPort> {
+    check_image(file, false, config)?;
+    let image = &PreparedImage::new(file, config);
+    configure_serial_port(&mut port, config.baud(), config.line_settings)?;
 
+    // the upload gets its own handle, so ours comes back untouched when it is done
+    let open = |path: &Path| {
+        let handle = port
+            .try_clone()
+            .wrap_err("failed to duplicate the handle of the serial port")?;
+        Ok(Serial::with_transport(
+            path.to_path_buf(),
+            Box::new(handle),
+            clock.clone(),
+        ))
+    };
+    let serial = open(Path::new(OPEN_PORT_PATH));
+    let (_, serial) = upload_to_ports(vec![serial], true, false, image, false, config, &open)?;
+    // closing the duplicate would purge and release the port we give back
+    drop(serial.into_inner());
+
+    Ok(port)
+}
+
+/// Opens a port again by its path, for the next attempt at an upload.
+type Reopen<'a> = dyn Fn(&Path) -> Result<Serial> + 'a;
+
+/// Upload to the first of these ports that works, and return that port with the report. While
+/// `searching`, the search timeout of the config applies to the start of every upload, so ports
+/// that don't answer are skipped quickly. Otherwise, a failed upload is attempted again on a
+/// port opened with `reopen`, as often as the config says. The deadline of the config counts
+/// from the first port tried, and is checked before every port and attempt.
+fn upload_to_ports(
+    ports_to_try: Vec<Result<Serial>>,
+    stop_after_first_error: bool,
+    searching: bool,
+    image: &PreparedImage,
+    dry_run: bool,
+    config: &UploadConfig,
+    reopen: &Reopen<'_>,
+) -> Result<(UploadReport, Serial)> {
     let mut errors = Vec::new();
     let num_ports = ports_to_try.len();
+    let mut deadline = None;
 
     for i in ports_to_try {
         let mut port = match i {
@@ -160,24 +493,582 @@ fn upload_internal(port: PortSelector<'_>, file: &[u8], dry_run: bool) -> Result
         };
 
         if dry_run {
-            return Ok(port.path);
+            return Ok((UploadReport::new(port.path.clone()), port));
+        }
+        if let Some(d) = config.deadline {
+            port.deadline = Some(*deadline.get_or_insert_with(|| port.now() + d));
+            port.check_deadline()?;
         }
 
-        if let Err(e) = port
-            .try_do_upload(file)
-            .wrap_err_with(|| format!("failed to upload to port {:?}", port.path))
-        {
-            if stop_after_first_error || num_ports == 1 {
-                return Err(e);
+        let attempts = if searching {
+            1
+        } else {
+            config.max_upload_attempts
+        };
+        match upload_with_attempts(port, attempts, searching, image, config, reopen) {
+            Ok(res) => return Ok(res),
+            Err(e) => {
+                if stop_after_first_error
+                    || num_ports == 1
+                    || e.is::<Cancelled>()
+                    || e.is::<DeadlineExceeded>()
+                {
+                    return Err(e);
+                }
+                eprintln!("WARNING: {e}");
+                errors.push(e);
             }
-            eprintln!("WARNING: {e}");
-            errors.push(e);
-            continue;
         }
-        return Ok(port.path);
     }
 
     Err(eyre!(
         "uploading failed because none of the ports tried worked (see previous warnings)"
     ))
 }
+
+/// Upload to `port`, and when that fails, open it again and start over, for at most `attempts`
+/// attempts in total.
+fn upload_with_attempts(
+    mut port: Serial,
+    attempts: usize,
+    searching: bool,
+    image: &PreparedImage,
+    config: &UploadConfig,
+    reopen: &Reopen<'_>,
+) -> Result<(UploadReport, Serial)> {
+    let mut failures = Vec::new();
+    let mut attempt = 1;
+
+    loop {
+        let e = match upload_once(&mut port, searching, image, config) {
+            Ok(report) => return Ok((report, port)),
+            Err(e) => e,
+        };
+        if attempt == attempts || e.is::<Cancelled>() || e.is::<DeadlineExceeded>() {
+            return Err(with_failures(e, &failures));
+        }
+
+        eprintln!("WARNING: {e}");
+        failures.push(format!("{e:#}"));
+        attempt += 1;
+        port.sleep(config.upload_retry_delay);
+        port.check_deadline()
+            .map_err(|e| with_failures(e, &failures))?;
+        eprintln!("trying again, attempt {attempt}/{attempts}");
+
+        // closed before it is opened again, which the D2XX driver needs
+        let path = port.path.clone();
+        let deadline = port.deadline;
+        if let Err(e) = port.close() {
+            eprintln!("WARNING: {e}");
+        }
+        port = reopen(&path).map_err(|e| with_failures(e, &failures))?;
+        port.deadline = deadline;
+    }
+}
+
+/// Add what went wrong in the earlier attempts at an upload to the error of the last one.
+fn with_failures(e: Report, failures: &[String]) -> Report {
+    if failures.is_empty() {
+        return e;
+    }
+
+    let earlier: String = failures
+        .iter()
+        .enumerate()
+        .map(|(i, f)| format!("\n    attempt {}: {f}", i + 1))
+        .collect();
+    e.wrap_err(format!(
+        "the upload failed {} times, the earlier attempts with:{earlier}",
+        failures.len() + 1
+    ))
+}
+
+/// A single attempt at uploading to `port`, recorded in the history when the config says so.
+fn upload_once(
+    port: &mut Serial,
+    searching: bool,
+    image: &PreparedImage,
+    config: &UploadConfig,
+) -> Result<UploadReport> {
+    if searching {
+        port.handshake_timeout = config.search_timeout;
+    }
+
+    let mut entry = config.record_history.then(|| HistoryEntry {
+        adapter_serial: port.adapter_serial(),
+        ..HistoryEntry::new(&port.path, image)
+    });
+    let start = port.now();
+
+    let res = port
+        .try_upload_prepared(image, config)
+        .wrap_err_with(|| format!("failed to upload to port {:?}", port.path));
+
+    if let Some(entry) = &mut entry {
+        entry.duration = port.now() - start;
+        entry.error = res.as_ref().err().map(|e| format!("{e:#}"));
+        history::record(entry, config.history_path.as_deref());
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread::spawn;
+    use std::time::Duration;
+
+    use color_eyre::Result;
+    use serial2::SerialPort;
+
+    use super::{check_image, copy_object, replay, upload_over_port_with_clock, upload_to_ports};
+    use crate::clock::FakeClock;
+    use crate::config::{UploadConfig, DEFAULT_UPLOAD_RETRY_DELAY};
+    use crate::crc::calc_crc16_default;
+    use crate::dfu::InitPacket;
+    use crate::elf::{elf_to_bin, test_application, ConversionOptions};
+    use crate::emulator::{emulator_serial, Emulator};
+    use crate::history::read_history;
+    use crate::image::PreparedImage;
+    use crate::report::Phase;
+    use crate::serial::{Cancelled, DeadlineExceeded, Serial};
+    use crate::transport::Transport;
+    use crate::{elf, PortSelector, SERIAL_TIMEOUT, SIMULATED_PORT};
+
+    #[test]
+    fn test_record_and_replay() {
+        let image: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let recording = std::env::temp_dir().join(format!("upload-{}.jsonl", std::process::id()));
+        let record = |emulator: &Emulator, config: &UploadConfig| {
+            let clock = Arc::new(FakeClock::new());
+            Serial::with_transport(
+                PathBuf::from("/dev/ttyUSB0"),
+                Box::new(emulator.clone().clock(clock.clone())),
+                clock,
+            )
+            .try_do_upload(&image, &config.clone().record_to(&recording))
+        };
+
+        let config = UploadConfig::default().max_retries(2);
+        let report = record(&Emulator::new().noise(b"log\n"), &config).unwrap();
+        let replayed = replay(&recording, &image, &config).unwrap();
+        assert_eq!(
+            (replayed.bytes, replayed.discarded_bytes),
+            (report.bytes, report.discarded_bytes)
+        );
+
+        // an upload that fails because the board stops answering fails the same way again
+        let emulator = (4..10).fold(Emulator::new(), Emulator::drop_frame);
+        let err = record(&emulator, &config).unwrap_err();
+        let replayed = replay(&recording, &image, &config).unwrap_err();
+        assert_eq!(format!("{replayed:#}"), format!("{err:#}"));
+        assert!(format!("{replayed:#}").contains("sent 3 times"));
+
+        // with another image, the upload goes another way
+        let err = replay(&recording, [0u8; 1000], &config).unwrap_err();
+        assert!(format!("{err:#}").contains("was it replayed with another image or config?"));
+        let _ = std::fs::remove_file(&recording);
+    }
+
+    #[test]
+    fn test_search_timeout_skips_slow_ports() {
+        let clock = Arc::new(FakeClock::new());
+        let ports = |emulators: &[&Emulator]| {
+            emulators
+                .iter()
+                .enumerate()
+                .map(|(i, &e)| {
+                    Ok(Serial::with_transport(
+                        PathBuf::from(format!("/dev/ttyUSB{i}")),
+                        Box::new(e.clone()),
+                        clock.clone(),
+                    ))
+                })
+                .collect()
+        };
+        let slow = || {
+            Emulator::new()
+                .clock(clock.clone())
+                .response_time(Duration::from_secs(2))
+        };
+        let image = [0x55; 1000];
+        let config = UploadConfig::default().search_timeout(Duration::from_millis(500));
+
+        let prepared = PreparedImage::new(image, &config);
+        let silent = Emulator::new().clock(clock.clone()).unresponsive();
+        let too_slow = slow();
+        let board = Emulator::new().clock(clock.clone());
+        let (report, _) = upload_to_ports(
+            ports(&[&silent, &too_slow, &board]),
+            false,
+            true,
+            &prepared,
+            false,
+            &config,
+            &not_reopened,
+        )
+        .unwrap();
+
+        assert_eq!(report.port, PathBuf::from("/dev/ttyUSB2"));
+        assert_eq!(board.image(), image);
+        assert!(too_slow.init_packet().is_none());
+        // both ports that didn't work took less time together than a single normal timeout
+        assert!(clock.elapsed() - report.duration < SERIAL_TIMEOUT);
+
+        // the normal timeouts apply when only one port is tried, which is enough for a slow board
+        let slow_board = slow();
+        // when it isn't pinged, which is only answered quickly
+        let ports = ports(&[&slow_board]);
+        let config = config.ping(false);
+        upload_to_ports(ports, true, false, &prepared, false, &config, &not_reopened).unwrap();
+        assert_eq!(slow_board.image(), image);
+    }
+
+    fn not_reopened(path: &Path) -> Result<Serial> {
+        panic!("{path:?} was opened again")
+    }
+
+    #[test]
+    fn test_upload_is_attempted_again() {
+        let clock = Arc::new(FakeClock::new());
+        let image = [0x55; 1000];
+        let config = UploadConfig::default().max_upload_attempts(2);
+        let prepared = PreparedImage::new(image, &config);
+        let upload = |board: &Emulator| {
+            let open = |path: &Path| {
+                Ok(Serial::with_transport(
+                    path.to_path_buf(),
+                    Box::new(board.clone()),
+                    clock.clone(),
+                ))
+            };
+            let port = open(Path::new("/dev/ttyUSB0"));
+            upload_to_ports(vec![port], true, false, &prepared, false, &config, &open)
+        };
+
+        // the ping of the first attempt gets lost
+        let board = Emulator::new().drop_frame(0);
+        upload(&board).unwrap();
+        assert_eq!(board.image(), image);
+        assert!(clock.elapsed() >= DEFAULT_UPLOAD_RETRY_DELAY);
+
+        // and of both attempts
+        let board = Emulator::new().drop_frame(0).drop_frame(1);
+        let err = upload(&board).map(|_| ()).unwrap_err();
+        let message = format!("{err:#}");
+        assert!(
+            message.starts_with("the upload failed 2 times, the earlier attempts with:\n    attempt 1: failed to upload to port \"/dev/ttyUSB0\""),
+            "{message}"
+        );
+        assert!(board.image().is_empty());
+    }
+
+    #[test]
+    fn test_cancelled_upload_is_not_attempted_again() {
+        let board = Emulator::new();
+        let port = emulator_serial(&board);
+        let config = UploadConfig::default()
+            .max_upload_attempts(2)
+            .cancel_flag(Arc::new(AtomicBool::new(true)));
+        let err = upload_to_ports(
+            vec![Ok(port)],
+            true,
+            false,
+            &PreparedImage::new([0; 1000], &config),
+            false,
+            &config,
+            &not_reopened,
+        )
+        .map(|_| ())
+        .unwrap_err();
+        assert!(err.is::<Cancelled>());
+    }
+
+    #[test]
+    fn test_attempts_are_recorded_in_the_history() {
+        let path =
+            std::env::temp_dir().join(format!("upload-history-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let clock = Arc::new(FakeClock::new());
+        // the ping of the first attempt gets lost
+        let board = Emulator::new()
+            .clock(clock.clone())
+            .response_time(Duration::from_millis(5))
+            .drop_frame(0);
+        let config = UploadConfig::default()
+            .max_upload_attempts(2)
+            .record_history_to(&path);
+        let prepared = PreparedImage::new([0x55; 1000], &config);
+        let open = |path: &Path| {
+            Ok(Serial::with_transport(
+                path.to_path_buf(),
+                Box::new(board.clone()),
+                clock.clone(),
+            ))
+        };
+        let port = open(Path::new("/dev/ttyUSB0"));
+        upload_to_ports(vec![port], true, false, &prepared, false, &config, &open).unwrap();
+
+        let entries = read_history(&path, 10).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].error.is_some());
+        assert_eq!(entries[1].error, None);
+        assert_eq!(entries[1].port, PathBuf::from("/dev/ttyUSB0"));
+        assert_eq!(entries[1].sha256, prepared.sha256);
+        // the time on the clock of the port, which only the upload moves on from
+        let waited = entries[0].duration + DEFAULT_UPLOAD_RETRY_DELAY + entries[1].duration;
+        assert!(entries[1].duration > Duration::ZERO);
+        assert!(waited <= clock.elapsed());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_deadline_covers_all_attempts() {
+        let clock = Arc::new(FakeClock::new());
+        let port = Serial::with_transport(
+            PathBuf::from("/dev/ttyUSB0"),
+            Box::new(Emulator::new().unresponsive()),
+            clock.clone(),
+        );
+        // the first attempt fails after the ping, and the deadline passes before the next
+        let config = UploadConfig::default()
+            .max_upload_attempts(3)
+            .deadline(Duration::from_secs(1));
+        let err = upload_to_ports(
+            vec![Ok(port)],
+            true,
+            false,
+            &PreparedImage::new([0; 1000], &config),
+            false,
+            &config,
+            &not_reopened,
+        )
+        .map(|_| ())
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DeadlineExceeded>(),
+            Some(&DeadlineExceeded {
+                chunks_sent: 0,
+                total_chunks: 2
+            }),
+            "{err:?}"
+        );
+        assert!(clock.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_simulated_upload() {
+        let image = test_application(2000);
+        let report =
+            super::upload_with_config(PortSelector::Simulated, &image, &UploadConfig::default())
+                .unwrap();
+        assert_eq!(report.port, PathBuf::from(SIMULATED_PORT));
+        assert_eq!(report.chunks, 4);
+        let phases: Vec<_> = report.phases.iter().map(|p| p.phase).collect();
+        assert_eq!(phases[..3], [Phase::Select, Phase::Open, Phase::Start]);
+
+        // the image is still checked like for a board
+        let config = UploadConfig::default().max_image_size(1000);
+        assert!(super::upload_with_config(PortSelector::Simulated, &image, &config).is_err());
+    }
+
+    #[test]
+    fn test_image_size_limit() {
+        let image = vec![0; 0x0002_4001];
+        let err = check_image(&image, true, &UploadConfig::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the image is 147457 bytes, but at most 147456 bytes fit in the flash for the application starting at 0x00018000"
+        );
+
+        check_image(&image, true, &UploadConfig::default().force(true)).unwrap();
+        check_image(
+            &image,
+            true,
+            &UploadConfig::default().max_image_size(0x0003_0000),
+        )
+        .unwrap();
+        assert!(check_image(
+            &[0; 1001],
+            true,
+            &UploadConfig::default().max_image_size(1000)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_padding() {
+        // the size in the start packet, the CRC in the init packet and the hash in the report
+        // are of the padded image
+        let config = UploadConfig::default();
+        let image = PreparedImage::new([0x55; 1001], &config);
+        let board = Emulator::new();
+        let port = emulator_serial(&board);
+        let (report, _) = upload_to_ports(
+            vec![Ok(port)],
+            true,
+            false,
+            &image,
+            false,
+            &config,
+            &not_reopened,
+        )
+        .unwrap();
+        assert_eq!(board.image_size(), Some(1004));
+        assert_eq!(board.image(), image.bytes());
+        assert_eq!(report.sha256, image.sha256);
+        let init_packet = board.init_packet().unwrap();
+        assert_eq!(
+            InitPacket::parse(&init_packet).unwrap().1[..2],
+            calc_crc16_default(image.bytes()).to_le_bytes()
+        );
+        assert!(config.pad_to(6).validate().is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_upload_over_open_port() {
+        let (ours, mut theirs) = SerialPort::pair().unwrap();
+        theirs.set_read_timeout(Duration::from_millis(5)).unwrap();
+        // the pseudo terminal waits in real time, so the wait after the init packet is ended
+        // by a response instead of by the fake clock
+        let emulator = Emulator::new().banner(b"hello").init_response();
+        let done = Arc::new(AtomicBool::new(false));
+        let bridge = spawn_board(theirs, &emulator, &done);
+        let image = test_application(2000);
+
+        let port = upload_over_port_with_clock(
+            ours,
+            &image,
+            &UploadConfig::default(),
+            Arc::new(FakeClock::new()),
+        )
+        .unwrap();
+        assert_eq!(emulator.image(), image);
+
+        // the port we got back still works, and the application's banner is waiting on it
+        let mut banner = [0; 5];
+        port.read_exact(&mut banner).unwrap();
+        assert_eq!(&banner, b"hello");
+
+        done.store(true, Ordering::Relaxed);
+        bridge.join().unwrap();
+    }
+
+    /// Run `emulator` as the board on the other end of a pseudo terminal, until `done`.
+    #[cfg(unix)]
+    fn spawn_board(
+        mut theirs: SerialPort,
+        emulator: &Emulator,
+        done: &Arc<AtomicBool>,
+    ) -> std::thread::JoinHandle<()> {
+        let mut board = emulator.clone();
+        let done = done.clone();
+        spawn(move || {
+            let mut buf = [0; 256];
+            while !done.load(Ordering::Relaxed) {
+                let n = Transport::read(&mut theirs, &mut buf).unwrap();
+                board.write_all(&buf[..n]).unwrap();
+                let n = board.read(&mut buf).unwrap();
+                Transport::write_all(&mut theirs, &buf[..n]).unwrap();
+            }
+        })
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_upload_over_pty_path() {
+        use std::os::fd::AsRawFd;
+
+        // open the pseudo terminal again by its path, like a port of the operating system, which
+        // is how the serial2 backend (or the fallback of the D2XX one) opens ports
+        let pair = SerialPort::pair().unwrap();
+        let name = |port: &SerialPort| {
+            std::fs::read_link(format!("/proc/self/fd/{}", port.as_raw_fd())).unwrap()
+        };
+        let (terminal, mut board_side) = if name(&pair.1).starts_with("/dev/pts/") {
+            (pair.1, pair.0)
+        } else {
+            (pair.0, pair.1)
+        };
+        let path = name(&terminal);
+        if path.ends_with("ptmx") {
+            eprintln!("the pseudo terminal has no path, not opening it by one");
+            return;
+        }
+        board_side
+            .set_read_timeout(Duration::from_millis(5))
+            .unwrap();
+
+        let emulator = Emulator::new().init_response();
+        let done = Arc::new(AtomicBool::new(false));
+        let bridge = spawn_board(board_side, &emulator, &done);
+        let image = test_application(2000);
+
+        let config = UploadConfig::default();
+        let mut serial = Serial::open_with_config(path, &config).unwrap();
+        serial.try_do_upload(&image, &config).unwrap();
+        serial.close().unwrap();
+        assert_eq!(emulator.image(), image);
+
+        done.store(true, Ordering::Relaxed);
+        bridge.join().unwrap();
+        drop(terminal);
+    }
+
+    /// Compare the in-process converter with an objcopy that is installed, for all these options.
+    fn compare_with_objcopy(objcopy: &str, options: &[ConversionOptions]) {
+        if Command::new(objcopy).arg("--version").output().is_err() {
+            eprintln!("{objcopy} isn't installed, not comparing with it");
+            return;
+        }
+
+        let elf = elf::tests::sections_fixture();
+        let dir = std::env::temp_dir().join(format!("tudelft-objcopy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join(format!("{objcopy}.elf"));
+        let target = dir.join(format!("{objcopy}.bin"));
+        std::fs::write(&source, &elf).unwrap();
+
+        for options in options {
+            copy_object(objcopy, &source, &target, options).unwrap();
+            assert_eq!(
+                std::fs::read(&target).unwrap(),
+                elf_to_bin(&elf, 0x0001_8000, options).unwrap(),
+                "{objcopy} {:?}",
+                options.objcopy_args()
+            );
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_conversion_matches_objcopy() {
+        let mut options = vec![
+            ConversionOptions::default(),
+            ConversionOptions::default().remove_sections(vec![".noinit".into()]),
+            ConversionOptions::default()
+                .remove_sections(vec![".noinit".into()])
+                .keep_data(false),
+            ConversionOptions::default().only_sections(vec![".text".into(), ".rodata".into()]),
+        ];
+
+        // older versions of llvm-objcopy don't know about --gap-fill
+        let help = Command::new("llvm-objcopy").arg("--help").output();
+        if help.is_ok_and(|h| String::from_utf8_lossy(&h.stdout).contains("--gap-fill")) {
+            options.push(ConversionOptions::default().gap_fill(0xff));
+            options.push(
+                ConversionOptions::default()
+                    .remove_sections(vec![".data".into()])
+                    .gap_fill(0xa5),
+            );
+        }
+
+        compare_with_objcopy("llvm-objcopy", &options);
+    }
+}
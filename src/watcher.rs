@@ -0,0 +1,178 @@
+//! Keeping track of which serial ports are connected, for programs that poll for that.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serial_enumerator::{get_serial_list, SerialInfo};
+
+use crate::clock::{Clock, SystemClock};
+
+/// How often a [`PortWatcher`] asks the operating system for the serial ports by default.
+pub const DEFAULT_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The serial ports that appeared and disappeared since the previous [`PortWatcher::refresh`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChangeSet {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl ChangeSet {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// A cached list of the connected serial ports, which is kept up to date with [`refresh`](Self::refresh).
+///
+/// Listing the serial ports walks the device registry of the operating system, which is slow
+/// (and on macOS sometimes hangs for a moment), so the watcher does that at most once per
+/// [`min_interval`](Self::min_interval) however often it is refreshed. That makes it cheap to
+/// call from a UI that wants to update its list of devices a few times per second.
+pub struct PortWatcher {
+    enumerate: Box<dyn FnMut() -> Vec<SerialInfo> + Send>,
+    clock: Arc<dyn Clock + Send + Sync>,
+    min_interval: Duration,
+    last_query: Option<Instant>,
+    ports: Vec<SerialInfo>,
+}
+
+impl Default for PortWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PortWatcher {
+    /// A watcher that hasn't looked at the ports yet. The first [`refresh`](Self::refresh)
+    /// reports every connected port as added.
+    pub fn new() -> Self {
+        Self::with_enumerator(get_serial_list, Arc::new(SystemClock))
+    }
+
+    pub(crate) fn with_enumerator(
+        enumerate: impl FnMut() -> Vec<SerialInfo> + Send + 'static,
+        clock: Arc<dyn Clock + Send + Sync>,
+    ) -> Self {
+        Self {
+            enumerate: Box::new(enumerate),
+            clock,
+            min_interval: DEFAULT_MIN_REFRESH_INTERVAL,
+            last_query: None,
+            ports: Vec::new(),
+        }
+    }
+
+    /// Ask the operating system for the serial ports at most once per this interval.
+    /// Refreshing more often than that returns an empty [`ChangeSet`].
+    pub fn min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    /// The serial ports found by the last refresh.
+    pub fn ports(&self) -> &[SerialInfo] {
+        &self.ports
+    }
+
+    /// Update the list of ports, if the last update was long enough ago, and return what changed.
+    pub fn refresh(&mut self) -> ChangeSet {
+        let now = self.clock.now();
+        if self
+            .last_query
+            .is_some_and(|last| now.duration_since(last) < self.min_interval)
+        {
+            return ChangeSet::default();
+        }
+        self.last_query = Some(now);
+
+        let ports = (self.enumerate)();
+        let changes = ChangeSet {
+            added: names_missing_from(&ports, &self.ports),
+            removed: names_missing_from(&self.ports, &ports),
+        };
+        self.ports = ports;
+
+        changes
+    }
+}
+
+/// The names of the ports in `ports` that aren't in `other`.
+fn names_missing_from(ports: &[SerialInfo], other: &[SerialInfo]) -> Vec<String> {
+    ports
+        .iter()
+        .filter(|p| other.iter().all(|o| o.name != p.name))
+        .map(|p| p.name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use serial_enumerator::SerialInfo;
+
+    use super::{ChangeSet, PortWatcher};
+    use crate::clock::{Clock, FakeClock};
+
+    fn changes(added: &[&str], removed: &[&str]) -> ChangeSet {
+        ChangeSet {
+            added: added.iter().map(|s| s.to_string()).collect(),
+            removed: removed.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_refresh_reports_changes() {
+        let connected = Arc::new(Mutex::new(vec!["/dev/ttyS0", "/dev/ttyUSB0"]));
+        let queries = Arc::new(Mutex::new(0));
+        let clock = Arc::new(FakeClock::new());
+
+        let enumerate = {
+            let connected = connected.clone();
+            let queries = queries.clone();
+            move || {
+                *queries.lock().unwrap() += 1;
+                connected
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|&name| SerialInfo {
+                        name: name.to_string(),
+                        vendor: None,
+                        product: None,
+                        driver: None,
+                        usb_info: None,
+                    })
+                    .collect()
+            }
+        };
+        let mut watcher = PortWatcher::with_enumerator(enumerate, clock.clone())
+            .min_interval(Duration::from_millis(500));
+
+        assert_eq!(
+            watcher.refresh(),
+            changes(&["/dev/ttyS0", "/dev/ttyUSB0"], &[])
+        );
+        assert_eq!(watcher.ports().len(), 2);
+        assert!(watcher.refresh().is_empty());
+
+        *connected.lock().unwrap() = vec!["/dev/ttyS0", "/dev/ttyUSB1"];
+        // too soon, the operating system isn't asked again
+        clock.sleep(Duration::from_millis(100));
+        assert!(watcher.refresh().is_empty());
+        assert_eq!(*queries.lock().unwrap(), 1);
+
+        clock.sleep(Duration::from_millis(400));
+        assert_eq!(
+            watcher.refresh(),
+            changes(&["/dev/ttyUSB1"], &["/dev/ttyUSB0"])
+        );
+        assert_eq!(*queries.lock().unwrap(), 2);
+
+        clock.sleep(Duration::from_secs(1));
+        assert!(watcher.refresh().is_empty());
+        assert_eq!(watcher.ports()[1].name, "/dev/ttyUSB1");
+    }
+}